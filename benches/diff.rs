@@ -0,0 +1,100 @@
+use std::hint::black_box;
+
+use anycoder::coder::{Coder, CURSOR_MARKER};
+use anycoder::diff::compute_text_edits;
+use anycoder::llm::LlmClient;
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion};
+
+const SIZES: &[usize] = &[1_000, 10_000, 100_000];
+
+/// Builds a Rust-looking file with `lines` lines
+fn source(lines: usize) -> String {
+    (0..lines)
+        .map(|i| match i % 4 {
+            0 => format!("fn function_{i}(value: usize) -> usize {{"),
+            1 => format!("    let result = value * {i} + {};", i % 7),
+            2 => "    result".to_string(),
+            _ => "}".to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+/// Rewrites `count` lines spread evenly across the file
+fn change_lines(original: &str, count: usize) -> String {
+    let mut lines: Vec<String> = original.lines().map(str::to_string).collect();
+    let step = (lines.len() / count.max(1)).max(1);
+    for i in (0..lines.len()).step_by(step).take(count) {
+        lines[i] = format!("{} // edited", lines[i]);
+    }
+    lines.join("\n")
+}
+
+/// Small, medium and large changes of a file
+fn changes(original: &str) -> Vec<(&'static str, String)> {
+    let mut small = original.to_string();
+    small.insert(original.len() / 2, 'x');
+
+    vec![
+        ("small", small),
+        ("medium", change_lines(original, 10)),
+        ("large", change_lines(original, 100)),
+    ]
+}
+
+fn bench_compute_text_edits(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compute_text_edits");
+    group.sample_size(10);
+
+    for &lines in SIZES {
+        let old = source(lines);
+        for (change, new) in changes(&old) {
+            group.bench_with_input(
+                BenchmarkId::new(change, lines), &new,
+                |b, new| b.iter(|| compute_text_edits(black_box(&old), black_box(new))),
+            );
+        }
+    }
+
+    group.finish();
+}
+
+fn bench_apply_text_edits(c: &mut Criterion) {
+    let coder = Coder::new(LlmClient::new("", "", ""));
+    let mut group = c.benchmark_group("apply_text_edits");
+    group.sample_size(10);
+
+    for &lines in SIZES {
+        let old = source(lines);
+        for (change, new) in changes(&old) {
+            let edits = compute_text_edits(&old, &new);
+            group.bench_with_input(
+                BenchmarkId::new(change, lines), &edits,
+                |b, edits| b.iter(|| coder.apply_text_edits(black_box(&old), black_box(edits))),
+            );
+        }
+    }
+
+    group.finish();
+}
+
+fn bench_build_context(c: &mut Criterion) {
+    let coder = Coder::new(LlmClient::new("", "", ""));
+    let mut group = c.benchmark_group("build_context");
+
+    for &lines in SIZES {
+        let mut code = source(lines);
+        let cursor = code.len() / 2;
+        let cursor = code[cursor..].find('\n').map_or(cursor, |i| cursor + i);
+        code.insert_str(cursor, CURSOR_MARKER);
+
+        group.bench_with_input(BenchmarkId::from_parameter(lines), &code, |b, code| {
+            b.iter(|| coder.build_context(black_box(code), cursor, 1000))
+        });
+    }
+
+    group.finish();
+}
+
+criterion_group!(benches, bench_compute_text_edits, bench_apply_text_edits, bench_build_context);
+criterion_main!(benches);
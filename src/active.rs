@@ -0,0 +1,145 @@
+use log::{info, warn};
+use regex::Regex;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use crate::utils::{glob_to_regex, relative_slash_path};
+
+/// Files the editor has open, read from a control file with one path or
+/// glob per line. The file is re-read whenever it changes on disk.
+pub struct ActiveFiles {
+    source: PathBuf,
+    modified: Option<SystemTime>,
+    /// `None` while the control file is missing, which lets every file through
+    patterns: Option<Vec<Regex>>,
+}
+
+impl ActiveFiles {
+    pub fn new(source: &Path) -> Self {
+        let mut active = Self { source: source.to_path_buf(), modified: None, patterns: None };
+        active.modified = active.modified_at();
+        active.load();
+        active
+    }
+
+    /// Tells whether events for `path` should be processed
+    pub fn is_active(&mut self, path: &Path) -> bool {
+        self.reload();
+        self.patterns.is_none() || self.lists(path)
+    }
+
+    /// Tells whether the control file names `path`, never while it is missing
+    pub fn lists(&mut self, path: &Path) -> bool {
+        self.reload();
+        let Some(patterns) = &self.patterns else {
+            return false;
+        };
+
+        let path = relative_slash_path(path);
+        patterns.iter().any(|p| p.is_match(&path))
+    }
+
+    /// Loads the control file again if it changed since the last look
+    fn reload(&mut self) {
+        let modified = self.modified_at();
+        if modified != self.modified {
+            self.modified = modified;
+            self.load();
+        }
+    }
+
+    fn load(&mut self) {
+        self.patterns = match std::fs::read_to_string(&self.source) {
+            Ok(content) => {
+                let patterns = parse_patterns(&content);
+                info!("Active files from {:?}: {} patterns", self.source, patterns.len());
+                Some(patterns)
+            }
+            Err(e) => {
+                warn!("Active files {:?} unreadable, watching all files: {}", self.source, e);
+                None
+            }
+        };
+    }
+
+    fn modified_at(&self) -> Option<SystemTime> {
+        std::fs::metadata(&self.source).and_then(|m| m.modified()).ok()
+    }
+}
+
+/// One pattern per line, skipping blanks and `#` comments
+fn parse_patterns(content: &str) -> Vec<Regex> {
+    content.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .filter_map(|line| {
+            // Written with either separator, matched against `/`-separated paths
+            let line = line.replace('\\', "/");
+            let line = line.strip_prefix("./").unwrap_or(&line);
+            glob_to_regex(line)
+                .inspect_err(|e| warn!("Skipping active file pattern {:?}: {}", line, e))
+                .ok()
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_glob_to_regex() {
+        let matches = |glob: &str, path: &str| glob_to_regex(glob).unwrap().is_match(path);
+
+        assert!(matches("src/main.rs", "src/main.rs"));
+        assert!(!matches("src/main.rs", "src/main.rsx"));
+        assert!(matches("src/*.rs", "src/lib.rs"));
+        assert!(!matches("src/*.rs", "src/bin/tool.rs"));
+        assert!(matches("src/**/*.rs", "src/bin/tool.rs"));
+        assert!(matches("src/**/*.rs", "src/lib.rs"));
+        assert!(matches("file?.txt", "file1.txt"));
+        assert!(!matches("a.b", "axb"));
+    }
+
+    #[test]
+    fn test_active_files_hot_reload() -> anyhow::Result<()> {
+        let source = std::env::temp_dir()
+            .join(format!("anycoder-active-{}.txt", std::process::id()));
+        let _ = std::fs::remove_file(&source);
+
+        // Without a control file nothing is filtered
+        let mut active = ActiveFiles::new(&source);
+        assert!(active.is_active(Path::new("src/other.rs")));
+
+        std::fs::write(&source, "# open in editor\n./src/main.rs\nsrc/**/*.py\n")?;
+        assert!(active.is_active(Path::new("src/main.rs")));
+        assert!(active.is_active(Path::new("./src/pkg/app.py")));
+        assert!(!active.is_active(Path::new("src/other.rs")));
+
+        // Make sure the new content gets a different modification time
+        let file = std::fs::File::options().write(true).open(&source)?;
+        std::fs::write(&source, "src/other.rs\n")?;
+        file.set_modified(SystemTime::now() + std::time::Duration::from_secs(5))?;
+        assert!(active.is_active(Path::new("src/other.rs")));
+        assert!(!active.is_active(Path::new("src/main.rs")));
+
+        std::fs::remove_file(&source)?;
+        Ok(())
+    }
+
+    #[test]
+    fn test_windows_style_patterns_and_paths() -> anyhow::Result<()> {
+        let source = std::env::temp_dir()
+            .join(format!("anycoder-active-windows-{}.txt", std::process::id()));
+        std::fs::write(&source, ".\\src\\*.rs\nweb/**/*.ts\n")?;
+
+        let mut active = ActiveFiles::new(&source);
+        let matched = [r"src\main.rs", r".\src\lib.rs", r"web\app\index.ts", "src/main.rs"]
+            .map(|path| active.is_active(Path::new(path)));
+        let other = active.is_active(Path::new(r"src\bin\tool.rs"));
+        std::fs::remove_file(&source)?;
+
+        assert_eq!(matched, [true; 4]);
+        assert!(!other);
+        Ok(())
+    }
+}
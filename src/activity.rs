@@ -0,0 +1,146 @@
+use serde::Serialize;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Where a completion task stands
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Waiting out the coalesce window or for a free slot
+    Queued,
+    Running,
+}
+
+#[derive(Debug, Clone)]
+struct Task {
+    path: PathBuf,
+    phase: Phase,
+    queued_at: Instant,
+    started_at: Option<Instant>,
+}
+
+/// Completion tasks currently queued or running, for status reports.
+/// Clones share the same tasks.
+#[derive(Debug, Clone, Default)]
+pub struct Activity {
+    tasks: Arc<Mutex<HashMap<u64, Task>>>,
+    next_id: Arc<AtomicU64>,
+}
+
+impl Activity {
+    /// Registers a queued task for `path`, listed until the ticket is dropped
+    pub fn queue(&self, path: &Path) -> Ticket {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let task = Task { path: path.to_path_buf(), phase: Phase::Queued, queued_at: Instant::now(), started_at: None };
+        self.lock().insert(id, task);
+        Ticket { activity: self.clone(), id }
+    }
+
+    /// Tasks as of `now`, the longest waiting first
+    pub fn status(&self, now: Instant) -> Status {
+        let mut tasks = self.lock().values()
+            .map(|task| TaskStatus {
+                path: task.path.clone(),
+                phase: task.phase,
+                age_ms: millis(now.saturating_duration_since(task.queued_at)),
+                running_ms: task.started_at.map(|started| millis(now.saturating_duration_since(started))),
+            })
+            .collect::<Vec<_>>();
+        tasks.sort_by(|a, b| b.age_ms.cmp(&a.age_ms).then_with(|| a.path.cmp(&b.path)));
+        let running = tasks.iter().filter(|task| task.phase == Phase::Running).count();
+        Status { running, queued: tasks.len() - running, tasks }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, HashMap<u64, Task>> {
+        self.tasks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+fn millis(duration: Duration) -> u64 {
+    duration.as_millis().try_into().unwrap_or(u64::MAX)
+}
+
+/// A registered task, removed from the activity when dropped, including
+/// when the task holding it is aborted
+pub struct Ticket {
+    activity: Activity,
+    id: u64,
+}
+
+impl Ticket {
+    /// Marks the task as running
+    pub fn start(&self) {
+        if let Some(task) = self.activity.lock().get_mut(&self.id) {
+            task.phase = Phase::Running;
+            task.started_at = Some(Instant::now());
+        }
+    }
+}
+
+impl Drop for Ticket {
+    fn drop(&mut self) {
+        self.activity.lock().remove(&self.id);
+    }
+}
+
+/// Snapshot of the completion tasks
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct Status {
+    pub running: usize,
+    /// Tasks waiting to run, the queue depth
+    pub queued: usize,
+    pub tasks: Vec<TaskStatus>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Serialize)]
+pub struct TaskStatus {
+    pub path: PathBuf,
+    pub phase: Phase,
+    /// Time since the task was queued
+    pub age_ms: u64,
+    /// Time since it started running, unset while queued
+    pub running_ms: Option<u64>,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn test_status_of_queued_and_running_tasks() {
+        let activity = Activity::default();
+        let start = Instant::now();
+        let first = activity.queue(Path::new("src/a.rs"));
+        let second = activity.queue(Path::new("src/b.rs"));
+        first.start();
+
+        let status = activity.status(start + Duration::from_secs(60));
+        assert_eq!((status.running, status.queued), (1, 1));
+        assert_eq!(status.tasks.iter().map(|t| t.phase).collect::<Vec<_>>(), [Phase::Running, Phase::Queued]);
+        assert!(status.tasks.iter().all(|t| t.age_ms >= 59_000));
+        assert!(status.tasks[0].running_ms.is_some() && status.tasks[1].running_ms.is_none());
+
+        drop(first);
+        drop(second);
+        assert_eq!(activity.status(Instant::now()), Status { running: 0, queued: 0, tasks: vec![] });
+    }
+
+    #[test]
+    fn test_status_serializes() -> serde_json::Result<()> {
+        let status = Status {
+            running: 0,
+            queued: 1,
+            tasks: vec![TaskStatus { path: "src/a.rs".into(), phase: Phase::Queued, age_ms: 120, running_ms: None }],
+        };
+        assert_eq!(serde_json::to_value(&status)?, json!({
+            "running": 0,
+            "queued": 1,
+            "tasks": [{ "path": "src/a.rs", "phase": "queued", "age_ms": 120, "running_ms": null }],
+        }));
+        Ok(())
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::Result;
+use std::path::PathBuf;
+
+pub const USAGE: &str = "usage: anycoder [--env-file <path>] [replay <transcript.jsonl> | complete-all [dir] | serve]";
+
+/// What anycoder was asked to do on the command line
+#[derive(Debug, PartialEq)]
+pub enum Command {
+    /// Watch the current directory and complete markers
+    Watch,
+    /// Re-run the completions of a recorded transcript offline
+    Replay(PathBuf),
+    /// Complete every marker found under a directory, then exit
+    CompleteAll(PathBuf),
+    /// Answer JSON-RPC completion requests on stdin, for editors
+    Serve,
+}
+
+/// Command line of one run
+#[derive(Debug, PartialEq)]
+pub struct Args {
+    pub command: Command,
+    /// Env file loaded ahead of `.env` in the current directory
+    pub env_file: Option<PathBuf>,
+}
+
+/// Parses the arguments following the program name
+pub fn parse_args(args: impl IntoIterator<Item = String>) -> Result<Args> {
+    let mut env_file = None;
+    let mut rest = Vec::new();
+    let mut args = args.into_iter();
+    while let Some(arg) = args.next() {
+        match arg.strip_prefix("--env-file") {
+            Some("") => {
+                let path = args.next()
+                    .ok_or_else(|| anyhow::anyhow!("missing path after --env-file\n{}", USAGE))?;
+                env_file = Some(PathBuf::from(path));
+            }
+            Some(value) if value.starts_with('=') => env_file = Some(PathBuf::from(&value[1..])),
+            _ => rest.push(arg),
+        }
+    }
+    Ok(Args { command: parse_command(rest)?, env_file })
+}
+
+fn parse_command(args: Vec<String>) -> Result<Command> {
+    let mut args = args.into_iter();
+    let command = match args.next().as_deref() {
+        None => Command::Watch,
+        Some("replay") => {
+            let path = args.next()
+                .ok_or_else(|| anyhow::anyhow!("missing transcript path\n{}", USAGE))?;
+            Command::Replay(PathBuf::from(path))
+        }
+        Some("complete-all") => {
+            Command::CompleteAll(args.next().map_or_else(|| PathBuf::from("."), PathBuf::from))
+        }
+        Some("serve") => Command::Serve,
+        Some(other) => anyhow::bail!("unknown command {:?}\n{}", other, USAGE),
+    };
+
+    if let Some(extra) = args.next() {
+        anyhow::bail!("unexpected argument {:?}\n{}", extra, USAGE);
+    }
+    Ok(command)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(args: &[&str]) -> Result<Command> {
+        Ok(parse_args(args.iter().map(|a| a.to_string()))?.command)
+    }
+
+    #[test]
+    fn test_parse_args() -> Result<()> {
+        assert_eq!(parse(&[])?, Command::Watch);
+        assert_eq!(
+            parse(&["replay", "session.jsonl"])?,
+            Command::Replay(PathBuf::from("session.jsonl"))
+        );
+
+        assert_eq!(parse(&["complete-all"])?, Command::CompleteAll(PathBuf::from(".")));
+        assert_eq!(parse(&["complete-all", "src"])?, Command::CompleteAll(PathBuf::from("src")));
+        assert_eq!(parse(&["serve"])?, Command::Serve);
+
+        assert!(parse(&["replay"]).is_err());
+        assert!(parse(&["complete-all", "a", "b"]).is_err());
+        assert!(parse(&["replay", "a", "b"]).is_err());
+        assert!(parse(&["lint"]).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_file_option() -> Result<()> {
+        let args = |args: &[&str]| parse_args(args.iter().map(|a| a.to_string()));
+
+        let parsed = args(&["--env-file", "/etc/anycoder.env", "complete-all", "src"])?;
+        assert_eq!(parsed.env_file, Some(PathBuf::from("/etc/anycoder.env")));
+        assert_eq!(parsed.command, Command::CompleteAll(PathBuf::from("src")));
+
+        let parsed = args(&["complete-all", "--env-file=work.env"])?;
+        assert_eq!(parsed.env_file, Some(PathBuf::from("work.env")));
+        assert_eq!(parsed.command, Command::CompleteAll(PathBuf::from(".")));
+
+        assert_eq!(args(&[])?.env_file, None);
+        assert!(args(&["--env-file"]).is_err());
+        Ok(())
+    }
+}
@@ -1,57 +1,799 @@
-use std::path::PathBuf;
-use crate::llm::LlmClient;
-use crate::diff::{compute_text_edits, TextEdit};
-use serde_json::json;
-use crate::prompts::{SYSTEM_PROMPT, REMINDER};
-use crate::utils::{ byte_to_point };
-use log::{debug};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use crate::llm::{ChatModel, ChatResponse};
+use crate::diff::{arrange_edits, compute_line_edits, minimize_edits, DiffEngine, OverlapPolicy, SimilarDiff, TextEdit};
+use serde_json::{json, Value};
+use crate::prompts::{Preset, PromptRegistry, APPLY_EDIT_TOOL, apply_edit_tool};
+use crate::utils::{ content_hash, truncate_for_log, dominant_newline, expand_indent, restore_indent, byte_to_point, NewlineMap };
+use crate::lang::{detect_language, interpolation_at, open_strings};
+use crate::marker::resolve_marker;
+use crate::error::{CoderError, Result};
+use crate::transcript::{Entry, Transcript};
+use crate::docs::DocIndex;
+use crate::git::ChangesProvider;
+use crate::context::{self, ContextProvider, LineWindow, Outline, Prebuilt};
+use crate::patch::{self, PatchFormat};
+use crate::template;
+use crate::trace::{self, TraceFiles};
+use crate::redact::{Redaction, Redactor};
+use log::{debug, error, info, warn};
 
 pub const CURSOR_MARKER: &str = "??";
-const STOKEN: &str = "<|SEARCH|>";
-const DTOKEN: &str = "<|DIVIDE|>";
-const RTOKEN: &str = "<|REPLACE|>";
-const CTOKEN: &str = "<|cursor|>";
+pub(crate) const STOKEN: &str = "<|SEARCH|>";
+pub(crate) const DTOKEN: &str = "<|DIVIDE|>";
+pub(crate) const RTOKEN: &str = "<|REPLACE|>";
+pub(crate) const CTOKEN: &str = "<|cursor|>";
+const SELECTION_START: &str = "<|selection|>";
+const SELECTION_END: &str = "<|/selection|>";
 
-#[derive(Debug)]
+/// Outlines kept before the cache starts over
+const OUTLINE_CACHE_SIZE: usize = 64;
+
+/// Files with context built ahead kept before the cache starts over
+const WARM_CACHE_SIZE: usize = 64;
+
+/// Search and replace pair the model answered with
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct Patch {
-    start: usize,
-    search: String,
-    replace: String,
+    /// Byte offset of `search` in the text without the marker
+    pub start: usize,
+    pub search: String,
+    pub replace: String,
+    /// 1-based line the model says the search starts on, from `@line N`
+    pub line: Option<usize>,
+}
+
+/// A completion worked out but not applied, see `Coder::prepare_completion`
+#[derive(Debug, Clone)]
+pub struct Completion {
+    pub patch: Patch,
+    /// Edits on `stripped` at absolute byte offsets, applied by `Coder::apply_text_edits`
+    pub edits: Vec<TextEdit>,
+    /// The text the edits apply to: the original without the completed marker
+    pub stripped: String,
+    /// Byte offset of the completed marker in `stripped`
+    pub cursor: usize,
+    pub response: ChatResponse,
+}
+
+/// Tunable behaviour of the coder
+#[derive(Debug, Clone)]
+pub struct CoderOptions {
+    /// Ask for a starting template when the file holds nothing but the marker
+    pub scaffold: bool,
+    /// Name of the prompt preset used when the marker doesn't pick one
+    pub preset: Option<String>,
+    /// User-defined presets, taking precedence over built-in ones
+    pub presets: HashMap<String, Preset>,
+    /// Offer the model an `apply_edit` tool instead of relying on text tokens
+    pub tool_calls: bool,
+    /// Send the whole file as big context alongside the small context
+    pub big_context: bool,
+    /// Lines sent on each side of the marker in the big context
+    pub big_context_lines: usize,
+    /// Max chars of code kept in debug logs, unlimited when unset
+    pub log_limit: Option<usize>,
+    /// Send LF-only text to the model and restore the file's line endings on apply
+    pub normalize_newlines: bool,
+    /// Approximate tokens of docs snippets sent along with the context
+    pub docs_budget: usize,
+    /// Edit format of text replies, detected per reply when unset
+    pub patch_format: Option<PatchFormat>,
+    /// Approximate tokens of provided context sent at most, unlimited when unset
+    pub context_budget: Option<usize>,
+    /// Bytes from the cursor beyond which edits are discarded, any distance when unset
+    pub max_edit_distance: Option<usize>,
+    /// Edits of a patch beyond which it is diffed by lines instead, never when 0
+    pub max_char_edits: usize,
+    /// Start of the assistant turn sent with text requests, such as `<|SEARCH|>`,
+    /// for providers that continue a prefilled reply
+    pub prefill: Option<String>,
+    /// Columns of an indentation tab in what the model sees, tabs are sent
+    /// as they are when unset. Changed lines are indented with tabs again.
+    pub tab_width: Option<usize>,
+    /// Votes a patch needs when voters are configured, a majority of all models when unset
+    pub quorum: Option<usize>,
+    /// Bytes of a file beyond which its big context is an outline the
+    /// summarizer writes instead of raw lines, never when unset
+    pub summarize_over: Option<usize>,
+    /// What applying does with edits whose ranges overlap or nest
+    pub overlapping_edits: OverlapPolicy,
+    /// Trim lines at the end of a completion repeating the text after it
+    pub trim_suffix: bool,
+}
+
+impl Default for CoderOptions {
+    fn default() -> Self {
+        Self {
+            scaffold: false,
+            preset: None,
+            presets: HashMap::new(),
+            tool_calls: false,
+            big_context: true,
+            big_context_lines: 1000,
+            log_limit: None,
+            normalize_newlines: false,
+            docs_budget: 1000,
+            patch_format: None,
+            context_budget: None,
+            max_edit_distance: None,
+            max_char_edits: 200,
+            prefill: None,
+            tab_width: None,
+            quorum: None,
+            summarize_over: None,
+            overlapping_edits: OverlapPolicy::Reject,
+            trim_suffix: true,
+        }
+    }
 }
 
 pub struct Coder {
-    llm: LlmClient,
+    llm: Box<dyn ChatModel>,
+    options: CoderOptions,
+    transcript: Option<Transcript>,
+    diff: Box<dyn DiffEngine>,
+    prompts: Arc<PromptRegistry>,
+    providers: Vec<Box<dyn ContextProvider>>,
+    /// Models asked alongside `llm`, whose patches must agree with a quorum
+    voters: Vec<Box<dyn ChatModel>>,
+    trace_files: Option<TraceFiles>,
+    /// Model writing outlines of large files, the main one when unset
+    summarizer: Option<Box<dyn ChatModel>>,
+    /// Secrets replaced with placeholders before completing
+    redactor: Option<Redactor>,
+    /// Outlines by content hash of the file they summarize
+    outlines: Mutex<HashMap<u64, String>>,
+    /// Context built ahead of the next completion, by path, see `Coder::warm`
+    warmed: Mutex<HashMap<PathBuf, Warmed>>,
+}
+
+/// Context of a file that doesn't depend on the cursor, built ahead
+struct Warmed {
+    /// Content hash of the file it was built for
+    hash: u64,
+    /// Texts of the per-file providers, in their order
+    texts: Vec<String>,
+    /// Time building it took, saved by a completion reusing it
+    took: std::time::Duration,
+}
+
+/// Context source added to a `CoderBuilder`, kept in the order it was added
+enum PendingProvider {
+    /// Sized by the docs budget the builder ends up with
+    Docs(DocIndex),
+    Provider(Box<dyn ContextProvider>),
+}
+
+/// Configures every part of a `Coder`, starting from the same defaults as `Coder::new`
+pub struct CoderBuilder {
+    llm: Box<dyn ChatModel>,
+    options: CoderOptions,
+    transcript: Option<Transcript>,
+    diff: Box<dyn DiffEngine>,
+    prompts: Arc<PromptRegistry>,
+    providers: Vec<PendingProvider>,
+    voters: Vec<Box<dyn ChatModel>>,
+    trace_files: Option<TraceFiles>,
+    summarizer: Option<Box<dyn ChatModel>>,
+    redactor: Option<Redactor>,
+}
+
+impl CoderBuilder {
+    pub fn new(llm: impl ChatModel + 'static) -> Self {
+        Self {
+            llm: Box::new(llm),
+            options: CoderOptions::default(),
+            transcript: None,
+            diff: Box::new(SimilarDiff),
+            prompts: Arc::new(PromptRegistry::default()),
+            providers: Vec::new(),
+            voters: Vec::new(),
+            trace_files: None,
+            summarizer: None,
+            redactor: None,
+        }
+    }
+
+    /// Replaces all options at once, the setters below change one each
+    pub fn options(mut self, options: CoderOptions) -> Self {
+        self.options = options;
+        self
+    }
+
+    pub fn scaffold(mut self, scaffold: bool) -> Self {
+        self.options.scaffold = scaffold;
+        self
+    }
+
+    pub fn preset(mut self, name: impl Into<String>) -> Self {
+        self.options.preset = Some(name.into());
+        self
+    }
+
+    /// Adds a user-defined preset, taking precedence over a built-in one of the same name
+    pub fn add_preset(mut self, name: impl Into<String>, preset: Preset) -> Self {
+        self.options.presets.insert(name.into(), preset);
+        self
+    }
+
+    pub fn tool_calls(mut self, tool_calls: bool) -> Self {
+        self.options.tool_calls = tool_calls;
+        self
+    }
+
+    /// Lines sent on each side of the marker as big context, none when `None`
+    pub fn big_context(mut self, lines: Option<usize>) -> Self {
+        self.options.big_context = lines.is_some();
+        if let Some(lines) = lines {
+            self.options.big_context_lines = lines;
+        }
+        self
+    }
+
+    pub fn log_limit(mut self, limit: Option<usize>) -> Self {
+        self.options.log_limit = limit;
+        self
+    }
+
+    pub fn normalize_newlines(mut self, normalize: bool) -> Self {
+        self.options.normalize_newlines = normalize;
+        self
+    }
+
+    pub fn docs_budget(mut self, tokens: usize) -> Self {
+        self.options.docs_budget = tokens;
+        self
+    }
+
+    pub fn context_budget(mut self, tokens: Option<usize>) -> Self {
+        self.options.context_budget = tokens;
+        self
+    }
+
+    pub fn max_edit_distance(mut self, bytes: Option<usize>) -> Self {
+        self.options.max_edit_distance = bytes;
+        self
+    }
+
+    pub fn trim_suffix(mut self, trim: bool) -> Self {
+        self.options.trim_suffix = trim;
+        self
+    }
+
+    pub fn max_char_edits(mut self, edits: usize) -> Self {
+        self.options.max_char_edits = edits;
+        self
+    }
+
+    pub fn prefill(mut self, prefill: Option<String>) -> Self {
+        self.options.prefill = prefill;
+        self
+    }
+
+    pub fn tab_width(mut self, columns: Option<usize>) -> Self {
+        self.options.tab_width = columns;
+        self
+    }
+
+    pub fn quorum(mut self, votes: Option<usize>) -> Self {
+        self.options.quorum = votes;
+        self
+    }
+
+    /// Asks `llm` too on every completion. Edits are only applied when the
+    /// models' patches agree, see `CoderOptions::quorum`.
+    pub fn voter(mut self, llm: impl ChatModel + 'static) -> Self {
+        self.voters.push(Box::new(llm));
+        self
+    }
+
+    pub fn patch_format(mut self, format: Option<PatchFormat>) -> Self {
+        self.options.patch_format = format;
+        self
+    }
+
+    /// Uses `prompts` instead of the built-in prompts
+    pub fn prompts(mut self, prompts: Arc<PromptRegistry>) -> Self {
+        self.prompts = prompts;
+        self
+    }
+
+    /// Records every successful completion to `transcript`
+    pub fn transcript(mut self, transcript: Transcript) -> Self {
+        self.transcript = Some(transcript);
+        self
+    }
+
+    /// Turns patches into edits with `diff` instead of the `similar` engine
+    pub fn diff_engine(mut self, diff: impl DiffEngine + 'static) -> Self {
+        self.diff = Box::new(diff);
+        self
+    }
+
+    /// Logs completions of the files `files` lists at debug and trace level
+    pub fn trace_files(mut self, files: TraceFiles) -> Self {
+        self.trace_files = Some(files);
+        self
+    }
+
+    /// Replaces what `redactor` matches in every request with placeholders,
+    /// and puts it back in the replies
+    pub fn redactor(mut self, redactor: Redactor) -> Self {
+        self.redactor = Some(redactor);
+        self
+    }
+
+    pub fn summarize_over(mut self, bytes: Option<usize>) -> Self {
+        self.options.summarize_over = bytes;
+        self
+    }
+
+    /// Outlines files over `summarize_over` bytes with `llm`, a cheaper model
+    /// than the main one will do
+    pub fn summarizer(mut self, llm: impl ChatModel + 'static) -> Self {
+        self.summarizer = Some(Box::new(llm));
+        self
+    }
+
+    /// Sends snippets of `docs` within the docs budget, in the order providers are added
+    pub fn docs(mut self, docs: DocIndex) -> Self {
+        self.providers.push(PendingProvider::Docs(docs));
+        self
+    }
+
+    /// Sends the uncommitted changes of the file being completed
+    pub fn changes(self, changes: impl ChangesProvider + 'static) -> Self {
+        self.provider(context::Changes(changes))
+    }
+
+    /// Sends the output of `provider` after the providers added before it
+    pub fn provider(mut self, provider: impl ContextProvider + 'static) -> Self {
+        self.providers.push(PendingProvider::Provider(Box::new(provider)));
+        self
+    }
+
+    pub fn build(self) -> Coder {
+        let budget = self.options.docs_budget;
+        let providers = self.providers.into_iter()
+            .map(|provider| match provider {
+                PendingProvider::Docs(index) => Box::new(context::Docs { index, budget }),
+                PendingProvider::Provider(provider) => provider,
+            })
+            .collect();
+        Coder {
+            llm: self.llm,
+            options: self.options,
+            transcript: self.transcript,
+            diff: self.diff,
+            prompts: self.prompts,
+            providers,
+            voters: self.voters,
+            trace_files: self.trace_files,
+            summarizer: self.summarizer,
+            redactor: self.redactor,
+            outlines: Mutex::new(HashMap::new()),
+            warmed: Mutex::new(HashMap::new()),
+        }
+    }
 }
 
 impl Coder {
-    pub fn new(llm: LlmClient) -> Self {
-        Self { llm }
+    /// Coder with default options, see `Coder::builder` to configure it
+    pub fn new(llm: impl ChatModel + 'static) -> Self {
+        CoderBuilder::new(llm).build()
+    }
+
+    pub fn builder(llm: impl ChatModel + 'static) -> CoderBuilder {
+        CoderBuilder::new(llm)
+    }
+
+    pub fn prompts(&self) -> &PromptRegistry {
+        &self.prompts
     }
 
     pub async fn autocomplete(
-        &self, original: &str, _path: &PathBuf, cursor: usize
-    ) -> anyhow::Result<String> {
+        &self, original: &str, path: &Path, cursor: usize
+    ) -> Result<String> {
+        self.autocomplete_with_task(original, path, cursor, None).await
+    }
+
+    /// Fills the `${name}` holes of `original` with one request, the model
+    /// answering with the code of each hole by name. Holes the model leaves
+    /// empty are kept, the rest of the file is untouched.
+    pub async fn fill_holes(&self, original: &str, path: &Path) -> Result<String> {
+        let holes = template::find_holes(original);
+        let names = template::hole_names(&holes);
+        if names.is_empty() {
+            return Ok(original.to_string());
+        }
+        let language = detect_language(path).unwrap_or("plain text");
+        let messages = vec![
+            json!({ "role": "system", "content": self.prompts.template() }),
+            json!({ "role": "user", "content": format!(
+                "language: {}\nholes: {}\ncontent:\n{}", language, names.join(", "), original
+            ) }),
+        ];
+
+        let reply = self.chat(self.llm.as_ref(), messages).await?;
+        debug!("response {}", truncate_for_log(&reply, self.options.log_limit));
+        let (filled, unfilled) = template::fill(original, &holes, &template::parse_fills(&reply)?);
+        if !unfilled.is_empty() {
+            warn!("Holes left unfilled in {:?}: {}", path, unfilled.join(", "));
+        }
+        Ok(filled)
+    }
+
+    /// Rewrites `content[start..end]` as `instruction` asks, sending the range
+    /// with the lines around it. The edit returned replaces exactly that range.
+    pub async fn rewrite_range(
+        &self, content: &str, path: &Path, start: usize, end: usize, instruction: &str
+    ) -> Result<TextEdit> {
+        if start > end || !content.is_char_boundary(start) || !content.is_char_boundary(end) {
+            return Err(CoderError::Context(format!(
+                "selection {}..{} is not a range of the {} byte text", start, end, content.len()
+            )));
+        }
+
+        let lines = if self.options.big_context { self.options.big_context_lines } else { 3 };
+        let window_start = content[..start].match_indices('\n')
+            .map(|(i, _)| i + 1)
+            .rev()
+            .nth(lines)
+            .unwrap_or(0);
+        let window_end = content[end..].match_indices('\n')
+            .map(|(i, _)| end + i)
+            .nth(lines)
+            .unwrap_or(content.len());
+        let context = format!(
+            "{}{}{}{}{}",
+            &content[window_start..start], SELECTION_START, &content[start..end], SELECTION_END, &content[end..window_end]
+        );
+        debug!("context {:?}", truncate_for_log(&context, self.options.log_limit));
+
+        let language = detect_language(path).unwrap_or("plain text");
+        let messages = vec![
+            json!({ "role": "system", "content": self.prompts.rewrite() }),
+            json!({ "role": "user", "content": format!(
+                "language: {}\ninstruction: {}\ncontext:\n{}", language, instruction, context
+            ) }),
+        ];
+
+        let reply = self.chat(self.llm.as_ref(), messages).await?;
+        debug!("response {}", truncate_for_log(&reply, self.options.log_limit));
+        let mut text = unfence(&reply).replace(SELECTION_START, "").replace(SELECTION_END, "");
+        // Models drop or add the newline closing a selection of whole lines
+        match (content[start..end].ends_with('\n'), text.ends_with('\n')) {
+            (true, false) => text.push('\n'),
+            (false, true) => { text.pop(); }
+            _ => {}
+        }
+        Ok(TextEdit { start, end, text })
+    }
+
+    /// Asks the model for a one-line commit message describing the change
+    /// of `path` from `before` to `after`
+    pub async fn commit_message(&self, path: &Path, before: &str, after: &str) -> Result<String> {
+        let diff = similar::TextDiff::from_lines(before, after)
+            .unified_diff()
+            .context_radius(2)
+            .header(&path.display().to_string(), &path.display().to_string())
+            .to_string();
+        let messages = vec![
+            json!({ "role": "system", "content": self.prompts.commit() }),
+            json!({ "role": "user", "content": diff }),
+        ];
+
+        let reply = self.chat(self.llm.as_ref(), messages).await?;
+        debug!("response {}", truncate_for_log(&reply, self.options.log_limit));
+        reply.lines()
+            .map(|line| line.trim().trim_matches(['"', '`']).trim())
+            .find(|line| !line.is_empty())
+            .map(str::to_string)
+            .ok_or_else(|| CoderError::InvalidResponse("empty commit message".to_string()))
+    }
+
+    pub fn transcript(&self) -> Option<&Transcript> {
+        self.transcript.as_ref()
+    }
+
+    /// Whether completions of `path` are traced
+    pub fn traces(&self, path: &Path) -> bool {
+        self.trace_files.as_ref().is_some_and(|files| files.traces(path))
+    }
+
+    /// Completes at `cursor`, telling the model which `task` to implement there
+    pub async fn autocomplete_with_task(
+        &self, original: &str, path: &Path, cursor: usize, task: Option<&str>
+    ) -> Result<String> {
+        let (updated, response) = trace::scope(
+            self.traces(path), self.complete(original, path, cursor, task)
+        ).await?;
+
+        if let Some(transcript) = &self.transcript {
+            let entry = Entry {
+                path: path.to_path_buf(),
+                cursor,
+                task: task.map(String::from),
+                original: original.to_string(),
+                response,
+                updated: updated.clone(),
+                normalize_newlines: self.options.normalize_newlines,
+                tab_width: self.options.tab_width,
+            };
+            if let Err(e) = transcript.record(&entry) {
+                error!("Failed to record transcript entry for {:?}: {}", path, e);
+            }
+        }
+
+        Ok(updated)
+    }
+
+    /// Completes at `cursor`, returning the new text along with the model reply
+    async fn complete(
+        &self, original: &str, path: &Path, cursor: usize, task: Option<&str>
+    ) -> Result<(String, ChatResponse)> {
+        // Empty and blank files, or a stale cursor, have no marker to resolve
+        if original.get(cursor..cursor + CURSOR_MARKER.len()) != Some(CURSOR_MARKER) {
+            return Err(CoderError::MarkerNotFound(cursor));
+        }
+
+        self.complete_indented(original, path, cursor, task).await
+    }
+
+    /// Completes the marker at `cursor`, with indentation tabs expanded when configured
+    async fn complete_indented(
+        &self, original: &str, path: &Path, cursor: usize, task: Option<&str>
+    ) -> Result<(String, ChatResponse)> {
+        match self.options.tab_width.filter(|_| original.contains('\t')) {
+            Some(width) => {
+                // The model sees spaces, changed lines get their tabs back
+                let expanded = expand_indent(original, width);
+                let cursor = expand_indent(&original[..cursor], width).len();
+                let (updated, response) = self.complete_text(&expanded, path, cursor, task).await?;
+                Ok((restore_indent(original, &updated, width), response))
+            }
+            None => self.complete_text(original, path, cursor, task).await,
+        }
+    }
+
+    /// Completes the marker at `cursor` in `original` as it is
+    async fn complete_text(
+        &self, original: &str, path: &Path, cursor: usize, task: Option<&str>
+    ) -> Result<(String, ChatResponse)> {
+        let completion = self.prepare_completion_with_task(original, path, cursor, task).await?;
+        let updated = self.apply_text_edits(&completion.stripped, &completion.edits)?;
+        if updated == completion.stripped {
+            info!("Completion at {} in {:?} only removes the marker", completion.cursor, path);
+        }
+        check_interpolation(path, &completion.stripped, completion.cursor, &updated)?;
+        Ok((updated, completion.response))
+    }
 
-        let context = self.build_context(original, cursor, 3);
-        debug!("context {:?}", context);
+    /// Asks the model for a completion at `cursor` and returns its patch and
+    /// edits without applying them, for callers applying completions their own way.
+    /// `autocomplete` applies what this returns. Tabs are sent as they are
+    /// here, whatever the `tab_width`.
+    pub async fn prepare_completion(
+        &self, original: &str, path: &Path, cursor: usize
+    ) -> Result<Completion> {
+        self.prepare_completion_with_task(original, path, cursor, None).await
+    }
+
+    /// Prepares the completion at `cursor`, telling the model which `task` to implement there
+    pub async fn prepare_completion_with_task(
+        &self, original: &str, path: &Path, cursor: usize, task: Option<&str>
+    ) -> Result<Completion> {
+        let (marker_options, original, cursor) = resolve_marker(original, cursor);
+        let original = original.as_str();
+
+        let preset_name = marker_options.preset.as_deref()
+            .or(self.options.preset.as_deref());
+        let preset = self.select_preset(preset_name);
+
+        let stripped = strip_marker_at(original, cursor)?;
+
+        if self.options.normalize_newlines && original.contains('\r') {
+            // Complete on LF-only text, then map edits back onto the original line endings
+            let (normalized, map) = NewlineMap::normalize(original);
+            let (patch, edits, response) = self.prepare_edits(
+                &normalized, path, map.to_normalized(cursor), &preset, task
+            ).await?;
+
+            let (_, stripped_map) = NewlineMap::normalize(&stripped);
+            let newline = dominant_newline(original);
+            let edits = edits.into_iter().map(|edit| TextEdit {
+                start: stripped_map.to_original(edit.start),
+                end: stripped_map.to_original(edit.end),
+                text: edit.text.replace("\r\n", "\n").replace('\n', newline),
+            }).collect::<Vec<_>>();
+            let patch = Patch { start: stripped_map.to_original(patch.start), ..patch };
+
+            return Ok(Completion { patch, edits, stripped, cursor, response });
+        }
+
+        let (patch, edits, response) = self.prepare_edits(original, path, cursor, &preset, task).await?;
+        Ok(Completion { patch, edits, stripped, cursor, response })
+    }
+
+    /// Asks the model for a patch at `cursor` and turns it into edits on
+    /// `original` with the marker at `cursor` removed
+    async fn prepare_edits(
+        &self, original: &str, path: &Path, cursor: usize, preset: &Preset, task: Option<&str>
+    ) -> Result<(Patch, Vec<TextEdit>, ChatResponse)> {
+
+        let stripped = strip_marker_at(original, cursor)?;
+        let outline = match self.summarizes(original) {
+            true => self.outline(&stripped).await
+                .inspect_err(|e| warn!("Sending lines of {:?} instead of its outline: {}", path, e))
+                .ok(),
+            false => None,
+        };
+        let messages = self.build_messages(original, path, cursor, preset, task, outline.as_deref()).await?;
+        if !self.voters.is_empty() {
+            return self.vote(messages, &stripped, cursor).await;
+        }
+
+        let response = self.ask(self.llm.as_ref(), messages).await?;
+        let (patch, edits) = self.edits_from_response(&response, &stripped, cursor)?;
+        Ok((patch, edits, response))
+    }
+
+    /// Tells whether `content` is large enough to send its outline as the big context
+    fn summarizes(&self, content: &str) -> bool {
+        self.options.big_context && self.options.summarize_over.is_some_and(|limit| content.len() > limit)
+    }
+
+    /// Builds the context of `path` holding `content` that doesn't depend on
+    /// the cursor, its outline and the sections of per-file providers, ahead
+    /// of its next completion. A completion of the same content with a marker
+    /// added reuses it. Returns how long building took.
+    pub async fn warm(&self, content: &str, path: &Path) -> Result<std::time::Duration> {
+        let start_time = std::time::Instant::now();
+        let summarizes = self.summarizes(content);
+        if summarizes {
+            self.outline(content).await?;
+        }
+        let mut texts = Vec::new();
+        for provider in self.providers.iter().filter(|p| p.per_file()) {
+            texts.push(provider.provide(content, 0, path).await?);
+        }
+        let took = start_time.elapsed();
+        if texts.is_empty() && !summarizes {
+            return Ok(took);
+        }
+
+        let mut warmed = self.warmed.lock().unwrap_or_else(|e| e.into_inner());
+        if warmed.len() >= WARM_CACHE_SIZE && !warmed.contains_key(path) {
+            warmed.clear();
+        }
+        warmed.insert(path.to_path_buf(), Warmed { hash: content_hash(content), texts, took });
+        Ok(took)
+    }
 
-        let big_context = self.build_context(original, cursor, 1000);
+    /// Texts of the per-file providers `warm` built for `path`, while its
+    /// content without the marker at `cursor` is still the one they were
+    /// built for. Context built for other content is dropped.
+    fn warmed_texts(&self, original: &str, cursor: usize, path: &Path) -> Option<Vec<String>> {
+        let mut warmed = self.warmed.lock().unwrap_or_else(|e| e.into_inner());
+        let entry = warmed.get(path)?;
+        let stripped = strip_marker_at(original, cursor).ok()?;
+        if entry.hash != content_hash(&stripped) {
+            debug!("context of {:?} built ahead is stale", path);
+            warmed.remove(path);
+            return None;
+        }
+        info!("Reusing the context of {:?} built ahead, saving {:?}", path, entry.took);
+        Some(entry.texts.clone())
+    }
+
+    /// Structural outline of `content` from the summarizer, asked once per content
+    async fn outline(&self, content: &str) -> Result<String> {
+        let hash = content_hash(content);
+        if let Some(outline) = self.outlines.lock().unwrap_or_else(|e| e.into_inner()).get(&hash) {
+            return Ok(outline.clone());
+        }
 
         let messages = vec![
-            json!({ "role": "system", "content": SYSTEM_PROMPT }),
-            json!({ "role": "user", "content": format!("big context:\n{}", big_context.0) }),
-            json!({ "role": "user", "content": format!("small context:\n{}", context.0) }),
-            json!({ "role": "user", "content": REMINDER }),
+            json!({ "role": "system", "content": self.prompts.summary() }),
+            json!({ "role": "user", "content": content }),
         ];
+        let llm = self.summarizer.as_ref().unwrap_or(&self.llm);
+        let reply = self.chat(llm.as_ref(), messages).await?;
+        let outline = unfence(&reply).trim().to_string();
+        if outline.is_empty() {
+            return Err(CoderError::InvalidResponse("empty outline".to_string()));
+        }
+        debug!("outline {}", truncate_for_log(&outline, self.options.log_limit));
+
+        let mut outlines = self.outlines.lock().unwrap_or_else(|e| e.into_inner());
+        // Old contents of files don't come back once edited
+        if outlines.len() >= OUTLINE_CACHE_SIZE {
+            outlines.clear();
+        }
+        outlines.insert(hash, outline.clone());
+        Ok(outline)
+    }
 
-        let response = self.llm.chat(messages).await?;
-        debug!("response {}", response);
+    /// Sends `messages` to `llm`, offering the edit tool when configured
+    async fn ask(&self, llm: &dyn ChatModel, messages: Vec<Value>) -> Result<ChatResponse> {
+        if self.options.tool_calls {
+            let (messages, redaction) = self.redact(messages);
+            let mut response = llm.chat_with_tools(messages, vec![apply_edit_tool()]).await?;
+            if let Some(redaction) = &redaction {
+                response.content = redaction.restore(&response.content);
+                for call in &mut response.tool_calls {
+                    restore_strings(&mut call.arguments, redaction);
+                }
+            }
+            debug!("response {:?}", truncate_for_log(&format!("{:?}", response), self.options.log_limit));
+            Ok(response)
+        } else {
+            let content = self.with_prefill(self.chat(llm, messages).await?);
+            debug!("response {}", truncate_for_log(&content, self.options.log_limit));
+            Ok(ChatResponse { content, tool_calls: vec![] })
+        }
+    }
+
+    /// Sends `messages` to `llm` with secrets redacted, restoring them in the reply
+    async fn chat(&self, llm: &dyn ChatModel, messages: Vec<Value>) -> Result<String> {
+        let (messages, redaction) = self.redact(messages);
+        let reply = llm.chat(messages).await?;
+        Ok(match redaction {
+            Some(redaction) => redaction.restore(&reply),
+            None => reply,
+        })
+    }
 
-        let patch = self.parse_patch(&response, cursor)?;
-        debug!("patch {:?}", patch);
+    /// Replaces the secrets in every message, whether it holds the file, a
+    /// diff, docs or examples. The model sees placeholders it may copy, the
+    /// redaction puts the secrets back in its reply.
+    fn redact(&self, mut messages: Vec<Value>) -> (Vec<Value>, Option<Redaction>) {
+        let Some(redactor) = &self.redactor else {
+            return (messages, None);
+        };
+        let redaction = {
+            let contents = messages.iter()
+                .map(|message| message["content"].as_str().unwrap_or_default())
+                .collect::<Vec<_>>();
+            redactor.redact(&contents, CTOKEN)
+        };
+        if redaction.is_empty() {
+            return (messages, None);
+        }
+        info!("Redacted {} secrets from the request", redaction.len());
+        for (message, text) in messages.iter_mut().zip(&redaction.texts) {
+            if message["content"].is_string() {
+                message["content"] = Value::String(text.clone());
+            }
+        }
+        (messages, Some(redaction))
+    }
 
-        let edits = compute_text_edits(&patch.search, &patch.replace);
-        debug!("edits {:?}", edits);
+    /// Turns the patch in `response` into edits on `stripped`, the text without the marker
+    fn edits_from_response(&self, response: &ChatResponse, stripped: &str, cursor: usize) -> Result<(Patch, Vec<TextEdit>)> {
+        let patch = anchor_at_line(self.patch_from_response(response, cursor)?, stripped);
+        let patch = match self.options.trim_suffix {
+            true => trim_duplicate_suffix(patch, stripped),
+            false => patch,
+        };
+        // Nothing to replace with, or nothing changed: only the marker goes
+        if patch.replace.trim().is_empty() || patch.replace == patch.search {
+            info!("model declined to complete at {}", cursor);
+            return Ok((patch, vec![]));
+        }
+        let mut edits = self.diff.edits(&patch.search, &patch.replace);
+        // A rewrite all over the block would be applied as countless tiny edits
+        if self.options.max_char_edits > 0 && edits.len() > self.options.max_char_edits {
+            debug!("{} edits, diffing the patch by lines instead", edits.len());
+            edits = compute_line_edits(&patch.search, &patch.replace);
+        }
+        let edits = minimize_edits(&patch.search, edits);
+        if self.options.log_limit.is_none() {
+            debug!("patch {:?}", patch);
+            debug!("edits {:?}", edits);
+        }
 
         let edits = edits.iter().map(|edit| {
             let s = edit.start + patch.start;
@@ -59,94 +801,242 @@ impl Coder {
             TextEdit { start: s, end: e, text: edit.text.clone() }
         }).collect::<Vec<_>>();
 
-        let apply_result = self.apply_text_edits(&original, &edits);
+        let edits = match self.options.max_edit_distance {
+            Some(max) => near_cursor(edits, cursor, max),
+            None => edits,
+        };
+        Ok((patch, edits))
+    }
+
+    /// Asks the main model and every voter at once and returns the edits
+    /// enough of them agree on. Ties go to the earliest model, the main one first.
+    async fn vote(&self, messages: Vec<Value>, stripped: &str, cursor: usize) -> Result<(Patch, Vec<TextEdit>, ChatResponse)> {
+        let models = std::iter::once(&self.llm).chain(&self.voters);
+        let responses = futures::future::join_all(
+            models.map(|llm| self.ask(llm.as_ref(), messages.clone()))
+        ).await;
+        let quorum = self.options.quorum.unwrap_or(responses.len() / 2 + 1);
+
+        // Distinct edits with the first reply that gave them and their votes
+        let mut ballots: Vec<(Vec<TextEdit>, Patch, ChatResponse, usize)> = Vec::new();
+        let mut first_error = None;
+        for (i, response) in responses.into_iter().enumerate() {
+            let edits = response.and_then(|r| Ok((self.edits_from_response(&r, stripped, cursor)?, r)));
+            match edits {
+                Ok(((patch, edits), response)) => match ballots.iter_mut().find(|b| b.0 == edits) {
+                    Some(ballot) => ballot.3 += 1,
+                    None => ballots.push((edits, patch, response, 1)),
+                },
+                Err(e) => {
+                    warn!("Model {} gave no usable patch: {}", i, e);
+                    first_error.get_or_insert(e);
+                }
+            }
+        }
 
-        apply_result
+        if ballots.len() > 1 {
+            warn!("Models disagree on the patch, votes {:?}", ballots.iter().map(|b| b.3).collect::<Vec<_>>());
+            for (edits, _, _, votes) in &ballots {
+                debug!("{} votes for {:?}", votes, edits);
+            }
+        }
+        ballots.sort_by_key(|ballot| std::cmp::Reverse(ballot.3));
+        match ballots.into_iter().next() {
+            Some((edits, patch, response, votes)) if votes >= quorum => Ok((patch, edits, response)),
+            Some((_, _, _, votes)) => Err(CoderError::NoConsensus(votes, quorum)),
+            None => Err(first_error.unwrap_or(CoderError::NoConsensus(0, quorum))),
+        }
     }
 
-    fn build_context(
-        &self, original: &str, cursor: usize, context_lines: usize
-    ) -> (String, usize) {
-        let lines: Vec<&str> = original.lines().collect();
+    fn select_preset(&self, name: Option<&str>) -> Preset {
+        let Some(name) = name else {
+            return self.prompts.default_preset();
+        };
+
+        self.options.presets.get(name).cloned()
+            .or_else(|| self.prompts.preset(name))
+            .unwrap_or_else(|| {
+                warn!("Unknown preset {:?}, using default", name);
+                self.prompts.default_preset()
+            })
+    }
 
-        let (line, _col) = byte_to_point(cursor, original);
-        let cursor_line = line;
+    /// Messages asking for a patch at `cursor`, with `outline` as the big context when given
+    async fn build_messages(
+        &self, original: &str, path: &Path, cursor: usize, preset: &Preset, task: Option<&str>, outline: Option<&str>
+    ) -> Result<Vec<Value>> {
+        if self.options.scaffold && is_near_empty(original) {
+            let language = detect_language(path).unwrap_or("plain text");
+            info!("Scaffolding {:?} as {}", path, language);
 
-        let mut before = context_lines;
-        let mut after = context_lines;
-        let max_row = lines.len().saturating_sub(1);
+            let file_name = path.file_name()
+                .map(|f| f.to_string_lossy().to_string())
+                .unwrap_or_default();
+            let content = original.replacen(CURSOR_MARKER, CTOKEN, 1);
 
-        if cursor_line < context_lines {
-            after += context_lines - cursor_line;
-        } else if cursor_line + context_lines > max_row {
-            before += (cursor_line + context_lines) - max_row;
+            return Ok(self.prefilled(vec![
+                json!({ "role": "system", "content": self.prompts.scaffold() }),
+                json!({ "role": "user", "content": format!(
+                    "file: {}\nlanguage: {}\ncontent:\n{}", file_name, language, content
+                ) }),
+            ]));
         }
 
-        let start_line = cursor_line.saturating_sub(before);
-        let end_line = (cursor_line + after).min(lines.len() - 1);
+        let start_time = std::time::Instant::now();
 
-        let context = lines[start_line..=end_line].join("\n");
-        
-        let cursor_relative = context.find(CURSOR_MARKER)
-            .ok_or_else(|| anyhow::anyhow!(
-                "CURSOR_MARKER not found in context, {}", context)
-            ).unwrap();
-        
-        let start = cursor - cursor_relative;
+        let context = self.build_context(original, cursor, 3)?;
+        debug!("context {:?}", truncate_for_log(&context.0, self.options.log_limit));
+
+        let mut messages = vec![json!({ "role": "system", "content": preset.system })];
+
+        let window: Option<Box<dyn ContextProvider>> = match outline {
+            Some(text) => Some(Box::new(Outline { text: text.to_string() })),
+            None => self.options.big_context
+                .then(|| Box::new(LineWindow { lines: self.options.big_context_lines }) as Box<dyn ContextProvider>),
+        };
+        // Sections built ahead stand in for their per-file providers
+        let prebuilt: Vec<Option<Prebuilt>> = match self.warmed_texts(original, cursor, path) {
+            Some(texts) => {
+                let mut texts = texts.into_iter();
+                self.providers.iter()
+                    .map(|p| p.per_file().then(|| Prebuilt {
+                        label: p.label().to_string(),
+                        text: texts.next().unwrap_or_default(),
+                    }))
+                    .collect()
+            }
+            None => Vec::new(),
+        };
+        let providers: Vec<&dyn ContextProvider> = self.providers.iter()
+            .enumerate()
+            .map(|(i, p)| match prebuilt.get(i) {
+                Some(Some(prebuilt)) => prebuilt as &dyn ContextProvider,
+                _ => p.as_ref(),
+            })
+            .chain(window.as_deref())
+            .collect();
+        let sections = context::collect(providers, original, cursor, path, self.options.context_budget).await?;
+        for section in sections {
+            debug!("{} {:?}", section.label, truncate_for_log(&section.text, self.options.log_limit));
+            messages.push(json!({ "role": "user", "content": format!("{}:\n{}", section.label, section.text) }));
+        }
+        debug!("context built in {:?}", start_time.elapsed());
+
+        // Lets the model number its `@line N` annotation
+        let first_line = byte_to_point(context.1, original).0 + 1;
+        messages.push(json!({ "role": "user", "content": format!("small context, from line {}:\n{}", first_line, context.0) }));
+        if let Some(task) = task {
+            messages.push(json!({ "role": "user", "content": format!("{}{}", self.prompts.task(), task) }));
+        }
+        if let Some(interpolation) = detect_language(path).and_then(|l| interpolation_at(l, original, cursor)) {
+            debug!("cursor in a {} interpolation", interpolation.describe());
+            messages.push(json!({ "role": "user", "content": format!(
+                "{}{}", self.prompts.interpolation(), interpolation.describe()
+            ) }));
+        }
+        messages.push(json!({ "role": "user", "content": preset.reminder }));
+
+        Ok(self.prefilled(messages))
+    }
+
+    /// Ends `messages` with the configured start of the reply. Tool calls
+    /// carry the patch as arguments, so there is nothing to prefill.
+    fn prefilled(&self, mut messages: Vec<Value>) -> Vec<Value> {
+        if let Some(prefill) = self.options.prefill.as_ref().filter(|_| !self.options.tool_calls) {
+            messages.push(json!({ "role": "assistant", "content": prefill }));
+        }
+        messages
+    }
+
+    /// Puts the prefill back in front of a reply continuing it, as most
+    /// providers return only the continuation
+    fn with_prefill(&self, reply: String) -> String {
+        match self.options.prefill.as_deref() {
+            Some(prefill) if !reply.trim_start().starts_with(prefill) => format!("{}{}", prefill, reply),
+            _ => reply,
+        }
+    }
+
+    pub fn build_context(
+        &self, original: &str, cursor: usize, context_lines: usize
+    ) -> Result<(String, usize)> {
+        context::line_window(original, cursor, context_lines)
+    }
 
-        (
-            context.replacen(CURSOR_MARKER, CTOKEN, 1),
-            start
-        )
+    /// Reads the patch from an `apply_edit` tool call, falling back to text parsing
+    fn patch_from_response(
+        &self, response: &ChatResponse, cursor: usize
+    ) -> Result<Patch> {
+        let Some(call) = response.tool_calls.iter().find(|c| c.name == APPLY_EDIT_TOOL) else {
+            return self.parse_patch(&response.content, cursor);
+        };
+
+        let field = |name: &str| call.arguments[name].as_str()
+            .ok_or_else(|| CoderError::InvalidResponse(
+                format!("{} call is missing {}", APPLY_EDIT_TOOL, name)
+            ));
+
+        self.build_patch(field("search")?, field("replace")?, cursor)
     }
 
     fn parse_patch(
         &self, patch: &str, cursor: usize
-    ) -> anyhow::Result<Patch> {
-        let search_start = patch.find(STOKEN)
-            .ok_or_else(|| anyhow::anyhow!("Invalid patch format: missing {}", STOKEN))?;
-        let replace_divider = patch.find(DTOKEN)
-            .ok_or_else(|| anyhow::anyhow!("Invalid patch format: missing {}", DTOKEN))?;
-        let _replace_end = patch.find(RTOKEN)
-            .ok_or_else(|| anyhow::anyhow!("Invalid patch format: missing {}", RTOKEN))?;
-
-        let search = &patch[search_start + STOKEN.len()..replace_divider];
-        
+    ) -> Result<Patch> {
+        let (line, patch) = patch::split_line_annotation(patch);
+        let (search, replace) = patch::parse_patch(&patch, self.options.patch_format)?;
+        Ok(Patch { line, ..self.build_patch(&search, &replace, cursor)? })
+    }
+
+    /// Anchors a search/replace pair at the cursor position
+    fn build_patch(
+        &self, search: &str, replace: &str, cursor: usize
+    ) -> Result<Patch> {
         let cursor_pos = search.find(CTOKEN)
-            .ok_or_else(|| anyhow::anyhow!("Invalid patch format: missing {}", CTOKEN))?;
+            .ok_or(CoderError::MissingToken(CTOKEN))?;
+
+        // Only one cursor is sent, extra tokens are the model's mistake: anchor
+        // on the first and strip them all so none ends up in the file
+        let cursors = search.matches(CTOKEN).count();
+        if cursors > 1 {
+            warn!("Search block holds {} cursor tokens, anchoring on the first", cursors);
+        }
 
         let search_no_cursor = search.replace(CTOKEN, "");
 
-        let replace = &patch[replace_divider + DTOKEN.len()..];
-        let replace = replace.replace(RTOKEN, "").replace(CTOKEN, "");
+        let replace = replace.replace(CTOKEN, "");
         
         let before = &search[..cursor_pos];
-        
-        let start = cursor.saturating_sub(before.len());
+
+        // Text before the cursor that the file doesn't have can't be anchored,
+        // e.g. a marker at byte 0 with a search starting above it
+        let start = cursor.checked_sub(before.len())
+            .ok_or_else(|| CoderError::Context(format!(
+                "search text starts {} bytes before the start of the file",
+                before.len() - cursor
+            )))?;
 
         Ok(Patch {
             start,
             search: search_no_cursor,
             replace,
+            line: None,
         })
     }
 
-    fn apply_text_edits(
-        &self, original: &str, edits: &Vec<TextEdit>,
-    ) -> anyhow::Result<String> {
-        let mut edits = edits.clone();
-        
-        // Sort edits by start position in descending order
-        // so that applying edits from the end prevents index shifting issues
-        edits.sort_by(|a, b| b.start.cmp(&a.start));
+    pub fn apply_text_edits(
+        &self, original: &str, edits: &[TextEdit],
+    ) -> Result<String> {
+        // Sorted from the end so that applying an edit doesn't shift the
+        // ones still to apply. Overlapping ranges would garble the text.
+        let edits = arrange_edits(edits, self.options.overlapping_edits)?;
 
-        let mut result = original.to_string().replace(CURSOR_MARKER, "");
+        let mut result = original.to_string();
 
         for edit in edits {
             // Replace the range [start, end) in the original string with new_text
             // Panics if the starting point or end point do not lie on a char boundary, or if they’re out of bounds.
             if edit.start > result.len() || edit.end > result.len() {
-                anyhow::bail!("Edit out of bounds {:?}", edit);
+                return Err(CoderError::EditOutOfBounds(edit));
             }else {
                 result.replace_range(edit.start..edit.end, &edit.text);
             }
@@ -157,12 +1047,159 @@ impl Coder {
 
 }
 
+/// Checks a patch annotated with `@line N` against `text`. When the search
+/// doesn't start on that line at the cursor-derived offset, it is moved to
+/// the occurrence of the search text nearest the annotated line.
+fn anchor_at_line(mut patch: Patch, text: &str) -> Patch {
+    let Some(line) = patch.line.filter(|_| !patch.search.is_empty()) else {
+        return patch;
+    };
+    let line_of = |start: usize| byte_to_point(start, text).0 + 1;
+    let fits = text.get(patch.start..).is_some_and(|rest| rest.starts_with(&patch.search));
+    if fits && line_of(patch.start) == line {
+        return patch;
+    }
+
+    let nearest = text.match_indices(patch.search.as_str())
+        .map(|(start, _)| start)
+        .min_by_key(|&start| line_of(start).abs_diff(line));
+    match nearest {
+        Some(start) => {
+            if start != patch.start {
+                warn!("Patch anchored at line {} instead of {} by its search text", line_of(start), line_of(patch.start));
+            }
+            patch.start = start;
+        }
+        None => warn!("Search text of the patch annotated @line {} not found, anchoring at the cursor", line),
+    }
+    patch
+}
+
+/// Drops whole lines at the end of the replacement that repeat the text
+/// right after the search block, such as a closing brace the model wrote
+/// again although it already follows. Only lines of closing brackets go,
+/// and only while the replacement closes more brackets than it opens, so
+/// code that merely repeats, like rows of a table, stays. Text the search
+/// block ends with too was kept by the model rather than added, and is left alone.
+fn trim_duplicate_suffix(mut patch: Patch, text: &str) -> Patch {
+    let end = patch.start + patch.search.len();
+    let fits = text.get(patch.start..).is_some_and(|rest| rest.starts_with(&patch.search));
+    let unbalanced = bracket_balance(&patch.replace) < bracket_balance(&patch.search);
+    if !fits || !unbalanced {
+        return patch;
+    }
+    let following = &text[end..];
+    let replace = patch.replace.as_str();
+
+    let overlap = (1..=replace.len().min(following.len())).rev()
+        .filter(|&k| following.is_char_boundary(k) && replace.is_char_boundary(replace.len() - k))
+        .find(|&k| {
+            let (kept, overlap) = replace.split_at(replace.len() - k);
+            let starts_line = kept.is_empty() || kept.ends_with('\n') || overlap.starts_with(['\r', '\n']);
+            let ends_line = overlap.ends_with('\n') || following[k..].is_empty() || following[k..].starts_with(['\r', '\n']);
+            let closes = overlap.lines().all(|line| line.trim().chars().all(|c| ")]};,".contains(c)));
+            following[..k] == *overlap && starts_line && ends_line && closes
+                && !overlap.trim().is_empty() && !patch.search.ends_with(overlap)
+        });
+    if let Some(k) = overlap {
+        info!("Trimming {:?} off the completion, it follows the cursor already", &following[..k]);
+        patch.replace.truncate(replace.len() - k);
+    }
+    patch
+}
+
+/// Brackets `text` opens minus those it closes
+fn bracket_balance(text: &str) -> isize {
+    text.chars()
+        .map(|c| match c {
+            '(' | '[' | '{' => 1,
+            ')' | ']' | '}' => -1,
+            _ => 0,
+        })
+        .sum()
+}
+
+/// Puts the redacted secrets back into every string of `value`
+fn restore_strings(value: &mut Value, redaction: &Redaction) {
+    match value {
+        Value::String(text) => *text = redaction.restore(text),
+        Value::Array(items) => items.iter_mut().for_each(|item| restore_strings(item, redaction)),
+        Value::Object(fields) => fields.values_mut().for_each(|field| restore_strings(field, redaction)),
+        _ => {}
+    }
+}
+
+/// The code inside a reply wrapped in a code fence, the reply as is otherwise
+fn unfence(reply: &str) -> &str {
+    let trimmed = reply.trim();
+    trimmed.strip_prefix("```")
+        .and_then(|rest| rest.split_once('\n'))
+        .and_then(|(_, body)| body.trim_end().strip_suffix("```"))
+        .unwrap_or(reply)
+}
+
+/// Removes the marker being completed at `cursor`, leaving any other markers intact
+fn strip_marker_at(original: &str, cursor: usize) -> Result<String> {
+    let end = cursor + CURSOR_MARKER.len();
+    if original.get(cursor..end) != Some(CURSOR_MARKER) {
+        return Err(CoderError::MarkerNotFound(cursor));
+    }
+
+    let mut stripped = original.to_string();
+    stripped.replace_range(cursor..end, "");
+    Ok(stripped)
+}
+
+/// Refuses a completion made inside a string interpolation that leaves the
+/// file's string literals open or closed differently than before
+fn check_interpolation(path: &Path, stripped: &str, cursor: usize, updated: &str) -> Result<()> {
+    let Some(language) = detect_language(path) else {
+        return Ok(());
+    };
+    // Text before the marker is the same with or without it
+    if interpolation_at(language, stripped, cursor).is_none() {
+        return Ok(());
+    }
+    let (before, after) = (open_strings(language, stripped), open_strings(language, updated));
+    if before != after {
+        return Err(CoderError::InvalidResponse(format!(
+            "completion breaks the quoting of the string interpolation at byte {}, {} literals open instead of {}",
+            cursor, after, before
+        )));
+    }
+    Ok(())
+}
+
+/// Keeps the edits within `max` bytes of `cursor`. Models sometimes "fix"
+/// unrelated code in the search block, which would land far from the marker.
+fn near_cursor(edits: Vec<TextEdit>, cursor: usize, max: usize) -> Vec<TextEdit> {
+    edits.into_iter()
+        .filter(|edit| {
+            let distance = cursor.saturating_sub(edit.end).max(edit.start.saturating_sub(cursor));
+            if distance > max {
+                info!("Discarding edit {} bytes from the cursor {:?}", distance, edit);
+            }
+            distance <= max
+        })
+        .collect()
+}
+
+/// A file is near-empty when nothing but whitespace surrounds the marker
+fn is_near_empty(original: &str) -> bool {
+    original.replacen(CURSOR_MARKER, "", 1).trim().is_empty()
+}
+
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::llm::LlmClient;
+    use crate::llm::mock::MockLlm;
+    use crate::llm::ToolCall;
+    use crate::prompts::{SYSTEM_PROMPT, LINE_REMINDER, BLOCK_REMINDER, SCAFFOLD_PROMPT, TASK_PROMPT};
     use indoc::indoc;
     use dotenv::dotenv;
+    use std::path::PathBuf;
 
     #[test]
     fn test_build_context_basic() {
@@ -179,7 +1216,7 @@ fn main() {
 
         let coder = Coder::new(LlmClient::new("", "", ""));
 
-        let context = coder.build_context(&code, cursor, 1);
+        let context = coder.build_context(code, cursor, 1).unwrap();
 
         println!("context:\n {:?}", context);
 
@@ -188,17 +1225,257 @@ fn main() {
     }
 
     #[test]
-    fn test_parse_patch() -> anyhow::Result<()> {
+    fn test_build_context_single_line() -> anyhow::Result<()> {
         let coder = Coder::new(LlmClient::new("", "", ""));
 
-        let patch = "<|SEARCH|>let <|cursor|> = 10;<|DIVIDE|>let x = 10;<|REPLACE|>";
-        let start_pos = 0;
+        for code in ["let x = ??;", "let x = ??;\n", "let x = ??;\r\n"] {
+            let cursor = code.find(CURSOR_MARKER).unwrap();
+            for context_lines in [0, 1, 3] {
+                let (context, start) = coder.build_context(code, cursor, context_lines)?;
+                assert_eq!(context, "let x = <|cursor|>;", "{:?} with {}", code, context_lines);
+                assert_eq!(start, 0);
+            }
+        }
 
-        let parsed = coder.parse_patch(patch, start_pos)?;
+        // The marker alone on the only line
+        let (context, start) = coder.build_context("??", 0, 1)?;
+        assert_eq!((context.as_str(), start), ("<|cursor|>", 0));
 
-        assert_eq!(parsed.start, start_pos);
-        assert_eq!(parsed.search, "let  = 10;");
-        assert_eq!(parsed.replace, "let x = 10;");
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_context_with_earlier_marker() -> anyhow::Result<()> {
+        let coder = Coder::new(LlmClient::new("", "", ""));
+        let code = "fn main() {\n    let a = ??;\n    let b = ??;\n}\n";
+        let cursor = code.rfind(CURSOR_MARKER).unwrap();
+
+        let (context, start) = coder.build_context(code, cursor, 3)?;
+
+        assert_eq!(start, 0);
+        assert_eq!(context, "fn main() {\n    let a = ??;\n    let b = <|cursor|>;\n}");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_build_context_crlf_mismatch() {
+        let code = "fn main() {\r\n    let x = ??;\r\n}\r\n";
+
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let coder = Coder::new(LlmClient::new("", "", ""));
+
+        let context = coder.build_context(code, cursor, 1);
+
+        assert!(matches!(context, Err(CoderError::Context(_))));
+    }
+
+    #[tokio::test]
+    async fn test_autocomplete_mixed_line_endings() -> anyhow::Result<()> {
+        let reply = "<|SEARCH|>    let x = <|cursor|>;<|DIVIDE|>    let x = 1;\n    let y = 2;<|REPLACE|>";
+        let options = CoderOptions { normalize_newlines: true, ..Default::default() };
+        let coder = Coder::builder(MockLlm::new(reply)).options(options).build();
+
+        let code = "fn main() {\r\n    let x = ??;\r\n    let z = 3;\n}\r\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let updated = coder.autocomplete(code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(
+            updated,
+            "fn main() {\r\n    let x = 1;\r\n    let y = 2;\r\n    let z = 3;\n}\r\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prepare_completion_leaves_text_alone() -> anyhow::Result<()> {
+        let reply = "<|SEARCH|>    let x = <|cursor|>;<|DIVIDE|>    let x = 1;\n    let y = 2;<|REPLACE|>";
+        let coder = Coder::new(MockLlm::new(reply));
+
+        let code = String::from("fn main() {\n    let x = ??;\n}\n");
+        let before = code.clone();
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let completion = coder.prepare_completion(&code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(code, before);
+        assert_eq!(completion.stripped, "fn main() {\n    let x = ;\n}\n");
+        assert_eq!(completion.cursor, cursor);
+        assert_eq!(completion.patch.start, completion.stripped.find("    let x").unwrap());
+        assert_eq!(completion.patch.search, "    let x = ;");
+        assert_eq!(completion.patch.replace, "    let x = 1;\n    let y = 2;");
+
+        let end = completion.patch.start + completion.patch.search.len();
+        assert!(completion.edits.iter().all(|edit| completion.patch.start <= edit.start && edit.end <= end));
+        assert_eq!(
+            coder.apply_text_edits(&completion.stripped, &completion.edits)?,
+            "fn main() {\n    let x = 1;\n    let y = 2;\n}\n"
+        );
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prepare_completion_offsets_on_crlf_text() -> anyhow::Result<()> {
+        let reply = "<|SEARCH|>    let x = <|cursor|>;<|DIVIDE|>    let x = 1;<|REPLACE|>";
+        let options = CoderOptions { normalize_newlines: true, ..Default::default() };
+        let coder = Coder::builder(MockLlm::new(reply)).options(options).build();
+
+        let code = "fn main() {\r\n    let x = ??;\r\n}\r\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let completion = coder.prepare_completion(code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(completion.stripped, "fn main() {\r\n    let x = ;\r\n}\r\n");
+        assert_eq!(completion.patch.start, completion.stripped.find("    let x").unwrap());
+        assert_eq!(completion.edits, vec![TextEdit { start: cursor, end: cursor, text: "1".into() }]);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_autocomplete_crlf_without_normalization_fails() {
+        let coder = Coder::new(MockLlm::new(""));
+
+        let code = "fn main() {\r\n    let x = ??;\r\n}\r\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let result = coder.autocomplete(code, Path::new("main.rs"), cursor).await;
+        assert!(matches!(result, Err(CoderError::Context(_))));
+    }
+
+    #[tokio::test]
+    async fn test_docs_sent_before_big_context() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("anycoder-docs-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("auth.md"), "Call login_user(name) to open a session.\n\nUnrelated.")?;
+        let docs = DocIndex::load(&dir)?;
+        std::fs::remove_dir_all(&dir)?;
+
+        let coder = Coder::builder(MockLlm::new("")).docs(docs).build();
+        let code = "fn main() {\n    let s = login_user(??);\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let messages = coder.build_messages(code, Path::new("main.rs"), cursor, &Preset::default(), None, None).await?;
+        assert_eq!(
+            messages[1]["content"],
+            "docs:\nauth.md:\nCall login_user(name) to open a session."
+        );
+        assert!(messages[2]["content"].as_str().unwrap().starts_with("big context:"));
+
+        Ok(())
+    }
+
+    /// Reports the same changes for every file
+    struct FixedChanges(Option<String>);
+
+    #[async_trait::async_trait]
+    impl ChangesProvider for FixedChanges {
+        async fn changes(&self, _path: &Path) -> Option<String> {
+            self.0.clone()
+        }
+    }
+
+    /// Reports the same changes for every file, counting how often it is asked
+    struct CountingChanges(Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait::async_trait]
+    impl ChangesProvider for CountingChanges {
+        async fn changes(&self, _path: &Path) -> Option<String> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Some("@@ -1 +1 @@".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_warmed_context_reused_until_content_changes() -> anyhow::Result<()> {
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let count = || calls.load(std::sync::atomic::Ordering::SeqCst);
+        let coder = Coder::builder(MockLlm::new("")).changes(CountingChanges(calls.clone())).build();
+        let path = Path::new("main.rs");
+        coder.warm("fn main() {\n    let x = ;\n}\n", path).await?;
+        assert_eq!(count(), 1);
+
+        // Only the marker added, the changes built ahead are sent
+        let code = "fn main() {\n    let x = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+        let messages = coder.build_messages(code, path, cursor, &Preset::default(), None, None).await?;
+        assert_eq!(messages[1]["content"], "uncommitted changes:\n@@ -1 +1 @@");
+        assert_eq!(count(), 1);
+
+        // Edited besides, they are built again and no longer kept
+        let edited = "fn main() {\n    let x = ??;\n    let y = 2;\n}\n";
+        let cursor = edited.find(CURSOR_MARKER).unwrap();
+        coder.build_messages(edited, path, cursor, &Preset::default(), None, None).await?;
+        assert_eq!(count(), 2);
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+        coder.build_messages(code, path, cursor, &Preset::default(), None, None).await?;
+        assert_eq!(count(), 3);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_uncommitted_changes_sent() -> anyhow::Result<()> {
+        let code = "fn main() {\n    let x = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let diff = "@@ -1 +1 @@\n-fn old() {}\n+fn main() {";
+        let coder = Coder::builder(MockLlm::new("")).changes(FixedChanges(Some(diff.to_string()))).build();
+        let messages = coder.build_messages(code, Path::new("main.rs"), cursor, &Preset::default(), None, None).await?;
+        assert_eq!(messages[1]["content"], format!("uncommitted changes:\n{}", diff));
+        assert!(messages[2]["content"].as_str().unwrap().starts_with("big context:"));
+
+        // Nothing to show, e.g. outside a repository, sends no message
+        let coder = Coder::builder(MockLlm::new("")).changes(FixedChanges(None)).build();
+        let messages = coder.build_messages(code, Path::new("main.rs"), cursor, &Preset::default(), None, None).await?;
+        assert_eq!(messages.len(), 4);
+
+        Ok(())
+    }
+
+    /// Replaces the whole old text in one edit
+    struct WholeDiff;
+
+    impl DiffEngine for WholeDiff {
+        fn edits(&self, old: &str, new: &str) -> Vec<TextEdit> {
+            vec![TextEdit { start: 0, end: old.len(), text: new.to_string() }]
+        }
+    }
+
+    #[tokio::test]
+    async fn test_custom_diff_engine() -> anyhow::Result<()> {
+        let reply = "<|SEARCH|>let x = <|cursor|>;<|DIVIDE|>let x = 42;<|REPLACE|>";
+        let code = "fn main() {\n    let x = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let default = Coder::new(MockLlm::new(reply));
+        let custom = Coder::builder(MockLlm::new(reply)).diff_engine(WholeDiff).build();
+
+        let expected = "fn main() {\n    let x = 42;\n}\n";
+        assert_eq!(default.autocomplete(code, Path::new("main.rs"), cursor).await?, expected);
+        assert_eq!(custom.autocomplete(code, Path::new("main.rs"), cursor).await?, expected);
+
+        // The whole-span edit is shrunk to the inserted value
+        let preset = custom.select_preset(None);
+        let (_, edits, _) = custom.prepare_edits(code, Path::new("main.rs"), cursor, &preset, None).await?;
+        assert_eq!(edits, vec![TextEdit { start: cursor, end: cursor, text: "42".to_string() }]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_patch() -> anyhow::Result<()> {
+        let coder = Coder::new(LlmClient::new("", "", ""));
+
+        let patch = "<|SEARCH|>let <|cursor|> = 10;<|DIVIDE|>let x = 10;<|REPLACE|>";
+        let start_pos = 0;
+
+        let parsed = coder.parse_patch(patch, start_pos + "let ".len())?;
+
+        assert_eq!(parsed.start, start_pos);
+        assert_eq!(parsed.search, "let  = 10;");
+        assert_eq!(parsed.replace, "let x = 10;");
 
         Ok(())
     }
@@ -210,7 +1487,7 @@ fn main() {
         let patch = r#"<|SEARCH|>let <|cursor|> = "йцук";<|DIVIDE|>let x = "йцук";<|REPLACE|>"#;
         let start_pos = 0;
 
-        let parsed = coder.parse_patch(patch, start_pos)?;
+        let parsed = coder.parse_patch(patch, start_pos + "let ".len())?;
 
         assert_eq!(parsed.start, start_pos);
         assert_eq!(parsed.search, "let  = \"йцук\";");
@@ -219,6 +1496,147 @@ fn main() {
         Ok(())
     }
 
+    #[tokio::test]
+    async fn test_autocomplete_with_tool_call() -> anyhow::Result<()> {
+        let response = ChatResponse {
+            content: String::new(),
+            tool_calls: vec![ToolCall {
+                name: APPLY_EDIT_TOOL.to_string(),
+                arguments: json!({ "search": "let x = <|cursor|>;", "replace": "let x = 42;" }),
+            }],
+        };
+        let options = CoderOptions { tool_calls: true, ..Default::default() };
+        let coder = Coder::builder(MockLlm::with_response(response)).options(options).build();
+
+        let code = "fn main() {\n    let x = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let updated = coder.autocomplete(code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(updated, "fn main() {\n    let x = 42;\n}\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_autocomplete_with_diff_reply() -> anyhow::Result<()> {
+        let reply = "```diff\n-    let x = <|cursor|>;\n+    let x = 42;\n```";
+        let coder = Coder::new(MockLlm::new(reply));
+
+        let code = "fn main() {\n    let x = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let updated = coder.autocomplete(code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(updated, "fn main() {\n    let x = 42;\n}\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_patch_from_response_falls_back_to_text() -> anyhow::Result<()> {
+        let coder = Coder::new(MockLlm::new(""));
+        let response = ChatResponse {
+            content: "<|SEARCH|>let <|cursor|> = 10;<|DIVIDE|>let x = 10;<|REPLACE|>".to_string(),
+            tool_calls: vec![],
+        };
+
+        let parsed = coder.patch_from_response(&response, "let ".len())?;
+
+        assert_eq!(parsed.search, "let  = 10;");
+        assert_eq!(parsed.replace, "let x = 10;");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_marker_at_start_of_file() -> anyhow::Result<()> {
+        let reply = "<|SEARCH|><|cursor|>\nfn main() {}<|DIVIDE|>use std::io;\nfn main() {}<|REPLACE|>";
+        let coder = Coder::new(MockLlm::new(reply));
+
+        let updated = coder.autocomplete("??\nfn main() {}\n", Path::new("main.rs"), 0).await?;
+        assert_eq!(updated, "use std::io;\nfn main() {}\n");
+
+        let edits = coder.prepare_edits(
+            "??\nfn main() {}\n", Path::new("main.rs"), 0, &Preset::default(), None
+        ).await?.1;
+        assert_eq!(edits[0].start, 0);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_tab_indented_file() -> anyhow::Result<()> {
+        let reply = "<|SEARCH|>        <|cursor|><|DIVIDE|>        let y = x * 2;\n        println!(\"{}\", y);<|REPLACE|>";
+        let llm = MockLlm::new(reply);
+        let coder = Coder::builder(llm.clone()).tab_width(Some(4)).build();
+
+        let code = "fn main() {\n\tfor x in 0..3 {\n\t\t??\n\t}\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+        let updated = coder.autocomplete(code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(updated, "fn main() {\n\tfor x in 0..3 {\n\t\tlet y = x * 2;\n\t\tprintln!(\"{}\", y);\n\t}\n}\n");
+
+        // The model only sees spaces
+        let calls = llm.calls();
+        assert!(calls[0].iter().all(|m| !m["content"].as_str().unwrap_or_default().contains('\t')));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_file_has_no_marker() {
+        let coder = Coder::new(MockLlm::new(""));
+        for code in ["", "\n"] {
+            let result = coder.autocomplete(code, Path::new("main.rs"), 0).await;
+            assert!(matches!(result, Err(CoderError::MarkerNotFound(0))), "{:?}", code);
+        }
+    }
+
+    #[test]
+    fn test_search_before_start_of_file() {
+        let coder = Coder::new(MockLlm::new(""));
+
+        let patch = coder.parse_patch("<|SEARCH|>let <|cursor|><|DIVIDE|>let x<|REPLACE|>", 2);
+        assert!(matches!(patch, Err(CoderError::Context(_))));
+
+        let patch = coder.parse_patch("<|SEARCH|><|cursor|>;<|DIVIDE|>x;<|REPLACE|>", 0);
+        assert_eq!(patch.unwrap().start, 0);
+    }
+
+    #[test]
+    fn test_parse_patch_two_cursor_tokens() -> anyhow::Result<()> {
+        let coder = Coder::new(MockLlm::new(""));
+        let code = "let a = ??;\nlet b = 2;\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let patch = coder.parse_patch(
+            "<|SEARCH|>let a = <|cursor|>;\nlet b = <|cursor|>2;<|DIVIDE|>let a = 1;\nlet b = 2;<|REPLACE|>",
+            cursor,
+        )?;
+        assert_eq!(patch.start, 0);
+        // The search still matches the file once the marker is gone
+        assert_eq!(patch.search, "let a = ;\nlet b = 2;");
+        assert_eq!(patch.replace, "let a = 1;\nlet b = 2;");
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_patch_missing_tokens() {
+        let coder = Coder::new(MockLlm::new(""));
+
+        let missing_replace = coder.parse_patch("<|SEARCH|>let <|cursor|><|DIVIDE|>let x", 0);
+        assert!(matches!(missing_replace, Err(CoderError::MissingToken(RTOKEN))));
+
+        let missing_cursor = coder.parse_patch("<|SEARCH|>let <|DIVIDE|>let x<|REPLACE|>", 0);
+        assert!(matches!(missing_cursor, Err(CoderError::MissingToken(CTOKEN))));
+    }
+
+    #[test]
+    fn test_apply_text_edits_out_of_bounds() {
+        let coder = Coder::new(MockLlm::new(""));
+        let edits = vec![TextEdit { start: 10, end: 12, text: "x".to_string() }];
+
+        let result = coder.apply_text_edits("short", &edits);
+        assert!(matches!(result, Err(CoderError::EditOutOfBounds(_))));
+    }
+
     #[test]
     fn test_apply_text_edits() -> anyhow::Result<()> {
         let coder = Coder::new(LlmClient::new("", "", ""));
@@ -272,6 +1690,664 @@ fn main() {
         Ok(())
     }
 
+    #[test]
+    fn test_strip_marker_at() -> anyhow::Result<()> {
+        let code = "let a = ??;\nlet b = ??;";
+        let second = code.rfind(CURSOR_MARKER).unwrap();
+
+        assert_eq!(strip_marker_at(code, second)?, "let a = ??;\nlet b = ;");
+        assert!(strip_marker_at(code, 0).is_err());
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_autocomplete_keeps_other_markers() -> anyhow::Result<()> {
+        let reply = "<|SEARCH|>let a = <|cursor|>;<|DIVIDE|>let a = 1;<|REPLACE|>";
+        let coder = Coder::new(MockLlm::new(reply));
+
+        let code = "fn main() {\n    let a = ??;\n    let b = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let updated = coder.autocomplete(code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(updated, "fn main() {\n    let a = 1;\n    let b = ??;\n}\n");
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_text_edits_overlapping() {
+        let coder = Coder::new(MockLlm::new(""));
+        let original = "The quick brown fox";
+
+        let edits = vec![
+            TextEdit { start: 4, end: 15, text: "slow red".to_string() },
+            TextEdit { start: 10, end: 19, text: "grey cat".to_string() },
+        ];
+
+        let err = coder.apply_text_edits(original, &edits).unwrap_err();
+        let message = err.to_string();
+
+        assert!(matches!(err, CoderError::OverlappingEdits(_)));
+        assert!(message.contains("slow red"));
+        assert!(message.contains("grey cat"));
+    }
+
+    #[test]
+    fn test_apply_text_edits_adjacent_and_nested() -> anyhow::Result<()> {
+        let coder = Coder::new(MockLlm::new(""));
+        let original = "The quick brown fox";
+
+        // The first edit ends where the second starts, both apply
+        let adjacent = vec![
+            TextEdit { start: 4, end: 9, text: "slow".to_string() },
+            TextEdit { start: 9, end: 10, text: "-".to_string() },
+            TextEdit { start: 9, end: 9, text: "ish".to_string() },
+        ];
+        assert_eq!(coder.apply_text_edits(original, &adjacent)?, "The slowish-brown fox");
+
+        let nested = vec![
+            TextEdit { start: 4, end: 15, text: "lazy red".to_string() },
+            TextEdit { start: 10, end: 15, text: "green".to_string() },
+        ];
+        assert!(matches!(coder.apply_text_edits(original, &nested), Err(CoderError::OverlappingEdits(_))));
+
+        let merging = Coder::builder(MockLlm::new(""))
+            .options(CoderOptions { overlapping_edits: OverlapPolicy::Merge, ..Default::default() })
+            .build();
+        assert_eq!(merging.apply_text_edits(original, &nested)?, "The lazy red fox");
+
+        let first_wins = Coder::builder(MockLlm::new(""))
+            .options(CoderOptions { overlapping_edits: OverlapPolicy::FirstWins, ..Default::default() })
+            .build();
+        let reversed = nested.into_iter().rev().collect::<Vec<_>>();
+        assert_eq!(first_wins.apply_text_edits(original, &reversed)?, "The quick green fox");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_secrets_redacted_from_requests() -> anyhow::Result<()> {
+        let reply = "<|SEARCH|>    let key = \"<|secret_1|>\";\n    let n = <|cursor|>;<|DIVIDE|>    let key = \"<|secret_1|>\";\n    let n = key.len();<|REPLACE|>";
+        let llm = MockLlm::new(reply);
+        let coder = Coder::builder(llm.clone())
+            .redactor(Redactor::new(&[r"sk-[A-Za-z0-9]{16,}"])?)
+            .build();
+
+        let code = "fn main() {\n    let key = \"sk-0123456789abcdef\";\n    let n = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let updated = coder.autocomplete(code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(updated, "fn main() {\n    let key = \"sk-0123456789abcdef\";\n    let n = key.len();\n}\n");
+
+        let sent = serde_json::to_string(&llm.calls())?;
+        assert!(!sent.contains("sk-0123456789abcdef"));
+        assert!(sent.contains("let key = \\\"<|secret_1|>\\\";"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_secrets_redacted_from_provided_context() -> anyhow::Result<()> {
+        let reply = "<|SEARCH|>    let n = <|cursor|>;<|DIVIDE|>    let n = \"<|secret_1|>\".len();<|REPLACE|>";
+        let llm = MockLlm::new(reply);
+        let diff = "@@ -1,2 +1,3 @@\n fn main() {\n+    let key = \"sk-0123456789abcdef\";";
+        let coder = Coder::builder(llm.clone())
+            .changes(FixedChanges(Some(diff.to_string())))
+            .redactor(Redactor::new(&[r"sk-[A-Za-z0-9]{16,}"])?)
+            .build();
+
+        let code = "fn main() {\n    let n = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let updated = coder.autocomplete(code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(updated, "fn main() {\n    let n = \"sk-0123456789abcdef\".len();\n}\n");
+
+        let sent = serde_json::to_string(&llm.calls())?;
+        assert!(!sent.contains("sk-0123456789abcdef"));
+        assert!(sent.contains("+    let key = \\\"<|secret_1|>\\\";"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scaffold_empty_python_file() -> anyhow::Result<()> {
+        let reply = "<|SEARCH|><|cursor|><|DIVIDE|>def main():\n    pass\n<|REPLACE|>";
+        let llm = MockLlm::new(reply);
+        let options = CoderOptions { scaffold: true, ..Default::default() };
+        let coder = Coder::builder(llm.clone()).options(options).build();
+
+        let code = "??\n";
+        let path = PathBuf::from("app.py");
+
+        let updated = coder.autocomplete(code, &path, 0).await?;
+        assert_eq!(updated, "def main():\n    pass\n\n");
+
+        let calls = llm.calls();
+        assert_eq!(calls.len(), 1);
+        assert_eq!(calls[0][0]["content"], SCAFFOLD_PROMPT);
+
+        let request = calls[0][1]["content"].as_str().unwrap();
+        assert!(request.contains("language: python"));
+        assert!(request.contains(CTOKEN));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_scaffold_disabled_uses_regular_prompt() -> anyhow::Result<()> {
+        let coder = Coder::new(MockLlm::new(""));
+
+        let preset = Preset::default();
+        let messages = coder.build_messages("??", Path::new("app.py"), 0, &preset, None, None).await?;
+        assert_eq!(messages[0]["content"], SYSTEM_PROMPT);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_small_context_tells_its_first_line() -> anyhow::Result<()> {
+        let coder = Coder::new(MockLlm::new(""));
+        let code = (1..=10).map(|i| format!("let x{} = {};\n", i, i)).collect::<String>() + "let y = ??;\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let messages = coder.build_messages(&code, Path::new("main.rs"), cursor, &Preset::default(), None, None).await?;
+        let small = messages.iter()
+            .filter_map(|m| m["content"].as_str())
+            .find(|content| content.starts_with("small context"))
+            .unwrap();
+        assert!(small.starts_with("small context, from line 5:\nlet x5 = 5;"), "{}", small);
+        assert!(SYSTEM_PROMPT.contains("@line N"));
+
+        Ok(())
+    }
+
+    /// Sends a fixed note as context
+    struct Note;
+
+    #[async_trait::async_trait]
+    impl ContextProvider for Note {
+        fn label(&self) -> &str {
+            "note"
+        }
+
+        async fn provide(&self, _original: &str, _cursor: usize, _path: &Path) -> Result<String> {
+            Ok("x is the answer".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_builder_customizes_everything() -> anyhow::Result<()> {
+        let mut prompts = PromptRegistry::default();
+        prompts.set_system("registry system");
+        let terse = Preset { system: "terse system".to_string(), reminder: "be brief".to_string() };
+
+        let llm = MockLlm::new("<|SEARCH|>let x = <|cursor|>;<|DIVIDE|>let x = 42;<|REPLACE|>");
+        let coder = Coder::builder(llm.clone())
+            .prompts(Arc::new(prompts))
+            .add_preset("terse", terse)
+            .preset("terse")
+            .big_context(Some(1))
+            .patch_format(Some(PatchFormat::Tokens))
+            .diff_engine(WholeDiff)
+            .provider(Note)
+            .changes(FixedChanges(Some("@@ -1 +1 @@".to_string())))
+            .build();
+        assert_eq!(coder.prompts().system(), "registry system");
+
+        let code = "fn main() {\n    let y = 1;\n    let x = ??;\n    println!(\"{}\", x);\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+        let updated = coder.autocomplete(code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(updated, code.replace("??", "42"));
+
+        let messages = &llm.calls()[0];
+        let contents = messages.iter().map(|m| m["content"].as_str().unwrap()).collect::<Vec<_>>();
+        assert_eq!(contents[0], "terse system");
+        assert_eq!(contents[1], "note:\nx is the answer");
+        assert_eq!(contents[2], "uncommitted changes:\n@@ -1 +1 @@");
+        assert_eq!(contents[3], "big context:\n    let y = 1;\n    let x = <|cursor|>;\n    println!(\"{}\", x);");
+        assert_eq!(contents.last(), Some(&"be brief"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_prefill_seeds_reply() -> anyhow::Result<()> {
+        let code = "let x = ??;";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+        let prefilled = |reply: &str| Coder::builder(MockLlm::new(reply))
+            .prefill(Some(STOKEN.to_string()))
+            .build();
+
+        let coder = prefilled("");
+        let messages = coder.build_messages(code, Path::new("main.rs"), cursor, &coder.select_preset(None), None, None).await?;
+        assert_eq!(messages.last().unwrap(), &json!({ "role": "assistant", "content": "<|SEARCH|>" }));
+
+        // The reply continues the prefill, or repeats it
+        for reply in ["let x = <|cursor|>;<|DIVIDE|>let x = 1;<|REPLACE|>", "<|SEARCH|>let x = <|cursor|>;<|DIVIDE|>let x = 1;<|REPLACE|>"] {
+            assert_eq!(prefilled(reply).autocomplete(code, Path::new("main.rs"), cursor).await?, "let x = 1;");
+        }
+
+        let tools = Coder::builder(MockLlm::new("")).prefill(Some(STOKEN.to_string())).tool_calls(true).build();
+        let messages = tools.build_messages(code, Path::new("main.rs"), cursor, &tools.select_preset(None), None, None).await?;
+        assert_eq!(messages.last().unwrap()["role"], "user");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_line_annotation_reanchors_patch() -> anyhow::Result<()> {
+        let code = "fn a() {\n    ??\n}\n\nfn b() {\n    let y = 1;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+        let complete = |reply: &str| {
+            let coder = Coder::new(MockLlm::new(reply));
+            async move { coder.autocomplete(code, Path::new("main.rs"), cursor).await }
+        };
+        let expected = "fn a() {\n    todo!()\n}\n\nfn b() {\n    let y = 1;\n}\n";
+
+        // The cursor token sits at the start of the search instead of inside it
+        let misplaced = "<|SEARCH|><|cursor|>fn a() {\n    \n}<|DIVIDE|>fn a() {\n    todo!()\n}<|REPLACE|>";
+        assert_ne!(complete(misplaced).await.ok().as_deref(), Some(expected));
+        assert_eq!(complete(&format!("@line 1\n{}", misplaced)).await?, expected);
+
+        // An annotation agreeing with the cursor changes nothing
+        let agreeing = "@line 2\n<|SEARCH|>    <|cursor|>\n}<|DIVIDE|>    todo!()\n}<|REPLACE|>";
+        assert_eq!(complete(agreeing).await?, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_duplicated_closing_brace_trimmed() -> anyhow::Result<()> {
+        let code = "fn main() {\n    ??\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+        let reply = "<|SEARCH|>fn main() {\n    <|cursor|><|DIVIDE|>fn main() {\n    println!(\"hi\");\n}<|REPLACE|>";
+        let expected = "fn main() {\n    println!(\"hi\");\n}\n";
+
+        let coder = Coder::new(MockLlm::new(reply));
+        assert_eq!(coder.autocomplete(code, Path::new("main.rs"), cursor).await?, expected);
+
+        let untrimmed = Coder::builder(MockLlm::new(reply)).trim_suffix(false).build();
+        let updated = untrimmed.autocomplete(code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(updated, "fn main() {\n    println!(\"hi\");\n}\n}\n");
+        Ok(())
+    }
+
+    #[test]
+    fn test_trim_duplicate_suffix_only_whole_lines() {
+        let patch = |search: &str, replace: &str| Patch {
+            start: 0, search: search.to_string(), replace: replace.to_string(), line: None,
+        };
+        let text = "if ok {\n    \n}\n}\n";
+
+        // The search block already ends with the brace, the model kept it
+        let kept = trim_duplicate_suffix(patch("if ok {\n    \n}", "if ok {\n    run();\n}"), text);
+        assert_eq!(kept.replace, "if ok {\n    run();\n}");
+
+        let added = trim_duplicate_suffix(patch("if ok {\n    ", "if ok {\n    run();\n}\n}"), text);
+        assert_eq!(added.replace, "if ok {\n    run();");
+
+        // Part of a line after the cursor is not a duplicate
+        let text = "let x = 1;\n";
+        let partial = trim_duplicate_suffix(patch("let x = ", "let x = 1"), text);
+        assert_eq!(partial.replace, "let x = 1");
+    }
+
+    #[test]
+    fn test_trim_duplicate_suffix_keeps_repeated_rows() {
+        let patch = |search: &str, replace: &str| Patch {
+            start: 0, search: search.to_string(), replace: replace.to_string(), line: None,
+        };
+        let text = "let grid = [\n    \n    0,\n    0,\n];\n";
+
+        // Rows like the ones below the cursor are what the model was asked for
+        let rows = trim_duplicate_suffix(patch("let grid = [\n    ", "let grid = [\n    1,\n    0,\n    0,"), text);
+        assert_eq!(rows.replace, "let grid = [\n    1,\n    0,\n    0,");
+
+        // A block the replacement opens and closes itself closes nothing twice
+        let text = "run();\n}\n";
+        let block = trim_duplicate_suffix(patch("run();\n", "run();\nif ok {\n    stop();\n}\n"), text);
+        assert_eq!(block.replace, "run();\nif ok {\n    stop();\n}\n");
+    }
+
+    #[tokio::test]
+    async fn test_declined_completion_strips_marker() -> anyhow::Result<()> {
+        let code = "fn main() {\n    let x = 1;\n    ??\n    let y = 2;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+        let expected = "fn main() {\n    let x = 1;\n    \n    let y = 2;\n}\n";
+
+        for reply in [
+            "<|SEARCH|>    let x = 1;\n    <|cursor|>\n    let y = 2;<|DIVIDE|>    let x = 1;\n    \n    let y = 2;<|REPLACE|>",
+            "<|SEARCH|>    let x = 1;\n    <|cursor|>\n    let y = 2;<|DIVIDE|><|REPLACE|>",
+            "<|SEARCH|>    let x = 1;\n    <|cursor|>\n    let y = 2;<|DIVIDE|>\n<|REPLACE|>",
+        ] {
+            let coder = Coder::new(MockLlm::new(reply));
+            assert_eq!(coder.autocomplete(code, Path::new("main.rs"), cursor).await?, expected, "{}", reply);
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_completion_inside_template_literal() -> anyhow::Result<()> {
+        let code = "const greeting = `Hello, ${??}!`;\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let llm = MockLlm::new("<|SEARCH|>${<|cursor|>}!`;<|DIVIDE|>${user.name}!`;<|REPLACE|>");
+        let coder = Coder::new(llm.clone());
+        let completed = coder.autocomplete(code, Path::new("app.js"), cursor).await?;
+        assert_eq!(completed, "const greeting = `Hello, ${user.name}!`;\n");
+        let note = format!("{}`...${{...}}...`", crate::prompts::INTERPOLATION_PROMPT);
+        assert!(llm.calls()[0].iter().any(|m| m["content"] == note.as_str()), "{:?}", llm.calls()[0]);
+
+        // The reply closes the template inside the interpolation
+        let coder = Coder::new(MockLlm::new("<|SEARCH|>${<|cursor|>}!`;<|DIVIDE|>${`user.name}!`;<|REPLACE|>"));
+        let broken = coder.autocomplete(code, Path::new("app.js"), cursor).await;
+        assert!(matches!(broken, Err(CoderError::InvalidResponse(_))), "{:?}", broken);
+
+        // Outside any interpolation nothing is checked
+        let plain = MockLlm::new("<|SEARCH|>x = <|cursor|>;<|DIVIDE|>x = 1;<|REPLACE|>");
+        let coder = Coder::new(plain.clone());
+        coder.autocomplete("x = ??;", Path::new("app.js"), 4).await?;
+        assert!(!plain.calls()[0].iter().any(|m| m["content"].as_str().is_some_and(|c| c.starts_with(crate::prompts::INTERPOLATION_PROMPT))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_autocomplete_traces_listed_paths() -> anyhow::Result<()> {
+        /// Remembers whether each request was made in a traced task
+        #[derive(Clone)]
+        struct TraceProbe(Arc<std::sync::Mutex<Vec<bool>>>);
+
+        #[async_trait::async_trait]
+        impl ChatModel for TraceProbe {
+            async fn chat(&self, _messages: Vec<Value>) -> Result<String> {
+                self.0.lock().unwrap().push(trace::is_traced());
+                Ok("<|SEARCH|>x = <|cursor|>;<|DIVIDE|>x = 1;<|REPLACE|>".to_string())
+            }
+        }
+
+        let source = std::env::temp_dir().join(format!("anycoder-coder-trace-{}.txt", std::process::id()));
+        std::fs::write(&source, "src/traced.rs\n")?;
+        let probe = TraceProbe(Arc::default());
+        let coder = Coder::builder(probe.clone()).trace_files(TraceFiles::new(&source)).build();
+
+        coder.autocomplete("x = ??;", Path::new("src/traced.rs"), 4).await?;
+        coder.autocomplete("x = ??;", Path::new("src/other.rs"), 4).await?;
+        std::fs::remove_file(&source)?;
+
+        assert_eq!(*probe.0.lock().unwrap(), [true, false]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_large_file_sends_outline() -> anyhow::Result<()> {
+        let body: String = (0..200).map(|i| format!("fn f{}() -> u32 {{ {} }}\n", i, i)).collect();
+        let code = format!("{}fn main() {{\n    let x = ??;\n}}\n", body);
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+        let summarizer = MockLlm::new("```\nfn f0..f199() -> u32: constants\nfn main()\n```");
+        let llm = MockLlm::new("<|SEARCH|>    let x = <|cursor|>;<|DIVIDE|>    let x = f1();<|REPLACE|>");
+        let coder = Coder::builder(llm.clone())
+            .summarize_over(Some(1000))
+            .summarizer(summarizer.clone())
+            .build();
+
+        let completed = coder.autocomplete(&code, Path::new("main.rs"), cursor).await?;
+        assert!(completed.ends_with("fn main() {\n    let x = f1();\n}\n"));
+        let big_context = "big context:\nfn f0..f199() -> u32: constants\nfn main()";
+        assert!(llm.calls()[0].iter().any(|m| m["content"] == big_context), "{:?}", llm.calls()[0]);
+        assert!(!llm.calls()[0].iter().any(|m| m["content"].as_str().unwrap_or_default().contains("fn f100()")));
+
+        // The same content is outlined once
+        coder.autocomplete(&code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(summarizer.calls().len(), 1);
+        assert!(summarizer.calls()[0][1]["content"].as_str().unwrap_or_default().contains("let x = ;"));
+
+        // Small files still send their lines
+        let small = "fn f() {}\nfn main() {\n    let x = ??;\n}\n";
+        coder.autocomplete(small, Path::new("main.rs"), small.find(CURSOR_MARKER).unwrap()).await?;
+        assert_eq!(summarizer.calls().len(), 1);
+        assert!(llm.calls()[2].iter().any(|m| m["content"].as_str().unwrap_or_default().starts_with("big context:\nfn f() {}")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_many_char_edits_fall_back_to_lines() -> anyhow::Result<()> {
+        let old: String = (0..50).map(|i| format!("    let v{} = a{};\n", i, i)).collect();
+        let new: String = (0..50).map(|i| format!("    let w{} = b{} + 1;\n", i, i)).collect();
+        let code = format!("fn main() {{\n??\n{}}}\n", old);
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+        let reply = format!("<|SEARCH|><|cursor|>\n{}<|DIVIDE|>\n{}<|REPLACE|>", old, new);
+        let expected = format!("fn main() {{\n\n{}}}\n", new);
+
+        let response = ChatResponse { content: reply.clone(), tool_calls: vec![] };
+        let stripped = strip_marker_at(&code, cursor)?;
+        let by_chars = Coder::builder(MockLlm::new(&reply)).max_char_edits(0).build();
+        let by_lines = Coder::builder(MockLlm::new(&reply)).max_char_edits(20).build();
+        let (_, char_edits) = by_chars.edits_from_response(&response, &stripped, cursor)?;
+        let (_, line_edits) = by_lines.edits_from_response(&response, &stripped, cursor)?;
+        assert!(char_edits.len() > 20, "{}", char_edits.len());
+        assert_eq!(line_edits.len(), 1);
+
+        assert_eq!(by_lines.autocomplete(&code, Path::new("main.rs"), cursor).await?, expected);
+        assert_eq!(by_chars.autocomplete(&code, Path::new("main.rs"), cursor).await?, expected);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_range() -> anyhow::Result<()> {
+        let code = "fn main() {\n    let total = a + b;\n    println!(\"{}\", total);\n}\n";
+        let start = code.find("    let").unwrap();
+        let end = code.find("    println").unwrap();
+
+        let llm = MockLlm::new("```rust\n    let total = a.checked_add(b).unwrap();\n```");
+        let coder = Coder::new(llm.clone());
+        let edit = coder.rewrite_range(code, Path::new("main.rs"), start, end, "avoid overflow").await?;
+        assert_eq!(edit, TextEdit { start, end, text: "    let total = a.checked_add(b).unwrap();\n".to_string() });
+        assert_eq!(
+            coder.apply_text_edits(code, &[edit])?,
+            "fn main() {\n    let total = a.checked_add(b).unwrap();\n    println!(\"{}\", total);\n}\n"
+        );
+
+        let request = llm.calls()[0][1]["content"].as_str().unwrap_or_default().to_string();
+        assert!(request.contains("instruction: avoid overflow"), "{}", request);
+        assert!(request.contains("fn main() {\n<|selection|>    let total = a + b;\n<|/selection|>    println!"), "{}", request);
+
+        // A selection within a line keeps the rest of the line
+        let start = code.find("a + b").unwrap();
+        let coder = Coder::new(MockLlm::new("b + a\n"));
+        let edit = coder.rewrite_range(code, Path::new("main.rs"), start, start + 5, "swap").await?;
+        assert_eq!(edit.text, "b + a");
+
+        let reversed = coder.rewrite_range(code, Path::new("main.rs"), 10, 5, "swap").await;
+        assert!(matches!(reversed, Err(CoderError::Context(_))));
+        let outside = coder.rewrite_range(code, Path::new("main.rs"), 0, code.len() + 1, "swap").await;
+        assert!(matches!(outside, Err(CoderError::Context(_))));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_consensus_on_patch() -> anyhow::Result<()> {
+        let code = "fn main() {\n    let x = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+        let one = "<|SEARCH|>    let x = <|cursor|>;<|DIVIDE|>    let x = 1;<|REPLACE|>";
+        // A different search block with the same resulting edit still agrees
+        let same = "<|SEARCH|>let x = <|cursor|>;\n}<|DIVIDE|>let x = 1;\n}<|REPLACE|>";
+        let two = "<|SEARCH|>    let x = <|cursor|>;<|DIVIDE|>    let x = 2;<|REPLACE|>";
+        let coder = |main: &str, voters: &[&str]| voters.iter()
+            .fold(Coder::builder(MockLlm::new(main)), |builder, reply| builder.voter(MockLlm::new(reply)))
+            .build();
+        let path = Path::new("main.rs");
+
+        let agreed = coder(one, &[same]).autocomplete(code, path, cursor).await?;
+        assert_eq!(agreed, "fn main() {\n    let x = 1;\n}\n");
+
+        // The majority wins over the main model
+        let majority = coder(two, &[one, same]).autocomplete(code, path, cursor).await?;
+        assert_eq!(majority, "fn main() {\n    let x = 1;\n}\n");
+
+        let split = coder(one, &[two]).autocomplete(code, path, cursor).await;
+        assert!(matches!(split, Err(CoderError::NoConsensus(1, 2))), "{:?}", split);
+
+        // An unparseable reply is no vote
+        let broken = coder(one, &[one, "no patch"]).autocomplete(code, path, cursor).await?;
+        assert_eq!(broken, agreed);
+        Ok(())
+    }
+
+    #[test]
+    fn test_builder_defaults_match_new() {
+        let built = Coder::builder(MockLlm::new("")).build();
+        let default = CoderOptions::default();
+        assert_eq!(built.options.big_context_lines, default.big_context_lines);
+        assert_eq!(built.options.docs_budget, default.docs_budget);
+        assert!(built.providers.is_empty() && built.transcript.is_none());
+    }
+
+    #[tokio::test]
+    async fn test_far_edits_discarded() -> anyhow::Result<()> {
+        let reply = indoc! {"
+            <|SEARCH|>fn total(items: &[u32]) -> u32 {
+                items.iter().sum()
+            }
+
+            fn main() {
+                let x = <|cursor|>;
+            }<|DIVIDE|>fn total(items: &[u64]) -> u64 {
+                items.iter().sum()
+            }
+
+            fn main() {
+                let x = total(&[1, 2]);
+            }<|REPLACE|>"};
+        let code = "fn total(items: &[u32]) -> u32 {\n    items.iter().sum()\n}\n\nfn main() {\n    let x = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let unconstrained = Coder::new(MockLlm::new(reply));
+        let updated = unconstrained.autocomplete(code, Path::new("main.rs"), cursor).await?;
+        assert!(updated.contains("u64"));
+
+        // The type change is over 40 bytes above the marker
+        let constrained = Coder::builder(MockLlm::new(reply)).max_edit_distance(Some(16)).build();
+        let updated = constrained.autocomplete(code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(updated, code.replace("??", "total(&[1, 2])"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_big_context_lines_cap() -> anyhow::Result<()> {
+        let options = CoderOptions { big_context_lines: 2, ..Default::default() };
+        let coder = Coder::builder(MockLlm::new("")).options(options).build();
+
+        let code = (0..20).map(|i| format!("line {}\n", i)).collect::<String>()
+            .replace("line 10", "line 10 ??");
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let messages = coder.build_messages(&code, Path::new("main.rs"), cursor, &Preset::default(), None, None).await?;
+        let big = messages[1]["content"].as_str().unwrap();
+        assert!(big.starts_with("big context:"));
+        assert!(big.contains("\nline 8\n") && big.ends_with("\nline 12"));
+        assert!(!big.contains("line 7"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_small_context_only() -> anyhow::Result<()> {
+        let options = CoderOptions { big_context: false, ..Default::default() };
+        let coder = Coder::builder(MockLlm::new("")).options(options).build();
+
+        let code = "fn main() {\n    let x = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let messages = coder.build_messages(code, Path::new("main.rs"), cursor, &Preset::default(), None, None).await?;
+        assert_eq!(messages.len(), 3);
+        assert!(messages.iter().all(|m| !m["content"].as_str().unwrap().starts_with("big context")));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_autocomplete_with_task() -> anyhow::Result<()> {
+        let reply = "<|SEARCH|>    <|cursor|><|DIVIDE|>    println!(\"hi\");<|REPLACE|>";
+        let llm = MockLlm::new(reply);
+        let coder = Coder::new(llm.clone());
+
+        let code = "fn main() {\n    ??\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let updated = coder.autocomplete_with_task(code, Path::new("main.rs"), cursor, Some("greeting")).await?;
+        assert_eq!(updated, "fn main() {\n    println!(\"hi\");\n}\n");
+
+        let calls = llm.calls();
+        assert_eq!(calls[0][3]["content"], format!("{}greeting", TASK_PROMPT));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_fill_holes() -> anyhow::Result<()> {
+        let reply = r#"{"body": "let sum: i32 = xs.iter().sum();", "return": "", "unused": "x"}"#;
+        let llm = MockLlm::new(reply);
+        let coder = Coder::new(llm.clone());
+
+        let code = "fn total(xs: &[i32]) -> i32 {\n    ${body}\n    ${return}\n}\n";
+        let updated = coder.fill_holes(code, Path::new("main.rs")).await?;
+        assert_eq!(updated, "fn total(xs: &[i32]) -> i32 {\n    let sum: i32 = xs.iter().sum();\n    ${return}\n}\n");
+
+        let calls = llm.calls();
+        assert!(calls[0][1]["content"].as_str().unwrap().contains("holes: body, return"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_select_preset() {
+        let terse = Preset { system: "sys".to_string(), reminder: "terse".to_string() };
+        let options = CoderOptions {
+            presets: HashMap::from([("terse".to_string(), terse.clone())]),
+            ..Default::default()
+        };
+        let coder = Coder::builder(MockLlm::new("")).options(options).build();
+
+        assert_eq!(coder.select_preset(None), Preset::default());
+        assert_eq!(coder.select_preset(Some("line")).reminder, LINE_REMINDER);
+        assert_eq!(coder.select_preset(Some("terse")), terse);
+        assert_eq!(coder.select_preset(Some("missing")), Preset::default());
+    }
+
+    #[tokio::test]
+    async fn test_prompt_registry_drives_messages() -> anyhow::Result<()> {
+        let mut prompts = PromptRegistry::default();
+        prompts.set_system("custom system");
+        prompts.set_reminder("custom reminder");
+        let coder = Coder::builder(MockLlm::new("")).prompts(Arc::new(prompts)).build();
+
+        let preset = coder.select_preset(None);
+        let messages = coder.build_messages("let x = ??;", Path::new("main.rs"), 8, &preset, None, None).await?;
+
+        assert_eq!(messages[0]["content"], "custom system");
+        assert_eq!(messages.last().unwrap()["content"], "custom reminder");
+        assert_eq!(coder.select_preset(Some("line")).system, "custom system");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_marker_preset_overrides_config() -> anyhow::Result<()> {
+        let reply = "<|SEARCH|>let x = <|cursor|>;<|DIVIDE|>let x = 1;<|REPLACE|>";
+        let llm = MockLlm::new(reply);
+        let options = CoderOptions { preset: Some("line".to_string()), ..Default::default() };
+        let coder = Coder::builder(llm.clone()).options(options).build();
+
+        let code = "fn main() {\n    let x = ??{preset=block};\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let updated = coder.autocomplete(code, Path::new("main.rs"), cursor).await?;
+        assert_eq!(updated, "fn main() {\n    let x = 1;\n}\n");
+
+        let calls = llm.calls();
+        assert_eq!(calls[0][3]["content"], BLOCK_REMINDER);
+
+        Ok(())
+    }
+
     #[tokio::test]
     #[ignore]
     async fn test_coder() -> anyhow::Result<()> {
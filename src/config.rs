@@ -1,37 +1,1088 @@
 use anyhow::Result;
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+use reqwest::header::{HeaderMap, HeaderName, HeaderValue};
+use crate::coder::{CoderOptions, RTOKEN};
+use crate::prompts::{Preset, load_presets};
+use crate::trigger::{EolTrigger, TodoTrigger};
+use crate::patch::PatchFormat;
+use crate::diff::OverlapPolicy;
+use crate::redact::Redactor;
+
+/// Chars of file content kept in logs under privacy mode
+const PRIVACY_LOG_LIMIT: usize = 40;
+
+/// Declares `Var`, the variables the config is read from, and `KEYS`, their
+/// names, from one list. `Config::from_lookup` can only look up a `Var`, so a
+/// new setting can't be read without a config file key mapping to it.
+macro_rules! variables {
+    ($($var:ident => $key:literal,)*) => {
+        #[derive(Debug, Clone, Copy, PartialEq, Eq)]
+        enum Var { $($var),* }
+
+        impl Var {
+            fn key(self) -> &'static str {
+                match self { $(Var::$var => $key),* }
+            }
+        }
+
+        /// Variables the config is read from, which a config file's keys must map to
+        const KEYS: &[&str] = &[$($key),*];
+    };
+}
+
+variables! {
+    ActiveFiles => "ANYCODER_ACTIVE_FILES",
+    AllowedModels => "ANYCODER_ALLOWED_MODELS",
+    Autocommit => "ANYCODER_AUTOCOMMIT",
+    AutocommitSummary => "ANYCODER_AUTOCOMMIT_SUMMARY",
+    BulkThreshold => "ANYCODER_BULK_THRESHOLD",
+    BulkWindowMs => "ANYCODER_BULK_WINDOW_MS",
+    CheckModels => "ANYCODER_CHECK_MODELS",
+    CoalesceMs => "ANYCODER_COALESCE_MS",
+    Color => "ANYCODER_COLOR",
+    CompletionDeadline => "ANYCODER_COMPLETION_DEADLINE",
+    CompletionLimit => "ANYCODER_COMPLETION_LIMIT",
+    CompletionWindowMs => "ANYCODER_COMPLETION_WINDOW_MS",
+    Concurrency => "ANYCODER_CONCURRENCY",
+    ConsensusModels => "ANYCODER_CONSENSUS_MODELS",
+    ConsensusQuorum => "ANYCODER_CONSENSUS_QUORUM",
+    Consent => "ANYCODER_CONSENT",
+    ContextBudget => "ANYCODER_CONTEXT_BUDGET",
+    Diagnostics => "ANYCODER_DIAGNOSTICS",
+    DocsBudget => "ANYCODER_DOCS_BUDGET",
+    DocsDir => "ANYCODER_DOCS_DIR",
+    EolTrigger => "ANYCODER_EOL_TRIGGER",
+    Examples => "ANYCODER_EXAMPLES",
+    FailureCooldownMs => "ANYCODER_FAILURE_COOLDOWN_MS",
+    FailureLimit => "ANYCODER_FAILURE_LIMIT",
+    Feedback => "ANYCODER_FEEDBACK",
+    GitChanges => "ANYCODER_GIT_CHANGES",
+    Headers => "ANYCODER_HEADERS",
+    Interactive => "ANYCODER_INTERACTIVE",
+    KeepRejectedMarker => "ANYCODER_KEEP_REJECTED_MARKER",
+    KeepTimedOutMarker => "ANYCODER_KEEP_TIMED_OUT_MARKER",
+    MarkerOnce => "ANYCODER_MARKER_ONCE",
+    MaxCharEdits => "ANYCODER_MAX_CHAR_EDITS",
+    MaxContextLines => "ANYCODER_MAX_CONTEXT_LINES",
+    MaxContinuations => "ANYCODER_MAX_CONTINUATIONS",
+    MaxEditDistance => "ANYCODER_MAX_EDIT_DISTANCE",
+    NormalizeNewlines => "ANYCODER_NORMALIZE_NEWLINES",
+    OverlappingEdits => "ANYCODER_OVERLAPPING_EDITS",
+    PatchFormat => "ANYCODER_PATCH_FORMAT",
+    PinnedFiles => "ANYCODER_PINNED_FILES",
+    Prefetch => "ANYCODER_PREFETCH",
+    Prefill => "ANYCODER_PREFILL",
+    Preset => "ANYCODER_PRESET",
+    PresetsFile => "ANYCODER_PRESETS_FILE",
+    Privacy => "ANYCODER_PRIVACY",
+    Proxy => "ANYCODER_PROXY",
+    RateLimitBackoffMs => "ANYCODER_RATE_LIMIT_BACKOFF_MS",
+    RateLimitRetries => "ANYCODER_RATE_LIMIT_RETRIES",
+    Recomplete => "ANYCODER_RECOMPLETE",
+    Redact => "ANYCODER_REDACT",
+    ReviewMarks => "ANYCODER_REVIEW_MARKS",
+    Scaffold => "ANYCODER_SCAFFOLD",
+    Seed => "ANYCODER_SEED",
+    Sidecar => "ANYCODER_SIDECAR",
+    StartupCooldownMs => "ANYCODER_STARTUP_COOLDOWN_MS",
+    Stop => "ANYCODER_STOP",
+    StoreContent => "ANYCODER_STORE_CONTENT",
+    StripBom => "ANYCODER_STRIP_BOM",
+    SummarizeOver => "ANYCODER_SUMMARIZE_OVER",
+    SummaryModel => "ANYCODER_SUMMARY_MODEL",
+    TabWidth => "ANYCODER_TAB_WIDTH",
+    TemplateHoles => "ANYCODER_TEMPLATE_HOLES",
+    TodoPattern => "ANYCODER_TODO_PATTERN",
+    TodoRemove => "ANYCODER_TODO_REMOVE",
+    TodoTrigger => "ANYCODER_TODO_TRIGGER",
+    ToolCalls => "ANYCODER_TOOL_CALLS",
+    TraceFiles => "ANYCODER_TRACE_FILES",
+    Transcript => "ANYCODER_TRANSCRIPT",
+    TrimSuffix => "ANYCODER_TRIM_SUFFIX",
+    ValidateCmd => "ANYCODER_VALIDATE_CMD",
+    ValidateTimeoutMs => "ANYCODER_VALIDATE_TIMEOUT_MS",
+    WatchdogMs => "ANYCODER_WATCHDOG_MS",
+    WriteDelayMs => "ANYCODER_WRITE_DELAY_MS",
+    WriteIntervalMs => "ANYCODER_WRITE_INTERVAL_MS",
+    ApiKey => "OPENROUTER_API_KEY",
+    BaseUrl => "OPENROUTER_BASE_URL",
+    Model => "OPENROUTER_MODEL",
+}
+
+/// Project config file, looked up in the working directory
+const PROJECT_CONFIG: &str = ".anycoder.toml";
+
+/// User config file, relative to the home directory
+const USER_CONFIG: &str = ".config/anycoder/config.toml";
 
 /// Application configuration
 pub struct Config {
     pub api_key: String,
     pub base_url: String,
     pub model: String,
+    /// Proxy for model requests, overriding `HTTPS_PROXY` and `HTTP_PROXY`
+    pub proxy: Option<String>,
+    /// Extra headers sent with every model request, for gateways that need them
+    pub headers: HeaderMap,
+    /// The user agreed to send code to a provider off this machine
+    pub consent: bool,
+    pub scaffold: bool,
+    pub prefetch: bool,
+    pub preset: Option<String>,
+    pub presets: HashMap<String, Preset>,
+    pub tool_calls: bool,
+    pub startup_cooldown: Duration,
+    pub bulk_window: Duration,
+    pub bulk_threshold: usize,
+    /// Quiet time after a save before it is handled, so repeated events for one save collapse
+    pub coalesce_window: Duration,
+    pub privacy: bool,
+    pub big_context: bool,
+    /// Lines sent on each side of the marker in the big context
+    pub max_context_lines: usize,
+    pub log_limit: Option<usize>,
+    pub todo_trigger: Option<TodoTrigger>,
+    pub store_content: bool,
+    pub normalize_newlines: bool,
+    /// Columns an indentation tab is expanded to in what the model sees
+    pub tab_width: Option<usize>,
+    pub transcript: Option<PathBuf>,
+    pub diagnostics: bool,
+    pub docs_dir: Option<PathBuf>,
+    pub docs_budget: usize,
+    /// Approximate tokens of docs, changes and big context sent at most
+    pub context_budget: Option<usize>,
+    /// Bytes from the marker beyond which edits are dropped, unlimited when unset
+    pub max_edit_distance: Option<usize>,
+    /// Edits of a patch beyond which it is diffed by lines, never when 0
+    pub max_char_edits: usize,
+    /// Bytes of a file beyond which an outline is sent as its big context
+    pub summarize_over: Option<usize>,
+    /// Model writing the outlines, `model` when unset
+    pub summary_model: Option<String>,
+    /// Start of the model's reply sent along with the request
+    pub prefill: Option<String>,
+    pub eol_trigger: Option<EolTrigger>,
+    /// Models that may be used, any model when unset
+    pub allowed_models: Option<Vec<String>>,
+    /// Models asked along with `model`, applying a patch only when enough agree
+    pub consensus_models: Vec<String>,
+    /// Models that must agree on a patch, a majority when unset
+    pub consensus_quorum: Option<usize>,
+    /// Look the configured models up in the provider's `/models` list at startup
+    pub check_models: bool,
+    pub recomplete: bool,
+    pub seed: Option<u64>,
+    /// Follow-up requests for a reply cut off at the token limit
+    pub max_continuations: usize,
+    /// Stop sequences for plain text requests, `<|REPLACE|>` unless overridden
+    pub stop: Vec<String>,
+    /// Control file listing the files to process, all files when unset
+    pub active_files: Option<PathBuf>,
+    /// Hot files `complete-all` completes first, relative to the watched directory
+    pub pinned_files: Vec<PathBuf>,
+    /// Control file listing the files whose completions log at debug and trace level
+    pub trace_files: Option<PathBuf>,
+    pub feedback: bool,
+    /// Put comment lines around what a completion changed, stripped on the next save
+    pub review_marks: bool,
+    /// Patterns of secrets replaced with placeholders before completing
+    pub redact: Vec<String>,
+    /// Drop the byte order mark from files anycoder writes instead of keeping it
+    pub strip_bom: bool,
+    /// Ask before writing each completion
+    pub interactive: bool,
+    /// Leave the marker in place when a completion is rejected instead of removing it
+    pub keep_rejected_marker: bool,
+    /// Command run on each completed file before writing it, with `{file}` as its path
+    pub validate_cmd: Option<String>,
+    pub validate_timeout: Duration,
+    /// Wall-clock limit for one completion, none when unset or zero
+    pub completion_deadline: Option<Duration>,
+    /// How often the watcher is probed for liveness, never when unset
+    pub watchdog_interval: Option<Duration>,
+    /// Leave the marker in place when a completion times out instead of removing it
+    pub keep_timed_out_marker: bool,
+    /// Write completions to `<file>.anycoder` and leave the original untouched
+    pub sidecar: bool,
+    /// Complete a marker once per appearance, ignoring saves while it stays
+    pub marker_once: bool,
+    /// Fill `${name}` holes of files without a marker
+    pub template_holes: bool,
+    /// Edit format of model replies, detected per reply when unset
+    pub patch_format: Option<PatchFormat>,
+    /// What applying does with edits whose ranges overlap or nest
+    pub overlapping_edits: OverlapPolicy,
+    /// Trim lines at the end of a completion repeating the text after it
+    pub trim_suffix: bool,
+    /// Completions run at once by `complete-all`
+    pub concurrency: usize,
+    /// Completions allowed per file within `completion_window`, unlimited when 0
+    pub completion_limit: usize,
+    pub completion_window: Duration,
+    /// Consecutive failed or rejected completions that pause a file, never when 0
+    pub failure_limit: usize,
+    pub failure_cooldown: Duration,
+    /// Least time between a save and our write of the completion
+    pub write_delay: Duration,
+    /// Least time between two of our writes to the same file
+    pub write_interval: Duration,
+    /// First pause of all requests after a rate limited reply, doubling while they continue
+    pub rate_limit_backoff: Duration,
+    /// Retries of a rate limited request before it fails
+    pub rate_limit_retries: usize,
+    /// Send the file's uncommitted git changes along with the context
+    pub git_changes: bool,
+    /// Commit each completed file on its own
+    pub autocommit: bool,
+    /// Have the model write the messages of those commits
+    pub autocommit_summary: bool,
+    /// Similar code from elsewhere in the file sent as examples, none when 0
+    pub examples: usize,
 }
 
 impl Config {
     /// Load configuration from environment variables
     pub fn from_env() -> Result<Self> {
-        let api_key = std::env::var("OPENROUTER_API_KEY")
-            .map_err(|_| anyhow::anyhow!("OPENROUTER_API_KEY environment variable not set"))?;
+        Self::from_lookup(|key| std::env::var(key).ok())
+    }
+
+    /// Load configuration from the first config file found, with
+    /// environment variables taking precedence over its values
+    pub fn load() -> Result<Self> {
+        let values = match find_config_file() {
+            Some(path) => {
+                log::info!("Reading config from {:?}", path);
+                file_values(&path)?
+            }
+            None => HashMap::new(),
+        };
+        Self::from_lookup(layered(|key| std::env::var(key).ok(), &values))
+    }
+
+    /// Loads the variables of `env_file` into the environment, where they
+    /// override a later `.env` but not variables that are already set
+    pub fn load_env_file(env_file: &Path) -> Result<()> {
+        dotenv::from_path(env_file)
+            .map_err(|e| anyhow::anyhow!("Failed to load env file {:?}: {}", env_file, e))
+    }
+
+    /// Load configuration from a TOML file only
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let values = file_values(path)?;
+        Self::from_lookup(|key| values.get(key).cloned())
+    }
+
+    /// Load configuration from any key-value source
+    pub fn from_lookup(lookup: impl Fn(&str) -> Option<String>) -> Result<Self> {
+        let lookup = |var: Var| lookup(var.key());
+        // A local provider may not need a key
+        let api_key = lookup(Var::ApiKey).unwrap_or_default();
         
-        let base_url = std::env::var("OPENROUTER_BASE_URL")
-            .unwrap_or_else(|_| "https://openrouter.ai/api/v1".to_string());
+        let base_url = lookup(Var::BaseUrl)
+            .unwrap_or_else(|| "https://openrouter.ai/api/v1".to_string());
         
-        let model = std::env::var("OPENROUTER_MODEL")
-            .unwrap_or_else(|_| "mistralai/codestral-2501".to_string());
+        let model = lookup(Var::Model)
+            .unwrap_or_else(|| "mistralai/codestral-2501".to_string());
+        check_provider(&api_key, &base_url, &model)?;
+
+        let allowed_models = lookup(Var::AllowedModels)
+            .map(|v| v.split(',')
+                .map(|m| m.trim().to_string())
+                .filter(|m| !m.is_empty())
+                .collect::<Vec<_>>());
+        check_model(allowed_models.as_deref(), &model)?;
+
+        let consensus_models = lookup(Var::ConsensusModels)
+            .map(|v| v.split(',')
+                .map(|m| m.trim().to_string())
+                .filter(|m| !m.is_empty())
+                .collect::<Vec<_>>())
+            .unwrap_or_default();
+        for voter in &consensus_models {
+            check_model(allowed_models.as_deref(), voter)?;
+        }
+        let summary_model = lookup(Var::SummaryModel).filter(|v| !v.trim().is_empty());
+        if let Some(model) = &summary_model {
+            check_model(allowed_models.as_deref(), model)?;
+        }
+
+        let privacy = flag(&lookup, Var::Privacy);
+        let max_context_lines = number(&lookup, Var::MaxContextLines, 1000)?;
+        if privacy && !is_local_url(&base_url) {
+            anyhow::bail!(
+                "ANYCODER_PRIVACY refuses non-local OPENROUTER_BASE_URL {:?}", base_url
+            );
+        }
+
+        let preset = lookup(Var::Preset)
+            .filter(|v| !v.trim().is_empty());
+
+        let presets = match lookup(Var::PresetsFile) {
+            Some(path) => load_presets(Path::new(&path))?,
+            None => HashMap::new(),
+        };
+
+        // One pattern per line, since patterns may hold commas
+        let redact = lookup(Var::Redact)
+            .map(|v| v.lines()
+                .map(|p| p.trim().to_string())
+                .filter(|p| !p.is_empty())
+                .collect::<Vec<_>>())
+            .unwrap_or_default();
+        Redactor::new(&redact).map_err(|e| anyhow::anyhow!("Invalid ANYCODER_REDACT pattern: {}", e))?;
+
+        let todo_trigger = if flag(&lookup, Var::TodoTrigger) {
+            let pattern = lookup(Var::TodoPattern);
+            let remove = lookup(Var::TodoRemove).is_none_or(|v| parse_bool(&v));
+            Some(TodoTrigger::new(pattern.as_deref(), remove)?)
+        } else {
+            None
+        };
 
         Ok(Self {
             api_key,
             base_url,
             model,
+            scaffold: flag(&lookup, Var::Scaffold),
+            prefetch: flag(&lookup, Var::Prefetch),
+            preset,
+            presets,
+            tool_calls: flag(&lookup, Var::ToolCalls),
+            startup_cooldown: millis(&lookup, Var::StartupCooldownMs, 0)?,
+            bulk_window: millis(&lookup, Var::BulkWindowMs, 1000)?,
+            bulk_threshold: number(&lookup, Var::BulkThreshold, 0)?,
+            coalesce_window: millis(&lookup, Var::CoalesceMs, 50)?,
+            privacy,
+            big_context: !privacy && max_context_lines > 0,
+            max_context_lines,
+            log_limit: privacy.then_some(PRIVACY_LOG_LIMIT),
+            todo_trigger,
+            store_content: lookup(Var::StoreContent).is_none_or(|v| parse_bool(&v)),
+            normalize_newlines: flag(&lookup, Var::NormalizeNewlines),
+            tab_width: optional_number(&lookup, Var::TabWidth)?.filter(|&columns| columns > 0),
+            // Records whole files and prompts on disk
+            transcript: lookup(Var::Transcript)
+                .filter(|v| !v.trim().is_empty() && !privacy)
+                .map(PathBuf::from),
+            diagnostics: flag(&lookup, Var::Diagnostics),
+            // Doc snippets go beyond the small context
+            docs_dir: lookup(Var::DocsDir)
+                .filter(|v| !v.trim().is_empty() && !privacy)
+                .map(PathBuf::from),
+            docs_budget: number(&lookup, Var::DocsBudget, 1000)?,
+            context_budget: optional_number(&lookup, Var::ContextBudget)?,
+            max_edit_distance: optional_number(&lookup, Var::MaxEditDistance)?,
+            max_char_edits: number(&lookup, Var::MaxCharEdits, 200)?,
+            summarize_over: optional_number(&lookup, Var::SummarizeOver)?.filter(|_| !privacy),
+            summary_model,
+            prefill: lookup(Var::Prefill).filter(|v| !v.is_empty()),
+            eol_trigger: lookup(Var::EolTrigger)
+                .filter(|v| !v.is_empty())
+                .map(|v| EolTrigger::new(&v))
+                .transpose()?,
+            allowed_models,
+            consensus_models,
+            consensus_quorum: optional_number(&lookup, Var::ConsensusQuorum)?,
+            check_models: flag(&lookup, Var::CheckModels),
+            trim_suffix: lookup(Var::TrimSuffix).is_none_or(|v| parse_bool(&v)),
+            recomplete: flag(&lookup, Var::Recomplete),
+            seed: optional_number(&lookup, Var::Seed)?,
+            max_continuations: number(&lookup, Var::MaxContinuations, 2)?,
+            proxy: lookup(Var::Proxy).filter(|v| !v.trim().is_empty()),
+            headers: parse_headers(lookup(Var::Headers).as_deref().unwrap_or_default())?,
+            consent: flag(&lookup, Var::Consent),
+            stop: lookup(Var::Stop)
+                .map(|v| v.split(',')
+                    .filter(|s| !s.is_empty())
+                    .map(String::from)
+                    .collect())
+                .unwrap_or_else(|| vec![RTOKEN.to_string()]),
+            active_files: lookup(Var::ActiveFiles)
+                .filter(|v| !v.trim().is_empty())
+                .map(PathBuf::from),
+            pinned_files: lookup(Var::PinnedFiles)
+                .map(|v| v.split(',')
+                    .map(str::trim)
+                    .filter(|p| !p.is_empty())
+                    .map(PathBuf::from)
+                    .collect())
+                .unwrap_or_default(),
+            trace_files: lookup(Var::TraceFiles)
+                .filter(|v| !v.trim().is_empty())
+                .map(PathBuf::from),
+            feedback: flag(&lookup, Var::Feedback),
+            review_marks: flag(&lookup, Var::ReviewMarks),
+            redact,
+            strip_bom: flag(&lookup, Var::StripBom),
+            interactive: flag(&lookup, Var::Interactive),
+            keep_rejected_marker: flag(&lookup, Var::KeepRejectedMarker),
+            validate_cmd: lookup(Var::ValidateCmd).filter(|v| !v.trim().is_empty()),
+            validate_timeout: millis(&lookup, Var::ValidateTimeoutMs, 10_000)?,
+            watchdog_interval: optional_number(&lookup, Var::WatchdogMs)?
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            completion_deadline: optional_number(&lookup, Var::CompletionDeadline)?
+                .filter(|&ms| ms > 0)
+                .map(Duration::from_millis),
+            keep_timed_out_marker: flag(&lookup, Var::KeepTimedOutMarker),
+            sidecar: flag(&lookup, Var::Sidecar),
+            marker_once: flag(&lookup, Var::MarkerOnce),
+            // Filling holes sends the whole file
+            template_holes: flag(&lookup, Var::TemplateHoles) && !privacy,
+            patch_format: lookup(Var::PatchFormat)
+                .filter(|v| !v.trim().is_empty() && !v.trim().eq_ignore_ascii_case("auto"))
+                .map(|v| v.parse())
+                .transpose()?,
+            overlapping_edits: lookup(Var::OverlappingEdits)
+                .filter(|v| !v.trim().is_empty())
+                .map(|v| v.parse())
+                .transpose()?
+                .unwrap_or_default(),
+            concurrency: number(&lookup, Var::Concurrency, 4)?.max(1),
+            completion_limit: number(&lookup, Var::CompletionLimit, 0)?,
+            completion_window: millis(&lookup, Var::CompletionWindowMs, 60_000)?,
+            failure_limit: number(&lookup, Var::FailureLimit, 0)?,
+            failure_cooldown: millis(&lookup, Var::FailureCooldownMs, 300_000)?,
+            write_delay: millis(&lookup, Var::WriteDelayMs, 0)?,
+            write_interval: millis(&lookup, Var::WriteIntervalMs, 0)?,
+            rate_limit_backoff: millis(&lookup, Var::RateLimitBackoffMs, 1_000)?,
+            rate_limit_retries: number(&lookup, Var::RateLimitRetries, 5)?,
+            // Diffs carry file content beyond the small context
+            git_changes: flag(&lookup, Var::GitChanges) && !privacy,
+            autocommit: flag(&lookup, Var::Autocommit),
+            // The diff may reach beyond the small context
+            autocommit_summary: flag(&lookup, Var::AutocommitSummary) && !privacy,
+            examples: match privacy {
+                true => 0,
+                false => number(&lookup, Var::Examples, 0)?,
+            },
+        })
+    }
+
+    /// Fails for a model outside `ANYCODER_ALLOWED_MODELS`. Anything that
+    /// switches models after startup must go through this check.
+    pub fn check_model(&self, model: &str) -> Result<()> {
+        check_model(self.allowed_models.as_deref(), model)
+    }
+
+    /// Tells whether completions send code off this machine, which needs the user's consent
+    pub fn sends_code_out(&self) -> bool {
+        !is_local_url(&self.base_url)
+    }
+
+    /// Options for the coder derived from this configuration
+    pub fn coder_options(&self) -> CoderOptions {
+        CoderOptions {
+            scaffold: self.scaffold,
+            preset: self.preset.clone(),
+            presets: self.presets.clone(),
+            tool_calls: self.tool_calls,
+            big_context: self.big_context,
+            big_context_lines: self.max_context_lines,
+            log_limit: self.log_limit,
+            normalize_newlines: self.normalize_newlines,
+            tab_width: self.tab_width,
+            docs_budget: self.docs_budget,
+            context_budget: self.context_budget,
+            max_edit_distance: self.max_edit_distance,
+            max_char_edits: self.max_char_edits,
+            prefill: self.prefill.clone(),
+            quorum: self.consensus_quorum,
+            summarize_over: self.summarize_over,
+            patch_format: self.patch_format,
+            overlapping_edits: self.overlapping_edits,
+            trim_suffix: self.trim_suffix,
+        }
+    }
+}
+
+/// Looks keys up in `env` first and falls back to config file `values`
+fn layered<'a>(
+    env: impl Fn(&str) -> Option<String> + 'a, values: &'a HashMap<String, String>
+) -> impl Fn(&str) -> Option<String> + 'a {
+    move |key| env(key).or_else(|| values.get(key).cloned())
+}
+
+/// `.anycoder.toml` in the working directory, then the user config file
+fn find_config_file() -> Option<PathBuf> {
+    std::iter::once(PathBuf::from(PROJECT_CONFIG))
+        .chain(user_config_file())
+        .find(|path| path.is_file())
+}
+
+/// The user config file, whether it exists or not
+pub fn user_config_file() -> Option<PathBuf> {
+    std::env::var_os("HOME").map(|home| PathBuf::from(home).join(USER_CONFIG))
+}
+
+/// Tells whether watcher event `path` is one of the config files
+/// `Config::load` reads, as reported when watching the working directory
+pub fn is_config_file(path: &Path) -> bool {
+    let relative = path.strip_prefix(".").unwrap_or(path);
+    relative == Path::new(PROJECT_CONFIG) || user_config_file().is_some_and(|user| user == path)
+}
+
+/// Reads a TOML config file into the environment variables its keys stand for.
+/// `api_key`, `base_url` and `model` map to the `OPENROUTER_` variables, any
+/// other key `name` to `ANYCODER_NAME`; arrays become comma-separated lists,
+/// the `redact` patterns and the `headers` table one per line. Unknown keys,
+/// such as a misspelled `modle`, are left out with a warning.
+pub fn file_values(path: &Path) -> Result<HashMap<String, String>> {
+    let content = std::fs::read_to_string(path)?;
+    let table: toml::Table = toml::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("Invalid config file {:?}: {}", path, e))?;
+
+    table.into_iter()
+        .filter(|(key, _)| {
+            let known = KEYS.contains(&env_key(key).as_str());
+            if !known {
+                log::warn!("Ignoring unknown key {:?} in {:?}", key, path);
+            }
+            known
+        })
+        .map(|(key, value)| {
+            let value = match (key.as_str(), &value) {
+                ("headers", toml::Value::Table(headers)) => headers.iter()
+                    .map(|(name, value)| Some(format!("{}={}", name, toml_value(value)?)))
+                    .collect::<Option<Vec<_>>>()
+                    .map(|headers| headers.join("\n")),
+                ("redact", toml::Value::Array(patterns)) => patterns.iter()
+                    .map(toml_value)
+                    .collect::<Option<Vec<_>>>()
+                    .map(|patterns| patterns.join("\n")),
+                _ => toml_value(&value),
+            };
+            let value = value
+                .ok_or_else(|| anyhow::anyhow!("Unsupported value for {:?} in {:?}", key, path))?;
+            Ok((env_key(&key), value))
         })
+        .collect()
+}
+
+fn env_key(key: &str) -> String {
+    match key {
+        "api_key" | "base_url" | "model" => format!("OPENROUTER_{}", key.to_uppercase()),
+        key => format!("ANYCODER_{}", key.to_uppercase()),
+    }
+}
+
+fn toml_value(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(s) => Some(s.clone()),
+        toml::Value::Integer(n) => Some(n.to_string()),
+        toml::Value::Float(n) => Some(n.to_string()),
+        toml::Value::Boolean(b) => Some(b.to_string()),
+        toml::Value::Array(items) => items.iter()
+            .map(toml_value)
+            .collect::<Option<Vec<_>>>()
+            .map(|items| items.join(",")),
+        toml::Value::Datetime(_) | toml::Value::Table(_) => None,
+    }
+}
+
+/// Reads `Name=value` pairs one per line, such as
+/// `HTTP-Referer=https://example.com` and `X-Title=anycoder`. Values may hold
+/// commas, as in `Accept=text/plain, application/json`, but never a line break.
+fn parse_headers(value: &str) -> Result<HeaderMap> {
+    let mut headers = HeaderMap::new();
+    for pair in value.lines().filter(|pair| !pair.trim().is_empty()) {
+        let (name, value) = pair.split_once('=')
+            .ok_or_else(|| anyhow::anyhow!("Expected Name=value in ANYCODER_HEADERS, got {:?}", pair.trim()))?;
+        let name = HeaderName::from_bytes(name.trim().as_bytes())
+            .map_err(|_| anyhow::anyhow!("Invalid header name {:?} in ANYCODER_HEADERS", name.trim()))?;
+        let value = HeaderValue::from_str(value.trim())
+            .map_err(|_| anyhow::anyhow!("Invalid value for header {} in ANYCODER_HEADERS", name))?;
+        headers.append(name, value);
+    }
+    Ok(headers)
+}
+
+/// Checks the provider settings up front, so mistakes show at startup rather
+/// than on the first request. Every problem found is listed in one error.
+fn check_provider(api_key: &str, base_url: &str, model: &str) -> Result<()> {
+    let mut problems = Vec::new();
+    match url::Url::parse(base_url.trim()) {
+        Ok(url) if !matches!(url.scheme(), "http" | "https") => problems.push(format!(
+            "OPENROUTER_BASE_URL {:?} must be an http or https URL", base_url
+        )),
+        Ok(_) => {}
+        Err(e) => problems.push(format!("OPENROUTER_BASE_URL {:?} is not a valid URL: {}", base_url, e)),
+    }
+    if api_key.trim().is_empty() && !is_local_url(base_url) {
+        problems.push(format!("OPENROUTER_API_KEY is not set, the provider at {} needs one", base_url));
     }
+    if model.trim().is_empty() {
+        problems.push("OPENROUTER_MODEL is empty".to_string());
+    }
+
+    if !problems.is_empty() {
+        anyhow::bail!("Invalid configuration:\n- {}", problems.join("\n- "));
+    }
+    Ok(())
+}
+
+fn check_model(allowed: Option<&[String]>, model: &str) -> Result<()> {
+    match allowed {
+        Some(allowed) if !allowed.iter().any(|m| m == model) => anyhow::bail!(
+            "Model {:?} is not in ANYCODER_ALLOWED_MODELS ({})", model, allowed.join(", ")
+        ),
+        _ => Ok(()),
+    }
+}
+
+/// Tells whether a URL points at this machine
+fn is_local_url(url: &str) -> bool {
+    let Ok(url) = url::Url::parse(url) else {
+        return false;
+    };
+    match url.host() {
+        Some(url::Host::Domain(domain)) => domain == "localhost",
+        Some(url::Host::Ipv4(ip)) => ip.is_loopback(),
+        Some(url::Host::Ipv6(ip)) => ip.is_loopback(),
+        None => false,
+    }
+}
+
+/// Parses a boolean flag such as `1`, `true`, `yes` or `on`
+fn parse_bool(value: &str) -> bool {
+    matches!(value.trim().to_lowercase().as_str(), "1" | "true" | "yes" | "on")
+}
+
+/// Reads a boolean flag, defaulting to off
+fn flag(lookup: &impl Fn(Var) -> Option<String>, var: Var) -> bool {
+    lookup(var).is_some_and(|v| parse_bool(&v))
+}
+
+/// Reads a numeric setting, failing on values that don't parse
+fn number<T: FromStr>(
+    lookup: &impl Fn(Var) -> Option<String>, var: Var, default: T
+) -> Result<T> {
+    Ok(optional_number(lookup, var)?.unwrap_or(default))
+}
+
+/// Reads a numeric setting that has no default
+fn optional_number<T: FromStr>(
+    lookup: &impl Fn(Var) -> Option<String>, var: Var
+) -> Result<Option<T>> {
+    lookup(var)
+        .map(|value| value.trim().parse()
+            .map_err(|_| anyhow::anyhow!("{} must be a number, got {:?}", var.key(), value)))
+        .transpose()
+}
 
+/// Reads a duration given in milliseconds
+fn millis(
+    lookup: &impl Fn(Var) -> Option<String>, var: Var, default: u64
+) -> Result<Duration> {
+    number(lookup, var, default).map(Duration::from_millis)
+}
 
+/// Whether log levels are colored. `ANYCODER_COLOR` decides when set to
+/// `always` or `never`, otherwise only a terminal gets colors, unless `NO_COLOR` is set.
+pub fn log_color(lookup: impl Fn(&str) -> Option<String>, terminal: bool) -> bool {
+    let choice = lookup(Var::Color.key()).map(|v| v.trim().to_lowercase()).unwrap_or_default();
+    match choice.as_str() {
+        "always" => true,
+        "never" => false,
+        "" | "auto" => terminal && lookup("NO_COLOR").is_none_or(|v| v.is_empty()),
+        other => parse_bool(other),
+    }
 }
 
-/// Initialize the logger
+/// Writes one log line as `[timestamp LEVEL target] message`, with an ANSI
+/// colored level when `color` is set and no escape codes otherwise
+pub fn write_log_line(
+    out: &mut impl std::io::Write, timestamp: impl std::fmt::Display, record: &log::Record, color: bool
+) -> std::io::Result<()> {
+    let level = format!("{:<5}", record.level());
+    let level = if color {
+        let code = match record.level() {
+            log::Level::Error => 31,
+            log::Level::Warn => 33,
+            log::Level::Info => 32,
+            log::Level::Debug => 34,
+            log::Level::Trace => 36,
+        };
+        format!("\x1b[{}m{}\x1b[0m", code, level)
+    } else {
+        level
+    };
+    writeln!(out, "[{} {} {}] {}", timestamp, level, record.target(), record.args())
+}
+
+/// Initialize the logger, at debug level and at trace level for anycoder
+/// itself in the completions of the files listed by `ANYCODER_TRACE_FILES`
 pub fn init_logger() {
-    env_logger::Builder::from_default_env()
-        .filter_level(log::LevelFilter::Debug)
-        .init();
-}
\ No newline at end of file
+    use std::io::IsTerminal;
+
+    let color = log_color(|key| std::env::var(key).ok(), std::io::stderr().is_terminal());
+    let style = if color { env_logger::WriteStyle::Always } else { env_logger::WriteStyle::Never };
+    let builder = || {
+        let mut builder = env_logger::Builder::from_default_env();
+        builder
+            .filter_level(log::LevelFilter::Debug)
+            .write_style(style)
+            .format(move |buf, record| {
+                let timestamp = buf.timestamp();
+                write_log_line(buf, timestamp, record, color)
+            });
+        builder
+    };
+    let verbose = builder().filter_module("anycoder", log::LevelFilter::Trace).build();
+    crate::trace::PathLogger::new(builder().build(), verbose)
+        .init()
+        .expect("logger initialized once");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn lookup_from(pairs: &[(&str, &str)]) -> impl Fn(&str) -> Option<String> {
+        let map: HashMap<String, String> = pairs.iter()
+            .map(|(k, v)| (k.to_string(), v.to_string()))
+            .collect();
+        move |key| map.get(key).cloned()
+    }
+
+    #[test]
+    fn test_log_color() {
+        assert!(log_color(lookup_from(&[]), true));
+        assert!(!log_color(lookup_from(&[]), false));
+        assert!(!log_color(lookup_from(&[("NO_COLOR", "1")]), true));
+        assert!(log_color(lookup_from(&[("NO_COLOR", "")]), true));
+        assert!(log_color(lookup_from(&[("ANYCODER_COLOR", "always"), ("NO_COLOR", "1")]), false));
+        assert!(!log_color(lookup_from(&[("ANYCODER_COLOR", "never")]), true));
+        assert!(!log_color(lookup_from(&[("ANYCODER_COLOR", "auto")]), false));
+    }
+
+    #[test]
+    fn test_log_line_plain_when_piped() -> std::io::Result<()> {
+        let args = format_args!("completed main.rs");
+        let record = log::Record::builder().level(log::Level::Warn).target("anycoder").args(args).build();
+
+        let mut plain = Vec::new();
+        write_log_line(&mut plain, "2026-01-01T00:00:00Z", &record, log_color(lookup_from(&[]), false))?;
+        assert_eq!(String::from_utf8_lossy(&plain), "[2026-01-01T00:00:00Z WARN  anycoder] completed main.rs\n");
+
+        let mut colored = Vec::new();
+        write_log_line(&mut colored, "2026-01-01T00:00:00Z", &record, true)?;
+        assert!(String::from_utf8_lossy(&colored).contains("\x1b[33mWARN \x1b[0m"));
+        Ok(())
+    }
+
+    #[test]
+    fn test_config_file_events() {
+        assert!(is_config_file(Path::new("./.anycoder.toml")));
+        assert!(is_config_file(Path::new(".anycoder.toml")));
+        assert!(!is_config_file(Path::new("./src/.anycoder.toml")));
+        assert!(!is_config_file(Path::new("./pyproject.toml")));
+    }
+
+    #[test]
+    fn test_from_lookup_defaults() -> Result<()> {
+        let config = Config::from_lookup(lookup_from(&[("OPENROUTER_API_KEY", "sk")]))?;
+
+        assert_eq!(config.model, "mistralai/codestral-2501");
+        assert!(!config.scaffold);
+        assert_eq!(config.startup_cooldown, Duration::ZERO);
+        assert_eq!(config.bulk_threshold, 0);
+        assert_eq!(config.seed, None);
+        assert_eq!(config.completion_deadline, None);
+        assert_eq!(config.patch_format, None);
+        assert_eq!(config.overlapping_edits, OverlapPolicy::Reject);
+        assert_eq!(config.concurrency, 4);
+        assert_eq!(config.max_char_edits, 200);
+        assert_eq!(config.max_continuations, 2);
+        assert_eq!(config.summarize_over, None);
+        assert!(config.sends_code_out());
+        assert!(!config.consent);
+        assert!(!config.check_models);
+        assert!(!config.review_marks);
+        assert!(config.redact.is_empty());
+        assert!(config.pinned_files.is_empty());
+        assert!(config.trim_suffix);
+
+        let seeded = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("ANYCODER_SEED", "7"),
+            ("ANYCODER_COMPLETION_DEADLINE", "1500"),
+            ("ANYCODER_PATCH_FORMAT", "conflict"),
+            ("ANYCODER_OVERLAPPING_EDITS", "first-wins"),
+        ]))?;
+        assert_eq!(seeded.seed, Some(7));
+        assert_eq!(seeded.completion_deadline, Some(Duration::from_millis(1500)));
+        assert_eq!(seeded.patch_format, Some(PatchFormat::Conflict));
+        assert_eq!(seeded.overlapping_edits, OverlapPolicy::FirstWins);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_from_lookup_storm_settings() -> Result<()> {
+        let config = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("ANYCODER_STARTUP_COOLDOWN_MS", "5000"),
+            ("ANYCODER_BULK_THRESHOLD", "20"),
+        ]))?;
+
+        assert_eq!(config.startup_cooldown, Duration::from_secs(5));
+        assert_eq!(config.bulk_window, Duration::from_secs(1));
+        assert_eq!(config.bulk_threshold, 20);
+        assert_eq!(config.completion_limit, 0);
+        assert_eq!(config.completion_window, Duration::from_secs(60));
+        assert_eq!(config.failure_limit, 0);
+        assert_eq!(config.failure_cooldown, Duration::from_secs(300));
+        assert_eq!(config.write_delay, Duration::ZERO);
+        assert_eq!(config.write_interval, Duration::ZERO);
+
+        let invalid = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("ANYCODER_BULK_THRESHOLD", "many"),
+        ]));
+        assert!(invalid.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_privacy_mode() -> Result<()> {
+        let config = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("OPENROUTER_BASE_URL", "http://127.0.0.1:11434/v1"),
+            ("ANYCODER_PRIVACY", "1"),
+            ("ANYCODER_GIT_CHANGES", "1"),
+            ("ANYCODER_EXAMPLES", "2"),
+            ("ANYCODER_AUTOCOMMIT", "1"),
+            ("ANYCODER_AUTOCOMMIT_SUMMARY", "1"),
+            ("ANYCODER_SUMMARIZE_OVER", "20000"),
+            ("ANYCODER_TRANSCRIPT", "transcript.jsonl"),
+            ("ANYCODER_DOCS_DIR", "docs"),
+        ]))?;
+
+        assert!(config.privacy);
+        assert!(!config.coder_options().big_context);
+        assert!(!config.sends_code_out());
+        assert!(!config.git_changes);
+        assert!(config.autocommit);
+        assert!(!config.autocommit_summary);
+        assert_eq!(config.summarize_over, None);
+        assert_eq!(config.examples, 0);
+        assert_eq!(config.transcript, None);
+        assert_eq!(config.docs_dir, None);
+        assert_eq!(config.log_limit, Some(PRIVACY_LOG_LIMIT));
+
+        let remote = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("ANYCODER_PRIVACY", "1"),
+        ]));
+        assert!(remote.is_err());
+
+        let capped = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("ANYCODER_MAX_CONTEXT_LINES", "50"),
+        ]))?;
+        assert!(capped.big_context);
+        assert_eq!(capped.coder_options().big_context_lines, 50);
+
+        let disabled = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("ANYCODER_MAX_CONTEXT_LINES", "0"),
+        ]))?;
+        assert!(!disabled.big_context);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_todo_trigger_settings() -> Result<()> {
+        let config = Config::from_lookup(lookup_from(&[("OPENROUTER_API_KEY", "sk")]))?;
+        assert!(config.todo_trigger.is_none());
+
+        let config = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("ANYCODER_TODO_TRIGGER", "1"),
+        ]))?;
+        assert!(config.todo_trigger.unwrap().removes_comment());
+
+        let invalid = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("ANYCODER_TODO_TRIGGER", "1"),
+            ("ANYCODER_TODO_PATTERN", "no task group"),
+        ]));
+        assert!(invalid.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_allowed_models() -> Result<()> {
+        let config = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("OPENROUTER_MODEL", "cheap/model"),
+            ("ANYCODER_ALLOWED_MODELS", "cheap/model, other/model"),
+        ]))?;
+        assert!(config.check_model("other/model").is_ok());
+        assert!(config.check_model("expensive/model").is_err());
+
+        let disallowed = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("OPENROUTER_MODEL", "expensive/model"),
+            ("ANYCODER_ALLOWED_MODELS", "cheap/model"),
+        ]));
+        let error = disallowed.err().unwrap().to_string();
+        assert!(error.contains("expensive/model"), "{}", error);
+
+        // The default model must be listed too once an allowlist is set
+        let default_model = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("ANYCODER_ALLOWED_MODELS", "cheap/model"),
+        ]));
+        assert!(default_model.is_err());
+
+        let unrestricted = Config::from_lookup(lookup_from(&[("OPENROUTER_API_KEY", "sk")]))?;
+        assert!(unrestricted.check_model("anything").is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_invalid_provider_settings() {
+        let error = |pairs: &[(&str, &str)]| Config::from_lookup(lookup_from(pairs))
+            .err()
+            .map(|e| e.to_string())
+            .unwrap_or_default();
+
+        let missing_key = error(&[]);
+        assert!(missing_key.contains("OPENROUTER_API_KEY is not set"), "{}", missing_key);
+        assert!(error(&[("OPENROUTER_API_KEY", "  ")]).contains("OPENROUTER_API_KEY is not set"));
+
+        let bad_url = error(&[("OPENROUTER_API_KEY", "sk"), ("OPENROUTER_BASE_URL", "openrouter.ai/api/v1")]);
+        assert!(bad_url.contains("is not a valid URL"), "{}", bad_url);
+        let bad_scheme = error(&[("OPENROUTER_API_KEY", "sk"), ("OPENROUTER_BASE_URL", "ftp://example.com/v1")]);
+        assert!(bad_scheme.contains("must be an http or https URL"), "{}", bad_scheme);
+
+        let empty_model = error(&[("OPENROUTER_API_KEY", "sk"), ("OPENROUTER_MODEL", " ")]);
+        assert!(empty_model.contains("OPENROUTER_MODEL is empty"), "{}", empty_model);
+
+        // Every problem is reported at once
+        let all = error(&[("OPENROUTER_BASE_URL", "not a url"), ("OPENROUTER_MODEL", "")]);
+        assert_eq!(all.lines().filter(|line| line.starts_with("- ")).count(), 3, "{}", all);
+
+        // A local provider needs no key
+        let local = Config::from_lookup(lookup_from(&[("OPENROUTER_BASE_URL", "http://localhost:11434/v1")]));
+        assert!(local.is_ok());
+    }
+
+    #[test]
+    fn test_headers() -> Result<()> {
+        let config = Config::from_lookup(lookup_from(&[
+            ("OPENROUTER_API_KEY", "sk"),
+            ("ANYCODER_HEADERS", "HTTP-Referer=https://example.com/?a=b\n X-Title = anycoder\nAccept=text/plain, application/json\n"),
+        ]))?;
+        assert_eq!(config.headers.len(), 3);
+        assert_eq!(config.headers["http-referer"], "https://example.com/?a=b");
+        assert_eq!(config.headers["x-title"], "anycoder");
+        assert_eq!(config.headers["accept"], "text/plain, application/json");
+
+        assert!(Config::from_lookup(lookup_from(&[("OPENROUTER_API_KEY", "sk")]))?.headers.is_empty());
+        for invalid in ["X Title=anycoder", "X-Title", "X-Title=line\nbreak", "=anycoder"] {
+            let config = Config::from_lookup(lookup_from(&[
+                ("OPENROUTER_API_KEY", "sk"),
+                ("ANYCODER_HEADERS", invalid),
+            ]));
+            assert!(config.is_err(), "{:?}", invalid);
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_values() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("anycoder-config-{}.toml", std::process::id()));
+        std::fs::write(&path, indoc::indoc! {r#"
+            api_key = "sk-file"
+            model = "openai/gpt-4o-mini"
+            allowed_models = ["openai/gpt-4o-mini", "mistralai/codestral-2501"]
+            completion_limit = 10
+            privacy = false
+            modle = "typo/model"
+            redact = ['sk-[A-Za-z0-9]{20,}', 'password = "([^"]+)"']
+
+            [headers]
+            "HTTP-Referer" = "https://example.com"
+            X-Title = "anycoder"
+            Accept = "text/plain, application/json"
+        "#})?;
+
+        let values = file_values(&path);
+        let config = Config::from_file(&path);
+        std::fs::write(&path, "[model]\nkey = 1\n")?;
+        let nested = file_values(&path);
+        std::fs::remove_file(&path)?;
+
+        let values = values?;
+        assert_eq!(values["OPENROUTER_API_KEY"], "sk-file");
+        assert_eq!(values["ANYCODER_ALLOWED_MODELS"], "openai/gpt-4o-mini,mistralai/codestral-2501");
+        assert_eq!(values["ANYCODER_COMPLETION_LIMIT"], "10");
+        assert_eq!(values["ANYCODER_PRIVACY"], "false");
+        assert!(!values.contains_key("ANYCODER_MODLE"));
+        assert_eq!(values["ANYCODER_HEADERS"], "Accept=text/plain, application/json\nHTTP-Referer=https://example.com\nX-Title=anycoder");
+
+        let config = config?;
+        assert_eq!(config.model, "openai/gpt-4o-mini");
+        assert_eq!(config.completion_limit, 10);
+        assert_eq!(config.headers["x-title"], "anycoder");
+        assert_eq!(config.headers["accept"], "text/plain, application/json");
+        assert_eq!(config.redact, ["sk-[A-Za-z0-9]{20,}", r#"password = "([^"]+)""#]);
+        assert!(nested.is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_env_overrides_file() -> Result<()> {
+        let file = HashMap::from([
+            ("OPENROUTER_API_KEY".to_string(), "sk-file".to_string()),
+            ("OPENROUTER_MODEL".to_string(), "file/model".to_string()),
+            ("ANYCODER_BULK_THRESHOLD".to_string(), "5".to_string()),
+        ]);
+        let env = lookup_from(&[("OPENROUTER_MODEL", "env/model")]);
+
+        let config = Config::from_lookup(layered(env, &file))?;
+
+        // Env beats the file, the file beats the defaults
+        assert_eq!(config.model, "env/model");
+        assert_eq!(config.api_key, "sk-file");
+        assert_eq!(config.bulk_threshold, 5);
+        assert_eq!(config.bulk_window, Duration::from_secs(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_local_url() {
+        assert!(is_local_url("http://localhost:8080/v1"));
+        assert!(is_local_url("http://[::1]/v1"));
+        assert!(!is_local_url("https://openrouter.ai/api/v1"));
+        assert!(!is_local_url("not a url"));
+    }
+
+    #[test]
+    fn test_env_file_loaded() -> Result<()> {
+        let key = format!("ANYCODER_TEST_ENV_FILE_{}", std::process::id());
+        let path = std::env::temp_dir().join(format!("anycoder-{}.env", std::process::id()));
+        std::fs::write(&path, format!("{}=from-file\n", key))?;
+
+        Config::load_env_file(&path)?;
+        std::fs::remove_file(&path)?;
+        assert_eq!(std::env::var(&key)?, "from-file");
+
+        assert!(Config::load_env_file(&path).is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,124 @@
+use async_trait::async_trait;
+use serde_json::Value;
+use std::path::PathBuf;
+use crate::error::{CoderError, Result};
+use crate::llm::{ChatModel, ChatResponse};
+
+/// File in the state dir whose presence records the user's consent
+const CONSENT_FILE: &str = "consent";
+
+/// Directory for what anycoder remembers between runs,
+/// `$XDG_STATE_HOME/anycoder` or `~/.local/state/anycoder`
+pub fn state_dir() -> Option<PathBuf> {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state")))?;
+    Some(base.join("anycoder"))
+}
+
+/// The user's acknowledgment that code is sent to the model provider,
+/// kept across runs
+pub struct Consent {
+    file: Option<PathBuf>,
+}
+
+impl Consent {
+    /// Consent kept in `dir`, never given when there is none
+    pub fn new(dir: Option<PathBuf>) -> Self {
+        Self { file: dir.map(|dir| dir.join(CONSENT_FILE)) }
+    }
+
+    pub fn in_state_dir() -> Self {
+        Self::new(state_dir())
+    }
+
+    pub fn given(&self) -> bool {
+        self.file.as_ref().is_some_and(|file| file.is_file())
+    }
+
+    /// Remembers the consent for later runs
+    pub fn record(&self) -> std::io::Result<()> {
+        let file = self.file.as_ref().ok_or_else(|| std::io::Error::new(
+            std::io::ErrorKind::NotFound, "no state dir, set HOME or XDG_STATE_HOME"
+        ))?;
+        if let Some(dir) = file.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        std::fs::write(file, "Code may be sent to the configured model provider\n")
+    }
+}
+
+/// Chat model wrapper that refuses every request until the user consented
+/// to sending code out. Nothing reaches the inner model without consent.
+pub struct ConsentGate<M> {
+    inner: M,
+    allowed: bool,
+}
+
+impl<M: ChatModel> ConsentGate<M> {
+    pub fn new(inner: M, allowed: bool) -> Self {
+        Self { inner, allowed }
+    }
+}
+
+#[async_trait]
+impl<M: ChatModel> ChatModel for ConsentGate<M> {
+    async fn chat(&self, messages: Vec<Value>) -> Result<String> {
+        if !self.allowed {
+            return Err(CoderError::NoConsent);
+        }
+        self.inner.chat(messages).await
+    }
+
+    async fn chat_with_tools(
+        &self, messages: Vec<Value>, tools: Vec<Value>
+    ) -> Result<ChatResponse> {
+        if !self.allowed {
+            return Err(CoderError::NoConsent);
+        }
+        self.inner.chat_with_tools(messages, tools).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coder::{Coder, CURSOR_MARKER};
+    use crate::llm::mock::MockLlm;
+    use std::path::Path;
+
+    #[tokio::test]
+    async fn test_no_chat_without_consent() {
+        let llm = MockLlm::new("<|SEARCH|>x = <|cursor|>;<|DIVIDE|>x = 1;<|REPLACE|>");
+        let code = "x = ??;";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let refused = Coder::new(ConsentGate::new(llm.clone(), false));
+        let result = refused.autocomplete(code, Path::new("main.rs"), cursor).await;
+        assert!(matches!(result, Err(CoderError::NoConsent)), "{:?}", result);
+        let template = refused.fill_holes("x = ${value};", Path::new("main.rs")).await;
+        assert!(matches!(template, Err(CoderError::NoConsent)));
+        assert!(llm.calls().is_empty());
+
+        let allowed = Coder::new(ConsentGate::new(llm.clone(), true));
+        assert_eq!(allowed.autocomplete(code, Path::new("main.rs"), cursor).await.unwrap(), "x = 1;");
+        assert_eq!(llm.calls().len(), 1);
+    }
+
+    #[test]
+    fn test_consent_persists() -> std::io::Result<()> {
+        let dir = std::env::temp_dir().join(format!("anycoder-consent-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+
+        assert!(!Consent::new(Some(dir.clone())).given());
+        Consent::new(Some(dir.clone())).record()?;
+        let given = Consent::new(Some(dir.clone())).given();
+        std::fs::remove_dir_all(&dir)?;
+
+        assert!(given);
+        assert!(!Consent::new(None).given());
+        assert!(Consent::new(None).record().is_err());
+        Ok(())
+    }
+}
@@ -0,0 +1,435 @@
+use async_trait::async_trait;
+use log::debug;
+use std::path::Path;
+use crate::coder::{CTOKEN, CURSOR_MARKER};
+use crate::docs::{estimate_tokens, DocIndex};
+use crate::error::{CoderError, Result};
+use crate::git::ChangesProvider;
+use crate::utils::byte_to_point;
+
+/// Lines around the cursor used to look up docs
+const DOCS_QUERY_LINES: usize = 3;
+
+/// Lines of one example at most, its first line included
+const EXAMPLE_LINES: usize = 8;
+
+/// Lines above the cursor searched for the construct being completed
+const EXAMPLE_ANCHOR_LINES: usize = 3;
+
+/// Supplies one piece of context sent to the model ahead of the small context
+#[async_trait]
+pub trait ContextProvider: Send + Sync {
+    /// Heading of the message, e.g. `big context`
+    fn label(&self) -> &str;
+
+    /// Context for the marker at `cursor` in `original`, empty when there is none
+    async fn provide(&self, original: &str, cursor: usize, path: &Path) -> Result<String>;
+
+    /// Tells whether the context depends on the file alone and not on the
+    /// cursor, so that `Coder::warm` can build it ahead of a completion
+    fn per_file(&self) -> bool {
+        false
+    }
+}
+
+/// Provided context that made it into the request
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Section {
+    pub label: String,
+    pub text: String,
+}
+
+/// Runs `providers` in order, keeping what fits in `budget` tokens.
+/// A section too large for what is left is skipped, later ones may still fit.
+pub async fn collect<'a>(
+    providers: impl IntoIterator<Item = &'a dyn ContextProvider>,
+    original: &str, cursor: usize, path: &Path, budget: Option<usize>,
+) -> Result<Vec<Section>> {
+    let mut remaining = budget.unwrap_or(usize::MAX);
+    let mut sections = Vec::new();
+    for provider in providers {
+        let text = provider.provide(original, cursor, path).await?;
+        if text.is_empty() {
+            continue;
+        }
+        let tokens = estimate_tokens(&text);
+        if tokens > remaining {
+            debug!("Skipping {} context, {} tokens over the {} left", provider.label(), tokens, remaining);
+            continue;
+        }
+        remaining -= tokens;
+        sections.push(Section { label: provider.label().to_string(), text });
+    }
+    Ok(sections)
+}
+
+/// The lines around the cursor, the default context
+pub struct LineWindow {
+    pub lines: usize,
+}
+
+#[async_trait]
+impl ContextProvider for LineWindow {
+    fn label(&self) -> &str {
+        "big context"
+    }
+
+    async fn provide(&self, original: &str, cursor: usize, _path: &Path) -> Result<String> {
+        Ok(line_window(original, cursor, self.lines)?.0)
+    }
+}
+
+/// Outline of a file too large to send as lines, sent as its big context
+pub struct Outline {
+    pub text: String,
+}
+
+#[async_trait]
+impl ContextProvider for Outline {
+    fn label(&self) -> &str {
+        "big context"
+    }
+
+    async fn provide(&self, _original: &str, _cursor: usize, _path: &Path) -> Result<String> {
+        Ok(self.text.clone())
+    }
+}
+
+/// Context another provider built ahead, sent under its label
+pub struct Prebuilt {
+    pub label: String,
+    pub text: String,
+}
+
+#[async_trait]
+impl ContextProvider for Prebuilt {
+    fn label(&self) -> &str {
+        &self.label
+    }
+
+    async fn provide(&self, _original: &str, _cursor: usize, _path: &Path) -> Result<String> {
+        Ok(self.text.clone())
+    }
+}
+
+/// Docs snippets relevant to the code around the cursor
+pub struct Docs {
+    pub index: DocIndex,
+    /// Approximate tokens of snippets sent at most
+    pub budget: usize,
+}
+
+#[async_trait]
+impl ContextProvider for Docs {
+    fn label(&self) -> &str {
+        "docs"
+    }
+
+    async fn provide(&self, original: &str, cursor: usize, _path: &Path) -> Result<String> {
+        let (query, _) = line_window(original, cursor, DOCS_QUERY_LINES)?;
+        let snippets = self.index.search(&query, self.budget);
+        debug!("docs {:?}", snippets.iter().map(|s| &s.source).collect::<Vec<_>>());
+        Ok(snippets.iter()
+            .map(|s| format!("{}:\n{}", s.source.display(), s.text))
+            .collect::<Vec<_>>()
+            .join("\n\n"))
+    }
+}
+
+/// Uncommitted changes of the file being completed
+pub struct Changes<P>(pub P);
+
+#[async_trait]
+impl<P: ChangesProvider> ContextProvider for Changes<P> {
+    fn label(&self) -> &str {
+        "uncommitted changes"
+    }
+
+    async fn provide(&self, _original: &str, _cursor: usize, path: &Path) -> Result<String> {
+        Ok(self.0.changes(path).await.unwrap_or_default())
+    }
+
+    fn per_file(&self) -> bool {
+        true
+    }
+}
+
+/// Other places in the file that look like the code being completed, such
+/// as other `for` loops when the cursor is inside one, so the model follows
+/// the file's own style
+pub struct Examples {
+    /// Examples sent at most
+    pub count: usize,
+}
+
+#[async_trait]
+impl ContextProvider for Examples {
+    fn label(&self) -> &str {
+        "examples from this file"
+    }
+
+    async fn provide(&self, original: &str, cursor: usize, _path: &Path) -> Result<String> {
+        let lines: Vec<&str> = original.lines().collect();
+        let (cursor_line, _) = byte_to_point(cursor, original);
+        if cursor_line >= lines.len() {
+            return Ok(String::new());
+        }
+        let anchor = anchor_line(&lines, cursor_line);
+        let anchor_tokens = tokens(&lines[anchor].replacen(CURSOR_MARKER, "", 1));
+        let Some(keyword) = anchor_tokens.first().cloned() else {
+            return Ok(String::new());
+        };
+
+        // The block being completed, so it can't be its own example
+        let own = anchor..=block_end(&lines, anchor).max(cursor_line);
+        let mut candidates = (0..lines.len())
+            .filter(|i| !own.contains(i) && !lines[*i].contains(CURSOR_MARKER))
+            .filter_map(|i| {
+                let line_tokens = tokens(lines[i]);
+                (line_tokens.first() == Some(&keyword))
+                    .then(|| (similarity(&anchor_tokens, &line_tokens), i))
+            })
+            .collect::<Vec<_>>();
+        candidates.sort_by(|a, b| b.0.total_cmp(&a.0).then(a.1.cmp(&b.1)));
+
+        let mut picked: Vec<std::ops::RangeInclusive<usize>> = Vec::new();
+        for (_, start) in candidates {
+            if picked.len() == self.count {
+                break;
+            }
+            let block = start..=block_end(&lines, start);
+            let overlaps = |other: &std::ops::RangeInclusive<usize>| {
+                block.start() <= other.end() && other.start() <= block.end()
+            };
+            if !picked.iter().any(overlaps) && !overlaps(&own) {
+                picked.push(block);
+            }
+        }
+        picked.sort_by_key(|block| *block.start());
+
+        Ok(picked.into_iter()
+            .map(|block| lines[block].join("\n"))
+            .collect::<Vec<_>>()
+            .join("\n\n"))
+    }
+}
+
+/// The line of the construct holding the cursor: the nearest line above it
+/// indented less than the cursor line, else the cursor line itself
+fn anchor_line(lines: &[&str], cursor_line: usize) -> usize {
+    let indent = indent_of(lines[cursor_line]);
+    (cursor_line.saturating_sub(EXAMPLE_ANCHOR_LINES)..cursor_line).rev()
+        .find(|&i| !lines[i].trim().is_empty() && indent_of(lines[i]) < indent)
+        .unwrap_or(cursor_line)
+}
+
+/// Last line of the block starting at `start`: the lines after it indented
+/// deeper, or up to a closing line at its own indent, capped at `EXAMPLE_LINES`
+fn block_end(lines: &[&str], start: usize) -> usize {
+    let indent = indent_of(lines[start]);
+    let mut end = start;
+    for (i, line) in lines.iter().enumerate().take(start + EXAMPLE_LINES).skip(start + 1) {
+        if line.trim().is_empty() || indent_of(line) > indent {
+            end = i;
+        } else {
+            // A closing `}` or `end` belongs to the block
+            if line.trim_start().starts_with(['}', ')', ']']) || line.trim() == "end" {
+                end = i;
+            }
+            break;
+        }
+    }
+    // Trailing blank lines are not part of the example
+    while end > start && lines[end].trim().is_empty() {
+        end -= 1;
+    }
+    end
+}
+
+fn indent_of(line: &str) -> usize {
+    line.len() - line.trim_start().len()
+}
+
+/// Words and symbols of a line, in order
+fn tokens(line: &str) -> Vec<String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    for c in line.chars() {
+        if c.is_alphanumeric() || c == '_' {
+            word.push(c);
+            continue;
+        }
+        if !word.is_empty() {
+            tokens.push(std::mem::take(&mut word));
+        }
+        if !c.is_whitespace() {
+            tokens.push(c.to_string());
+        }
+    }
+    if !word.is_empty() {
+        tokens.push(word);
+    }
+    tokens
+}
+
+/// Jaccard similarity of the distinct tokens of `a` and `b`
+fn similarity(a: &[String], b: &[String]) -> f32 {
+    let a: std::collections::HashSet<_> = a.iter().collect();
+    let b: std::collections::HashSet<_> = b.iter().collect();
+    let union = a.union(&b).count();
+    match union {
+        0 => 0.0,
+        _ => a.intersection(&b).count() as f32 / union as f32,
+    }
+}
+
+/// Cuts `context_lines` lines on each side of the marker at `cursor` out of
+/// `original`, shifting the window when it hits either end of the file.
+/// Returns the window with the marker replaced by the cursor token, and the
+/// byte offset of its first line.
+pub fn line_window(original: &str, cursor: usize, context_lines: usize) -> Result<(String, usize)> {
+    let lines: Vec<&str> = original.lines().collect();
+    if lines.is_empty() {
+        return Err(CoderError::Context(format!("no lines around byte {} in an empty file", cursor)));
+    }
+
+    let (line, _col) = byte_to_point(cursor, original);
+    let cursor_line = line;
+
+    let mut before = context_lines;
+    let mut after = context_lines;
+    let max_row = lines.len().saturating_sub(1);
+
+    if cursor_line < context_lines {
+        after += context_lines - cursor_line;
+    } else if cursor_line + context_lines > max_row {
+        before += (cursor_line + context_lines) - max_row;
+    }
+
+    let start_line = cursor_line.saturating_sub(before);
+    let end_line = (cursor_line + after).min(max_row);
+
+    let mut context = lines[start_line..=end_line].join("\n");
+
+    // Anchor on the first context line rather than searching for the marker,
+    // which may also appear earlier in the window
+    let start = match start_line {
+        0 => 0,
+        n => original.match_indices('\n').nth(n - 1).map_or(0, |(i, _)| i + 1),
+    };
+
+    let cursor_relative = cursor.checked_sub(start)
+        .filter(|&rel| context.get(rel..).is_some_and(|rest| rest.starts_with(CURSOR_MARKER)))
+        .ok_or_else(|| CoderError::Context(format!(
+            "CURSOR_MARKER not found at byte {} in context, {}", cursor, context))
+        )?;
+
+    // `lines()` drops `\r` and the joined context may not map back onto
+    // the original bytes, in which case every later edit would be shifted
+    let matches = original.get(start..)
+        .is_some_and(|rest| rest.starts_with(&context));
+    if !matches {
+        return Err(CoderError::Context(format!(
+            "Context does not match original at byte {}, {:?}", start, context
+        )));
+    }
+
+    context.replace_range(cursor_relative..cursor_relative + CURSOR_MARKER.len(), CTOKEN);
+    Ok((context, start))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Provides fixed text
+    struct Fixed(&'static str, &'static str);
+
+    #[async_trait]
+    impl ContextProvider for Fixed {
+        fn label(&self) -> &str {
+            self.0
+        }
+
+        async fn provide(&self, _original: &str, _cursor: usize, _path: &Path) -> Result<String> {
+            Ok(self.1.to_string())
+        }
+    }
+
+    fn labels(sections: &[Section]) -> Vec<&str> {
+        sections.iter().map(|s| s.label.as_str()).collect()
+    }
+
+    #[tokio::test]
+    async fn test_providers_compose_in_order() -> anyhow::Result<()> {
+        let code = "fn main() {\n    let x = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+        let window = LineWindow { lines: 1000 };
+        let providers: Vec<&dyn ContextProvider> = vec![
+            &Fixed("imports", "use std::io;"),
+            &Fixed("empty", ""),
+            &window,
+        ];
+
+        let sections = collect(providers, code, cursor, Path::new("main.rs"), None).await?;
+        assert_eq!(labels(&sections), ["imports", "big context"]);
+        assert_eq!(sections[0].text, "use std::io;");
+        assert_eq!(sections[1].text, "fn main() {\n    let x = <|cursor|>;\n}");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_example_for_repeated_pattern() -> anyhow::Result<()> {
+        let code = indoc::indoc! {"
+            fn report(users: &[User], orders: &[Order]) {
+                for user in users.iter() {
+                    println!(\"user {}\", user.name);
+                }
+
+                let total = orders.len();
+                if total > 0 {
+                    println!(\"{} orders\", total);
+                }
+
+                for order in orders.iter() {
+                    ??
+                }
+            }
+        "};
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let examples = Examples { count: 2 }.provide(code, cursor, Path::new("report.rs")).await?;
+        assert_eq!(examples, "    for user in users.iter() {\n        println!(\"user {}\", user.name);\n    }");
+
+        // Nothing else in the file starts like the line being completed
+        let lone = "fn main() {\n    let x = ??;\n}\n";
+        let cursor = lone.find(CURSOR_MARKER).unwrap();
+        assert_eq!(Examples { count: 2 }.provide(lone, cursor, Path::new("main.rs")).await?, "");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_and_blank_files() -> anyhow::Result<()> {
+        for code in ["", "\n", "  \n\n"] {
+            assert!(matches!(line_window(code, 0, 3), Err(CoderError::Context(_))), "{:?}", code);
+            assert_eq!(Examples { count: 2 }.provide(code, code.len(), Path::new("a.rs")).await?, "");
+        }
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_providers_share_budget() -> anyhow::Result<()> {
+        let providers: Vec<&dyn ContextProvider> = vec![
+            &Fixed("first", "12345678"),
+            &Fixed("too big", "1234567890"),
+            &Fixed("last", "1234"),
+        ];
+
+        // 2 + 3 + 1 tokens against a budget of 3
+        let sections = collect(providers, "??", 0, Path::new("a.rs"), Some(3)).await?;
+        assert_eq!(labels(&sections), ["first", "last"]);
+
+        Ok(())
+    }
+}
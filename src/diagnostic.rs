@@ -0,0 +1,86 @@
+use std::path::Path;
+use crate::coder::CURSOR_MARKER;
+use crate::lang::{comment_syntax, detect_language};
+
+/// Leads every diagnostic comment so a later one can replace it
+const DIAGNOSTIC_TAG: &str = "anycoder: no completion,";
+
+/// Longest reason kept in a diagnostic comment
+const MAX_REASON_LEN: usize = 120;
+
+/// Writes a comment above the marker line at `cursor` explaining why no
+/// completion happened, replacing an earlier diagnostic right above it.
+/// Returns `None` when the language has no known comment syntax.
+pub fn annotate(content: &str, path: &Path, cursor: usize, reason: &str) -> Option<String> {
+    let (open, close) = comment_syntax(detect_language(path)?)?;
+
+    let line_start = content[..cursor].rfind('\n').map_or(0, |i| i + 1);
+    let line = &content[line_start..];
+    let indent = &line[..line.len() - line.trim_start_matches([' ', '\t']).len()];
+    let newline = if content.contains("\r\n") { "\r\n" } else { "\n" };
+
+    let prefix = format!("{} {}", open, DIAGNOSTIC_TAG);
+    let comment = format!("{}{} {}{}", indent, prefix, sanitize(reason), close);
+
+    let mut updated = content.to_string();
+    match previous_line(content, line_start) {
+        Some((start, end)) if content[start..end].trim_start().starts_with(&prefix) => {
+            updated.replace_range(start..end, &comment);
+        }
+        _ => updated.insert_str(line_start, &format!("{}{}", comment, newline)),
+    }
+    Some(updated)
+}
+
+/// Byte range of the line before the one starting at `line_start`, without its terminator
+fn previous_line(content: &str, line_start: usize) -> Option<(usize, usize)> {
+    let end = line_start.checked_sub(1)?;
+    let end = if content[..end].ends_with('\r') { end - 1 } else { end };
+    let start = content[..end].rfind('\n').map_or(0, |i| i + 1);
+    Some((start, end))
+}
+
+/// Keeps the reason on one line and free of anything that would trigger a completion
+fn sanitize(reason: &str) -> String {
+    let reason = reason.split_whitespace().collect::<Vec<_>>().join(" ");
+    let reason = reason.replace(CURSOR_MARKER, "marker").replace("TODO", "todo");
+    match reason.char_indices().nth(MAX_REASON_LEN) {
+        Some((i, _)) => format!("{}...", &reason[..i]),
+        None => reason,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_annotate() {
+        let code = "fn main() {\n    let x = ??;\n}\n";
+        let cursor = code.find(CURSOR_MARKER).unwrap();
+
+        let annotated = annotate(code, Path::new("main.rs"), cursor, "missing\n<|SEARCH|>").unwrap();
+        assert_eq!(
+            annotated,
+            "fn main() {\n    // anycoder: no completion, missing <|SEARCH|>\n    let x = ??;\n}\n"
+        );
+
+        // A second failure replaces the first comment instead of stacking up
+        let cursor = annotated.find(CURSOR_MARKER).unwrap();
+        let again = annotate(&annotated, Path::new("main.rs"), cursor, "timeout").unwrap();
+        assert_eq!(
+            again,
+            "fn main() {\n    // anycoder: no completion, timeout\n    let x = ??;\n}\n"
+        );
+
+        assert_eq!(annotate(code, Path::new("notes.unknown"), cursor, "x"), None);
+    }
+
+    #[test]
+    fn test_annotate_never_adds_markers() {
+        let code = "x = ??\n";
+        let annotated = annotate(code, Path::new("a.py"), 4, "bad ?? TODO: implement it").unwrap();
+        assert_eq!(annotated, "# anycoder: no completion, bad marker todo: implement it\nx = ??\n");
+        assert_eq!(annotated.matches(CURSOR_MARKER).count(), 1);
+    }
+}
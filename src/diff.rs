@@ -1,14 +1,231 @@
+use std::str::FromStr;
+use log::{debug, warn};
+use serde::{Deserialize, Serialize};
 use similar::{ChangeTag, TextDiff};
+use crate::error::{CoderError, Result};
 
-#[derive(Debug, Clone, PartialEq, Eq)]
+/// Replacement of `start..end` with `text`. Offsets are UTF-8 byte offsets
+/// into the text the edit applies to, as used for slicing Rust strings.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
 pub struct TextEdit {
     pub start: usize,
     pub end: usize,
     pub text: String,
 }
 
+/// A `TextEdit` with offsets counted in Unicode scalar values (chars), as
+/// Python string indices are. JavaScript indexes UTF-16 code units, which
+/// differ from chars only outside the Basic Multilingual Plane.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CharEdit {
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+impl TextEdit {
+    /// Converts the byte offsets into char offsets within `original`, the text
+    /// the edit applies to. Offsets must fall on char boundaries.
+    pub fn to_char_edit(&self, original: &str) -> CharEdit {
+        let start = original[..self.start].chars().count();
+        let end = start + original[self.start..self.end].chars().count();
+        CharEdit { start, end, text: self.text.clone() }
+    }
+}
+
+impl CharEdit {
+    /// Converts the char offsets back into byte offsets within `original`,
+    /// `None` when they point past its end
+    pub fn to_text_edit(&self, original: &str) -> Option<TextEdit> {
+        let byte_offset = |chars: usize| original.char_indices()
+            .map(|(i, _)| i)
+            .chain(std::iter::once(original.len()))
+            .nth(chars);
+        Some(TextEdit {
+            start: byte_offset(self.start)?,
+            end: byte_offset(self.end)?,
+            text: self.text.clone(),
+        })
+    }
+}
+
+/// Computes the edits turning one text into another
+pub trait DiffEngine: Send + Sync {
+    fn edits(&self, old: &str, new: &str) -> Vec<TextEdit>;
+}
+
+/// Default engine, char-level diffing with `similar`
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SimilarDiff;
+
+impl DiffEngine for SimilarDiff {
+    fn edits(&self, old: &str, new: &str) -> Vec<TextEdit> {
+        compute_text_edits(old, new)
+    }
+}
+
 pub fn compute_text_edits(old: &str, new: &str) -> Vec<TextEdit> {
-    let diff = TextDiff::from_chars(old, new);
+    // Most of the file is usually untouched, so only diff the changed middle
+    let prefix = common_prefix_len(old, new);
+    let suffix = common_suffix_len(&old[prefix..], &new[prefix..]);
+
+    let old_middle = &old[prefix..old.len() - suffix];
+    let new_middle = &new[prefix..new.len() - suffix];
+
+    diff_chars(old_middle, new_middle).into_iter()
+        .map(|edit| TextEdit {
+            start: edit.start + prefix,
+            end: edit.end + prefix,
+            text: edit.text,
+        })
+        .collect()
+}
+
+/// Edits replacing whole lines, far fewer than char-level ones for a text
+/// that changed all over. Each run of changed lines becomes one edit.
+pub fn compute_line_edits(old: &str, new: &str) -> Vec<TextEdit> {
+    collect_edits(TextDiff::from_lines(old, new))
+}
+
+/// Shrinks each edit of `old` to the bytes it really changes and drops edits
+/// that rewrite text with itself, so engines that replace whole spans still
+/// leave the smallest possible change in the file
+pub fn minimize_edits(old: &str, edits: Vec<TextEdit>) -> Vec<TextEdit> {
+    edits.into_iter()
+        .filter_map(|edit| {
+            let replaced = old.get(edit.start..edit.end)?;
+            let prefix = common_prefix_len(replaced, &edit.text);
+            let suffix = common_suffix_len(&replaced[prefix..], &edit.text[prefix..]);
+            let edit = TextEdit {
+                start: edit.start + prefix,
+                end: edit.end - suffix,
+                text: edit.text[prefix..edit.text.len() - suffix].to_string(),
+            };
+            (edit.start != edit.end || !edit.text.is_empty()).then_some(edit)
+        })
+        .collect()
+}
+
+/// What `Coder::apply_text_edits` does with edits whose ranges overlap,
+/// one nested in another included. Edits that only touch, one ending where
+/// the next starts, never conflict: all of them apply, and insertions at
+/// the same offset land in the order given.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OverlapPolicy {
+    /// Fail with `CoderError::OverlappingEdits`, leaving the text as it is
+    #[default]
+    Reject,
+    /// Keep the edit given first and drop later ones overlapping a kept edit
+    FirstWins,
+    /// Drop edits nested in another and join partly overlapping ones into a
+    /// single edit over both ranges, their texts in offset order
+    Merge,
+}
+
+impl FromStr for OverlapPolicy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_lowercase().replace('_', "-").as_str() {
+            "reject" => Ok(OverlapPolicy::Reject),
+            "first-wins" => Ok(OverlapPolicy::FirstWins),
+            "merge" => Ok(OverlapPolicy::Merge),
+            other => anyhow::bail!("Unknown overlap policy {:?}, expected reject, first-wins or merge", other),
+        }
+    }
+}
+
+/// Tells whether `a` and `b` replace some of the same bytes, or one inserts
+/// strictly inside the range of the other
+fn overlaps(a: &TextEdit, b: &TextEdit) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Resolves overlapping `edits` per `policy` and orders the rest from the
+/// end of the text, so applying them one by one never shifts the offsets
+/// of those still to apply
+pub fn arrange_edits(edits: &[TextEdit], policy: OverlapPolicy) -> Result<Vec<TextEdit>> {
+    let mut indexed = edits.iter().cloned().enumerate().collect::<Vec<_>>();
+
+    match policy {
+        OverlapPolicy::Reject => {
+            let conflicts = indexed.iter()
+                .flat_map(|(i, a)| indexed[i + 1..].iter().map(move |(_, b)| (a, b)))
+                .filter(|(a, b)| overlaps(a, b))
+                .map(|(a, b)| format!("{:?} overlaps {:?}", a, b))
+                .collect::<Vec<_>>();
+            if !conflicts.is_empty() {
+                return Err(CoderError::OverlappingEdits(conflicts.join("; ")));
+            }
+        }
+        OverlapPolicy::FirstWins => {
+            let mut kept: Vec<(usize, TextEdit)> = Vec::new();
+            for (i, edit) in indexed {
+                match kept.iter().find(|(_, k)| overlaps(k, &edit)) {
+                    Some((_, winner)) => warn!("Dropping {:?}, it overlaps {:?}", edit, winner),
+                    None => kept.push((i, edit)),
+                }
+            }
+            indexed = kept;
+        }
+        OverlapPolicy::Merge => {
+            // Outer edits come before the ones they contain
+            indexed.sort_by_key(|(i, e)| (e.start, std::cmp::Reverse(e.end), *i));
+            let mut merged: Vec<(usize, TextEdit)> = Vec::new();
+            for (i, edit) in indexed {
+                match merged.last_mut() {
+                    Some((_, last)) if overlaps(last, &edit) && edit.end <= last.end => {
+                        debug!("Dropping {:?}, nested in {:?}", edit, last);
+                    }
+                    Some((first, last)) if overlaps(last, &edit) => {
+                        debug!("Merging {:?} into {:?}", edit, last);
+                        last.end = edit.end;
+                        last.text.push_str(&edit.text);
+                        *first = (*first).min(i);
+                    }
+                    _ => merged.push((i, edit)),
+                }
+            }
+            indexed = merged;
+        }
+    }
+
+    // From the end; at one offset a replacement goes before insertions, and
+    // insertions given later go first so the earlier ones land ahead of them
+    indexed.sort_by_key(|(i, e)| std::cmp::Reverse((e.start, e.end, *i)));
+    Ok(indexed.into_iter().map(|(_, edit)| edit).collect())
+}
+
+/// Byte range of `new` that differs from `old`, between their common prefix and suffix
+pub fn changed_range(old: &str, new: &str) -> std::ops::Range<usize> {
+    let prefix = common_prefix_len(old, new);
+    let suffix = common_suffix_len(&old[prefix..], &new[prefix..]);
+    prefix..new.len() - suffix
+}
+
+/// Byte length of the longest common prefix, on a char boundary
+fn common_prefix_len(a: &str, b: &str) -> usize {
+    a.chars().zip(b.chars())
+        .take_while(|(x, y)| x == y)
+        .map(|(x, _)| x.len_utf8())
+        .sum()
+}
+
+/// Byte length of the longest common suffix, on a char boundary
+fn common_suffix_len(a: &str, b: &str) -> usize {
+    a.chars().rev().zip(b.chars().rev())
+        .take_while(|(x, y)| x == y)
+        .map(|(x, _)| x.len_utf8())
+        .sum()
+}
+
+fn diff_chars(old: &str, new: &str) -> Vec<TextEdit> {
+    collect_edits(TextDiff::from_chars(old, new))
+}
+
+/// Merges the changes of `diff` into edits, adjacent deletes and inserts
+/// joining into one replacement
+fn collect_edits<'a>(diff: TextDiff<'a, 'a, 'a, str>) -> Vec<TextEdit> {
     let mut edits: Vec<TextEdit> = Vec::new();
 
     let mut old_pos = 0;
@@ -67,6 +284,207 @@ pub fn compute_text_edits(old: &str, new: &str) -> Vec<TextEdit> {
     edits
 }
 
+/// Renders the change from `old` to `new` as a unified diff of lines
+pub fn unified_diff(old: &str, new: &str, name: &str) -> String {
+    TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(name, name)
+        .to_string()
+}
+
+/// Counts of a batch of edits, for logging large changes in one line
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EditSummary {
+    pub insertions: usize,
+    pub deletions: usize,
+    /// Bytes removed plus bytes inserted
+    pub bytes: usize,
+}
+
+impl EditSummary {
+    /// A replacement counts as both an insertion and a deletion
+    pub fn of(edits: &[TextEdit]) -> Self {
+        edits.iter().fold(Self::default(), |summary, edit| Self {
+            insertions: summary.insertions + usize::from(!edit.text.is_empty()),
+            deletions: summary.deletions + usize::from(edit.end > edit.start),
+            bytes: summary.bytes + (edit.end - edit.start) + edit.text.len(),
+        })
+    }
+}
+
+impl std::fmt::Display for EditSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(
+            f, "{} insertions, {} deletions, {} bytes changed",
+            self.insertions, self.deletions, self.bytes
+        )
+    }
+}
+
+/// Chars of unchanged context kept around each change in a DMP patch
+const DMP_MARGIN: usize = 4;
+
+/// Chars that `encodeURI` leaves alone, besides ASCII alphanumerics
+const DMP_UNESCAPED: &str = ";,/?:@&=+$-_.!~*'()# ";
+
+/// Renders the change from `old` to `new` as Google diff-match-patch patch text.
+/// Positions count chars, which matches the JavaScript implementation for
+/// text without characters outside the Basic Multilingual Plane.
+pub fn to_dmp_patch(old: &str, new: &str) -> String {
+    let edits = compute_text_edits(old, new);
+
+    // Changes whose context would overlap share one hunk
+    let mut hunks: Vec<Vec<&TextEdit>> = Vec::new();
+    for edit in &edits {
+        match hunks.last_mut() {
+            Some(hunk) if old[hunk.last().unwrap().end..edit.start].chars().count() <= 2 * DMP_MARGIN => {
+                hunk.push(edit);
+            }
+            _ => hunks.push(vec![edit]),
+        }
+    }
+
+    let mut patch = String::new();
+    let mut delta: isize = 0;
+    for hunk in hunks {
+        let start = back_chars(old, hunk[0].start, DMP_MARGIN);
+        let end = forward_chars(old, hunk.last().unwrap().end, DMP_MARGIN);
+
+        let mut lines = Vec::new();
+        let mut pos = start;
+        for edit in &hunk {
+            lines.push((' ', &old[pos..edit.start]));
+            lines.push(('-', &old[edit.start..edit.end]));
+            lines.push(('+', edit.text.as_str()));
+            pos = edit.end;
+        }
+        lines.push((' ', &old[pos..end]));
+        lines.retain(|(_, text)| !text.is_empty());
+
+        let count = |ops: &[char]| lines.iter()
+            .filter(|(op, _)| ops.contains(op))
+            .map(|(_, text)| text.chars().count())
+            .sum::<usize>();
+        let (len1, len2) = (count(&[' ', '-']), count(&[' ', '+']));
+        let start1 = old[..start].chars().count();
+        let start2 = start1.saturating_add_signed(delta);
+        delta += len2 as isize - len1 as isize;
+
+        patch.push_str(&format!(
+            "@@ -{} +{} @@\n", dmp_coords(start1, len1), dmp_coords(start2, len2)
+        ));
+        for (op, text) in lines {
+            patch.push(op);
+            patch.push_str(&dmp_encode(text));
+            patch.push('\n');
+        }
+    }
+    patch
+}
+
+/// Applies DMP patch text to `old`, requiring every hunk to match exactly
+pub fn apply_dmp_patch(old: &str, patch: &str) -> anyhow::Result<String> {
+    let old: Vec<char> = old.chars().collect();
+    let mut new = String::new();
+    let mut pos = 0;
+
+    let mut lines = patch.lines().filter(|line| !line.is_empty()).peekable();
+    while let Some(header) = lines.next() {
+        let coords = header.strip_prefix("@@ -")
+            .and_then(|rest| rest.split(' ').next())
+            .ok_or_else(|| anyhow::anyhow!("invalid hunk header {:?}", header))?;
+        let start = parse_dmp_start(coords)?;
+        if start < pos || start > old.len() {
+            anyhow::bail!("hunk {:?} is out of order or out of bounds", header);
+        }
+        new.extend(&old[pos..start]);
+        pos = start;
+
+        while let Some(line) = lines.next_if(|line| !line.starts_with("@@")) {
+            let mut chars = line.chars();
+            let op = chars.next().unwrap_or(' ');
+            let text: Vec<char> = dmp_decode(chars.as_str())?.chars().collect();
+            match op {
+                '+' => new.extend(&text),
+                ' ' | '-' => {
+                    if old.get(pos..pos + text.len()) != Some(&text[..]) {
+                        anyhow::bail!("hunk {:?} does not match at char {}", header, pos);
+                    }
+                    if op == ' ' {
+                        new.extend(&text);
+                    }
+                    pos += text.len();
+                }
+                _ => anyhow::bail!("invalid patch line {:?}", line),
+            }
+        }
+    }
+    new.extend(&old[pos..]);
+    Ok(new)
+}
+
+/// `start,len` as DMP writes it: 1-based, except for empty ranges
+fn dmp_coords(start: usize, len: usize) -> String {
+    match len {
+        0 => format!("{},0", start),
+        1 => format!("{}", start + 1),
+        _ => format!("{},{}", start + 1, len),
+    }
+}
+
+fn parse_dmp_start(coords: &str) -> anyhow::Result<usize> {
+    let invalid = || anyhow::anyhow!("invalid hunk coordinates {:?}", coords);
+    let (start, len) = coords.split_once(',').unwrap_or((coords, "1"));
+    let start: usize = start.parse().map_err(|_| invalid())?;
+    let len: usize = len.parse().map_err(|_| invalid())?;
+    if len == 0 { Ok(start) } else { start.checked_sub(1).ok_or_else(invalid) }
+}
+
+/// Percent-encodes like `encodeURI`, keeping spaces readable as DMP does
+fn dmp_encode(text: &str) -> String {
+    let mut encoded = String::new();
+    for c in text.chars() {
+        if c.is_ascii_alphanumeric() || DMP_UNESCAPED.contains(c) {
+            encoded.push(c);
+        } else {
+            let mut buf = [0; 4];
+            for byte in c.encode_utf8(&mut buf).bytes() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        }
+    }
+    encoded
+}
+
+fn dmp_decode(text: &str) -> anyhow::Result<String> {
+    let mut bytes = Vec::new();
+    let mut rest = text.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        if byte == b'%' {
+            let hex = tail.get(..2)
+                .and_then(|hex| std::str::from_utf8(hex).ok())
+                .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                .ok_or_else(|| anyhow::anyhow!("invalid escape in {:?}", text))?;
+            bytes.push(hex);
+            rest = &tail[2..];
+        } else {
+            bytes.push(byte);
+            rest = tail;
+        }
+    }
+    Ok(String::from_utf8(bytes)?)
+}
+
+/// Byte offset `n` chars before `pos`, or the start of `text`
+fn back_chars(text: &str, pos: usize, n: usize) -> usize {
+    text[..pos].char_indices().rev().nth(n - 1).map_or(0, |(i, _)| i)
+}
+
+/// Byte offset `n` chars after `pos`, or the end of `text`
+fn forward_chars(text: &str, pos: usize, n: usize) -> usize {
+    text[pos..].char_indices().nth(n).map_or(text.len(), |(i, _)| pos + i)
+}
 
 #[cfg(test)]
 mod tests {
@@ -90,6 +508,23 @@ mod tests {
         );    
     }
     
+    #[test]
+    fn test_minimize_edits() {
+        let old = "let total = price * count;";
+        let whole = vec![TextEdit { start: 0, end: old.len(), text: "let total = price * counts;".to_string() }];
+        assert_eq!(minimize_edits(old, whole), vec![
+            TextEdit { start: 25, end: 25, text: "s".to_string() },
+        ]);
+
+        let one_char = vec![TextEdit { start: 4, end: 9, text: "tital".to_string() }];
+        assert_eq!(minimize_edits(old, one_char), vec![
+            TextEdit { start: 5, end: 6, text: "i".to_string() },
+        ]);
+
+        let no_op = vec![TextEdit { start: 4, end: 9, text: "total".to_string() }];
+        assert_eq!(minimize_edits(old, no_op), vec![]);
+    }
+
     #[test]
     fn test_compute_edits_simple2() {
         let before = r#"println!("Current value: {}", );"#;
@@ -102,6 +537,50 @@ mod tests {
         ])    
     }
     
+    #[test]
+    fn test_compute_edits_matches_full_diff() {
+        let cases = [
+            ("", ""),
+            ("", "abc"),
+            ("abc", ""),
+            ("same", "same"),
+            ("let mut foo = 2;\nfoo *= 50;", "let mut foo = 5;\naaaa foo *= 50;"),
+            ("aaaa", "aaaaaa"),
+            ("fn a() {}\nfn b() {}\n", "fn a() {}\nfn c() { 1 }\nfn b() {}\n"),
+            ("привет мир", "привет, дорогой мир"),
+            ("значение: 1", "value: 1"),
+            ("x = 1;\ny = 2;\nz = 3;", "x = 10;\ny = 2;\nz = 30;"),
+        ];
+
+        for (old, new) in cases {
+            assert_eq!(compute_text_edits(old, new), diff_chars(old, new), "{:?} -> {:?}", old, new);
+        }
+    }
+
+    #[test]
+    fn test_common_prefix_suffix_unicode() {
+        assert_eq!(common_prefix_len("йцук", "йцен"), "йц".len());
+        assert_eq!(common_suffix_len("йцук", "фвук"), "ук".len());
+        assert_eq!(common_prefix_len("abc", "xyz"), 0);
+    }
+
+    #[test]
+    fn test_compute_line_edits() {
+        let old = "fn a() {\n    one();\n}\n\nfn b() {\n    two();\n}\n";
+        let new = "fn a() {\n    uno();\n}\n\nfn b() {\n    dos();\n    tres();\n}\n";
+
+        let edits = compute_line_edits(old, new);
+        assert_eq!(edits, [
+            TextEdit { start: 9, end: 20, text: "    uno();\n".to_string() },
+            TextEdit { start: 32, end: 43, text: "    dos();\n    tres();\n".to_string() },
+        ]);
+        let mut applied = old.to_string();
+        for edit in edits.iter().rev() {
+            applied.replace_range(edit.start..edit.end, &edit.text);
+        }
+        assert_eq!(applied, new);
+    }
+
     #[test]
     fn test_compute_edits_unicode() {
         let before = r#"println!("Current значение: {}", i);"#;
@@ -113,4 +592,128 @@ mod tests {
             TextEdit { start: 18, end: 18 + 8*2, text: "value".to_string() },
         ])    
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_unified_diff() {
+        let diff = unified_diff("a\nb\nc\n", "a\nB\nc\n", "main.rs");
+        assert_eq!(diff, "--- main.rs\n+++ main.rs\n@@ -1,3 +1,3 @@\n a\n-b\n+B\n c\n");
+    }
+
+    #[test]
+    fn test_edit_summary() {
+        let edits = compute_text_edits("let a = 1;\nlet b = 2;\n", "let a = 10;\nlet c = 2;\n");
+        let summary = EditSummary::of(&edits);
+        assert_eq!(summary, EditSummary { insertions: 2, deletions: 1, bytes: 3 });
+        assert_eq!(summary.to_string(), "2 insertions, 1 deletions, 3 bytes changed");
+    }
+
+    #[test]
+    fn test_char_edit_over_cyrillic() {
+        let original = "let s = \"привет\";";
+        let updated = "let s = \"привет мир\";";
+        let edits = compute_text_edits(original, updated);
+        assert_eq!(edits, vec![TextEdit { start: 21, end: 21, text: " мир".to_string() }]);
+
+        let char_edit = edits[0].to_char_edit(original);
+        assert_eq!(char_edit, CharEdit { start: 15, end: 15, text: " мир".to_string() });
+        assert_eq!(char_edit.to_text_edit(original), Some(edits[0].clone()));
+
+        // "привет" is 6 chars but 12 bytes
+        let replace = TextEdit { start: 9, end: 21, text: "мир".to_string() };
+        let char_edit = replace.to_char_edit(original);
+        assert_eq!((char_edit.start, char_edit.end), (9, 15));
+        assert_eq!(char_edit.to_text_edit(original), Some(replace));
+
+        let past_end = CharEdit { start: 16, end: 40, text: String::new() };
+        assert_eq!(past_end.to_text_edit(original), None);
+    }
+
+    #[test]
+    fn test_changed_range() {
+        assert_eq!(changed_range("let x = ??;", "let x = 42;"), 8..10);
+        assert_eq!(changed_range("same", "same"), 4..4);
+        assert_eq!(changed_range("", "new"), 0..3);
+    }
+
+    #[test]
+    fn test_to_dmp_patch_format() {
+        let patch = to_dmp_patch("let x = 1;\nlet y = 2;\n", "let x = 10;\nlet y = 2;\n");
+        assert_eq!(patch, "@@ -6,8 +6,9 @@\n  = 1\n+0\n ;%0Ale\n");
+
+        assert_eq!(to_dmp_patch("", "ab"), "@@ -0,0 +1,2 @@\n+ab\n");
+        assert_eq!(to_dmp_patch("same", "same"), "");
+    }
+
+    #[test]
+    fn test_dmp_patch_round_trip() -> anyhow::Result<()> {
+        let cases = [
+            ("let x = 1;\nlet y = 2;\n", "let x = 10;\nlet y = 2;\n"),
+            ("", "fn main() {}\n"),
+            ("fn main() {}\n", ""),
+            ("a", "b"),
+            ("100% done", "50% done?"),
+            ("fn a() {}\nfn b() {}\n", "fn a() {}\nfn c() { 1 }\nfn b() {}\n"),
+            ("start ... lots of unchanged text in between ... end", "START ... lots of unchanged text in between ... END"),
+            ("héllo wörld 😀", "hello world 😀!"),
+            ("tabs\tand\r\nlines", "tabs and\nlines"),
+        ];
+
+        for (old, new) in cases {
+            let patch = to_dmp_patch(old, new);
+            assert_eq!(apply_dmp_patch(old, &patch)?, new, "patch {:?}", patch);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_apply_dmp_patch_rejects_mismatch() {
+        let patch = to_dmp_patch("let x = 1;", "let x = 2;");
+        assert!(apply_dmp_patch("let y = 1;", &patch).is_err());
+        assert!(apply_dmp_patch("abc", "@@ -x +1 @@\n").is_err());
+    }
+
+    fn edit(start: usize, end: usize, text: &str) -> TextEdit {
+        TextEdit { start, end, text: text.to_string() }
+    }
+
+    #[test]
+    fn test_adjacent_edits_never_conflict() -> anyhow::Result<()> {
+        let edits = [edit(0, 3, "a"), edit(3, 5, "b"), edit(5, 5, "c"), edit(5, 5, "d")];
+        for policy in [OverlapPolicy::Reject, OverlapPolicy::FirstWins, OverlapPolicy::Merge] {
+            assert_eq!(
+                arrange_edits(&edits, policy)?,
+                [edit(5, 5, "d"), edit(5, 5, "c"), edit(3, 5, "b"), edit(0, 3, "a")]
+            );
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_nested_edits_per_policy() -> anyhow::Result<()> {
+        let edits = [edit(6, 8, "inner"), edit(2, 10, "outer"), edit(4, 4, "insert")];
+
+        let err = arrange_edits(&edits, OverlapPolicy::Reject).unwrap_err();
+        assert!(matches!(err, CoderError::OverlappingEdits(_)));
+        assert!(err.to_string().contains("inner") && err.to_string().contains("insert"));
+
+        assert_eq!(arrange_edits(&edits, OverlapPolicy::FirstWins)?, [edit(6, 8, "inner"), edit(4, 4, "insert")]);
+        assert_eq!(arrange_edits(&edits, OverlapPolicy::Merge)?, [edit(2, 10, "outer")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_partly_overlapping_edits_merge() -> anyhow::Result<()> {
+        let edits = [edit(4, 8, "b"), edit(2, 6, "a"), edit(7, 9, "c"), edit(12, 13, "d")];
+        assert_eq!(arrange_edits(&edits, OverlapPolicy::Merge)?, [edit(12, 13, "d"), edit(2, 9, "abc")]);
+        assert_eq!(arrange_edits(&edits, OverlapPolicy::FirstWins)?, [edit(12, 13, "d"), edit(4, 8, "b")]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_overlap_policy_from_str() {
+        assert_eq!("first_wins".parse::<OverlapPolicy>().ok(), Some(OverlapPolicy::FirstWins));
+        assert_eq!(" Merge ".parse::<OverlapPolicy>().ok(), Some(OverlapPolicy::Merge));
+        assert!("newest".parse::<OverlapPolicy>().is_err());
+    }
+}
@@ -0,0 +1,190 @@
+use anyhow::Result;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use crate::utils::collect_files;
+
+/// Lines kept together in one snippet at most
+const SNIPPET_LINES: usize = 20;
+
+/// Rough chars per model token, good enough to stay within a budget
+const CHARS_PER_TOKEN: usize = 4;
+
+/// A piece of a docs file that can be retrieved on its own
+#[derive(Debug, Clone)]
+pub struct Snippet {
+    pub source: PathBuf,
+    pub text: String,
+    terms: HashMap<String, usize>,
+}
+
+/// TF-IDF index over the snippets of a docs directory
+#[derive(Debug, Clone, Default)]
+pub struct DocIndex {
+    snippets: Vec<Snippet>,
+    /// Number of snippets each term appears in
+    doc_freq: HashMap<String, usize>,
+}
+
+impl DocIndex {
+    /// Indexes every readable text file under `dir`
+    pub fn load(dir: &Path) -> Result<Self> {
+        let mut files = Vec::new();
+        collect_files(dir, dir, &mut files)?;
+        files.sort();
+
+        let mut index = Self::default();
+        for file in files {
+            // Binary files are not docs
+            let Ok(content) = std::fs::read_to_string(&file) else {
+                continue;
+            };
+            let source = file.strip_prefix(dir).unwrap_or(&file).to_path_buf();
+            for text in split_snippets(&content) {
+                index.add(source.clone(), text);
+            }
+        }
+        Ok(index)
+    }
+
+    fn add(&mut self, source: PathBuf, text: String) {
+        let mut terms = HashMap::new();
+        for term in tokenize(&text) {
+            *terms.entry(term).or_insert(0) += 1;
+        }
+        for term in terms.keys() {
+            *self.doc_freq.entry(term.clone()).or_insert(0) += 1;
+        }
+        self.snippets.push(Snippet { source, text, terms });
+    }
+
+    pub fn len(&self) -> usize {
+        self.snippets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.snippets.is_empty()
+    }
+
+    /// Most relevant snippets for `query`, best first, fitting in `budget` tokens
+    pub fn search(&self, query: &str, budget: usize) -> Vec<&Snippet> {
+        let query: HashSet<String> = tokenize(query).collect();
+        let total = self.snippets.len() as f64;
+
+        let mut scored: Vec<(f64, &Snippet)> = self.snippets.iter()
+            .map(|snippet| {
+                let len = snippet.terms.values().sum::<usize>().max(1) as f64;
+                let score = query.iter()
+                    .filter_map(|term| {
+                        let tf = *snippet.terms.get(term)? as f64 / len;
+                        let idf = (total / self.doc_freq[term] as f64).ln() + 1.0;
+                        Some(tf * idf)
+                    })
+                    .sum::<f64>();
+                (score, snippet)
+            })
+            .filter(|(score, _)| *score > 0.0)
+            .collect();
+        scored.sort_by(|a, b| b.0.total_cmp(&a.0));
+
+        let mut remaining = budget;
+        let mut selected = Vec::new();
+        for (_, snippet) in scored {
+            let tokens = estimate_tokens(&snippet.text);
+            if tokens > remaining {
+                continue;
+            }
+            remaining -= tokens;
+            selected.push(snippet);
+        }
+        selected
+    }
+}
+
+/// Approximate number of model tokens in `text`
+pub fn estimate_tokens(text: &str) -> usize {
+    text.chars().count().div_ceil(CHARS_PER_TOKEN)
+}
+
+/// Splits a file into paragraphs, cutting long ones every `SNIPPET_LINES` lines
+fn split_snippets(content: &str) -> Vec<String> {
+    let mut snippets = Vec::new();
+    let mut current: Vec<&str> = Vec::new();
+
+    for line in content.lines() {
+        if line.trim().is_empty() || current.len() == SNIPPET_LINES {
+            if !current.is_empty() {
+                snippets.push(current.join("\n"));
+                current.clear();
+            }
+            if line.trim().is_empty() {
+                continue;
+            }
+        }
+        current.push(line);
+    }
+    if !current.is_empty() {
+        snippets.push(current.join("\n"));
+    }
+    snippets
+}
+
+/// Lowercase words of two or more chars, `snake_case` split into its parts too
+fn tokenize(text: &str) -> impl Iterator<Item = String> + '_ {
+    text.split(|c: char| !c.is_alphanumeric() && c != '_')
+        .flat_map(|word| {
+            let parts = word.split('_').filter(move |part| *part != word);
+            std::iter::once(word).chain(parts)
+        })
+        .filter(|word| word.chars().count() >= 2)
+        .map(|word| word.to_lowercase())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn index(docs: &[(&str, &str)]) -> DocIndex {
+        let mut index = DocIndex::default();
+        for (source, content) in docs {
+            for text in split_snippets(content) {
+                index.add(PathBuf::from(source), text);
+            }
+        }
+        index
+    }
+
+    #[test]
+    fn test_search_selects_relevant_snippet() {
+        let index = index(&[
+            ("auth.md", "# Auth\n\nCall `login_user(name, token)` to open a session.\n\nSessions expire after an hour."),
+            ("storage.md", "# Storage\n\nUse `save_blob(bytes)` to persist data."),
+        ]);
+
+        let found = index.search("let session = login_user(name, ", 1000);
+        assert_eq!(found[0].source, PathBuf::from("auth.md"));
+        assert!(found[0].text.contains("login_user"));
+        assert!(found.iter().all(|s| !s.text.contains("save_blob")));
+
+        assert!(index.search("unrelated words", 1000).is_empty());
+    }
+
+    #[test]
+    fn test_search_respects_budget() {
+        let long = "login ".repeat(100);
+        let index = index(&[("a.md", &format!("login here\n\n{}", long))]);
+
+        let found = index.search("login", 10);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].text, "login here");
+        assert!(index.search("login", 0).is_empty());
+    }
+
+    #[test]
+    fn test_split_snippets() {
+        let content = format!("a\nb\n\n\nc\n{}", "x\n".repeat(SNIPPET_LINES + 1));
+        let snippets = split_snippets(&content);
+        assert_eq!(snippets.len(), 3);
+        assert_eq!(snippets[0], "a\nb");
+        assert_eq!(snippets[1].lines().count(), SNIPPET_LINES);
+    }
+}
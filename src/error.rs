@@ -0,0 +1,51 @@
+use crate::diff::TextEdit;
+
+/// Failures of the completion pipeline that callers may want to tell apart
+#[derive(Debug, thiserror::Error)]
+pub enum CoderError {
+    /// The model reply lacks one of the patch tokens
+    #[error("Invalid patch format: missing {0}")]
+    MissingToken(&'static str),
+
+    /// The model reply or tool call is structurally malformed
+    #[error("Invalid model response: {0}")]
+    InvalidResponse(String),
+
+    /// The context window could not be mapped back onto the file
+    #[error("Invalid context: {0}")]
+    Context(String),
+
+    /// The marker being completed is not where it was expected
+    #[error("Marker not found at byte {0}")]
+    MarkerNotFound(usize),
+
+    /// Two edits touch the same bytes
+    #[error("Overlapping edits: {0}")]
+    OverlappingEdits(String),
+
+    /// An edit points past the end of the file
+    #[error("Edit out of bounds {0:?}")]
+    EditOutOfBounds(TextEdit),
+
+    /// An identical request this one was coalesced with failed
+    #[error("Coalesced LLM request failed: {0}")]
+    Shared(String),
+
+    /// The provider rejected the request for exceeding its rate limit
+    #[error("LLM request rate limited: {0}")]
+    RateLimited(String),
+
+    /// Too few of the models asked agreed on the patch
+    #[error("No consensus: the best patch got {0} votes, {1} needed")]
+    NoConsensus(usize, usize),
+
+    /// The user hasn't agreed to send code to the model provider
+    #[error("No consent to send code to the model provider, set ANYCODER_CONSENT=1 or accept the prompt at startup")]
+    NoConsent,
+
+    /// The request to the model failed in transport or at the provider
+    #[error("LLM request failed: {0}")]
+    Llm(#[from] async_openai::error::OpenAIError),
+}
+
+pub type Result<T> = std::result::Result<T, CoderError>;
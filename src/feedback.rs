@@ -0,0 +1,118 @@
+use serde::{Deserialize, Serialize};
+use std::path::Path;
+use crate::lang::{comment_syntax, detect_language};
+use crate::state::Completion;
+
+/// What the user thought of a completion
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Verdict {
+    Accept,
+    Reject,
+}
+
+/// A `//+` or `//-` left at the end of a completed line
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mark {
+    pub verdict: Verdict,
+    /// Byte range to strip, including the whitespace before the token
+    pub start: usize,
+    pub end: usize,
+}
+
+/// Finds a feedback mark on one of the lines of `completion`. The token is the
+/// line comment of the file's language followed by `+` or `-`, `//` when unknown.
+pub fn find_mark(content: &str, path: &Path, completion: &Completion) -> Option<Mark> {
+    let comment = detect_language(path)
+        .and_then(comment_syntax)
+        .map_or("//", |(open, _)| open);
+    let accept = format!("{}+", comment);
+    let reject = format!("{}-", comment);
+
+    let mut line_start = 0;
+    for line in content.split_inclusive('\n') {
+        let text = line.trim_end_matches('\n').trim_end_matches('\r');
+        let offset = line_start;
+        line_start += line.len();
+
+        let (verdict, code) = if let Some(code) = text.strip_suffix(accept.as_str()) {
+            (Verdict::Accept, code)
+        } else if let Some(code) = text.strip_suffix(reject.as_str()) {
+            (Verdict::Reject, code)
+        } else {
+            continue;
+        };
+        let mark = Mark { verdict, start: offset + code.trim_end().len(), end: offset + text.len() };
+
+        if on_completion(content, &mark, completion) {
+            return Some(mark);
+        }
+    }
+    None
+}
+
+/// Removes `mark` from `content`
+pub fn strip_mark(content: &str, mark: &Mark) -> String {
+    let mut stripped = content.to_string();
+    stripped.replace_range(mark.start..mark.end, "");
+    stripped
+}
+
+/// Whether `mark` sits on a line written by `completion`
+fn on_completion(content: &str, mark: &Mark, completion: &Completion) -> bool {
+    // The token may split the completion text, so look for it without the mark
+    let stripped = strip_mark(content, mark);
+    let Some(start) = completion.locate(&stripped) else {
+        return false;
+    };
+    let end = start + completion.text.trim_end_matches('\n').len();
+
+    let line_of = |pos: usize| stripped[..pos].matches('\n').count();
+    let mark_line = line_of(mark.start);
+    (line_of(start)..=line_of(end)).contains(&mark_line)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn completion(content: &str, text: &str) -> Completion {
+        let start = content.find(text).unwrap();
+        Completion::new(content, start..start + text.len()).unwrap()
+    }
+
+    #[test]
+    fn test_find_and_strip_mark() {
+        let completed = "fn main() {\n    let x = 1;\n    let y = 2;\n}\n";
+        let completion = completion(completed, "let x = 1;\n    let y = 2;\n");
+
+        let accepted = "fn main() {\n    let x = 1;\n    let y = 2; //+\n}\n";
+        let mark = find_mark(accepted, Path::new("main.rs"), &completion).unwrap();
+        assert_eq!(mark.verdict, Verdict::Accept);
+        assert_eq!(strip_mark(accepted, &mark), completed);
+
+        let rejected = "fn main() {\n    let x = 1;//-\n    let y = 2;\n}\n";
+        let mark = find_mark(rejected, Path::new("main.rs"), &completion).unwrap();
+        assert_eq!(mark.verdict, Verdict::Reject);
+        assert_eq!(strip_mark(rejected, &mark), completed);
+    }
+
+    #[test]
+    fn test_mark_must_be_on_completion() {
+        let completed = "let a = 0;\nlet x = 1;\n";
+        let completion = completion(completed, "let x = 1;");
+
+        assert_eq!(find_mark("let a = 0; //+\nlet x = 1;\n", Path::new("a.rs"), &completion), None);
+        assert_eq!(find_mark(completed, Path::new("a.rs"), &completion), None);
+    }
+
+    #[test]
+    fn test_mark_uses_language_comment() {
+        let completed = "x = compute()\n";
+        let completion = completion(completed, "compute()");
+
+        let mark = find_mark("x = compute() #+\n", Path::new("a.py"), &completion).unwrap();
+        assert_eq!(mark.verdict, Verdict::Accept);
+        assert_eq!(find_mark("x = compute() //+\n", Path::new("a.py"), &completion), None);
+    }
+}
@@ -0,0 +1,199 @@
+use async_trait::async_trait;
+use log::debug;
+use std::ffi::OsStr;
+use std::path::Path;
+use std::process::Command;
+
+/// Lines of a file's diff sent to the model at most
+const MAX_DIFF_LINES: usize = 60;
+
+/// Source of the uncommitted changes of a file
+#[async_trait]
+pub trait ChangesProvider: Send + Sync {
+    /// Working-tree diff of `path`, `None` when there is nothing to show
+    async fn changes(&self, path: &Path) -> Option<String>;
+}
+
+/// Asks the `git` command line for the working-tree diff
+pub struct GitCli;
+
+#[async_trait]
+impl ChangesProvider for GitCli {
+    async fn changes(&self, path: &Path) -> Option<String> {
+        let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+        let file = path.file_name()?;
+
+        // Fails outside a repository or without git installed, which just means no changes
+        let output = tokio::process::Command::new("git")
+            .current_dir(dir)
+            .args(["diff", "--no-color", "--no-ext-diff", "-U1", "--"])
+            .arg(file)
+            .output()
+            .await
+            .inspect_err(|e| debug!("git diff for {:?} failed: {}", path, e))
+            .ok()?;
+        if !output.status.success() {
+            debug!("git diff for {:?}: {}", path, String::from_utf8_lossy(&output.stderr).trim());
+            return None;
+        }
+
+        summarize(&String::from_utf8_lossy(&output.stdout))
+    }
+}
+
+/// Keeps the hunks of a diff, dropping its headers and cutting it to `MAX_DIFF_LINES`
+pub fn summarize(diff: &str) -> Option<String> {
+    let hunks: Vec<&str> = diff.lines()
+        .skip_while(|line| !line.starts_with("@@"))
+        .collect();
+    if hunks.is_empty() {
+        return None;
+    }
+
+    let mut summary = hunks.iter().take(MAX_DIFF_LINES).copied().collect::<Vec<_>>().join("\n");
+    if hunks.len() > MAX_DIFF_LINES {
+        summary.push_str(&format!("\n... {} more lines", hunks.len() - MAX_DIFF_LINES));
+    }
+    Some(summary)
+}
+
+/// Why a completed file was not committed
+#[derive(Debug, thiserror::Error)]
+pub enum CommitError {
+    #[error("other changes are staged: {}", .0.join(", "))]
+    OtherStaged(Vec<String>),
+
+    #[error("git {command} failed: {output}")]
+    Git { command: String, output: String },
+
+    #[error("git could not run: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Stages `path` and commits it alone with `message`. Refuses when anything
+/// else is staged, so changes the user is preparing never end up in our
+/// commit. Returns false when the file had nothing to commit.
+pub fn commit_file(path: &Path, message: &str) -> Result<bool, CommitError> {
+    let dir = path.parent().filter(|dir| !dir.as_os_str().is_empty()).unwrap_or(Path::new("."));
+    let file = path.file_name().unwrap_or(path.as_os_str());
+
+    // Staged paths are relative to the top of the repository
+    let prefix = git(dir, ["rev-parse", "--show-prefix"])?;
+    let own = format!("{}{}", prefix.trim_end(), file.to_string_lossy());
+    let others: Vec<String> = git(dir, ["diff", "--cached", "--name-only"])?
+        .lines()
+        .filter(|staged| *staged != own)
+        .map(str::to_string)
+        .collect();
+    if !others.is_empty() {
+        return Err(CommitError::OtherStaged(others));
+    }
+
+    git(dir, [OsStr::new("add"), OsStr::new("--"), file])?;
+    if git(dir, ["diff", "--cached", "--name-only"])?.trim().is_empty() {
+        return Ok(false);
+    }
+    git(dir, [OsStr::new("commit"), OsStr::new("-q"), OsStr::new("-m"), OsStr::new(message), OsStr::new("--"), file])?;
+    Ok(true)
+}
+
+/// Runs git in `dir`, returning its output
+fn git<I, S>(dir: &Path, args: I) -> Result<String, CommitError>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<OsStr>,
+{
+    let args: Vec<S> = args.into_iter().collect();
+    let output = Command::new("git").current_dir(dir).args(&args).output()?;
+    if !output.status.success() {
+        let command = args.iter().map(|arg| arg.as_ref().to_string_lossy()).collect::<Vec<_>>().join(" ");
+        let output = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        return Err(CommitError::Git { command, output });
+    }
+    Ok(String::from_utf8_lossy(&output.stdout).into_owned())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_summarize() {
+        let diff = "diff --git a/main.rs b/main.rs\nindex 1..2 100644\n--- a/main.rs\n+++ b/main.rs\n\
+            @@ -1,2 +1,2 @@\n fn main() {\n-    old();\n+    new();\n";
+        assert_eq!(summarize(diff).unwrap(), "@@ -1,2 +1,2 @@\n fn main() {\n-    old();\n+    new();");
+        assert_eq!(summarize(""), None);
+
+        let long = format!("@@ -1 +1 @@\n{}", "+x\n".repeat(MAX_DIFF_LINES + 5));
+        let summary = summarize(&long).unwrap();
+        assert_eq!(summary.lines().count(), MAX_DIFF_LINES + 1);
+        assert!(summary.ends_with("... 6 more lines"));
+    }
+
+    #[tokio::test]
+    async fn test_outside_repository() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("anycoder-nogit-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        std::fs::write(dir.join("main.rs"), "fn main() {}\n")?;
+        // Outside a repository git fails, inside one the file is untracked: no changes either way
+        let changes = GitCli.changes(&dir.join("main.rs")).await;
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(changes, None);
+        Ok(())
+    }
+
+    /// A fresh repository with one committed file
+    fn temp_repo(name: &str) -> anyhow::Result<std::path::PathBuf> {
+        let dir = std::env::temp_dir().join(format!("anycoder-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_dir_all(&dir);
+        std::fs::create_dir_all(dir.join("src"))?;
+        for args in [
+            &["init", "-q"][..],
+            &["config", "user.name", "test"],
+            &["config", "user.email", "test@example.com"],
+            &["config", "commit.gpgsign", "false"],
+        ] {
+            git(&dir, args)?;
+        }
+        std::fs::write(dir.join("src/main.rs"), "fn main() {}\n")?;
+        git(&dir, ["add", "."])?;
+        git(&dir, ["commit", "-q", "-m", "Initial commit"])?;
+        Ok(dir)
+    }
+
+    #[test]
+    fn test_commit_file() -> anyhow::Result<()> {
+        let dir = temp_repo("autocommit")?;
+        let path = dir.join("src/main.rs");
+        std::fs::write(&path, "fn main() {\n    println!(\"hi\");\n}\n")?;
+        std::fs::write(dir.join("notes.txt"), "unrelated\n")?;
+
+        assert!(commit_file(&path, "Print a greeting")?);
+        let log = git(&dir, ["log", "--format=%s", "--name-only", "-1"])?;
+        // Nothing left to commit the second time
+        let again = commit_file(&path, "Print a greeting")?;
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(log.trim(), "Print a greeting\n\nsrc/main.rs");
+        assert!(!again);
+        Ok(())
+    }
+
+    #[test]
+    fn test_commit_refused_with_other_staged_changes() -> anyhow::Result<()> {
+        let dir = temp_repo("autocommit-staged")?;
+        let path = dir.join("src/main.rs");
+        std::fs::write(dir.join("notes.txt"), "staged by the user\n")?;
+        git(&dir, ["add", "notes.txt"])?;
+        std::fs::write(&path, "fn main() { run(); }\n")?;
+
+        let refused = commit_file(&path, "Call run");
+        let commits = git(&dir, ["rev-list", "--count", "HEAD"])?;
+        std::fs::remove_dir_all(&dir)?;
+
+        assert!(matches!(refused, Err(CommitError::OtherStaged(ref staged)) if staged == &["notes.txt"]), "{:?}", refused);
+        assert_eq!(commits.trim(), "1");
+        Ok(())
+    }
+}
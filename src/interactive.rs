@@ -0,0 +1,182 @@
+use anyhow::Result;
+use std::path::Path;
+use std::sync::OnceLock;
+use tokio::sync::{mpsc, Mutex};
+use crate::diff::unified_diff;
+
+/// What the user chose for a proposed completion
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Accept,
+    Reject,
+    /// Accept after changing the proposal in `$EDITOR`
+    Edit,
+}
+
+/// Reads `y`, `n` or `e`, in any case and with or without the full word
+pub fn parse_decision(input: &str) -> Option<Decision> {
+    match input.trim().to_lowercase().as_str() {
+        "y" | "yes" | "a" | "accept" => Some(Decision::Accept),
+        "n" | "no" | "r" | "reject" => Some(Decision::Reject),
+        "e" | "edit" => Some(Decision::Edit),
+        _ => None,
+    }
+}
+
+/// Lines typed on stdin, read by a single thread for the whole process.
+/// The thread reads a line only when asked to, so stdin is left alone
+/// while nothing is, such as while `$EDITOR` runs in the terminal.
+struct Input {
+    requests: std::sync::mpsc::Sender<()>,
+    lines: mpsc::UnboundedReceiver<String>,
+    /// A line was asked for and not received yet, by a prompt that may
+    /// have been dropped while waiting
+    pending: bool,
+}
+
+impl Input {
+    /// Starts the thread reading lines of `reader` as they are asked for
+    fn spawn(reader: impl std::io::BufRead + Send + 'static) -> Self {
+        let (requests, asked) = std::sync::mpsc::channel::<()>();
+        let (tx, lines) = mpsc::unbounded_channel();
+        std::thread::spawn(move || {
+            let mut reader = reader.lines();
+            while asked.recv().is_ok() {
+                let Some(Ok(line)) = reader.next() else { break };
+                if tx.send(line).is_err() {
+                    break;
+                }
+            }
+        });
+        Self { requests, lines, pending: false }
+    }
+
+    /// Next line typed, `None` once stdin is closed
+    async fn read_line(&mut self) -> Option<String> {
+        if !self.pending {
+            self.requests.send(()).ok()?;
+            self.pending = true;
+        }
+        let line = self.lines.recv().await;
+        self.pending = false;
+        line
+    }
+
+    /// Drops a line typed for a prompt that is gone, it must not answer the next one
+    fn discard_stale(&mut self) {
+        if self.pending && self.lines.try_recv().is_ok() {
+            self.pending = false;
+        }
+    }
+}
+
+/// Holding the lock also keeps prompts for different files from interleaving
+fn input() -> &'static Mutex<Input> {
+    static INPUT: OnceLock<Mutex<Input>> = OnceLock::new();
+    INPUT.get_or_init(|| Mutex::new(Input::spawn(std::io::BufReader::new(std::io::stdin()))))
+}
+
+/// Shows the proposed change to `path` and asks whether to write it.
+/// Returns the decision and the content to write when accepted.
+pub async fn confirm(path: &Path, old: &str, new: &str) -> Result<(Decision, String)> {
+    let mut input = input().lock().await;
+
+    input.discard_stale();
+
+    println!("{}", unified_diff(old, new, &path.display().to_string()));
+    loop {
+        println!("Apply to {}? [y]es / [n]o / [e]dit", path.display());
+        let line = input.read_line().await
+            .ok_or_else(|| anyhow::anyhow!("stdin closed"))?;
+
+        match parse_decision(&line) {
+            Some(Decision::Edit) => {
+                let edited = edit_in_editor(path, new).await?;
+                return Ok((Decision::Edit, edited));
+            }
+            Some(decision) => return Ok((decision, new.to_string())),
+            None => continue,
+        }
+    }
+}
+
+/// Asks once whether code may be sent to `base_url`, until answered yes or no
+pub async fn ask_consent(base_url: &str) -> Result<bool> {
+    let mut input = input().lock().await;
+    println!("anycoder sends the code around each marker, and more of the file, to {}.", base_url);
+    loop {
+        println!("Allow sending code there from now on? [y]es / [n]o");
+        let line = input.read_line().await
+            .ok_or_else(|| anyhow::anyhow!("stdin closed"))?;
+        match line.trim().to_lowercase().as_str() {
+            "y" | "yes" => return Ok(true),
+            "n" | "no" => return Ok(false),
+            _ => continue,
+        }
+    }
+}
+
+/// Opens `content` in `$EDITOR` (or `vi`) and returns it as saved
+async fn edit_in_editor(path: &Path, content: &str) -> Result<String> {
+    let extension = path.extension().map_or(String::new(), |e| format!(".{}", e.to_string_lossy()));
+    let draft = std::env::temp_dir()
+        .join(format!("anycoder-proposal-{}{}", std::process::id(), extension));
+    tokio::fs::write(&draft, content).await?;
+
+    let editor = std::env::var("EDITOR").unwrap_or_else(|_| "vi".to_string());
+    let status = tokio::process::Command::new(&editor).arg(&draft).status().await?;
+    if !status.success() {
+        anyhow::bail!("{} exited with {}", editor, status);
+    }
+
+    let edited = tokio::fs::read_to_string(&draft).await?;
+    tokio::fs::remove_file(&draft).await?;
+    Ok(edited)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    #[test]
+    fn test_parse_decision() {
+        assert_eq!(parse_decision("y\n"), Some(Decision::Accept));
+        assert_eq!(parse_decision(" Yes "), Some(Decision::Accept));
+        assert_eq!(parse_decision("n"), Some(Decision::Reject));
+        assert_eq!(parse_decision("E"), Some(Decision::Edit));
+        assert_eq!(parse_decision(""), None);
+        assert_eq!(parse_decision("maybe"), None);
+    }
+
+    #[tokio::test]
+    async fn test_input_read_only_when_asked() -> anyhow::Result<()> {
+        /// Lines of `text`, counting the reads made from it
+        struct Counted(std::io::Cursor<&'static str>, Arc<AtomicUsize>);
+
+        impl std::io::Read for Counted {
+            fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+                self.1.fetch_add(1, Ordering::SeqCst);
+                self.0.read(buf)
+            }
+        }
+
+        let reads = Arc::new(AtomicUsize::new(0));
+        let reader = std::io::BufReader::with_capacity(1, Counted(std::io::Cursor::new("y\nn\n"), reads.clone()));
+        let mut input = Input::spawn(reader);
+
+        // Nothing asked, so the terminal is left to whoever else reads it
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(reads.load(Ordering::SeqCst), 0);
+
+        assert_eq!(input.read_line().await.as_deref(), Some("y"));
+        let after_one = reads.load(Ordering::SeqCst);
+        tokio::time::sleep(std::time::Duration::from_millis(50)).await;
+        assert_eq!(reads.load(Ordering::SeqCst), after_one);
+
+        assert_eq!(input.read_line().await.as_deref(), Some("n"));
+        assert_eq!(input.read_line().await, None);
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+use std::path::Path;
+
+/// Language names keyed by file extension
+const LANGUAGES: &[(&str, &[&str])] = &[
+    ("rust", &["rs"]),
+    ("python", &["py", "pyi"]),
+    ("javascript", &["js", "mjs", "cjs", "jsx"]),
+    ("typescript", &["ts", "tsx"]),
+    ("go", &["go"]),
+    ("java", &["java"]),
+    ("kotlin", &["kt", "kts"]),
+    ("c", &["c", "h"]),
+    ("cpp", &["cpp", "cc", "cxx", "hpp", "hh"]),
+    ("csharp", &["cs"]),
+    ("ruby", &["rb"]),
+    ("php", &["php"]),
+    ("swift", &["swift"]),
+    ("scala", &["scala"]),
+    ("haskell", &["hs"]),
+    ("elixir", &["ex", "exs"]),
+    ("lua", &["lua"]),
+    ("shell", &["sh", "bash", "zsh"]),
+    ("html", &["html", "htm"]),
+    ("css", &["css", "scss"]),
+    ("sql", &["sql"]),
+    ("markdown", &["md"]),
+];
+
+/// Detects the language of a file from its extension
+pub fn detect_language(path: &Path) -> Option<&'static str> {
+    let ext = path.extension()?.to_str()?.to_lowercase();
+    LANGUAGES.iter()
+        .find(|(_, exts)| exts.contains(&ext.as_str()))
+        .map(|(name, _)| *name)
+}
+
+/// Opening and closing tokens of a single-line comment in `language`
+pub fn comment_syntax(language: &str) -> Option<(&'static str, &'static str)> {
+    match language {
+        "python" | "ruby" | "shell" | "elixir" => Some(("#", "")),
+        "haskell" | "lua" | "sql" => Some(("--", "")),
+        "css" => Some(("/*", " */")),
+        "html" | "markdown" => Some(("<!--", " -->")),
+        "rust" | "javascript" | "typescript" | "go" | "java" | "kotlin" | "c" | "cpp"
+            | "csharp" | "php" | "swift" | "scala" => Some(("//", "")),
+        _ => None,
+    }
+}
+
+/// Expression slot a string literal opens, such as `${...}` in a JavaScript template
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Interpolation {
+    /// Quote of the string holding the interpolation
+    pub quote: &'static str,
+    /// Letter in front of the quote that turns interpolation on, such as `f` in Python
+    pub prefix: &'static str,
+    pub open: &'static str,
+    pub close: char,
+}
+
+impl Interpolation {
+    const fn new(quote: &'static str, prefix: &'static str, open: &'static str, close: char) -> Self {
+        Self { quote, prefix, open, close }
+    }
+
+    /// How the interpolation reads in code, e.g. `f"...{...}..."`
+    pub fn describe(&self) -> String {
+        format!("{}{}...{}...{}...{}", self.prefix, self.quote, self.open, self.close, self.quote)
+    }
+}
+
+/// Quotes of the string literals of `language`, longest first
+fn string_quotes(language: &str) -> &'static [&'static str] {
+    match language {
+        "javascript" | "typescript" => &["`", "\"", "'"],
+        "python" => &["\"\"\"", "'''", "\"", "'"],
+        "kotlin" | "scala" | "swift" => &["\"\"\"", "\""],
+        "ruby" | "elixir" | "php" | "shell" => &["\"", "'"],
+        "csharp" => &["\""],
+        _ => &[],
+    }
+}
+
+/// String literals that interpolate expressions, keyed by language
+const INTERPOLATIONS: &[(&str, Interpolation)] = &[
+    ("javascript", Interpolation::new("`", "", "${", '}')),
+    ("typescript", Interpolation::new("`", "", "${", '}')),
+    ("python", Interpolation::new("\"\"\"", "f", "{", '}')),
+    ("python", Interpolation::new("'''", "f", "{", '}')),
+    ("python", Interpolation::new("\"", "f", "{", '}')),
+    ("python", Interpolation::new("'", "f", "{", '}')),
+    ("kotlin", Interpolation::new("\"\"\"", "", "${", '}')),
+    ("kotlin", Interpolation::new("\"", "", "${", '}')),
+    ("scala", Interpolation::new("\"\"\"", "s", "${", '}')),
+    ("scala", Interpolation::new("\"", "s", "${", '}')),
+    ("swift", Interpolation::new("\"\"\"", "", "\\(", ')')),
+    ("swift", Interpolation::new("\"", "", "\\(", ')')),
+    ("ruby", Interpolation::new("\"", "", "#{", '}')),
+    ("elixir", Interpolation::new("\"", "", "#{", '}')),
+    ("php", Interpolation::new("\"", "", "{$", '}')),
+    ("shell", Interpolation::new("\"", "", "${", '}')),
+    ("csharp", Interpolation::new("\"", "$", "{", '}')),
+];
+
+/// A string or interpolation still open at some point of the text
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Frame {
+    String { quote: &'static str, interpolation: Option<Interpolation> },
+    /// Code of an interpolation, with the brackets opened in it
+    Code { interpolation: Interpolation, depth: usize },
+}
+
+/// Strings and interpolations open at the end of `text`, outermost first.
+/// A lexical scan, not a parse: escapes, line comments and nesting are
+/// followed, other syntax is not.
+fn open_frames(language: &str, text: &str) -> Vec<Frame> {
+    let quotes = string_quotes(language);
+    let comment = comment_syntax(language).filter(|(_, close)| close.is_empty()).map(|(open, _)| open);
+    let mut frames = Vec::new();
+    let mut i = 0;
+    while let Some(c) = text[i..].chars().next() {
+        let rest = &text[i..];
+        match frames.last_mut() {
+            Some(Frame::String { quote, interpolation }) => {
+                if let Some(it) = interpolation.filter(|it| rest.starts_with(it.open)) {
+                    i += it.open.len();
+                    frames.push(Frame::Code { interpolation: it, depth: 0 });
+                    continue;
+                }
+                if c == '\\' {
+                    // Skips the escaped char along with the backslash
+                    i += 1 + rest[1..].chars().next().map_or(0, char::len_utf8);
+                    continue;
+                }
+                if rest.starts_with(*quote) {
+                    i += quote.len();
+                    frames.pop();
+                    continue;
+                }
+            }
+            code => {
+                if comment.is_some_and(|comment| rest.starts_with(comment)) {
+                    i += rest.find('\n').unwrap_or(rest.len());
+                    continue;
+                }
+                if let Some(quote) = quotes.iter().find(|quote| rest.starts_with(**quote)) {
+                    let prefix = text[..i].chars().rev()
+                        .take_while(|c| c.is_ascii_alphabetic() || *c == '$' || *c == '@')
+                        .collect::<String>()
+                        .to_lowercase();
+                    let interpolation = INTERPOLATIONS.iter()
+                        .find(|(name, it)| *name == language && it.quote == *quote
+                            && (it.prefix.is_empty() || prefix.contains(it.prefix)))
+                        .map(|(_, it)| *it);
+                    i += quote.len();
+                    frames.push(Frame::String { quote, interpolation });
+                    continue;
+                }
+                if let Some(Frame::Code { interpolation, depth }) = code {
+                    let opening = if interpolation.close == ')' { '(' } else { '{' };
+                    if c == opening {
+                        *depth += 1;
+                    } else if c == interpolation.close {
+                        if *depth == 0 {
+                            frames.pop();
+                        } else {
+                            *depth -= 1;
+                        }
+                    }
+                }
+            }
+        }
+        i += c.len_utf8();
+    }
+    frames
+}
+
+/// The interpolation `offset` of `text` sits in, if any
+pub fn interpolation_at(language: &str, text: &str, offset: usize) -> Option<Interpolation> {
+    match open_frames(language, text.get(..offset)?).last() {
+        Some(Frame::Code { interpolation, .. }) => Some(*interpolation),
+        _ => None,
+    }
+}
+
+/// Number of strings and interpolations `text` leaves open at its end,
+/// 0 for text whose literals are all closed
+pub fn open_strings(language: &str, text: &str) -> usize {
+    open_frames(language, text).len()
+}
+
+/// Tells whether `${name}` is code of `language`, a variable interpolated
+/// into a string or a command
+pub fn interpolates_dollar_braces(language: &str) -> bool {
+    // PHP strings take `${name}` as well as `{$name}`
+    language == "php" || INTERPOLATIONS.iter().any(|(name, it)| *name == language && it.open == "${")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detect_language() {
+        assert_eq!(detect_language(Path::new("main.rs")), Some("rust"));
+        assert_eq!(detect_language(Path::new("src/app.PY")), Some("python"));
+        assert_eq!(detect_language(Path::new("Makefile")), None);
+        assert_eq!(detect_language(Path::new("data.unknown")), None);
+    }
+
+    #[test]
+    fn test_comment_syntax() {
+        assert_eq!(comment_syntax("rust"), Some(("//", "")));
+        assert_eq!(comment_syntax("python"), Some(("#", "")));
+        assert_eq!(comment_syntax("html"), Some(("<!--", " -->")));
+        assert_eq!(comment_syntax("cobol"), None);
+    }
+
+    #[test]
+    fn test_interpolation_at() {
+        let at = |text: &str, needle: &str| text.find(needle).unwrap();
+        let js = "const a = `x ${user.name} y`; const b = '${not}';";
+        assert_eq!(interpolation_at("javascript", js, at(js, "name")).map(|it| it.open), Some("${"));
+        assert_eq!(interpolation_at("javascript", js, at(js, " y")), None);
+        assert_eq!(interpolation_at("javascript", js, at(js, "not")), None);
+
+        let nested = "`${items.map(i => `${i}`).join(`, `)} done`";
+        assert!(interpolation_at("javascript", nested, at(nested, "join")).is_some());
+        assert_eq!(interpolation_at("javascript", nested, at(nested, "done")), None);
+
+        let py = "print(f\"{total:>{width}}\", \"{plain}\")  # f\"{comment\n";
+        assert_eq!(interpolation_at("python", py, at(py, "total")).map(|it| it.prefix), Some("f"));
+        assert_eq!(interpolation_at("python", py, at(py, "plain")), None);
+        assert_eq!(open_strings("python", py), 0);
+
+        let escaped = "\"a \\\"${b}\\\" c\"";
+        assert!(interpolation_at("shell", escaped, at(escaped, "b")).is_some());
+        assert_eq!(interpolation_at("rust", "format!(\"{x}\")", 10), None);
+    }
+
+    #[test]
+    fn test_open_strings() {
+        assert_eq!(open_strings("javascript", "`a ${b} c`;"), 0);
+        assert_eq!(open_strings("javascript", "`a ${b;"), 2);
+        assert_eq!(open_strings("ruby", "\"#{a}\" + '#{b'"), 0);
+        assert_eq!(open_strings("swift", "\"\\(a) \\(b"), 2);
+    }
+}
@@ -0,0 +1,76 @@
+//! Completes code at a `??` marker with an LLM and turns the reply into
+//! minimal text edits. The `anycoder` binary watches files and drives a
+//! [`Coder`]; the same pieces are usable as a library.
+//!
+//! ```
+//! use anycoder::compute_text_edits;
+//!
+//! let old = "let x = ??;\n";
+//! let new = "let x = 42;\n";
+//! let edits = compute_text_edits(old, new);
+//!
+//! // Offsets point into `old`, so apply from the end
+//! let mut text = old.to_string();
+//! for edit in edits.iter().rev() {
+//!     text.replace_range(edit.start..edit.end, &edit.text);
+//! }
+//! assert_eq!(text, new);
+//! ```
+//!
+//! Completing a marker needs a [`ChatModel`], such as [`LlmClient`]:
+//!
+//! ```no_run
+//! # async fn run() -> Result<(), anycoder::CoderError> {
+//! use anycoder::{Coder, LlmClient};
+//!
+//! let llm = LlmClient::new("sk-...", "https://openrouter.ai/api/v1", "mistralai/codestral-2501");
+//! let coder = Coder::builder(llm).big_context(Some(200)).build();
+//!
+//! let code = "fn main() {\n    let x = ??;\n}\n";
+//! let cursor = code.find(anycoder::CURSOR_MARKER).unwrap();
+//! let completed = coder.autocomplete(code, std::path::Path::new("main.rs"), cursor).await?;
+//! # Ok(())
+//! # }
+//! ```
+
+pub mod utils;
+pub mod diff;
+pub mod llm;
+pub mod prompts;
+pub mod coder;
+pub mod error;
+pub mod state;
+pub mod config;
+pub mod lang;
+pub mod marker;
+pub mod patch;
+pub mod throttle;
+pub mod trigger;
+pub mod transcript;
+pub mod cli;
+pub mod diagnostic;
+pub mod docs;
+pub mod git;
+pub mod context;
+pub mod active;
+pub mod feedback;
+pub mod interactive;
+pub mod single_flight;
+pub mod rate_limit;
+pub mod notebook;
+pub mod validate;
+pub mod template;
+pub mod watchdog;
+pub mod rpc;
+pub mod consent;
+pub mod trace;
+pub mod activity;
+pub mod review;
+pub mod redact;
+#[cfg(test)]
+mod mock_server;
+
+pub use coder::{Coder, CoderBuilder, CoderOptions, Completion, CURSOR_MARKER};
+pub use diff::{compute_text_edits, OverlapPolicy, TextEdit};
+pub use error::CoderError;
+pub use llm::{ChatModel, ChatResponse, LlmClient};
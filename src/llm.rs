@@ -1,32 +1,405 @@
-use async_openai::{config::OpenAIConfig, Client};
+use async_openai::error::{ApiError, OpenAIError};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
 use serde_json::{json, Value};
+use crate::error::{CoderError, Result};
+use log::{debug, info, warn};
+use crate::coder::{DTOKEN, RTOKEN};
+
+/// Function call requested by the model
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct ToolCall {
+    pub name: String,
+    pub arguments: Value,
+}
+
+/// Model reply with its text content and any requested tool calls
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub struct ChatResponse {
+    pub content: String,
+    pub tool_calls: Vec<ToolCall>,
+}
+
+/// Chat completion backend used by the coder
+#[async_trait]
+pub trait ChatModel: Send + Sync {
+    async fn chat(&self, messages: Vec<Value>) -> Result<String>;
+
+    /// Chat offering `tools` to the model. Backends without tool
+    /// support answer in plain text.
+    async fn chat_with_tools(
+        &self, messages: Vec<Value>, _tools: Vec<Value>
+    ) -> Result<ChatResponse> {
+        let content = self.chat(messages).await?;
+        Ok(ChatResponse { content, tool_calls: vec![] })
+    }
+}
+
+/// Path the client appends to the base url for chat completions
+const CHAT_COMPLETIONS_PATH: &str = "/chat/completions";
+
+/// Price of a model in USD per token
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct ModelPrice {
+    pub prompt: f64,
+    pub completion: f64,
+}
+
+/// A model in the provider's `/models` list, with its price when the provider gives one
+#[derive(Debug, Clone, PartialEq)]
+pub struct ListedModel {
+    pub id: String,
+    pub price: Option<ModelPrice>,
+}
+
+/// Asks the model to pick up a reply cut off at the token limit
+const CONTINUE_PROMPT: &str = "Your reply was cut off. Continue exactly where it stopped, without repeating anything.";
+
+/// Error object providers nest under `error`
+#[derive(Deserialize)]
+struct WrappedError {
+    error: ApiError,
+}
 
 pub struct LlmClient {
-    client: Client<OpenAIConfig>,
+    http: reqwest::Client,
+    api_key: String,
+    api_base: String,
     model: String,
+    seed: Option<u64>,
+    stop: Vec<String>,
+    proxy: Option<reqwest::Proxy>,
+    headers: reqwest::header::HeaderMap,
+    max_continuations: usize,
+    /// Backoff for server errors, and for rate limits unless `retry_rate_limits` is off
+    backoff: backoff::ExponentialBackoff,
+    retry_rate_limits: bool,
 }
 
 impl LlmClient {
     pub fn new(api_key: &str, base_url: &str, model: &str) -> Self {
-        let config = OpenAIConfig::new()
-            .with_api_key(api_key)
-            .with_api_base(base_url);
-        
-        let client = Client::with_config(config);
-
         Self {
-            client,
+            http: reqwest::Client::new(),
+            api_key: api_key.into(),
+            api_base: api_base(base_url),
             model: model.into(),
+            seed: None,
+            stop: vec![RTOKEN.to_string()],
+            proxy: None,
+            headers: reqwest::header::HeaderMap::new(),
+            max_continuations: 2,
+            backoff: Default::default(),
+            retry_rate_limits: true,
+        }
+    }
+
+    /// Sequences that end generation, `<|REPLACE|>` by default
+    pub fn with_stop(mut self, stop: Vec<String>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Fails rate limited requests right away instead of retrying them inside
+    /// the client, for callers that coordinate those retries themselves.
+    /// Server errors are still retried.
+    pub fn without_rate_limit_retries(mut self) -> Self {
+        self.retry_rate_limits = false;
+        self
+    }
+
+    /// Sends requests through `proxy` rather than the one in `HTTPS_PROXY` or
+    /// `HTTP_PROXY`, still going direct for the hosts in `NO_PROXY`
+    pub fn with_proxy(mut self, proxy: &str) -> std::result::Result<Self, reqwest::Error> {
+        self.proxy = Some(reqwest::Proxy::all(proxy)?.no_proxy(reqwest::NoProxy::from_env()));
+        self.rebuild_http()
+    }
+
+    /// Sends `headers` with every request, on top of the authorization
+    pub fn with_headers(mut self, headers: reqwest::header::HeaderMap) -> std::result::Result<Self, reqwest::Error> {
+        self.headers = headers;
+        self.rebuild_http()
+    }
+
+    /// Swaps in an http client with the current proxy and headers
+    fn rebuild_http(mut self) -> std::result::Result<Self, reqwest::Error> {
+        let mut http = reqwest::Client::builder().default_headers(self.headers.clone());
+        if let Some(proxy) = &self.proxy {
+            http = http.proxy(proxy.clone());
         }
+        self.http = http.build()?;
+        Ok(self)
     }
 
-    pub async fn chat(&self, messages: Vec<Value>) -> anyhow::Result<String> {
-        let request = json!({ "model": self.model, "messages": messages });
-        let response: Value = self.client.chat().create_byot(request).await?;
-        let content = response["choices"][0]["message"]["content"]
-            .as_str().unwrap_or("").to_string();
+    /// Follow-up requests made for a reply cut off at the token limit,
+    /// 2 by default. The parts are joined before the patch is parsed.
+    pub fn with_max_continuations(mut self, max_continuations: usize) -> Self {
+        self.max_continuations = max_continuations;
+        self
+    }
+
+    /// Asks for reproducible sampling where the provider supports a seed
+    pub fn with_seed(mut self, seed: Option<u64>) -> Self {
+        self.seed = seed;
+        self
+    }
 
-        Ok(content)
+    /// Models the provider lists at `/models`
+    pub async fn list_models(&self) -> Result<Vec<ListedModel>> {
+        let url = format!("{}/models", self.api_base);
+        let response = self.send(|| self.http.get(&url)).await?;
+        parse_models(&response)
+    }
+
+    /// Looks the model up in the provider's `/models` list, see `check_listed`.
+    /// Providers without the endpoint are only logged. Tells whether the model
+    /// is listed, `None` when the list couldn't be had.
+    pub async fn check_model(&self) -> Option<bool> {
+        match self.list_models().await {
+            Ok(models) => Some(self.check_listed(&models)),
+            Err(e) => {
+                info!("Not checking {}, the provider doesn't list its models: {}", self.model, e);
+                None
+            }
+        }
+    }
+
+    /// Looks the model up in `models`, the provider's list fetched once for
+    /// every client, warning when it isn't there and logging its price when
+    /// the list gives one. Tells whether the model is listed.
+    pub fn check_listed(&self, models: &[ListedModel]) -> bool {
+        let listed = models.iter().find(|m| m.id == self.model);
+        match listed.map(|m| m.price) {
+            None => warn!(
+                "Model {} is not among the {} models the provider lists, requests may fail", self.model, models.len()
+            ),
+            Some(Some(price)) => info!("{} costs ${:.2}/M prompt and ${:.2}/M completion tokens",
+                self.model, price.prompt * 1e6, price.completion * 1e6),
+            Some(None) => {}
+        }
+        listed.is_some()
+    }
+
+    /// Chat request body shared by plain and tool requests
+    fn request(&self, messages: Vec<Value>) -> Value {
+        let mut request = json!({ "model": self.model, "messages": messages });
+        if let Some(seed) = self.seed {
+            debug!("request seed {}", seed);
+            request["seed"] = json!(seed);
+        }
+        request
+    }
+
+    /// Request for a plain text patch, stopping right after it
+    fn text_request(&self, messages: Vec<Value>) -> Value {
+        let mut request = self.request(messages);
+        if !self.stop.is_empty() {
+            request["stop"] = json!(self.stop);
+        }
+        request
+    }
+
+    /// Posts `request` to the chat endpoint
+    async fn post_chat(&self, request: &Value) -> Result<Value> {
+        let url = format!("{}{}", self.api_base, CHAT_COMPLETIONS_PATH);
+        self.send(|| self.http.post(&url).json(request)).await
+    }
+
+    /// Sends the request `build` makes, anew for every attempt, and reads the
+    /// JSON reply. Server errors are retried with backoff, rate limits too
+    /// unless the caller retries them itself.
+    async fn send(&self, build: impl Fn() -> reqwest::RequestBuilder) -> Result<Value> {
+        backoff::future::retry(self.backoff.clone(), || async {
+            let permanent = |e| backoff::Error::permanent(CoderError::Llm(e));
+            let response = build().bearer_auth(&self.api_key).send().await
+                .map_err(|e| permanent(OpenAIError::Reqwest(e)))?;
+            let status = response.status();
+            let body = response.bytes().await.map_err(|e| permanent(OpenAIError::Reqwest(e)))?;
+            if status.is_success() {
+                return serde_json::from_slice(&body).map_err(|e| permanent(OpenAIError::JSONDeserialize(e)));
+            }
+
+            let error = classify(status, &body);
+            let transient = match &error {
+                CoderError::RateLimited(_) => self.retry_rate_limits,
+                _ => status.is_server_error(),
+            };
+            if transient {
+                warn!("Request to {} failed, retrying: {}", self.api_base, error);
+                return Err(backoff::Error::transient(error));
+            }
+            Err(backoff::Error::permanent(error))
+        }).await
+    }
+}
+
+#[async_trait]
+impl ChatModel for LlmClient {
+    async fn chat(&self, messages: Vec<Value>) -> Result<String> {
+        let mut content = String::new();
+        let mut continuations = 0;
+        loop {
+            let mut messages = messages.clone();
+            if !content.is_empty() {
+                messages.push(json!({ "role": "assistant", "content": content }));
+                messages.push(json!({ "role": "user", "content": CONTINUE_PROMPT }));
+            }
+            let response = self.post_chat(&self.text_request(messages)).await?;
+            let choice = &response["choices"][0];
+            content.push_str(choice["message"]["content"].as_str().unwrap_or(""));
+
+            let finish_reason = choice["finish_reason"].as_str();
+            if finish_reason == Some("length") && continuations < self.max_continuations {
+                continuations += 1;
+                debug!("reply hit the token limit, continuing ({}/{})", continuations, self.max_continuations);
+                continue;
+            }
+            return Ok(restore_stop(&content, finish_reason, &self.stop));
+        }
+    }
+
+    async fn chat_with_tools(
+        &self, messages: Vec<Value>, tools: Vec<Value>
+    ) -> Result<ChatResponse> {
+        let mut request = self.request(messages);
+        request["tools"] = json!(tools);
+        request["tool_choice"] = json!("auto");
+        let response = self.post_chat(&request).await?;
+        parse_chat_response(&response)
+    }
+}
+
+/// Error for a reply with a failure `status`, telling rate limit rejections
+/// apart from other provider errors. Bodies that aren't an error object,
+/// as server errors often aren't, become the message.
+fn classify(status: reqwest::StatusCode, body: &[u8]) -> CoderError {
+    let api = match serde_json::from_slice::<WrappedError>(body) {
+        Ok(wrapped) => wrapped.error,
+        Err(_) => ApiError {
+            message: format!("{}: {}", status, String::from_utf8_lossy(body).trim()),
+            r#type: None,
+            param: None,
+            code: None,
+        },
+    };
+    // Quota errors come as 429 too, but waiting won't help
+    let out_of_quota = api.r#type.as_deref() == Some("insufficient_quota");
+    if status == reqwest::StatusCode::TOO_MANY_REQUESTS && !out_of_quota {
+        return CoderError::RateLimited(api.to_string());
+    }
+    CoderError::Llm(OpenAIError::ApiError(api))
+}
+
+/// Turns a user-supplied base url into the prefix the chat path is appended to.
+/// Accepts a trailing slash and urls already pointing at the chat endpoint,
+/// keeping any gateway prefix such as `/openai/v1`.
+fn api_base(base_url: &str) -> String {
+    let base = base_url.trim().trim_end_matches('/');
+    let base = base.strip_suffix(CHAT_COMPLETIONS_PATH).unwrap_or(base);
+    base.trim_end_matches('/').to_string()
+}
+
+/// Providers drop the stop sequence that ended generation. Puts `<|REPLACE|>`
+/// back so a patch cut short by it still parses; truncated replies stay as they are.
+fn restore_stop(content: &str, finish_reason: Option<&str>, stop: &[String]) -> String {
+    let stopped_at_replace = finish_reason == Some("stop")
+        && stop.iter().any(|s| s == RTOKEN)
+        && content.contains(DTOKEN)
+        && !content.contains(RTOKEN);
+
+    if stopped_at_replace {
+        format!("{}{}", content, RTOKEN)
+    } else {
+        content.to_string()
+    }
+}
+
+/// Reads an OpenAI-style model list. Prices are OpenRouter's `pricing` per
+/// token, given as strings or numbers.
+fn parse_models(response: &Value) -> Result<Vec<ListedModel>> {
+    let data = response["data"].as_array()
+        .ok_or_else(|| CoderError::InvalidResponse("model list without data".to_string()))?;
+    let per_token = |value: &Value| match value {
+        Value::String(s) => s.trim().parse::<f64>().ok(),
+        other => other.as_f64(),
+    };
+
+    Ok(data.iter()
+        .filter_map(|model| {
+            let id = model["id"].as_str()?.to_string();
+            let pricing = &model["pricing"];
+            let price = per_token(&pricing["prompt"])
+                .zip(per_token(&pricing["completion"]))
+                .map(|(prompt, completion)| ModelPrice { prompt, completion });
+            Some(ListedModel { id, price })
+        })
+        .collect())
+}
+
+/// Extracts content and tool calls from an OpenAI-style chat completion
+fn parse_chat_response(response: &Value) -> Result<ChatResponse> {
+    let message = &response["choices"][0]["message"];
+    let content = message["content"].as_str().unwrap_or("").to_string();
+
+    let mut tool_calls = Vec::new();
+    for call in message["tool_calls"].as_array().into_iter().flatten() {
+        let function = &call["function"];
+        let name = function["name"].as_str().unwrap_or("").to_string();
+
+        // Arguments arrive as a JSON-encoded string
+        let arguments = match &function["arguments"] {
+            Value::String(raw) => serde_json::from_str(raw)
+                .map_err(|e| CoderError::InvalidResponse(
+                    format!("Invalid arguments for tool {}: {}", name, e)
+                ))?,
+            other => other.clone(),
+        };
+
+        tool_calls.push(ToolCall { name, arguments });
+    }
+
+    Ok(ChatResponse { content, tool_calls })
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+
+    /// Canned chat model recording every request it receives
+    #[derive(Clone, Default)]
+    pub struct MockLlm {
+        response: ChatResponse,
+        calls: Arc<Mutex<Vec<Vec<Value>>>>,
+    }
+
+    impl MockLlm {
+        pub fn new(reply: &str) -> Self {
+            let response = ChatResponse { content: reply.to_string(), tool_calls: vec![] };
+            Self::with_response(response)
+        }
+
+        pub fn with_response(response: ChatResponse) -> Self {
+            Self { response, ..Default::default() }
+        }
+
+        pub fn calls(&self) -> Vec<Vec<Value>> {
+            self.calls.lock().unwrap().clone()
+        }
+    }
+
+    #[async_trait]
+    impl ChatModel for MockLlm {
+        async fn chat(&self, messages: Vec<Value>) -> Result<String> {
+            self.calls.lock().unwrap().push(messages);
+            Ok(self.response.content.clone())
+        }
+
+        async fn chat_with_tools(
+            &self, messages: Vec<Value>, _tools: Vec<Value>
+        ) -> Result<ChatResponse> {
+            self.calls.lock().unwrap().push(messages);
+            Ok(self.response.clone())
+        }
     }
 }
 
@@ -37,6 +410,283 @@ mod tests {
     use indoc::indoc;
     use dotenv::dotenv;
     use crate::prompts::{SYSTEM_PROMPT, REMINDER};
+    use crate::mock_server::{chat_reply, MockServer};
+
+    #[test]
+    fn test_parse_chat_response_tool_call() -> anyhow::Result<()> {
+        let response = json!({
+            "choices": [{
+                "message": {
+                    "content": null,
+                    "tool_calls": [{
+                        "type": "function",
+                        "function": {
+                            "name": "apply_edit",
+                            "arguments": "{\"search\":\"let <|cursor|>\",\"replace\":\"let x\"}"
+                        }
+                    }]
+                }
+            }]
+        });
+
+        let parsed = parse_chat_response(&response)?;
+
+        assert_eq!(parsed.content, "");
+        assert_eq!(parsed.tool_calls, vec![ToolCall {
+            name: "apply_edit".to_string(),
+            arguments: json!({ "search": "let <|cursor|>", "replace": "let x" }),
+        }]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_chat_response_text() -> anyhow::Result<()> {
+        let response = json!({ "choices": [{ "message": { "content": "hello" } }] });
+
+        let parsed = parse_chat_response(&response)?;
+
+        assert_eq!(parsed.content, "hello");
+        assert!(parsed.tool_calls.is_empty());
+
+        Ok(())
+    }
+
+    #[test]
+    fn test_parse_chat_response_invalid_arguments() {
+        let response = json!({
+            "choices": [{ "message": { "tool_calls": [{
+                "function": { "name": "apply_edit", "arguments": "{not json" }
+            }] } }]
+        });
+
+        let parsed = parse_chat_response(&response);
+        assert!(matches!(parsed, Err(CoderError::InvalidResponse(_))));
+    }
+
+    #[test]
+    fn test_restore_stop() {
+        let stop = vec![RTOKEN.to_string()];
+        let cut = "<|SEARCH|>let <|cursor|><|DIVIDE|>let x";
+
+        assert_eq!(restore_stop(cut, Some("stop"), &stop), format!("{}{}", cut, RTOKEN));
+
+        // Truncated or complete replies and other stop sequences are left alone
+        assert_eq!(restore_stop(cut, Some("length"), &stop), cut);
+        assert_eq!(restore_stop(cut, Some("stop"), &[]), cut);
+        let full = format!("{}{}", cut, RTOKEN);
+        assert_eq!(restore_stop(&full, Some("stop"), &stop), full);
+        assert_eq!(restore_stop("no patch", Some("stop"), &stop), "no patch");
+    }
+
+    #[test]
+    fn test_stop_sent_by_default() {
+        let messages = vec![json!({ "role": "user", "content": "hi" })];
+
+        let client = LlmClient::new("", "", "model");
+        assert_eq!(client.text_request(messages.clone())["stop"], json!([RTOKEN]));
+
+        let client = client.with_stop(vec![]);
+        assert!(client.text_request(messages).get("stop").is_none());
+    }
+
+    #[test]
+    fn test_request_seed() {
+        let messages = vec![json!({ "role": "user", "content": "hi" })];
+
+        let client = LlmClient::new("", "", "model");
+        assert!(client.request(messages.clone()).get("seed").is_none());
+
+        let client = client.with_seed(Some(42));
+        let request = client.request(messages);
+        assert_eq!(request["seed"], 42);
+        assert_eq!(request["model"], "model");
+    }
+
+    #[test]
+    fn test_chat_url_from_base_url() {
+        let chat_url = |base_url: &str| format!("{}{}", api_base(base_url), CHAT_COMPLETIONS_PATH);
+
+        let expected = "https://openrouter.ai/api/v1/chat/completions";
+        assert_eq!(chat_url("https://openrouter.ai/api/v1"), expected);
+        assert_eq!(chat_url("https://openrouter.ai/api/v1/"), expected);
+        assert_eq!(chat_url("https://openrouter.ai/api/v1/chat/completions"), expected);
+        assert_eq!(chat_url("https://openrouter.ai/api/v1/chat/completions/"), expected);
+
+        assert_eq!(
+            chat_url("https://gateway.example.com/openai/v1/"),
+            "https://gateway.example.com/openai/v1/chat/completions"
+        );
+        assert_eq!(
+            chat_url("http://localhost:11434/v1"),
+            "http://localhost:11434/v1/chat/completions"
+        );
+    }
+
+    #[test]
+    fn test_rate_limit_errors_classified() {
+        use reqwest::StatusCode;
+
+        let body = |message: &str, r#type: &str| json!({ "error": { "message": message, "type": r#type } }).to_string();
+
+        let limited = classify(StatusCode::TOO_MANY_REQUESTS, body("Slow down", "requests").as_bytes());
+        assert!(matches!(limited, CoderError::RateLimited(_)));
+        let bare = classify(StatusCode::TOO_MANY_REQUESTS, b"Too Many Requests");
+        assert!(matches!(bare, CoderError::RateLimited(_)));
+        let quota = classify(StatusCode::TOO_MANY_REQUESTS, body("You exceeded your current quota", "insufficient_quota").as_bytes());
+        assert!(matches!(quota, CoderError::Llm(_)));
+
+        // Mentioning rate limits or 429 doesn't make an error one
+        let other = classify(StatusCode::BAD_REQUEST, body("Rate limit of 429 tokens is invalid", "invalid_request_error").as_bytes());
+        assert!(matches!(other, CoderError::Llm(_)));
+        let server = classify(StatusCode::BAD_GATEWAY, b"<html>upstream timed out</html>");
+        assert!(matches!(server, CoderError::Llm(OpenAIError::ApiError(api)) if api.message.contains("502")));
+    }
+
+    #[test]
+    fn test_transport_error_variant() {
+        let error = async_openai::error::OpenAIError::InvalidArgument("bad".to_string());
+        let error: CoderError = error.into();
+        assert!(matches!(error, CoderError::Llm(_)));
+    }
+
+    #[tokio::test]
+    async fn test_custom_headers_sent() -> anyhow::Result<()> {
+        let mut server = MockServer::chat("hi").await?;
+        let mut headers = reqwest::header::HeaderMap::new();
+        headers.insert("http-referer", "https://example.com".parse()?);
+        headers.insert("x-title", "anycoder".parse()?);
+        let client = LlmClient::new("sk", &server.base_url(), "model").with_headers(headers)?;
+
+        let messages = vec![json!({ "role": "user", "content": "hi" })];
+        tokio::time::timeout(std::time::Duration::from_secs(5), client.chat(messages)).await??;
+
+        let head = server.received().remove(0).headers;
+        assert!(head.contains("\r\nhttp-referer: https://example.com\r\n"), "{}", head);
+        assert!(head.contains("\r\nx-title: anycoder\r\n"), "{}", head);
+        assert!(head.contains("\r\nauthorization: bearer sk\r\n"), "{}", head);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_requests_go_through_proxy() -> anyhow::Result<()> {
+        let mut proxy = MockServer::chat("hi").await?;
+        let client = LlmClient::new("sk", "http://anycoder.invalid/v1", "model")
+            .with_proxy(&proxy.url)?;
+
+        let messages = vec![json!({ "role": "user", "content": "hi" })];
+        let chat = tokio::time::timeout(std::time::Duration::from_secs(5), client.chat(messages)).await??;
+        assert_eq!(chat, "hi");
+
+        // A proxied plain http request names the full target url
+        let line = proxy.received().remove(0).line;
+        assert_eq!(line, "POST http://anycoder.invalid/v1/chat/completions HTTP/1.1");
+        Ok(())
+    }
+
+    /// Answers with each of `replies` in turn
+    async fn serve_replies(replies: Vec<Value>) -> std::io::Result<MockServer> {
+        MockServer::replying(replies.into_iter().map(|reply| (200, reply.to_string())).collect()).await
+    }
+
+    fn reply(content: &str, finish_reason: &str) -> Value {
+        chat_reply("model", content, finish_reason)
+    }
+
+    #[tokio::test]
+    async fn test_truncated_reply_continued() -> anyhow::Result<()> {
+        let mut server = serve_replies(vec![
+            reply("<|SEARCH|>let x = <|cursor|>;<|DIV", "length"),
+            reply("IDE|>let x = 1;", "stop"),
+        ]).await?;
+        let client = LlmClient::new("sk", &server.base_url(), "model");
+
+        let messages = vec![json!({ "role": "user", "content": "complete" })];
+        let chat = tokio::time::timeout(std::time::Duration::from_secs(5), client.chat(messages)).await??;
+        assert_eq!(chat, "<|SEARCH|>let x = <|cursor|>;<|DIVIDE|>let x = 1;<|REPLACE|>");
+
+        let requests = server.received();
+        let continuation = requests[1].body["messages"].as_array().unwrap();
+        assert_eq!(continuation.len(), 3);
+        assert_eq!(continuation[1], json!({ "role": "assistant", "content": "<|SEARCH|>let x = <|cursor|>;<|DIV" }));
+        assert_eq!(continuation[2]["content"], CONTINUE_PROMPT);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_continuations_capped() -> anyhow::Result<()> {
+        let mut server = serve_replies(vec![reply("a", "length"), reply("b", "length")]).await?;
+        let client = LlmClient::new("sk", &server.base_url(), "model").with_max_continuations(1);
+
+        let messages = vec![json!({ "role": "user", "content": "complete" })];
+        let chat = tokio::time::timeout(std::time::Duration::from_secs(5), client.chat(messages)).await??;
+        assert_eq!(chat, "ab");
+        assert_eq!(server.received().len(), 2);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_server_errors_retried_rate_limits_not() -> anyhow::Result<()> {
+        let limited = json!({ "error": { "message": "Slow down", "type": "requests" } }).to_string();
+        let mut server = MockServer::replying(vec![
+            (502, "upstream timed out".to_string()),
+            (200, reply("ok", "stop").to_string()),
+            (429, limited),
+        ]).await?;
+        let backoff = backoff::ExponentialBackoff {
+            initial_interval: std::time::Duration::from_millis(1),
+            ..Default::default()
+        };
+        let client = LlmClient { backoff, ..LlmClient::new("sk", &server.base_url(), "model") }.without_rate_limit_retries();
+
+        let messages = vec![json!({ "role": "user", "content": "hi" })];
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(tokio::time::timeout(timeout, client.chat(messages.clone())).await??, "ok");
+        let chat = tokio::time::timeout(timeout, client.chat(messages)).await?;
+        assert!(matches!(chat, Err(CoderError::RateLimited(_))), "{:?}", chat);
+        assert_eq!(server.received().len(), 3);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_model_against_models_endpoint() -> anyhow::Result<()> {
+        let models = json!({ "object": "list", "data": [
+            { "id": "vendor/listed", "pricing": { "prompt": "0.0000003", "completion": "0.0000009" } },
+            { "id": "vendor/free", "pricing": { "prompt": 0, "completion": 0 } },
+            { "id": "vendor/unpriced" },
+        ]});
+        let mut server = MockServer::replying(vec![(200, models.to_string())]).await?;
+        let base_url = server.base_url();
+
+        let listed = LlmClient::new("sk", &base_url, "vendor/listed");
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(tokio::time::timeout(timeout, listed.check_model()).await?, Some(true));
+
+        // Other clients check against the list already fetched
+        let models = parse_models(&models)?;
+        assert_eq!(models[0].price, Some(ModelPrice { prompt: 0.0000003, completion: 0.0000009 }));
+        assert_eq!(models[1].price, Some(ModelPrice { prompt: 0.0, completion: 0.0 }));
+        assert_eq!(models[2].price, None);
+        assert!(LlmClient::new("sk", &base_url, "vendor/free").check_listed(&models));
+        assert!(!LlmClient::new("sk", &base_url, "vendor/missing").check_listed(&models));
+
+        let requests = server.received();
+        assert_eq!(requests.len(), 1);
+        assert!(requests[0].line.starts_with("GET /v1/models"));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_check_model_without_models_endpoint() -> anyhow::Result<()> {
+        let mut server = MockServer::replying(vec![(404, "404 page not found".to_string()), (200, "{}".to_string())]).await?;
+
+        let client = LlmClient::new("sk", &server.base_url(), "local-model");
+        let timeout = std::time::Duration::from_secs(5);
+        assert_eq!(tokio::time::timeout(timeout, client.check_model()).await?, None);
+        assert_eq!(tokio::time::timeout(timeout, client.check_model()).await?, None);
+        assert_eq!(server.received().len(), 2);
+        Ok(())
+    }
 
     #[tokio::test]
     #[ignore]
@@ -1,30 +1,52 @@
-use log::{error, info};
+use log::{debug, error, info, trace, warn};
 use notify::{
-    recommended_watcher, Event, RecursiveMode, Watcher,
-    event::ModifyKind,
+    recommended_watcher, Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+    event::{ModifyKind, RenameMode},
 };
+use futures::FutureExt;
 use tokio::sync::mpsc;
-use anyhow::{Result};
+use anyhow::{Context, Result};
+use std::panic::AssertUnwindSafe;
 use std::path::{Path, PathBuf};
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::{HashMap, HashSet}, sync::Arc};
+use std::time::Duration;
 use tokio::sync::RwLock;
 use tokio::task::JoinHandle;
 use dotenv::dotenv;
 
-mod utils;
-use utils::{has_content_changed, is_ignored_path};
-
-mod diff;
-use crate::diff::compute_text_edits;
-mod llm;
-use llm::LlmClient;
-mod prompts;
-mod coder;
-use coder::{Coder, CURSOR_MARKER};
-mod state;
-use state::{State, SharedState, FileState};
-mod config;
-use config::{Config, init_logger};
+use anycoder::utils::{
+    collect_files, find_markers, is_ignored_path, panic_message, sidecar_path, strip_bom, truncate_for_log, BOM
+};
+use anycoder::diff::{changed_range, compute_text_edits, EditSummary};
+use anycoder::llm::LlmClient;
+use anycoder::single_flight::SingleFlight;
+use anycoder::rate_limit::RateLimitGate;
+use anycoder::consent::{Consent, ConsentGate};
+use anycoder::coder::{Coder, CURSOR_MARKER};
+use anycoder::context::Examples;
+use anycoder::state::{State, SharedState, FileState, Completion, Tracked};
+use anycoder::config::{self, Config, init_logger};
+use anycoder::throttle::StormGuard;
+use anycoder::transcript::{self, Feedback, Replayed, Transcript};
+use anycoder::cli::{self, Command};
+use anycoder::diagnostic;
+use anycoder::feedback;
+use anycoder::review;
+use anycoder::redact::Redactor;
+use anycoder::interactive::{self, Decision};
+use anycoder::marker::remove_marker;
+use anycoder::notebook;
+use anycoder::template;
+use anycoder::validate::Validator;
+use anycoder::watchdog::Watchdog;
+use anycoder::error::CoderError;
+use anycoder::docs::DocIndex;
+use anycoder::git::{self, GitCli};
+use anycoder::active::ActiveFiles;
+use anycoder::trace::{self, TraceFiles};
+
+#[cfg(test)]
+mod mock_server;
 
 fn log_create_event(path: &Path) {
     info!("watcher:create {:?}", (path, path.is_file()));
@@ -34,126 +56,1048 @@ fn log_remove_event(path: &Path) {
     info!("watcher:remove {:?}", (path, path.is_file()));
 }
 
-fn log_content_change(path: &Path, old: Option<&String>, new: &str) {
+/// How often states of files deleted without a remove event are dropped
+const PRUNE_INTERVAL: Duration = Duration::from_secs(600);
+
+/// Edits logged one by one at info level, larger changes are summarized
+const LOGGED_EDITS_LIMIT: usize = 10;
+
+/// Time the provider gets to list its models at startup
+const MODEL_CHECK_TIMEOUT: Duration = Duration::from_secs(10);
+
+tokio::task_local! {
+    /// Path of the event the task is handling, named by the panic hook
+    static EVENT_PATH: PathBuf;
+}
+
+fn log_content_change(path: &Path, old: Option<&FileState>, new: &str, limit: Option<usize>) {
     match old {
-        Some(old) => {
+        Some(FileState { content: None, .. }) => info!("File {:?} updated", path),
+        Some(FileState { content: Some(old), .. }) => {
             info!("File {:?} updated", path);
             let diffs = compute_text_edits(old, new);
-            for d in diffs { info!("{:?}", d) }
+            // A big paste diffs into hundreds of edits, keep those out of the info log
+            if diffs.len() > LOGGED_EDITS_LIMIT {
+                info!("{}", EditSummary::of(&diffs));
+                for d in diffs {
+                    trace!("{:?}", (d.start, d.end, truncate_for_log(&d.text, limit)))
+                }
+                return;
+            }
+            for d in diffs {
+                info!("{:?}", (d.start, d.end, truncate_for_log(&d.text, limit)))
+            }
         }
-        None => info!("File {:?} added with content:\n{}", path, new),
+        None => info!(
+            "File {:?} added with content:\n{}", path, truncate_for_log(new, limit)
+        ),
     }
 }
 
+/// Handles a save of `path`, logging at debug and trace level when the coder traces it
 async fn handle_modify_event(
     path: &PathBuf, state: SharedState
+) -> Result<()> {
+    let traced = state.read().await.coder.traces(path);
+    trace::scope(traced, handle_modify(path, state)).await
+}
+
+async fn handle_modify(
+    path: &PathBuf, state: SharedState
 ) -> Result<()> {
     info!("watcher:modify {:?}", (path, path.is_file()));
+    let saved_at = std::time::Instant::now();
 
-    let new_content = tokio::fs::read_to_string(path).await?;
-    info!("watcher:new_content {:?}", new_content);
+    // Only read access while checking the file: an abort at any await below
+    // leaves file states untouched
+    let guard = state.read().await;
+    let config = guard.config.clone();
+    let (new_content, bom) = read(path).await?;
+    let log_limit = config.log_limit;
+    info!("watcher:new_content {:?}", truncate_for_log(&new_content, log_limit));
 
-    let mut state = state.write().await;
-    let maybe_old_content = state.file2state.get(path).map(|fs| &fs.content);
+    let old_state = guard.files().get(path).cloned();
+    let old_state = old_state.as_ref();
+    let sidecar = config.sidecar.then(|| sidecar_path(path));
 
-    if !has_content_changed(maybe_old_content, &new_content) {
+    if old_state.is_some_and(|fs| fs.matches(&new_content)) {
         info!("watcher:content_unchanged {:?}", path);
         return Ok(());
     }
 
-    log_content_change(path, maybe_old_content, &new_content);
+    log_content_change(path, old_state, &new_content, log_limit);
+
+    // The original changed, so a sidecar from its previous version no longer applies
+    if let Some(sidecar) = &sidecar {
+        remove_stale_sidecar(sidecar).await?;
+    }
+
+    // Nothing to complete in an empty or blank file, only remember it
+    if new_content.trim().is_empty() {
+        info!("watcher:blank {:?}", path);
+        let file_state = guard.file_state(new_content);
+        guard.files().insert(path.clone(), file_state);
+        return Ok(());
+    }
+
+    let mut completed_todos = old_state
+        .map(|fs| fs.completed_todos.clone())
+        .unwrap_or_default();
+
+    let todo = config.todo_trigger.as_ref()
+        .and_then(|trigger| Some((trigger, trigger.find(&new_content, &completed_todos)?)));
+
+    // Diagnostic write-backs are not completions
+    let mut completed = true;
+
+    let deadline = config.completion_deadline;
+
+    let eol = config.eol_trigger.as_ref()
+        .and_then(|trigger| Some((trigger, trigger.find(&new_content)?)));
 
-    let final_content = if let Some(pos) = new_content.find(CURSOR_MARKER) {
-        let updated = state.coder.autocomplete(&new_content, path, pos).await?;
-        write(&path, &updated).await?;
-        updated
+    let hole_names = if config.template_holes && template::holes_allowed(path) {
+        template::hole_names(&template::find_holes(&new_content)).into_iter().map(String::from).collect()
     } else {
-        info!("No {} found in file {:?}", CURSOR_MARKER, path);
-        new_content
+        Vec::new()
     };
+    let mut unfilled_holes = old_state.map(|fs| fs.unfilled_holes.clone()).unwrap_or_default();
+    // Holes the model already left empty wait for the user to change them
+    let holes = !hole_names.is_empty() && hole_names != unfilled_holes;
 
-    state.file2state.insert(path.clone(), FileState {
-        content: final_content,
-    });
+    let markers = find_markers(&new_content, CURSOR_MARKER);
+    let marker_pos = markers.first().copied();
+    if !marker_allowed(&guard, path, marker_pos.is_some()) {
+        info!("Marker in {:?} already triggered a completion, remove it and add it again to retry", path);
+        return Ok(());
+    }
+    let wants_completion = marker_pos.is_some() || todo.is_some() || eol.is_some() || holes;
+    if wants_completion && !breaker_allows(&guard, path, &new_content) {
+        info!("Completions for {:?} paused after repeated failures", path);
+        return Ok(());
+    }
+    if wants_completion && !spend_budget(&guard, path) {
+        // File state stays as it was, so a later save tries again
+        warn!("Completion budget for {:?} used up, skipping until the window moves on", path);
+        return Ok(());
+    }
+
+    // The model is awaited without the lock, so reloading the config never
+    // waits for running completions, which finish with the coder they started with
+    let coder = guard.coder.clone();
+    drop(guard);
+
+    // Seen for the first time with nothing to complete, the context of its
+    // next completion can be built already
+    if config.prefetch && old_state.is_none() && !wants_completion {
+        let (coder, content, path) = (coder.clone(), new_content.clone(), path.clone());
+        tokio::spawn(async move { warm_context(&coder, &content, &path).await });
+    }
+
+    let mut updated = if let Some(pos) = marker_pos {
+        if markers.len() > 1 {
+            info!("Completing the first of {} markers in {:?}, the rest on later saves", markers.len(), path);
+        }
+        let completion = complete_marker(&coder, &new_content, path, pos);
+        let outcome = within(deadline, path, completion).await;
+        record_outcome(&*state.read().await, path, &new_content, &outcome);
+        match outcome {
+            None => {
+                completed = false;
+                (!config.keep_timed_out_marker).then(|| strip_marker(&new_content, path, pos))
+            }
+            Some(Ok(updated)) => Some(updated),
+            // A comment line would break the notebook's JSON
+            Some(Err(e)) if config.diagnostics && !notebook::is_notebook(path) => {
+                // Stored as the file state below, so writing it doesn't trigger another completion
+                warn!("No completion for {:?}: {}", path, e);
+                completed = false;
+                Some(diagnostic::annotate(&new_content, path, pos, &e.to_string()).ok_or(e)?)
+            }
+            Some(Err(e)) => return Err(e.into()),
+        }
+    } else if let Some((trigger, todo)) = todo {
+        info!("Implementing TODO {:?} in {:?}", todo.task, path);
+        let (marked, pos) = trigger.place_marker(&new_content, &todo);
+        let completion = coder.autocomplete_with_task(&marked, path, pos, Some(&todo.task));
+        let outcome = within(deadline, path, completion).await;
+        record_outcome(&*state.read().await, path, &new_content, &outcome);
+        match outcome.transpose()? {
+            Some(updated) => {
+                // A kept comment must not trigger again on the next save
+                if !trigger.removes_comment() {
+                    completed_todos.insert(todo.task);
+                }
+                Some(updated)
+            }
+            None => {
+                completed = false;
+                None
+            }
+        }
+    } else if let Some((trigger, sentinel)) = eol {
+        let (marked, pos) = trigger.place_marker(&new_content, sentinel);
+        let completion = coder.autocomplete(&marked, path, pos);
+        let outcome = within(deadline, path, completion).await;
+        record_outcome(&*state.read().await, path, &new_content, &outcome);
+        outcome.transpose()?
+    } else if holes {
+        info!("Filling template holes in {:?}", path);
+        let completion = coder.fill_holes(&new_content, path);
+        let outcome = within(deadline, path, completion).await;
+        record_outcome(&*state.read().await, path, &new_content, &outcome);
+        let filled = outcome.transpose()?;
+        if let Some(filled) = &filled {
+            unfilled_holes = template::hole_names(&template::find_holes(filled)).into_iter().map(String::from).collect();
+        }
+        filled
+    } else if let Some((completion, mark)) = old_state
+        .and_then(|fs| fs.last_completion.as_ref())
+        .filter(|_| config.feedback)
+        .and_then(|c| Some((c, feedback::find_mark(&new_content, path, c)?)))
+    {
+        info!("Completion in {:?} labeled {:?}", path, mark.verdict);
+        if let Some(transcript) = coder.transcript() {
+            let record = Feedback {
+                path: path.clone(),
+                verdict: mark.verdict,
+                completion: completion.text.clone(),
+                above: completion.above.clone(),
+            };
+            if let Err(e) = transcript.record_feedback(&record) {
+                error!("Failed to record feedback for {:?}: {}", path, e);
+            }
+        }
+        // Labeled once, so the completion is no longer tracked
+        completed = false;
+        Some(feedback::strip_mark(&new_content, &mark))
+    } else if let Some(stripped) = review::strip(&new_content, path).filter(|_| config.review_marks) {
+        info!("Removed the review marks from {:?}", path);
+        completed = false;
+        Some(stripped)
+    } else {
+        if config.diagnostics {
+            warn!("No completion for {:?}: no {} found", path, CURSOR_MARKER);
+        } else {
+            info!("No {} found in file {:?}", CURSOR_MARKER, path);
+        }
+        None
+    };
+
+    let recomplete = config.recomplete;
+    let track = recomplete || config.feedback;
+    let old_completion = old_state.and_then(|fs| fs.last_completion.clone());
+    let interactive = config.interactive;
+    let keep_marker = config.keep_rejected_marker;
+    let bom = bom && !config.strip_bom;
+    let validator = config.validate_cmd.as_ref()
+        .map(|cmd| Validator::new(cmd, config.validate_timeout));
+    // A sidecar leaves the original as it was, there is nothing to commit
+    let autocommit = config.autocommit && sidecar.is_none();
+    // Marks would end up in commits and sidecars, and break a notebook's JSON
+    let review_marks = config.review_marks && !autocommit && sidecar.is_none() && !notebook::is_notebook(path);
+
+    if let Some(validator) = validator
+        && completed
+        && let Some(candidate) = &updated
+        && let Err(e) = validator.check(path, candidate).await
+    {
+        // Left as saved, marker included, so fixing the code around it retries
+        warn!("Completion for {:?} failed validation: {}", path, e);
+        record_failure(&*state.read().await, path, &new_content);
+        completed = false;
+        updated = None;
+    }
+
+    if interactive && completed && let Some(proposal) = &updated {
+        let (decision, content) = interactive::confirm(path, &new_content, proposal).await?;
+        if decision == Decision::Reject {
+            info!("Completion for {:?} rejected", path);
+            record_failure(&*state.read().await, path, &new_content);
+            completed = false;
+            updated = marker_pos
+                .filter(|_| !keep_marker)
+                .map(|pos| strip_marker(&new_content, path, pos));
+        } else {
+            updated = Some(content);
+        }
+    }
+
+    let last_completion = match &updated {
+        _ if !track => None,
+        Some(updated) if completed => {
+            Completion::new(updated, changed_range(&new_content, updated))
+        }
+        Some(_) => None,
+        None => old_completion
+            .filter(|completion| match completion.track(&new_content) {
+                Tracked::Fresh => true,
+                Tracked::Stale { .. } if !recomplete => true,
+                Tracked::Stale { line } => {
+                    // Only a hint, rewriting code the user already accepted would be a surprise
+                    warn!(
+                        "Completion at {}:{} may be stale after edits above it, put {} there to redo it",
+                        path.display(), line + 1, CURSOR_MARKER
+                    );
+                    false
+                }
+                Tracked::Gone => false,
+            }),
+    };
+
+    if review_marks && completed && let Some(proposal) = &updated {
+        updated = Some(review::mark_completion(&new_content, proposal, path));
+    }
+
+    let final_content = updated.clone().unwrap_or_else(|| new_content.clone());
+    let file_state = FileState {
+        completed_todos,
+        unfilled_holes,
+        last_completion,
+        ..state.read().await.file_state(final_content)
+    };
+
+    // Spawned so that aborting this event can't split the file write from the state update
+    let path = path.clone();
+    tokio::spawn(trace::scope(trace::is_traced(), async move {
+        if let Some(updated) = &updated {
+            let wait = {
+                let state = state.read().await;
+                let mut spacer = state.spacer.lock().unwrap_or_else(|e| e.into_inner());
+                spacer.reserve(&path, saved_at, std::time::Instant::now())
+            };
+            if !wait.is_zero() {
+                debug!("watcher:write_delay {:?} {:?}", path, wait);
+                tokio::time::sleep(wait).await;
+            }
+            let on_disk = read(&path).await?;
+            // The file changed again while completing, its own event takes over
+            if on_disk.0 != new_content {
+                info!("watcher:stale_completion {:?}", path);
+                return Ok(());
+            }
+            // Rewriting the same bytes would only touch the file and wake up its watchers
+            if sidecar.is_none() && on_disk.0 == *updated && on_disk.1 == bom {
+                info!("watcher:unchanged {:?}", path);
+            } else {
+                write(sidecar.as_ref().unwrap_or(&path), updated, bom).await?;
+                if autocommit && completed {
+                    commit_completion(&path, &new_content, updated, &state).await;
+                }
+            }
+            // Aborted, skipped or failed completions leave the marker armed
+            if marker_pos.is_some() {
+                fire_marker(&*state.read().await, &path);
+            }
+        }
+        if sidecar.is_some() {
+            // The original is never rewritten, forgetting it lets the next save complete again
+            state.read().await.files().remove(&path);
+        } else {
+            state.read().await.files().insert(path, file_state);
+        }
+        anyhow::Ok(())
+    })).await?
+}
+
+/// Commits the completion of `path` from `before` to `after` on its own.
+/// Failing to commit leaves the completion in place and is only logged.
+async fn commit_completion(path: &Path, before: &str, after: &str, state: &SharedState) {
+    let mut message = format!("Complete {}", path.strip_prefix(".").unwrap_or(path).display());
+    let (coder, summarize) = {
+        let state = state.read().await;
+        (state.coder.clone(), state.config.autocommit_summary)
+    };
+    if summarize {
+        match coder.commit_message(path, before, after).await {
+            Ok(summary) => message = summary,
+            Err(e) => warn!("No commit message from the model for {:?}: {}", path, e),
+        }
+    }
+
+    let file = path.to_path_buf();
+    let committed = tokio::task::spawn_blocking(move || git::commit_file(&file, &message)).await;
+    match committed {
+        Ok(Ok(true)) => info!("Committed completion of {:?}", path),
+        Ok(Ok(false)) => debug!("Nothing to commit for {:?}", path),
+        Ok(Err(e)) => warn!("Completion of {:?} not committed: {}", path, e),
+        Err(e) => warn!("Completion of {:?} not committed: {}", path, e),
+    }
+}
+
+/// Completes the marker at `pos`. In a notebook only the source of the code
+/// cell holding a marker is sent, and the completed source is put back into the JSON.
+async fn complete_marker(
+    coder: &Coder, content: &str, path: &Path, pos: usize
+) -> anycoder::error::Result<String> {
+    if !notebook::is_notebook(path) {
+        return coder.autocomplete(content, path, pos).await;
+    }
+    let cell = notebook::marked_cell(content).ok_or_else(|| CoderError::Context(format!(
+        "no {} in the source of a code cell", CURSOR_MARKER
+    )))?;
+    let cursor = *find_markers(&cell.source, CURSOR_MARKER).first().ok_or(CoderError::MarkerNotFound(pos))?;
+    let source = coder.autocomplete(&cell.source, path, cursor).await?;
+    Ok(cell.replace(content, &source))
+}
+
+/// Removes the marker at `pos`. In a notebook it is the marker of the code
+/// cell `complete_marker` completes, taken out of the cell's source so the
+/// JSON around it and markers in outputs and markdown are left alone.
+fn strip_marker(content: &str, path: &Path, pos: usize) -> String {
+    if !notebook::is_notebook(path) {
+        return remove_marker(content, pos);
+    }
+    notebook::marked_cell(content)
+        .and_then(|cell| {
+            let cursor = *find_markers(&cell.source, CURSOR_MARKER).first()?;
+            Some(cell.replace(content, &remove_marker(&cell.source, cursor)))
+        })
+        .unwrap_or_else(|| content.to_string())
+}
+
+/// Deletes the sidecar of a previous completion, if there is one
+async fn remove_stale_sidecar(sidecar: &Path) -> Result<()> {
+    match tokio::fs::remove_file(sidecar).await {
+        Ok(()) => {
+            info!("Removed stale sidecar {:?}", sidecar);
+            Ok(())
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(e) => Err(e.into()),
+    }
+}
+
+/// Tells whether the marker in `path`, if any, may trigger a completion
+fn marker_allowed(state: &State, path: &Path, has_marker: bool) -> bool {
+    let mut latch = state.latch.lock().unwrap_or_else(|e| e.into_inner());
+    latch.allows(path, has_marker)
+}
+
+/// Keeps the marker in `path` from triggering again, once its completion is written
+fn fire_marker(state: &State, path: &Path) {
+    let mut latch = state.latch.lock().unwrap_or_else(|e| e.into_inner());
+    latch.fire(path);
+}
+
+/// Tells whether `path` may be completed, false while its breaker is open
+fn breaker_allows(state: &State, path: &Path, content: &str) -> bool {
+    let mut breaker = state.breaker.lock().unwrap_or_else(|e| e.into_inner());
+    breaker.allow(path, content, std::time::Instant::now())
+}
+
+/// Feeds a completion outcome to the breaker. Timeouts, shown as `None`,
+/// count as failures.
+fn record_outcome<T, E>(state: &State, path: &Path, content: &str, outcome: &Option<Result<T, E>>) {
+    if let Some(Ok(_)) = outcome {
+        let mut breaker = state.breaker.lock().unwrap_or_else(|e| e.into_inner());
+        breaker.success(path);
+    } else {
+        record_failure(state, path, content);
+    }
+}
+
+/// Counts a failed or rejected completion towards pausing `path`
+fn record_failure(state: &State, path: &Path, content: &str) {
+    let mut breaker = state.breaker.lock().unwrap_or_else(|e| e.into_inner());
+    if breaker.failure(path, content, std::time::Instant::now()) {
+        warn!(
+            "Completions for {:?} keep failing, pausing them for {:?} or until the file changes",
+            path, state.config.failure_cooldown
+        );
+    }
+}
+
+/// Counts a completion of `path` against its budget, false once it is used up
+fn spend_budget(state: &State, path: &Path) -> bool {
+    let mut budget = state.budget.lock().unwrap_or_else(|e| e.into_inner());
+    budget.spend(path, std::time::Instant::now())
+}
+
+/// Awaits a completion for at most `deadline`, `None` once it runs out.
+/// The completion is dropped on timeout, so nothing of it reaches the file.
+async fn within<T>(
+    deadline: Option<Duration>, path: &Path, completion: impl Future<Output = T>
+) -> Option<T> {
+    let Some(deadline) = deadline else {
+        return Some(completion.await);
+    };
+    match tokio::time::timeout(deadline, completion).await {
+        Ok(result) => Some(result),
+        Err(_) => {
+            warn!("Completion for {:?} timed out after {:?}", path, deadline);
+            None
+        }
+    }
+}
+
+/// Reads a newly created file into state ahead of its first modification
+/// and builds its context, so the first completion only has to diff against
+/// a warm baseline and reuses the context when nothing but a marker was added
+async fn prefetch_file(path: &Path, state: SharedState) -> Result<()> {
+    if !path.is_file() {
+        return Ok(());
+    }
 
+    let start_time = std::time::Instant::now();
+    let (content, _) = read(path).await?;
+
+    // A file created with a marker must still be completed on its first event
+    if content.contains(CURSOR_MARKER) {
+        return Ok(());
+    }
+
+    let coder = {
+        let state = state.read().await;
+        let file_state = state.file_state(content.clone());
+        state.files().entry(path.to_path_buf()).or_insert(file_state);
+        state.coder.clone()
+    };
+    warm_context(&coder, &content, path).await;
+
+    info!("Prefetched {:?} in {:?}", path, start_time.elapsed());
     Ok(())
 }
 
-async fn write(path: &PathBuf, content: &String) -> Result<()> {
-    tokio::fs::write(path, content).await?;
+/// Builds the context of `path` ahead of its next completion, see `Coder::warm`
+async fn warm_context(coder: &Coder, content: &str, path: &Path) {
+    match coder.warm(content, path).await {
+        Ok(took) => debug!("Built the context of {:?} ahead in {:?}", path, took),
+        Err(e) => warn!("Could not build the context of {:?} ahead: {}", path, e),
+    }
+}
+
+/// Reads a file without its byte order mark, telling whether it had one
+async fn read(path: &Path) -> Result<(String, bool)> {
+    Ok(strip_bom(tokio::fs::read_to_string(path).await?))
+}
+
+async fn write(path: &PathBuf, content: &String, bom: bool) -> Result<()> {
+    if bom {
+        tokio::fs::write(path, format!("{}{}", BOM, content)).await?;
+    } else {
+        tokio::fs::write(path, content).await?;
+    }
     Ok(())
 }
 
+/// Tells whether `event` leaves new content at `path`. Besides plain writes
+/// this covers editors that save atomically, writing a temp file and renaming
+/// it over the target, which notify reports differently per platform:
+/// - Linux (inotify): `Name(To)` for the target, or `Name(Both)` with
+///   `[from, to]` when both sides are watched
+/// - macOS (FSEvents): `Name(Any)` for both files, the target is the one left
+/// - Windows: `Name(From)` for the temp file, then `Name(To)` for the target
+/// - Some backends report the replaced target as created, so a `Create`
+///   for a file anycoder already tracks counts as a save too
+fn saves_content(event: &Event, path: &Path, tracked: bool) -> bool {
+    match event.kind {
+        EventKind::Modify(ModifyKind::Data(_)) => true,
+        EventKind::Modify(ModifyKind::Name(RenameMode::To)) => true,
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            event.paths.last().is_some_and(|to| to == path)
+        }
+        EventKind::Modify(ModifyKind::Name(RenameMode::Any)) => path.is_file(),
+        EventKind::Create(_) => tracked,
+        _ => false,
+    }
+}
+
 async fn process_path(
     path: PathBuf,
     event: notify::Event,
     shared_state: SharedState,
     in_flight: &mut HashMap<PathBuf, JoinHandle<()>>,
 ) {
-    match event.kind {
-        notify::EventKind::Create(_) => log_create_event(&path),
-        notify::EventKind::Remove(_) => log_remove_event(&path),
-        notify::EventKind::Modify(ModifyKind::Data(_)) => {
+    let tracked = matches!(event.kind, EventKind::Create(_))
+        && shared_state.read().await.files().contains_key(&path);
+
+    if saves_content(&event, &path, tracked) {
+        // A directory changing says nothing about which file was saved,
+        // the watch is recursive so the file's own event follows
+        if path.is_dir() {
+            debug!("watcher:directory {:?}", path);
+            return;
+        }
+
+        if let Some(handle) = in_flight.remove(&path) {
+            handle.abort();
+        }
+    
+        let state = shared_state.clone();
+        let path_clone = path.clone();
+        let (window, ticket) = {
+            let state = shared_state.read().await;
+            (state.config.coalesce_window, state.activity.queue(&path))
+        };
+    
+        let handle = tokio::spawn(async move {
+            // Another event for the path within the window aborts this one
+            // before anything is read, so duplicates collapse into one completion
+            tokio::time::sleep(window).await;
+            ticket.start();
+            let start_time = std::time::Instant::now();
             
-            if let Some(handle) = in_flight.remove(&path) {
-                handle.abort();
-            }
-        
-            let state = shared_state.clone();
-            let path_clone = path.clone();
-        
-            let handle = tokio::spawn(async move {
-                let start_time = std::time::Instant::now();
-                
-                let res = handle_modify_event(&path_clone, state).await;
-                if let Err(e) = res {
-                    error!("Error handling event for {:?}: {}", path_clone, e);
+            // A panicking completion must not take the watcher down, the
+            // panic hook has already logged it with the path
+            let handling = EVENT_PATH.scope(path_clone.clone(), handle_modify_event(&path_clone, state));
+            match AssertUnwindSafe(handling).catch_unwind().await {
+                Ok(Ok(())) | Err(_) => {}
+                Ok(Err(e)) => error!("Error handling event for {:?}: {}", path_clone, e),
+            }
+            let elapsed = start_time.elapsed();
+            info!("Done handling event for {:?} in {:?}", path_clone, elapsed);
+        });
+    
+        in_flight.insert(path, handle);
+        return;
+    }
+
+    match event.kind {
+        EventKind::Create(_) => {
+            log_create_event(&path);
+
+            if shared_state.read().await.config.prefetch {
+                let state = shared_state.clone();
+                tokio::spawn(async move {
+                    if let Err(e) = prefetch_file(&path, state).await {
+                        error!("Error prefetching {:?}: {}", path, e);
+                    }
+                });
+            }
+        }
+        EventKind::Remove(_) => {
+            log_remove_event(&path);
+            // Deleting and writing a file anew can both land before the event is handled
+            if !path.exists() {
+                if let Some(handle) = in_flight.remove(&path) {
+                    handle.abort();
                 }
-                let elapsed = start_time.elapsed();
-                info!("Done handling event for {:?} in {:?}", path_clone, elapsed);
-            });
-        
-            in_flight.insert(path, handle);
+                // Spawned, even a short wait for the lock would hold up the event loop
+                let state = shared_state.clone();
+                let forgotten = path.clone();
+                let handle = tokio::spawn(async move { state.read().await.forget(&forgotten) });
+                in_flight.insert(path, handle);
+            }
         }
         _ => { }
     }
 }
 
+/// Routes panics through the logger so they end up next to the event that
+/// caused them, naming its path when an event task panicked
+fn install_panic_hook() {
+    std::panic::set_hook(Box::new(|info| {
+        let location = info.location()
+            .map(|l| format!("{}:{}", l.file(), l.line()))
+            .unwrap_or_default();
+        let event = EVENT_PATH.try_with(|path| format!(" handling event for {:?}", path)).unwrap_or_default();
+        error!("panic{} at {}: {}", event, location, panic_message(info.payload()));
+    }));
+}
+
+/// Re-runs every completion of a transcript and reports those that no longer
+/// reproduce the recorded result
+async fn replay(path: &Path) -> Result<()> {
+    let entries = transcript::read_entries(path)?;
+
+    let mut failures = 0;
+    for (i, entry) in entries.iter().enumerate() {
+        match transcript::replay(entry).await {
+            Replayed::Match => info!("replay #{} {:?}: ok", i + 1, entry.path),
+            Replayed::Mismatch { expected, actual } => {
+                failures += 1;
+                error!("replay #{} {:?}: result differs", i + 1, entry.path);
+                for edit in compute_text_edits(&expected, &actual) {
+                    error!("{:?}", (edit.start, edit.end, edit.text));
+                }
+            }
+            Replayed::Failed(e) => {
+                failures += 1;
+                error!("replay #{} {:?}: {}", i + 1, entry.path, e);
+            }
+        }
+    }
+
+    info!("Replayed {} completions, {} mismatched", entries.len(), failures);
+    if failures > 0 {
+        anyhow::bail!("{} of {} completions did not reproduce", failures, entries.len());
+    }
+    Ok(())
+}
+
+/// Sets up the coder with everything the configuration asks for
+fn build_coder(config: &Config) -> Result<Coder> {
+    if let Some(proxy) = &config.proxy {
+        info!("Sending model requests through {}", proxy);
+    }
+    let mut builder = Coder::builder(model_client(config, &config.model)?).options(config.coder_options());
+    for model in &config.consensus_models {
+        builder = builder.voter(model_client(config, model)?);
+    }
+    if let Some(model) = config.summary_model.as_ref().filter(|_| config.summarize_over.is_some()) {
+        info!("Outlining files over {} bytes with {}", config.summarize_over.unwrap_or_default(), model);
+        builder = builder.summarizer(model_client(config, model)?);
+    }
+    if !config.consensus_models.is_empty() {
+        info!("Applying patches {} and {:?} agree on", config.model, config.consensus_models);
+    }
+    if let Some(path) = &config.transcript {
+        info!("Recording completions to {:?}", path);
+        builder = builder.transcript(Transcript::open(path)?);
+    } else if config.feedback {
+        warn!("ANYCODER_FEEDBACK without ANYCODER_TRANSCRIPT: feedback marks are stripped but not recorded");
+    }
+    if let Some(dir) = &config.docs_dir {
+        let docs = DocIndex::load(dir)?;
+        info!("Indexed {} docs snippets from {:?}", docs.len(), dir);
+        builder = builder.docs(docs);
+    }
+    if config.examples > 0 {
+        builder = builder.provider(Examples { count: config.examples });
+    }
+    if config.git_changes {
+        builder = builder.changes(GitCli);
+    }
+    if !config.redact.is_empty() {
+        info!("Redacting {} secret patterns from completion requests", config.redact.len());
+        builder = builder.redactor(Redactor::new(&config.redact)?);
+    }
+    if let Some(path) = &config.trace_files {
+        info!("Tracing completions of the files listed in {:?}, others log at info level", path);
+        builder = builder.trace_files(TraceFiles::new(path));
+    }
+    trace::focus(config.trace_files.is_some());
+    Ok(builder.build())
+}
+
+/// Loads the config and builds its coder. An invalid config is logged and
+/// `None` returned, so the running one is kept.
+fn load_config(load: impl FnOnce() -> Result<Config>) -> Option<(Coder, Config)> {
+    let reloaded = load().and_then(|config| Ok((build_coder(&config)?, config)));
+    reloaded.inspect_err(|e| error!("Keeping the previous config: {:#}", e)).ok()
+}
+
+/// Swaps in a reloaded coder and config, keeping file states
+async fn reconfigure(state: &SharedState, coder: Coder, config: Config) {
+    info!("Reloaded config, using {}", config.model);
+    // Running completions don't hold the read lock while the model answers
+    state.write().await.reconfigure(coder, config);
+    pin_files(state).await;
+}
+
+/// Pins the files the config lists, as the watcher names them
+async fn pin_files(state: &SharedState) {
+    let state = state.read().await;
+    for path in state.config.pinned_files.clone() {
+        // Events name files below the watched directory as `./path`
+        let path = match path.is_absolute() || path.starts_with(".") {
+            true => path,
+            false => Path::new(".").join(path),
+        };
+        if !path.is_file() {
+            warn!("Could not pin {:?}, it is not a file", path);
+            continue;
+        }
+        state.pin(&path);
+        info!("Pinned {:?}", path);
+    }
+}
+
+/// Makes sure the user agreed to send code to a provider off this machine,
+/// asking on the terminal when `may_ask`. The answer is kept in the state dir.
+async fn ensure_consent(config: &Config, may_ask: bool) -> Result<bool> {
+    let consent = Consent::in_state_dir();
+    if !config.sends_code_out() || consent.given() {
+        return Ok(true);
+    }
+    let agreed = match config.consent {
+        true => true,
+        false if may_ask && std::io::IsTerminal::is_terminal(&std::io::stdin()) => interactive::ask_consent(&config.base_url).await?,
+        false => false,
+    };
+    if agreed && let Err(e) = consent.record() {
+        warn!("Consent not remembered for later runs: {}", e);
+    }
+    Ok(agreed)
+}
+
+/// Tells whether requests to the configured provider may carry code
+fn consented(config: &Config) -> bool {
+    !config.sends_code_out() || config.consent || Consent::in_state_dir().given()
+}
+
+/// Client for `model` on the configured provider
+fn model_client(config: &Config, model: &str) -> Result<ConsentGate<SingleFlight<RateLimitGate<LlmClient>>>> {
+    // Rate limits are retried by the gate, so that one file's 429 holds back every file
+    let client = RateLimitGate::new(
+        llm_client(config, model)?, config.rate_limit_backoff, config.rate_limit_retries
+    );
+    Ok(ConsentGate::new(SingleFlight::new(client), consented(config)))
+}
+
+/// Bare client for `model` with the configured proxy and headers, leaving rate limits to the caller
+fn llm_client(config: &Config, model: &str) -> Result<LlmClient> {
+    let mut client = LlmClient::new(&config.api_key, &config.base_url, model)
+        .with_seed(config.seed)
+        .with_stop(config.stop.clone())
+        .with_max_continuations(config.max_continuations);
+    if let Some(proxy) = &config.proxy {
+        client = client.with_proxy(proxy).with_context(|| format!("Invalid ANYCODER_PROXY {:?}", proxy))?;
+    }
+    if !config.headers.is_empty() {
+        client = client.with_headers(config.headers.clone()).context("Invalid ANYCODER_HEADERS")?;
+    }
+    Ok(client.without_rate_limit_retries())
+}
+
+/// Looks every configured model up in the provider's model list, fetched
+/// once, warning about unlisted ones. A provider that is slow or has no list
+/// holds nothing up.
+async fn check_models(config: &Config) -> Result<()> {
+    let client = llm_client(config, &config.model)?;
+    let listed = match tokio::time::timeout(MODEL_CHECK_TIMEOUT, client.list_models()).await {
+        Ok(Ok(listed)) => listed,
+        Ok(Err(e)) => {
+            info!("Not checking the models, the provider doesn't list them: {}", e);
+            return Ok(());
+        }
+        Err(_) => {
+            info!("Not checking the models, the model list took over {:?}", MODEL_CHECK_TIMEOUT);
+            return Ok(());
+        }
+    };
+    let models = std::iter::once(&config.model)
+        .chain(&config.consensus_models)
+        .chain(config.summary_model.as_ref().filter(|_| config.summarize_over.is_some()));
+    for model in models {
+        llm_client(config, model)?.check_listed(&listed);
+    }
+    Ok(())
+}
+
+/// Completes the markers of every file under `dir` once, without watching
+async fn complete_all(dir: &Path, state: SharedState) -> Result<()> {
+    let mut files = Vec::new();
+    collect_files(dir, dir, &mut files)?;
+    files.sort();
+
+    let mut marked = Vec::new();
+    let mut markers = 0;
+    for path in files {
+        // Binary files hold no markers
+        let found = read(&path).await.map_or(0, |(content, _)| find_markers(&content, CURSOR_MARKER).len());
+        if found > 0 {
+            markers += found;
+            marked.push(path);
+        }
+    }
+    info!("Found {} {} in {} files under {:?}", markers, CURSOR_MARKER, marked.len(), dir);
+    {
+        let state = state.read().await;
+        marked.sort_by_key(|path| !state.is_pinned(path));
+    }
+
+    let (concurrency, interactive) = {
+        let state = state.read().await;
+        (state.config.concurrency, state.config.interactive)
+    };
+    // Prompts for several files at once would interleave on the terminal
+    let permits = if interactive { 1 } else { concurrency };
+    let semaphore = Arc::new(tokio::sync::Semaphore::new(permits));
+
+    let tickets: Vec<_> = {
+        let state = state.read().await;
+        marked.iter().map(|path| state.activity.queue(path)).collect()
+    };
+    // Slots are handed out in order, pinned files first
+    let mut tasks = Vec::new();
+    for (path, ticket) in marked.into_iter().zip(tickets) {
+        let permit = semaphore.clone().acquire_owned().await?;
+        let state = state.clone();
+        tasks.push(tokio::spawn(async move {
+            let _permit = permit;
+            ticket.start();
+            handle_modify_event(&path, state).await
+                .with_context(|| format!("{:?}", path))?;
+            anyhow::Ok(path)
+        }));
+    }
+
+    let (mut completed, mut failed) = (0, 0);
+    for task in tasks {
+        match task.await? {
+            Ok(path) => {
+                info!("complete-all: {:?} done", path);
+                completed += 1;
+            }
+            Err(e) => {
+                error!("complete-all: {}", e);
+                failed += 1;
+            }
+        }
+    }
+
+    info!("Completed {} files, {} failed", completed, failed);
+    if failed > 0 {
+        anyhow::bail!("{} of {} files could not be completed", failed, completed + failed);
+    }
+    Ok(())
+}
 
 #[tokio::main]
 async fn main() -> Result<()> {
+    // The env file comes first: dotenv never overrides a variable already set
+    let args = cli::parse_args(std::env::args().skip(1))?;
+    if let Some(env_file) = &args.env_file {
+        Config::load_env_file(env_file)?;
+    }
     dotenv().ok();
     init_logger();
+    install_panic_hook();
 
-    let config = Config::from_env()?;
-    let Config { api_key, base_url, model } = config;
-    
-    let client = LlmClient::new(&api_key, &base_url, &model);
-    let coder = Coder::new(client);
-    
-    let state = State::new(coder);
+    let command = args.command;
+    if let Command::Replay(path) = &command {
+        return replay(path).await;
+    }
+
+    let config = Config::load()?;
+
+    if config.privacy {
+        warn!("PRIVACY MODE: only the small context is sent, to {}", config.base_url);
+        warn!("PRIVACY MODE: logged file content is truncated");
+    }
+
+    // Serve reads requests from stdin, so it can't ask there
+    if !ensure_consent(&config, command != Command::Serve).await? {
+        warn!("No consent to send code to {}, completions are off", config.base_url);
+        warn!("Set ANYCODER_CONSENT=1 or answer the prompt at startup to turn them on");
+    }
+
+    let coder = build_coder(&config)?;
+    if config.check_models {
+        check_models(&config).await?;
+    }
+
+    if let Command::CompleteAll(dir) = command {
+        let state: SharedState = Arc::new(RwLock::new(State::new(coder, config)));
+        pin_files(&state).await;
+        return complete_all(&dir, state).await;
+    }
+
+    if command == Command::Serve {
+        info!("Serving completions over JSON-RPC on stdin");
+        let stdin = tokio::io::BufReader::new(tokio::io::stdin());
+        return Ok(anycoder::rpc::serve(&coder, stdin, tokio::io::stdout()).await?);
+    }
+
+    let mut storm_guard = StormGuard::new(
+        std::time::Instant::now(),
+        config.startup_cooldown,
+        config.bulk_window,
+        config.bulk_threshold,
+    );
+
+    let mut active_files = config.active_files.as_deref().map(ActiveFiles::new);
+
+    let state = State::new(coder, config);
     let shared_state: SharedState = Arc::new(RwLock::new(state));
+    pin_files(&shared_state).await;
 
     let (watch_tx, mut watch_rx) = mpsc::channel::<notify::Result<Event>>(32);
-    let mut watcher = recommended_watcher(move |res| {
-        let _ = watch_tx.blocking_send(res);
-    })?;
-
     let dir = Path::new(".");
-    watcher.watch(dir, RecursiveMode::Recursive)?;
+    let mut watcher = start_watcher(dir, watch_tx.clone())?;
 
     info!("Starting anycoder");
     info!("I'll help you to code.");
     info!("All you need is to write {} wherever you want", CURSOR_MARKER);
     info!("Watching files at {:?}", dir);
 
+    let mut watchdog = Watchdog::new(dir);
+    let mut probes = shared_state.read().await.config.watchdog_interval.map(|interval| {
+        let mut probes = tokio::time::interval_at(tokio::time::Instant::now() + interval, interval);
+        probes.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+        probes
+    });
+
+    let mut prunes = tokio::time::interval_at(tokio::time::Instant::now() + PRUNE_INTERVAL, PRUNE_INTERVAL);
+    let mut status_requests = StatusRequests::new()?;
+
     let mut in_flight: HashMap<PathBuf, JoinHandle<()>> = HashMap::new();
+    let mut shutdown = std::pin::pin!(tokio::signal::ctrl_c());
 
-    while let Some(res) = watch_rx.recv().await {
+    loop {
+        let res = tokio::select! {
+            res = watch_rx.recv() => match res {
+                Some(res) => res,
+                None => break,
+            },
+            // Leaves the loop so the watchdog cleans up its probe
+            _ = &mut shutdown => {
+                info!("Shutting down");
+                break;
+            }
+            _ = next_probe(&mut probes) => {
+                match watchdog.probe(std::time::Instant::now()) {
+                    Ok(Some(silent)) => {
+                        warn!("Watcher delivered no events for {:?}, restarting it", silent);
+                        match start_watcher(dir, watch_tx.clone()) {
+                            Ok(restarted) => {
+                                watcher = restarted;
+                                info!("Watcher restarted, watching files at {:?}", dir);
+                            }
+                            Err(e) => error!("Failed to restart watcher: {:#}", e),
+                        }
+                    }
+                    Ok(None) => {}
+                    Err(e) => error!("Failed to write watcher probe: {}", e),
+                }
+                continue;
+            }
+            _ = status_requests.recv() => {
+                let status = shared_state.read().await.activity.status(std::time::Instant::now());
+                info!("status {}", serde_json::to_string(&status)?);
+                continue;
+            }
+            _ = prunes.tick() => {
+                in_flight.retain(|_, handle| !handle.is_finished());
+                let state = shared_state.clone();
+                tokio::spawn(async move {
+                    let pruned = state.read().await.prune();
+                    if pruned > 0 {
+                        info!("Dropped the state of {} deleted files", pruned);
+                    }
+                });
+                continue;
+            }
+        };
         match res {
             Ok(event) => {
+                // Probes go around the storm guard, a suppressed probe would look like a dead watcher
+                if event.paths.iter().any(|path| Watchdog::is_probe(path)) {
+                    watchdog.answered();
+                    continue;
+                }
+
+                if !storm_guard.allow(std::time::Instant::now()) {
+                    debug!("watcher:suppressed {:?}", event.paths);
+                    continue;
+                }
+
+                if !event.kind.is_access() && event.paths.iter().any(|path| config::is_config_file(path)) {
+                    if let Some((coder, config)) = load_config(Config::load) {
+                        active_files = config.active_files.as_deref().map(ActiveFiles::new);
+                        // Spawned, the event loop must not wait for running completions
+                        let state = shared_state.clone();
+                        tokio::spawn(async move { reconfigure(&state, coder, config).await });
+                    }
+                    continue;
+                }
+
+                let mut seen = HashSet::new();
                 let filtered_paths: Vec<PathBuf> = event.paths.iter()
+                    .filter(|path| seen.insert(*path))
                     .filter(|path| !is_ignored_path(path))
+                    .filter(|path| active_files.as_mut().is_none_or(|a| a.is_active(path)))
                     .cloned().collect(); 
                 
                 for path in filtered_paths {
@@ -169,5 +1113,964 @@ async fn main() -> Result<()> {
         }
     }
 
+    drop(watcher);
     Ok(())
 }
+
+/// Watches `dir`, and the user config file if there is one, sending events to `tx`
+fn start_watcher(
+    dir: &Path, tx: mpsc::Sender<notify::Result<Event>>
+) -> Result<RecommendedWatcher> {
+    let mut watcher = recommended_watcher(move |res| {
+        let _ = tx.blocking_send(res);
+    })?;
+    watcher.watch(dir, RecursiveMode::Recursive)?;
+
+    // The project config is under `dir` already
+    if let Some(user_config) = config::user_config_file().filter(|path| path.is_file()) {
+        watcher.watch(&user_config, RecursiveMode::NonRecursive)?;
+    }
+    Ok(watcher)
+}
+
+/// SIGUSR1 signals asking for a dump of the queued and running completions
+#[cfg(unix)]
+struct StatusRequests(tokio::signal::unix::Signal);
+
+#[cfg(unix)]
+impl StatusRequests {
+    fn new() -> std::io::Result<Self> {
+        let signal = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::user_defined1())?;
+        Ok(Self(signal))
+    }
+
+    async fn recv(&mut self) {
+        if self.0.recv().await.is_none() {
+            std::future::pending().await
+        }
+    }
+}
+
+/// Status dumps need SIGUSR1, there is none to wait for elsewhere
+#[cfg(not(unix))]
+struct StatusRequests;
+
+#[cfg(not(unix))]
+impl StatusRequests {
+    fn new() -> std::io::Result<Self> {
+        Ok(Self)
+    }
+
+    async fn recv(&mut self) {
+        std::future::pending().await
+    }
+}
+
+/// Waits for the next watchdog probe, forever when probing is off
+async fn next_probe(probes: &mut Option<tokio::time::Interval>) {
+    match probes {
+        Some(probes) => {
+            probes.tick().await;
+        }
+        None => std::future::pending().await,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_server::MockServer;
+    use anycoder::activity::Phase;
+    use anycoder::llm::ChatModel;
+    use async_trait::async_trait;
+    use notify::event::{CreateKind, DataChange};
+    use serde_json::Value;
+
+    /// State completing with `coder`, configured by `extra` variables besides the API key
+    fn test_state(coder: Coder, extra: &[(&str, &str)]) -> SharedState {
+        let config = Config::from_lookup(|key| match key {
+            "OPENROUTER_API_KEY" => Some("sk".to_string()),
+            key => extra.iter().find(|(name, _)| *name == key).map(|(_, value)| value.to_string()),
+        }).expect("valid test config");
+        Arc::new(RwLock::new(State::new(coder, config)))
+    }
+
+    /// Fresh directory under the system temp dir, removed again on drop
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Result<Self> {
+            let dir = std::env::temp_dir().join(format!("anycoder-{}-{}", name, std::process::id()));
+            let _ = std::fs::remove_dir_all(&dir);
+            std::fs::create_dir_all(&dir)?;
+            Ok(Self(dir))
+        }
+    }
+
+    impl std::ops::Deref for TempDir {
+        type Target = Path;
+
+        fn deref(&self) -> &Path {
+            &self.0
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// Model slow enough for edits to land while it is still answering
+    struct SlowModel;
+
+    #[async_trait]
+    impl ChatModel for SlowModel {
+        async fn chat(&self, _messages: Vec<Value>) -> anycoder::error::Result<String> {
+            tokio::time::sleep(Duration::from_millis(20)).await;
+            Ok("<|SEARCH|>x = <|cursor|>;<|DIVIDE|>x = 1;<|REPLACE|>".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_bom_is_kept_and_ignored_for_offsets() -> Result<()> {
+        let dir = TempDir::new("bom")?;
+        let path = dir.join("main.rs");
+
+        let state = test_state(Coder::new(SlowModel), &[]);
+
+        tokio::fs::write(&path, "\u{feff}let y = 0;\nx = ??;\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+
+        let content = tokio::fs::read_to_string(&path).await?;
+
+        assert_eq!(content, "\u{feff}let y = 0;\nx = 1;\n");
+        assert!(state.read().await.files()[&path].matches("let y = 0;\nx = 1;\n"));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_empty_and_blank_files_skipped() -> Result<()> {
+        let dir = TempDir::new("blank")?;
+        let path = dir.join("main.rs");
+
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let coder = Coder::new(CountingModel(calls.clone()));
+        let state = test_state(coder, &[]);
+
+        for content in ["", "\n", " \t\r\n"] {
+            tokio::fs::write(&path, content).await?;
+            handle_modify_event(&path, state.clone()).await?;
+            assert!(state.read().await.files()[&path].matches(content));
+        }
+        let written = tokio::fs::read_to_string(&path).await?;
+
+        assert_eq!(written, " \t\r\n");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 0);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_remove_event_drops_state() -> Result<()> {
+        let dir = TempDir::new("remove")?;
+        let path = dir.join("main.rs");
+
+        let state = test_state(Coder::new(SlowModel), &[]);
+
+        tokio::fs::write(&path, "x = ??;\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+        assert!(state.read().await.files().contains_key(&path));
+
+        tokio::fs::remove_file(&path).await?;
+        let remove = Event::new(EventKind::Remove(notify::event::RemoveKind::File)).add_path(path.clone());
+        let mut in_flight = HashMap::new();
+        process_path(path.clone(), remove, state.clone(), &mut in_flight).await;
+        in_flight.remove(&path).unwrap().await?;
+
+        assert!(state.read().await.files().is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_sidecar_leaves_original_untouched() -> Result<()> {
+        let dir = TempDir::new("sidecar")?;
+        let path = dir.join("main.rs");
+        let sidecar = dir.join("main.rs.anycoder");
+
+        let state = test_state(Coder::new(SlowModel), &[("ANYCODER_SIDECAR", "1")]);
+
+        tokio::fs::write(&path, "x = ??;\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+        let original = tokio::fs::read_to_string(&path).await?;
+        let completed = tokio::fs::read_to_string(&sidecar).await?;
+        let tracked = state.read().await.files().contains_key(&path);
+
+        // Saving again without a marker leaves nothing to merge
+        tokio::fs::write(&path, "x = 0;\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+        let stale_removed = !sidecar.exists();
+
+        assert_eq!(original, "x = ??;\n");
+        assert_eq!(completed, "x = 1;\n");
+        assert!(!tracked);
+        assert!(stale_removed);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_review_marks_around_completion() -> Result<()> {
+        let dir = TempDir::new("review")?;
+        let path = dir.join("main.rs");
+
+        let state = test_state(Coder::new(SlowModel), &[("ANYCODER_REVIEW_MARKS", "1")]);
+
+        tokio::fs::write(&path, "fn f() {}\nx = ??;\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+        let marked = tokio::fs::read_to_string(&path).await?;
+
+        // Reviewed and saved with a change, the marks go
+        tokio::fs::write(&path, format!("{}y = 2;\n", marked)).await?;
+        handle_modify_event(&path, state.clone()).await?;
+        let reviewed = tokio::fs::read_to_string(&path).await?;
+
+        assert_eq!(marked, "fn f() {}\n// anycoder-start\nx = 1;\n// anycoder-end\n");
+        assert_eq!(reviewed, "fn f() {}\nx = 1;\ny = 2;\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_template_holes_filled() -> Result<()> {
+        /// Fills one of the two holes
+        struct HoleModel;
+
+        #[async_trait]
+        impl ChatModel for HoleModel {
+            async fn chat(&self, _messages: Vec<Value>) -> anycoder::error::Result<String> {
+                Ok(r#"{"body": "let a = w * h;", "return": ""}"#.to_string())
+            }
+        }
+
+        let dir = TempDir::new("holes")?;
+        let path = dir.join("area.rs");
+
+        let state = test_state(Coder::new(HoleModel), &[("ANYCODER_TEMPLATE_HOLES", "1")]);
+
+        tokio::fs::write(&path, "fn area(w: f64, h: f64) -> f64 {\n    ${body}\n    ${return}\n}\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+
+        let content = tokio::fs::read_to_string(&path).await?;
+
+        assert_eq!(content, "fn area(w: f64, h: f64) -> f64 {\n    let a = w * h;\n    ${return}\n}\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_template_holes_asked_once() -> Result<()> {
+        /// Fills `body` only and counts its calls
+        struct HoleModel(Arc<std::sync::atomic::AtomicUsize>);
+
+        #[async_trait]
+        impl ChatModel for HoleModel {
+            async fn chat(&self, _messages: Vec<Value>) -> anycoder::error::Result<String> {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                Ok(r#"{"body": "let a = w * h;"}"#.to_string())
+            }
+        }
+
+        let dir = TempDir::new("holes-once")?;
+        let path = dir.join("area.rs");
+        let script = dir.join("greet.ts");
+
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let coder = Coder::new(HoleModel(calls.clone()));
+        let state = test_state(coder, &[("ANYCODER_TEMPLATE_HOLES", "1")]);
+        let count = || calls.load(std::sync::atomic::Ordering::SeqCst);
+
+        tokio::fs::write(&path, "fn area(w: f64, h: f64) -> f64 {\n    ${body}\n    ${return}\n}\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+        assert_eq!(count(), 1);
+
+        // Only `return` is left, as the model left it
+        tokio::fs::write(&path, "// area\nfn area(w: f64, h: f64) -> f64 {\n    let a = w * h;\n    ${return}\n}\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+        assert_eq!(count(), 1);
+
+        tokio::fs::write(&path, "// area\nfn area(w: f64, h: f64) -> f64 {\n    ${body}\n    ${return}\n}\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+        assert_eq!(count(), 2);
+
+        // Interpolation in a template literal, not a hole
+        let greeting = "const greet = (name: string) => `Hello, ${name}`;\n";
+        tokio::fs::write(&script, greeting).await?;
+        handle_modify_event(&script, state.clone()).await?;
+        let after = tokio::fs::read_to_string(&script).await?;
+
+        assert_eq!(count(), 2);
+        assert_eq!(after, greeting);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_unchanged_completion_not_rewritten() -> Result<()> {
+        /// Fills no hole, leaving the file as it is on disk
+        struct NoFillModel;
+
+        #[async_trait]
+        impl ChatModel for NoFillModel {
+            async fn chat(&self, _messages: Vec<Value>) -> anycoder::error::Result<String> {
+                Ok("{}".to_string())
+            }
+        }
+
+        let dir = TempDir::new("unchanged")?;
+        let path = dir.join("area.rs");
+
+        let state = test_state(Coder::new(NoFillModel), &[("ANYCODER_TEMPLATE_HOLES", "1")]);
+
+        let content = "fn area() -> f64 {\n    ${body}\n}\n";
+        tokio::fs::write(&path, content).await?;
+        let long_ago = std::time::SystemTime::UNIX_EPOCH + Duration::from_secs(1_000_000);
+        std::fs::File::options().write(true).open(&path)?.set_modified(long_ago)?;
+        handle_modify_event(&path, state.clone()).await?;
+
+        let modified = std::fs::metadata(&path)?.modified()?;
+        let after = tokio::fs::read_to_string(&path).await?;
+        let tracked = state.read().await.files().get(&path).is_some_and(|fs| fs.matches(content));
+
+        assert_eq!(after, content);
+        assert_eq!(modified, long_ago);
+        assert!(tracked);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_notebook_cell_completed() -> Result<()> {
+        let dir = TempDir::new("notebook")?;
+        let path = dir.join("analysis.ipynb");
+
+        let state = test_state(Coder::new(SlowModel), &[]);
+
+        let notebook = concat!(
+            "{\n \"cells\": [\n  {\n   \"cell_type\": \"code\",\n   \"metadata\": {},\n",
+            "   \"outputs\": [{\"output_type\": \"stream\", \"text\": [\"??\\n\"]}],\n",
+            "   \"source\": [\n    \"y = 0\\n\",\n    \"x = ??;\"\n   ]\n  }\n ],\n",
+            " \"metadata\": {},\n \"nbformat\": 4,\n \"nbformat_minor\": 5\n}\n",
+        );
+        tokio::fs::write(&path, notebook).await?;
+        handle_modify_event(&path, state.clone()).await?;
+
+        let content = tokio::fs::read_to_string(&path).await?;
+
+        // Only the cell source changes, the output mentioning the marker stays
+        assert_eq!(content, notebook.replace("x = ??;", "x = 1;"));
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn test_failed_validation_keeps_file() -> Result<()> {
+        let dir = TempDir::new("validate-cmd")?;
+        let path = dir.join("main.rs");
+
+        let state_with = |cmd: &'static str| test_state(Coder::new(SlowModel), &[("ANYCODER_VALIDATE_CMD", cmd)]);
+
+        tokio::fs::write(&path, "x = ??;\n").await?;
+        handle_modify_event(&path, state_with("grep -q 'x = 2' {file}")).await?;
+        let rejected = tokio::fs::read_to_string(&path).await?;
+
+        handle_modify_event(&path, state_with("grep -q 'x = 1' {file}")).await?;
+        let accepted = tokio::fs::read_to_string(&path).await?;
+
+        assert_eq!(rejected, "x = ??;\n");
+        assert_eq!(accepted, "x = 1;\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_write_delay_yields_to_new_save() -> Result<()> {
+        let dir = TempDir::new("write-delay")?;
+        let path = dir.join("main.rs");
+
+        let state = test_state(Coder::new(SlowModel), &[("ANYCODER_WRITE_DELAY_MS", "300")]);
+
+        tokio::fs::write(&path, "x = ??;\n").await?;
+        let start = std::time::Instant::now();
+        handle_modify_event(&path, state.clone()).await?;
+        let delayed = start.elapsed();
+        let completed = tokio::fs::read_to_string(&path).await?;
+
+        // The user saves again while the completion waits to be written
+        tokio::fs::write(&path, "x = ??;\n").await?;
+        let pending = tokio::spawn({
+            let (path, state) = (path.clone(), state.clone());
+            async move { handle_modify_event(&path, state).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        tokio::fs::write(&path, "y = 2;\n").await?;
+        pending.await??;
+        let kept = tokio::fs::read_to_string(&path).await?;
+
+        assert!(delayed >= Duration::from_millis(300), "{:?}", delayed);
+        assert_eq!(completed, "x = 1;\n");
+        assert_eq!(kept, "y = 2;\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_autocommit_commits_completion() -> Result<()> {
+        /// Completes `x` and describes the change when asked for a commit message
+        struct CommitModel;
+
+        #[async_trait]
+        impl ChatModel for CommitModel {
+            async fn chat(&self, messages: Vec<Value>) -> anycoder::error::Result<String> {
+                Ok(match messages[0]["content"].as_str().unwrap_or_default().contains("commit messages") {
+                    true => "Set x to 1\n".to_string(),
+                    false => "<|SEARCH|>x = <|cursor|>;<|DIVIDE|>x = 1;<|REPLACE|>".to_string(),
+                })
+            }
+        }
+
+        let dir = TempDir::new("autocommit")?;
+        let git = |args: &[&str]| -> Result<String> {
+            let output = std::process::Command::new("git").current_dir(&*dir).args(args).output()?;
+            anyhow::ensure!(output.status.success(), "git {:?}: {}", args, String::from_utf8_lossy(&output.stderr));
+            Ok(String::from_utf8_lossy(&output.stdout).trim().to_string())
+        };
+        git(&["init", "-q"])?;
+        git(&["config", "user.name", "test"])?;
+        git(&["config", "user.email", "test@example.com"])?;
+        git(&["config", "commit.gpgsign", "false"])?;
+        let path = dir.join("main.rs");
+        tokio::fs::write(&path, "x = 0;\n").await?;
+        git(&["add", "."])?;
+        git(&["commit", "-q", "-m", "Initial commit"])?;
+
+        let state_with = |summary: &'static str| test_state(Coder::new(CommitModel), &[("ANYCODER_AUTOCOMMIT", "1"), ("ANYCODER_AUTOCOMMIT_SUMMARY", summary)]);
+
+        tokio::fs::write(&path, "x = ??;\n").await?;
+        handle_modify_event(&path, state_with("1")).await?;
+        let summarized = git(&["log", "-1", "--format=%s"])?;
+
+        tokio::fs::write(&path, "x = 0;\nx = ??;\n").await?;
+        handle_modify_event(&path, state_with("0")).await?;
+        let plain = git(&["log", "-1", "--format=%s"])?;
+        let clean = git(&["status", "--porcelain"])?;
+        let committed = git(&["show", "HEAD:main.rs"])?;
+
+        assert_eq!(summarized, "Set x to 1");
+        assert_eq!(plain, format!("Complete {}", path.display()));
+        assert_eq!(clean, "");
+        assert_eq!(committed, "x = 0;\nx = 1;");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_completion_deadline_removes_marker() -> Result<()> {
+        let dir = TempDir::new("deadline")?;
+        let path = dir.join("main.rs");
+
+        let state_with = |keep: &'static str| test_state(Coder::new(SlowModel), &[("ANYCODER_COMPLETION_DEADLINE", "1"), ("ANYCODER_KEEP_TIMED_OUT_MARKER", keep)]);
+
+        let state = state_with("0");
+        tokio::fs::write(&path, "x = ??;\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+        let removed = tokio::fs::read_to_string(&path).await?;
+        assert!(state.read().await.files()[&path].matches("x = ;\n"));
+
+        let state = state_with("1");
+        tokio::fs::write(&path, "x = ??;\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+        let kept = tokio::fs::read_to_string(&path).await?;
+        assert!(state.read().await.files()[&path].matches("x = ??;\n"));
+
+        // The late completion is never written
+        assert_eq!(removed, "x = ;\n");
+        assert_eq!(kept, "x = ??;\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_timed_out_notebook_marker_removed_from_cell() -> Result<()> {
+        let dir = TempDir::new("deadline-notebook")?;
+        let path = dir.join("analysis.ipynb");
+
+        let state = test_state(Coder::new(SlowModel), &[("ANYCODER_COMPLETION_DEADLINE", "1")]);
+
+        let notebook = concat!(
+            "{\n \"cells\": [\n  {\n   \"cell_type\": \"code\",\n   \"metadata\": {},\n",
+            "   \"outputs\": [{\"output_type\": \"stream\", \"text\": [\"??\\n\"]}],\n",
+            "   \"source\": [\n    \"x = ??{preset=\\\"line\\\"};\"\n   ]\n  }\n ],\n",
+            " \"metadata\": {},\n \"nbformat\": 4,\n \"nbformat_minor\": 5\n}\n",
+        );
+        tokio::fs::write(&path, notebook).await?;
+        handle_modify_event(&path, state.clone()).await?;
+
+        let content = tokio::fs::read_to_string(&path).await?;
+
+        // The cell's marker goes with its options, the output mentioning one stays
+        assert_eq!(content, notebook.replace("x = ??{preset=\\\"line\\\"};", "x = ;"));
+        serde_json::from_str::<Value>(&content)?;
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_complete_all() -> Result<()> {
+        let dir = TempDir::new("complete-all")?;
+        tokio::fs::create_dir_all(dir.join("src")).await?;
+        tokio::fs::create_dir_all(dir.join("node_modules")).await?;
+
+        tokio::fs::write(dir.join("a.rs"), "x = ??;\n").await?;
+        tokio::fs::write(dir.join("src/b.rs"), "let y = 2;\nx = ??;\n").await?;
+        tokio::fs::write(dir.join("src/plain.rs"), "x = 0;\n").await?;
+        tokio::fs::write(dir.join("node_modules/dep.js"), "x = ??;\n").await?;
+
+        let state = test_state(Coder::new(SlowModel), &[]);
+        complete_all(&dir, state).await?;
+
+        let read = |name: &str| std::fs::read_to_string(dir.join(name));
+        let (a, b) = (read("a.rs")?, read("src/b.rs")?);
+        let (plain, dep) = (read("src/plain.rs")?, read("node_modules/dep.js")?);
+
+        assert_eq!(a, "x = 1;\n");
+        assert_eq!(b, "let y = 2;\nx = 1;\n");
+        assert_eq!(plain, "x = 0;\n");
+        assert_eq!(dep, "x = ??;\n");
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_complete_all_completes_pinned_marked_file() -> Result<()> {
+        let dir = TempDir::new("complete-all-pinned")?;
+        let (hot, cold) = (dir.join("hot.rs"), dir.join("cold.rs"));
+        tokio::fs::write(&hot, "let y = 2;\nx = ??;\n").await?;
+        tokio::fs::write(&cold, "x = ??;\n").await?;
+
+        let pinned = hot.display().to_string();
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let coder = Coder::new(CountingModel(calls.clone()));
+        let state = test_state(coder, &[("ANYCODER_PINNED_FILES", &pinned)]);
+        pin_files(&state).await;
+        complete_all(&dir, state.clone()).await?;
+
+        let (hot_content, cold_content) = (std::fs::read_to_string(&hot)?, std::fs::read_to_string(&cold)?);
+
+        assert!(state.read().await.is_pinned(&hot));
+        assert_eq!(hot_content, "let y = 2;\nx = 1;\n");
+        assert_eq!(cold_content, "x = 1;\n");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_saves_content() {
+        let event = |kind: EventKind, paths: &[&str]| {
+            paths.iter().fold(Event::new(kind), |event, p| event.add_path(PathBuf::from(p)))
+        };
+        let target = Path::new("src/main.rs");
+
+        let write = event(EventKind::Modify(ModifyKind::Data(DataChange::Content)), &["src/main.rs"]);
+        assert!(saves_content(&write, target, false));
+
+        let rename_to = event(EventKind::Modify(ModifyKind::Name(RenameMode::To)), &["src/main.rs"]);
+        assert!(saves_content(&rename_to, target, false));
+
+        // Only the destination of a paired rename holds the saved content
+        let both = EventKind::Modify(ModifyKind::Name(RenameMode::Both));
+        let rename = event(both, &["src/main.rs~", "src/main.rs"]);
+        assert!(saves_content(&rename, target, false));
+        assert!(!saves_content(&rename, Path::new("src/main.rs~"), false));
+
+        let rename_from = event(EventKind::Modify(ModifyKind::Name(RenameMode::From)), &["src/main.rs"]);
+        assert!(!saves_content(&rename_from, target, false));
+
+        let create = event(EventKind::Create(CreateKind::File), &["src/main.rs"]);
+        assert!(saves_content(&create, target, true));
+        assert!(!saves_content(&create, target, false));
+    }
+
+    /// Completes like `SlowModel` and counts its calls
+    struct CountingModel(Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait]
+    impl ChatModel for CountingModel {
+        async fn chat(&self, messages: Vec<Value>) -> anycoder::error::Result<String> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            SlowModel.chat(messages).await
+        }
+    }
+
+    /// Reports the same changes for every file, counting how often it is asked
+    struct CountingChanges(Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait]
+    impl git::ChangesProvider for CountingChanges {
+        async fn changes(&self, _path: &Path) -> Option<String> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Some("@@ -1 +1 @@".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_prefetched_context_reused() -> Result<()> {
+        let dir = TempDir::new("prefetch")?;
+        let path = dir.join("main.rs");
+
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let coder = Coder::builder(SlowModel).changes(CountingChanges(calls.clone())).build();
+        let state = test_state(coder, &[("ANYCODER_PREFETCH", "1")]);
+
+        tokio::fs::write(&path, "let y = 0;\nx = ;\n").await?;
+        prefetch_file(&path, state.clone()).await?;
+        tokio::fs::write(&path, "let y = 0;\nx = ??;\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+
+        let content = tokio::fs::read_to_string(&path).await?;
+
+        assert_eq!(content, "let y = 0;\nx = 1;\n");
+        // Built on creation, not again for the completion
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_duplicate_events_complete_once() -> Result<()> {
+        let dir = TempDir::new("coalesce")?;
+        let path = dir.join("main.rs");
+
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let coder = Coder::new(CountingModel(calls.clone()));
+        let state = test_state(coder, &[]);
+
+        tokio::fs::write(&path, "x = ??;\n").await?;
+        let save = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content)))
+            .add_path(path.clone());
+        let mut in_flight = HashMap::new();
+        for _ in 0..3 {
+            process_path(path.clone(), save.clone(), state.clone(), &mut in_flight).await;
+        }
+        // Waiting out the coalesce window counts as queued
+        let status = state.read().await.activity.status(std::time::Instant::now());
+        assert_eq!(status.running, 0);
+        assert!(status.tasks.iter().any(|task| task.path == path && task.phase == Phase::Queued));
+        // The directory's own modify event is not a save
+        let dir_event = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Any)))
+            .add_path(dir.to_path_buf());
+        process_path(dir.to_path_buf(), dir_event, state.clone(), &mut in_flight).await;
+        assert!(!in_flight.contains_key(&*dir));
+
+        in_flight.remove(&path).unwrap().await?;
+        let content = tokio::fs::read_to_string(&path).await?;
+
+        assert_eq!(content, "x = 1;\n");
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 1);
+        assert!(state.read().await.activity.status(std::time::Instant::now()).tasks.is_empty());
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rename_over_target_completes() -> Result<()> {
+        let dir = TempDir::new("rename")?;
+        let path = dir.join("main.rs");
+        let temp = dir.join("main.rs___jb_tmp___");
+
+        let state = test_state(Coder::new(SlowModel), &[]);
+
+        tokio::fs::write(&path, "x = 0;\n").await?;
+        tokio::fs::write(&temp, "x = ??;\n").await?;
+        tokio::fs::rename(&temp, &path).await?;
+
+        let event = Event::new(EventKind::Modify(ModifyKind::Name(RenameMode::Both)))
+            .add_path(temp)
+            .add_path(path.clone());
+        let mut in_flight = HashMap::new();
+        process_path(path.clone(), event, state.clone(), &mut in_flight).await;
+        in_flight.remove(&path).unwrap().await?;
+
+        let content = tokio::fs::read_to_string(&path).await?;
+
+        assert_eq!(content, "x = 1;\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_completion_budget_skips_eleventh() -> Result<()> {
+        let dir = TempDir::new("budget")?;
+        let path = dir.join("main.rs");
+
+        let state = test_state(Coder::new(SlowModel), &[("ANYCODER_COMPLETION_LIMIT", "10")]);
+
+        let mut contents = Vec::new();
+        for i in 0..11 {
+            tokio::fs::write(&path, format!("let y = {};\nx = ??;\n", i)).await?;
+            handle_modify_event(&path, state.clone()).await?;
+            contents.push(tokio::fs::read_to_string(&path).await?);
+        }
+
+        assert_eq!(contents[9], "let y = 9;\nx = 1;\n");
+        assert_eq!(contents[10], "let y = 10;\nx = ??;\n");
+        assert!(state.read().await.files()[&path].matches(&contents[9]));
+
+        Ok(())
+    }
+
+    /// Model whose replies never parse
+    struct BrokenModel(Arc<std::sync::atomic::AtomicUsize>);
+
+    #[async_trait]
+    impl ChatModel for BrokenModel {
+        async fn chat(&self, _messages: Vec<Value>) -> anycoder::error::Result<String> {
+            self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+            Ok("no patch here".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_marker_once_ignores_autosaves() -> Result<()> {
+        let dir = TempDir::new("marker-once")?;
+        let path = dir.join("main.rs");
+
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let coder = Coder::new(BrokenModel(calls.clone()));
+        let state = test_state(coder, &[("ANYCODER_MARKER_ONCE", "1"), ("ANYCODER_DIAGNOSTICS", "1")]);
+        let calls_after = |content: String| {
+            let (path, state, calls) = (path.clone(), state.clone(), calls.clone());
+            async move {
+                tokio::fs::write(&path, content).await?;
+                let _ = handle_modify_event(&path, state).await;
+                anyhow::Ok(calls.load(std::sync::atomic::Ordering::SeqCst))
+            }
+        };
+
+        // The marker gets a diagnostic instead of a completion and stays
+        // while the file keeps being autosaved
+        let fired = calls_after("x = ??;\n".to_string()).await?;
+        let annotated = tokio::fs::read_to_string(&path).await?;
+        let mut autosaves = Vec::new();
+        for i in 0..3 {
+            autosaves.push(calls_after(format!("let y = {};\n{}", i, annotated)).await?);
+        }
+
+        // Removed, then added again
+        calls_after("x = 0;\n".to_string()).await?;
+        let refired = calls_after("x = ??;\n".to_string()).await?;
+
+        assert!(annotated.contains("anycoder: no completion"), "{}", annotated);
+        assert_eq!(fired, 1);
+        assert_eq!(autosaves, [1, 1, 1]);
+        assert_eq!(refired, 2);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_marker_once_survives_aborted_completion() -> Result<()> {
+        /// Answers late enough for an autosave to land while it is asked
+        struct LateModel(Arc<std::sync::atomic::AtomicUsize>);
+
+        #[async_trait]
+        impl ChatModel for LateModel {
+            async fn chat(&self, messages: Vec<Value>) -> anycoder::error::Result<String> {
+                self.0.fetch_add(1, std::sync::atomic::Ordering::SeqCst);
+                tokio::time::sleep(Duration::from_millis(200)).await;
+                SlowModel.chat(messages).await
+            }
+        }
+
+        let dir = TempDir::new("marker-once-abort")?;
+        let path = dir.join("main.rs");
+
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let coder = Coder::new(LateModel(calls.clone()));
+        let state = test_state(coder, &[("ANYCODER_MARKER_ONCE", "1"), ("ANYCODER_COALESCE_MS", "0")]);
+
+        let save = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content)))
+            .add_path(path.clone());
+        let mut in_flight = HashMap::new();
+        tokio::fs::write(&path, "x = ??;\n").await?;
+        process_path(path.clone(), save.clone(), state.clone(), &mut in_flight).await;
+        tokio::time::sleep(Duration::from_millis(100)).await;
+
+        // The autosave aborts the first completion while the model answers
+        tokio::fs::write(&path, "x = ??;\n").await?;
+        process_path(path.clone(), save, state.clone(), &mut in_flight).await;
+        in_flight.remove(&path).unwrap().await?;
+
+        let content = tokio::fs::read_to_string(&path).await?;
+
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 2);
+        assert_eq!(content, "x = 1;\n");
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_repeated_failures_pause_file() -> Result<()> {
+        let dir = TempDir::new("breaker")?;
+        let path = dir.join("main.rs");
+
+        let calls = Arc::new(std::sync::atomic::AtomicUsize::new(0));
+        let coder = Coder::new(BrokenModel(calls.clone()));
+        let state = test_state(coder, &[("ANYCODER_FAILURE_LIMIT", "3")]);
+
+        // Small edits between attempts, as when retrying a stubborn spot
+        let body = (0..10).map(|i| format!("let v{} = {};\n", i, i)).collect::<String>();
+        let mut results = Vec::new();
+        for i in 0..5 {
+            tokio::fs::write(&path, format!("{}let y = {};\nx = ??;\n", body, i)).await?;
+            results.push(handle_modify_event(&path, state.clone()).await.is_ok());
+        }
+
+        // A rewrite of the file lets completions through again
+        tokio::fs::write(&path, "fn main() {\n    ??\n}\n").await?;
+        let after_rewrite = handle_modify_event(&path, state.clone()).await;
+
+        // Three failures reach the model, then the file is skipped
+        assert_eq!(results, [false, false, false, true, true]);
+        assert!(after_rewrite.is_err());
+        assert_eq!(calls.load(std::sync::atomic::Ordering::SeqCst), 4);
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rapid_edits_keep_state_consistent() -> Result<()> {
+        let dir = TempDir::new("stress")?;
+        let path = dir.join("main.rs");
+
+        let state = test_state(Coder::new(SlowModel), &[]);
+
+        let event = Event::new(EventKind::Modify(ModifyKind::Data(DataChange::Content)));
+        let mut in_flight = HashMap::new();
+
+        for i in 0..50u64 {
+            tokio::fs::write(&path, format!("let y = {};\nx = ??;\n", i)).await?;
+            process_path(path.clone(), event.clone(), state.clone(), &mut in_flight).await;
+            tokio::time::sleep(Duration::from_millis(i % 5)).await;
+        }
+        in_flight.remove(&path).unwrap().await?;
+
+        let content = tokio::fs::read_to_string(&path).await?;
+
+        assert_eq!(content, "let y = 49;\nx = 1;\n");
+
+        let state = state.try_write().expect("aborted events must not keep the lock");
+        assert!(state.files()[&path].matches(&content));
+
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_models_listed_once() -> Result<()> {
+        let models = serde_json::json!({ "data": [{ "id": "vendor/main" }, { "id": "vendor/voter" }] });
+        let mut server = MockServer::replying(vec![(200, models.to_string())]).await?;
+        let base_url = server.base_url();
+        let config = Config::from_lookup(|key| match key {
+            "OPENROUTER_API_KEY" => Some("sk".to_string()),
+            "OPENROUTER_BASE_URL" => Some(base_url.clone()),
+            "OPENROUTER_MODEL" => Some("vendor/main".to_string()),
+            "ANYCODER_CONSENSUS_MODELS" => Some("vendor/voter,vendor/missing".to_string()),
+            _ => None,
+        })?;
+
+        check_models(&config).await?;
+        assert_eq!(server.received().len(), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_config_reload_switches_model() -> Result<()> {
+        let dir = TempDir::new("reload")?;
+        let path = dir.join("main.rs");
+        let config_path = dir.join(".anycoder.toml");
+
+        let mut server = MockServer::chat("<|SEARCH|>x = <|cursor|>;<|DIVIDE|>x = 1;<|REPLACE|>").await?;
+        let base_url = server.base_url();
+        let write_config = |model: &str| std::fs::write(&config_path, format!(
+            "api_key = \"sk\"\nbase_url = \"{}\"\nmodel = \"{}\"\n", base_url, model
+        ));
+
+        write_config("first")?;
+        let config = Config::from_file(&config_path)?;
+        let state: SharedState = Arc::new(RwLock::new(State::new(build_coder(&config)?, config)));
+
+        tokio::fs::write(&path, "x = ??;\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+        assert_eq!(server.next_request().await.map(|request| request.body["model"].clone()), Some("first".into()));
+        let tracked = state.read().await.files().contains_key(&path);
+
+        write_config("second")?;
+        let (coder, config) = load_config(|| Config::from_file(&config_path)).expect("valid config");
+        reconfigure(&state, coder, config).await;
+        tokio::fs::write(&path, "y = 0;\nx = ??;\n").await?;
+        handle_modify_event(&path, state.clone()).await?;
+        assert_eq!(server.next_request().await.map(|request| request.body["model"].clone()), Some("second".into()));
+
+        // A broken config keeps the model it replaced
+        std::fs::write(&config_path, "model = [\n")?;
+        assert!(load_config(|| Config::from_file(&config_path)).is_none());
+
+        drop(server);
+        let content = tokio::fs::read_to_string(&path).await?;
+
+        assert_eq!(content, "y = 0;\nx = 1;\n");
+        let state = state.read().await;
+        assert_eq!(state.config.model, "second");
+        assert!(tracked && state.files().contains_key(&path));
+        Ok(())
+    }
+
+    /// Model answering only once it is released
+    struct GatedModel {
+        entered: Arc<tokio::sync::Notify>,
+        release: Arc<tokio::sync::Notify>,
+    }
+
+    #[async_trait]
+    impl ChatModel for GatedModel {
+        async fn chat(&self, _messages: Vec<Value>) -> anycoder::error::Result<String> {
+            self.entered.notify_one();
+            self.release.notified().await;
+            Ok("<|SEARCH|>x = <|cursor|>;<|DIVIDE|>x = 2;<|REPLACE|>".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_reload_during_slow_completion() -> Result<()> {
+        let dir = TempDir::new("slow-reload")?;
+        let (slow, other) = (dir.join("slow.rs"), dir.join("other.rs"));
+
+        let (entered, release) = (Arc::new(tokio::sync::Notify::new()), Arc::new(tokio::sync::Notify::new()));
+        let gated = GatedModel { entered: entered.clone(), release: release.clone() };
+        let state = test_state(Coder::new(gated), &[]);
+
+        tokio::fs::write(&slow, "x = ??;\n").await?;
+        let running = tokio::spawn({
+            let (slow, state) = (slow.clone(), state.clone());
+            async move { handle_modify_event(&slow, state).await }
+        });
+        entered.notified().await;
+
+        // Neither the reload nor the next file's event waits for the model
+        let config = Config::from_lookup(|key| (key == "OPENROUTER_API_KEY").then(|| "sk".to_string()))?;
+        let timeout = Duration::from_secs(5);
+        let reloaded = tokio::time::timeout(timeout, reconfigure(&state, Coder::new(SlowModel), config)).await;
+        tokio::fs::write(&other, "x = ??;\n").await?;
+        let handled = tokio::time::timeout(timeout, handle_modify_event(&other, state.clone())).await;
+
+        release.notify_one();
+        running.await??;
+        let contents = (tokio::fs::read_to_string(&slow).await?, tokio::fs::read_to_string(&other).await?);
+
+        assert!(reloaded.is_ok());
+        handled.expect("other file handled during the slow completion")?;
+        // The running completion finishes with the coder it started with
+        assert_eq!(contents, ("x = 2;\n".to_string(), "x = 1;\n".to_string()));
+        Ok(())
+    }
+}
@@ -0,0 +1,189 @@
+use log::warn;
+use crate::coder::CURSOR_MARKER;
+
+/// Options written right after the marker, e.g. `??{preset=block}`
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct MarkerOptions {
+    pub preset: Option<String>,
+}
+
+/// Parses a relative line offset like `+2` or `-1` starting at `from`.
+/// Returns the offset and its byte length (0 when absent).
+pub fn parse_line_offset(content: &str, from: usize) -> (isize, usize) {
+    let rest = &content[from..];
+    let sign = match rest.chars().next() {
+        Some('+') => 1,
+        Some('-') => -1,
+        _ => return (0, 0),
+    };
+
+    let digits = rest[1..].chars().take_while(|c| c.is_ascii_digit()).count();
+    match rest[1..1 + digits].parse::<isize>() {
+        Ok(n) => (sign * n, 1 + digits),
+        Err(_) => (0, 0),
+    }
+}
+
+/// Parses the `{key=value,...}` block starting at `from`.
+/// Returns the options and the byte length of the block (0 when absent).
+pub fn parse_marker_options(content: &str, from: usize) -> (MarkerOptions, usize) {
+    let mut options = MarkerOptions::default();
+
+    let rest = &content[from..];
+    let Some(body) = rest.strip_prefix('{') else {
+        return (options, 0);
+    };
+    let Some(close) = body.find('}') else {
+        return (options, 0);
+    };
+    let body = &body[..close];
+    if body.contains('\n') {
+        return (options, 0);
+    }
+
+    for pair in body.split(',').map(str::trim).filter(|p| !p.is_empty()) {
+        match pair.split_once('=').map(|(k, v)| (k.trim(), v.trim())) {
+            Some(("preset", value)) => options.preset = Some(value.to_string()),
+            _ => warn!("Unknown marker option {:?}", pair),
+        }
+    }
+
+    (options, close + 2)
+}
+
+/// Resolves the marker token at `cursor` (e.g. `??+2{preset=block}`) into
+/// its options and a plain marker placed where the completion should happen.
+/// Returns the options, the rewritten content and the new cursor.
+pub fn resolve_marker(content: &str, cursor: usize) -> (MarkerOptions, String, usize) {
+    let suffix_start = cursor + CURSOR_MARKER.len();
+    let (offset, offset_len) = parse_line_offset(content, suffix_start);
+    let (options, options_len) = parse_marker_options(content, suffix_start + offset_len);
+
+    let mut resolved = content.to_string();
+    resolved.replace_range(suffix_start..suffix_start + offset_len + options_len, "");
+
+    if offset == 0 {
+        return (options, resolved, cursor);
+    }
+
+    let line_starts: Vec<usize> = std::iter::once(0)
+        .chain(resolved.match_indices('\n').map(|(i, _)| i + 1))
+        .collect();
+    let line = line_starts.partition_point(|&s| s <= cursor) - 1;
+    let last_line = line_starts.len() - 1;
+    let target = line.saturating_add_signed(offset).min(last_line);
+
+    if target == line {
+        return (options, resolved, cursor);
+    }
+
+    resolved.replace_range(cursor..suffix_start, "");
+
+    // Insert the marker at the end of the target line, before any line terminator
+    let line_start = line_starts[target];
+    let line_start = if target > line { line_start - CURSOR_MARKER.len() } else { line_start };
+    let line_end = resolved[line_start..].find('\n')
+        .map(|i| line_start + i)
+        .unwrap_or(resolved.len());
+    let line_end = if resolved[..line_end].ends_with('\r') { line_end - 1 } else { line_end };
+
+    resolved.insert_str(line_end, CURSOR_MARKER);
+    (options, resolved, line_end)
+}
+
+/// Removes the marker token at `cursor` along with its offset and options
+pub fn remove_marker(content: &str, cursor: usize) -> String {
+    let suffix_start = cursor + CURSOR_MARKER.len();
+    let (_, offset_len) = parse_line_offset(content, suffix_start);
+    let (_, options_len) = parse_marker_options(content, suffix_start + offset_len);
+
+    let mut removed = content.to_string();
+    removed.replace_range(cursor..suffix_start + offset_len + options_len, "");
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_remove_marker() {
+        assert_eq!(remove_marker("let x = ??;", 8), "let x = ;");
+        assert_eq!(remove_marker("let x = ??+1{preset=line};", 8), "let x = ;");
+    }
+
+    #[test]
+    fn test_parse_marker_options() {
+        let content = "let x = ??{preset=block};";
+        let cursor = content.find(CURSOR_MARKER).unwrap();
+
+        let (options, len) = parse_marker_options(content, cursor + CURSOR_MARKER.len());
+        assert_eq!(options.preset.as_deref(), Some("block"));
+        assert_eq!(len, "{preset=block}".len());
+    }
+
+    #[test]
+    fn test_parse_marker_without_options() {
+        let content = "let x = ??;\nlet y = {preset=block};";
+        let cursor = content.find(CURSOR_MARKER).unwrap();
+
+        let (options, len) = parse_marker_options(content, cursor + CURSOR_MARKER.len());
+        assert_eq!(options, MarkerOptions::default());
+        assert_eq!(len, 0);
+    }
+
+    #[test]
+    fn test_resolve_marker_options() {
+        let content = "let x = ??{ preset = line };";
+        let cursor = content.find(CURSOR_MARKER).unwrap();
+
+        let (options, resolved, new_cursor) = resolve_marker(content, cursor);
+        assert_eq!(options.preset.as_deref(), Some("line"));
+        assert_eq!(resolved, "let x = ??;");
+        assert_eq!(new_cursor, cursor);
+    }
+
+    #[test]
+    fn test_resolve_marker_lines_down() {
+        let content = "fn add(a: i32, b: i32) -> i32 { ??+2\n    // body\n    \n}\n";
+        let cursor = content.find(CURSOR_MARKER).unwrap();
+
+        let (_, resolved, new_cursor) = resolve_marker(content, cursor);
+        assert_eq!(resolved, "fn add(a: i32, b: i32) -> i32 { \n    // body\n    ??\n}\n");
+        assert_eq!(resolved.find(CURSOR_MARKER), Some(new_cursor));
+    }
+
+    #[test]
+    fn test_resolve_marker_lines_up() {
+        let content = "first\r\nsecond\r\nthird ??-2{preset=block}\r\n";
+        let cursor = content.find(CURSOR_MARKER).unwrap();
+
+        let (options, resolved, new_cursor) = resolve_marker(content, cursor);
+        assert_eq!(options.preset.as_deref(), Some("block"));
+        assert_eq!(resolved, "first??\r\nsecond\r\nthird \r\n");
+        assert_eq!(new_cursor, 5);
+    }
+
+    #[test]
+    fn test_resolve_marker_clamps_at_edges() {
+        let content = "a\nb ??+10\nc";
+        let cursor = content.find(CURSOR_MARKER).unwrap();
+        let (_, resolved, new_cursor) = resolve_marker(content, cursor);
+        assert_eq!(resolved, "a\nb \nc??");
+        assert_eq!(new_cursor, resolved.len() - CURSOR_MARKER.len());
+
+        let content = "a ??-3\nb";
+        let cursor = content.find(CURSOR_MARKER).unwrap();
+        let (_, resolved, new_cursor) = resolve_marker(content, cursor);
+        assert_eq!(resolved, "a ??\nb");
+        assert_eq!(new_cursor, cursor);
+    }
+
+    #[test]
+    fn test_parse_line_offset() {
+        assert_eq!(parse_line_offset("+12;", 0), (12, 3));
+        assert_eq!(parse_line_offset("-1", 0), (-1, 2));
+        assert_eq!(parse_line_offset("+x", 0), (0, 0));
+        assert_eq!(parse_line_offset(";", 0), (0, 0));
+    }
+}
@@ -0,0 +1,131 @@
+//! HTTP server standing in for a model provider in tests. Shared by the
+//! library's and the binary's tests, which each use part of it.
+#![allow(dead_code)]
+
+use serde_json::Value;
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc;
+
+/// A request the server received
+#[derive(Debug, Clone)]
+pub struct Request {
+    /// Request line as sent, such as `POST /v1/chat/completions HTTP/1.1`
+    pub line: String,
+    /// Header lines, lowercased, each starting with `\r\n`
+    pub headers: String,
+    /// JSON body, null for requests without one
+    pub body: Value,
+}
+
+/// Answers one request per connection on a local port until dropped
+pub struct MockServer {
+    /// `http://` and the address the server listens on
+    pub url: String,
+    requests: mpsc::UnboundedReceiver<Request>,
+    task: tokio::task::JoinHandle<()>,
+}
+
+impl MockServer {
+    /// Answers every request with the status and body `respond` gives for it
+    pub async fn start(respond: impl Fn(&Request) -> (u16, String) + Send + 'static) -> std::io::Result<Self> {
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await?;
+        let url = format!("http://{}", listener.local_addr()?);
+        let (tx, requests) = mpsc::unbounded_channel();
+        let task = tokio::spawn(async move {
+            while let Ok((mut socket, _)) = listener.accept().await {
+                let Ok(request) = read_request(&mut socket).await else { continue };
+                let (status, body) = respond(&request);
+                // Recorded before answering, so a client done waiting finds it
+                let _ = tx.send(request);
+                let response = format!(
+                    "HTTP/1.1 {} OK\r\ncontent-type: application/json\r\ncontent-length: {}\r\nconnection: close\r\n\r\n{}",
+                    status, body.len(), body
+                );
+                let _ = socket.write_all(response.as_bytes()).await;
+                let _ = socket.shutdown().await;
+            }
+        });
+        Ok(Self { url, requests, task })
+    }
+
+    /// Answers with each status and body in turn, and with a 400 once they run out
+    pub async fn replying(responses: Vec<(u16, String)>) -> std::io::Result<Self> {
+        let responses = Arc::new(Mutex::new(VecDeque::from(responses)));
+        Self::start(move |_| {
+            responses.lock().unwrap().pop_front()
+                .unwrap_or_else(|| (400, "no reply left".to_string()))
+        }).await
+    }
+
+    /// Answers every request with a chat completion of `content` by the model asked for
+    pub async fn chat(content: &str) -> std::io::Result<Self> {
+        let content = content.to_string();
+        Self::start(move |request| {
+            let model = request.body["model"].as_str().unwrap_or_default();
+            (200, chat_reply(model, &content, "stop").to_string())
+        }).await
+    }
+
+    /// Base url of an OpenAI-style API served here
+    pub fn base_url(&self) -> String {
+        format!("{}/v1", self.url)
+    }
+
+    /// Waits for the next request
+    pub async fn next_request(&mut self) -> Option<Request> {
+        self.requests.recv().await
+    }
+
+    /// Requests received so far and not yet taken
+    pub fn received(&mut self) -> Vec<Request> {
+        std::iter::from_fn(|| self.requests.try_recv().ok()).collect()
+    }
+}
+
+impl Drop for MockServer {
+    fn drop(&mut self) {
+        self.task.abort();
+    }
+}
+
+/// Chat completion reply of `model` with `content`
+pub fn chat_reply(model: &str, content: &str, finish_reason: &str) -> Value {
+    serde_json::json!({
+        "id": "chat", "object": "chat.completion", "created": 0, "model": model,
+        "choices": [{ "index": 0, "message": { "role": "assistant", "content": content }, "finish_reason": finish_reason }],
+    })
+}
+
+/// Reads the head and the body its content length announces
+async fn read_request(socket: &mut tokio::net::TcpStream) -> anyhow::Result<Request> {
+    let mut received = Vec::new();
+    let mut buf = [0; 4096];
+    let body_start = loop {
+        if let Some(end) = received.windows(4).position(|w| w == b"\r\n\r\n") {
+            break end + 4;
+        }
+        let n = socket.read(&mut buf).await?;
+        anyhow::ensure!(n > 0, "connection closed before the headers");
+        received.extend_from_slice(&buf[..n]);
+    };
+    let head = String::from_utf8_lossy(&received[..body_start]).to_string();
+    let (line, headers) = head.split_at(head.find("\r\n").unwrap_or(head.len()));
+    let headers = headers.to_lowercase();
+    let length: usize = headers.lines()
+        .find_map(|line| line.strip_prefix("content-length:"))
+        .map(|v| v.trim().parse())
+        .transpose()?
+        .unwrap_or(0);
+    while received.len() < body_start + length {
+        let n = socket.read(&mut buf).await?;
+        anyhow::ensure!(n > 0, "connection closed before the body");
+        received.extend_from_slice(&buf[..n]);
+    }
+    let body = match length {
+        0 => Value::Null,
+        _ => serde_json::from_slice(&received[body_start..body_start + length])?,
+    };
+    Ok(Request { line: line.to_string(), headers, body })
+}
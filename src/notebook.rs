@@ -0,0 +1,260 @@
+use std::ops::Range;
+use std::path::Path;
+use serde_json::Value;
+use crate::coder::CURSOR_MARKER;
+
+/// Tells whether `path` is a Jupyter notebook
+pub fn is_notebook(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext.eq_ignore_ascii_case("ipynb"))
+}
+
+/// Source of one code cell and where its JSON sits in the notebook
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Cell {
+    /// Byte range of the cell's `source` value in the notebook text
+    pub span: Range<usize>,
+    /// Cell source with its lines joined
+    pub source: String,
+}
+
+impl Cell {
+    /// Notebook text with this cell's source replaced by `source`. Everything
+    /// outside the `source` value, outputs included, is kept byte for byte.
+    pub fn replace(&self, notebook: &str, source: &str) -> String {
+        let old = &notebook[self.span.clone()];
+        let new = match old.starts_with('[') {
+            true => source_array(old, source),
+            false => Value::String(source.to_string()).to_string(),
+        };
+        format!("{}{}{}", &notebook[..self.span.start], new, &notebook[self.span.end..])
+    }
+}
+
+/// The first code cell whose source holds the marker. Markers in markdown
+/// cells and outputs are left alone.
+pub fn marked_cell(notebook: &str) -> Option<Cell> {
+    code_sources(notebook)?.into_iter()
+        .filter(|span| notebook[span.clone()].contains(CURSOR_MARKER))
+        .find_map(|span| {
+            let source = match serde_json::from_str(&notebook[span.clone()]).ok()? {
+                Value::String(source) => source,
+                Value::Array(lines) => lines.iter().map(|l| l.as_str()).collect::<Option<String>>()?,
+                _ => return None,
+            };
+            Some(Cell { span, source })
+        })
+}
+
+/// Serializes `source` as a list of lines, indented like the `old` list
+fn source_array(old: &str, source: &str) -> String {
+    if source.is_empty() {
+        return "[]".to_string();
+    }
+    let lines = source.split_inclusive('\n')
+        .map(|line| Value::String(line.to_string()).to_string());
+
+    // nbformat writes one line per row, indented one level deeper than the bracket
+    let Some(first) = old.find('\n') else {
+        return format!("[{}]", lines.collect::<Vec<_>>().join(", "));
+    };
+    let item_indent = &old[first + 1..first + 1 + old[first + 1..].find(|c: char| c != ' ' && c != '\t').unwrap_or(0)];
+    let close_indent = old.rfind('\n').map_or("", |last| &old[last + 1..old.len() - 1]);
+    let items = lines.map(|line| format!("{}{}", item_indent, line)).collect::<Vec<_>>();
+    format!("[\n{}\n{}]", items.join(",\n"), close_indent)
+}
+
+/// Byte ranges of the `source` values of the notebook's code cells
+fn code_sources(notebook: &str) -> Option<Vec<Range<usize>>> {
+    let mut scanner = Scanner { text: notebook.as_bytes(), pos: 0 };
+    let mut sources = Vec::new();
+    scanner.object(|scanner, key| {
+        if key != "cells" {
+            return scanner.skip_value();
+        }
+        scanner.array(|scanner| {
+            let mut code = false;
+            let mut source = None;
+            scanner.object(|scanner, key| {
+                let start = scanner.value_start()?;
+                scanner.skip_value()?;
+                match key {
+                    "cell_type" => code = &notebook[start..scanner.pos] == "\"code\"",
+                    "source" => source = Some(start..scanner.pos),
+                    _ => {}
+                }
+                Some(())
+            })?;
+            if let Some(source) = source.filter(|_| code) {
+                sources.push(source);
+            }
+            Some(())
+        })
+    })?;
+    Some(sources)
+}
+
+/// Walks JSON text keeping byte positions, which `serde_json` doesn't expose
+struct Scanner<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl Scanner<'_> {
+    fn skip_whitespace(&mut self) {
+        while self.text.get(self.pos).is_some_and(u8::is_ascii_whitespace) {
+            self.pos += 1;
+        }
+    }
+
+    fn value_start(&mut self) -> Option<usize> {
+        self.skip_whitespace();
+        (self.pos < self.text.len()).then_some(self.pos)
+    }
+
+    fn expect(&mut self, byte: u8) -> Option<()> {
+        self.skip_whitespace();
+        (self.text.get(self.pos) == Some(&byte)).then(|| self.pos += 1)
+    }
+
+    /// Consumes `byte` if it comes next
+    fn eat(&mut self, byte: u8) -> bool {
+        self.expect(byte).is_some()
+    }
+
+    /// Consumes a string, returning its raw contents between the quotes
+    fn string(&mut self) -> Option<&str> {
+        self.expect(b'"')?;
+        let start = self.pos;
+        loop {
+            match self.text.get(self.pos)? {
+                b'\\' => self.pos += 2,
+                b'"' => break,
+                _ => self.pos += 1,
+            }
+        }
+        self.pos += 1;
+        std::str::from_utf8(&self.text[start..self.pos - 1]).ok()
+    }
+
+    fn object(&mut self, mut field: impl FnMut(&mut Self, &str) -> Option<()>) -> Option<()> {
+        self.expect(b'{')?;
+        if self.eat(b'}') {
+            return Some(());
+        }
+        loop {
+            let key = self.string()?.to_string();
+            self.expect(b':')?;
+            field(self, &key)?;
+            if !self.eat(b',') {
+                return self.expect(b'}');
+            }
+        }
+    }
+
+    fn array(&mut self, mut item: impl FnMut(&mut Self) -> Option<()>) -> Option<()> {
+        self.expect(b'[')?;
+        if self.eat(b']') {
+            return Some(());
+        }
+        loop {
+            item(self)?;
+            if !self.eat(b',') {
+                return self.expect(b']');
+            }
+        }
+    }
+
+    fn skip_value(&mut self) -> Option<()> {
+        match self.text.get(self.value_start()?)? {
+            b'{' => self.object(|scanner, _| scanner.skip_value()),
+            b'[' => self.array(Self::skip_value),
+            b'"' => self.string().map(|_| ()),
+            _ => {
+                // Numbers, booleans and null run up to the next delimiter
+                let len = self.text[self.pos..].iter()
+                    .position(|b| matches!(b, b',' | b'}' | b']') || b.is_ascii_whitespace())
+                    .unwrap_or(self.text.len() - self.pos);
+                (len > 0).then(|| self.pos += len)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    const NOTEBOOK: &str = indoc! {r#"
+        {
+         "cells": [
+          {
+           "cell_type": "markdown",
+           "metadata": {},
+           "source": [
+            "Mean of ?? values"
+           ]
+          },
+          {
+           "cell_type": "code",
+           "execution_count": 1,
+           "metadata": {},
+           "outputs": [
+            {
+             "name": "stdout",
+             "output_type": "stream",
+             "text": [
+              "3.0\n"
+             ]
+            }
+           ],
+           "source": [
+            "import numpy as np\n",
+            "xs = np.array([1, 2, 3])\n",
+            "print(??)"
+           ]
+          }
+         ],
+         "metadata": {},
+         "nbformat": 4,
+         "nbformat_minor": 5
+        }
+    "#};
+
+    #[test]
+    fn test_marker_in_code_cell() {
+        // The marker in the markdown cell is prose, not a completion request
+        let cell = marked_cell(NOTEBOOK).unwrap();
+        assert_eq!(cell.source, "import numpy as np\nxs = np.array([1, 2, 3])\nprint(??)");
+
+        let updated = cell.replace(NOTEBOOK, "import numpy as np\nxs = np.array([1, 2, 3])\nprint(xs.mean())");
+        let expected = NOTEBOOK.replace("print(??)", "print(xs.mean())");
+        assert_eq!(updated, expected);
+    }
+
+    #[test]
+    fn test_added_lines_keep_layout() {
+        let cell = marked_cell(NOTEBOOK).unwrap();
+
+        let updated = cell.replace(NOTEBOOK, "xs = [1, 2]\n\nprint(\"mean\", sum(xs) / 2)\n");
+        let expected = concat!(
+            "   \"source\": [\n",
+            "    \"xs = [1, 2]\\n\",\n",
+            "    \"\\n\",\n",
+            "    \"print(\\\"mean\\\", sum(xs) / 2)\\n\"\n",
+            "   ]\n",
+        );
+        assert!(updated.contains(expected), "{}", updated);
+        serde_json::from_str::<Value>(&updated).unwrap();
+    }
+
+    #[test]
+    fn test_string_source_and_invalid_json() {
+        let notebook = r#"{"cells": [{"cell_type": "code", "source": "x = ??", "outputs": []}]}"#;
+        let cell = marked_cell(notebook).unwrap();
+        assert_eq!(cell.replace(notebook, "x = 1"), notebook.replace("??", "1"));
+
+        assert_eq!(marked_cell("{\"cells\": [\"??\""), None);
+        assert!(is_notebook(Path::new("analysis.IPYNB")));
+    }
+}
@@ -0,0 +1,244 @@
+use std::str::FromStr;
+use regex::Regex;
+use crate::coder::{DTOKEN, RTOKEN, STOKEN};
+use crate::error::{CoderError, Result};
+
+const CONFLICT_SEARCH: &str = "<<<<<<< SEARCH";
+const CONFLICT_DIVIDE: &str = "=======";
+const CONFLICT_REPLACE: &str = ">>>>>>> REPLACE";
+const DIFF_FENCE: &str = "```diff";
+/// Matches an `@line N` annotation on a line of its own
+const LINE_ANNOTATION: &str = r"(?m)^[ \t]*@line[ \t]+(\d+)[ \t]*(\r?\n|$)";
+
+/// Edit formats models reply with. Each one yields the text to search for,
+/// still holding the cursor token, and its replacement.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatchFormat {
+    /// `<|SEARCH|>old<|DIVIDE|>new<|REPLACE|>`, the format the prompts ask for
+    Tokens,
+    /// Merge conflict style blocks, `<<<<<<< SEARCH` / `=======` / `>>>>>>> REPLACE`
+    Conflict,
+    /// A fenced diff block with `-`, `+` and context lines
+    Diff,
+}
+
+impl PatchFormat {
+    /// Formats in the order they are tried when none is configured
+    pub const ALL: [PatchFormat; 3] = [PatchFormat::Tokens, PatchFormat::Conflict, PatchFormat::Diff];
+
+    /// Tells whether `content` looks like a reply in this format
+    fn detect(self, content: &str) -> bool {
+        match self {
+            PatchFormat::Tokens => content.contains(STOKEN),
+            PatchFormat::Conflict => content.contains(CONFLICT_SEARCH),
+            PatchFormat::Diff => content.contains(DIFF_FENCE),
+        }
+    }
+
+    /// Splits a reply in this format into search and replace text
+    pub fn parse(self, content: &str) -> Result<(String, String)> {
+        match self {
+            PatchFormat::Tokens => parse_tokens(content),
+            PatchFormat::Conflict => parse_conflict(content),
+            PatchFormat::Diff => parse_diff(content),
+        }
+    }
+}
+
+impl FromStr for PatchFormat {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        match s.trim().to_lowercase().as_str() {
+            "tokens" => Ok(PatchFormat::Tokens),
+            "conflict" => Ok(PatchFormat::Conflict),
+            "diff" => Ok(PatchFormat::Diff),
+            other => anyhow::bail!("Unknown patch format {:?}, expected tokens, conflict or diff", other),
+        }
+    }
+}
+
+/// Parses `content` in `format`, or in the first format it looks like when unset.
+/// Replies matching no format are reported as malformed token patches.
+pub fn parse_patch(content: &str, format: Option<PatchFormat>) -> Result<(String, String)> {
+    let format = format.unwrap_or_else(|| {
+        PatchFormat::ALL.into_iter()
+            .find(|f| f.detect(content))
+            .unwrap_or(PatchFormat::Tokens)
+    });
+    format.parse(content)
+}
+
+/// Takes an optional `@line N` annotation out of a reply in any format,
+/// giving the 1-based line of the file the search block starts on
+pub fn split_line_annotation(content: &str) -> (Option<usize>, String) {
+    let pattern = Regex::new(LINE_ANNOTATION).expect("valid line annotation pattern");
+    let Some(caps) = pattern.captures(content) else {
+        return (None, content.to_string());
+    };
+    let line = caps[1].parse().ok().filter(|&line| line > 0);
+    (line, pattern.replace(content, "").into_owned())
+}
+
+fn parse_tokens(patch: &str) -> Result<(String, String)> {
+    let search_start = patch.find(STOKEN)
+        .ok_or(CoderError::MissingToken(STOKEN))?;
+    let replace_divider = patch.find(DTOKEN)
+        .ok_or(CoderError::MissingToken(DTOKEN))?;
+    let _replace_end = patch.find(RTOKEN)
+        .ok_or(CoderError::MissingToken(RTOKEN))?;
+
+    let search = &patch[search_start + STOKEN.len()..replace_divider];
+    let replace = &patch[replace_divider + DTOKEN.len()..];
+    Ok((search.to_string(), replace.replace(RTOKEN, "")))
+}
+
+fn parse_conflict(patch: &str) -> Result<(String, String)> {
+    let search_start = patch.find(CONFLICT_SEARCH)
+        .ok_or(CoderError::MissingToken(CONFLICT_SEARCH))?;
+    let body = &patch[search_start + CONFLICT_SEARCH.len()..];
+    let body = body.strip_prefix('\n').unwrap_or(body);
+
+    let divider = body.find(CONFLICT_DIVIDE)
+        .ok_or(CoderError::MissingToken(CONFLICT_DIVIDE))?;
+    let rest = &body[divider + CONFLICT_DIVIDE.len()..];
+    let rest = rest.strip_prefix('\n').unwrap_or(rest);
+    let replace_end = rest.find(CONFLICT_REPLACE)
+        .ok_or(CoderError::MissingToken(CONFLICT_REPLACE))?;
+
+    // The newlines around the markers belong to the block, not the code
+    let search = &body[..divider];
+    let replace = &rest[..replace_end];
+    Ok((
+        search.strip_suffix('\n').unwrap_or(search).to_string(),
+        replace.strip_suffix('\n').unwrap_or(replace).to_string(),
+    ))
+}
+
+fn parse_diff(patch: &str) -> Result<(String, String)> {
+    let fence = patch.find(DIFF_FENCE)
+        .ok_or(CoderError::MissingToken(DIFF_FENCE))?;
+    let body = &patch[fence + DIFF_FENCE.len()..];
+    let body = body.split_once('\n').map_or("", |(_, body)| body);
+    let body = body.find("```").map_or(body, |end| &body[..end]);
+
+    let mut search = Vec::new();
+    let mut replace = Vec::new();
+    for line in body.lines() {
+        let is_header = line.starts_with("--- ") || line.starts_with("+++ ") || line.starts_with("@@");
+        if is_header && search.is_empty() && replace.is_empty() {
+            continue;
+        }
+        match line.chars().next() {
+            Some('-') => search.push(&line[1..]),
+            Some('+') => replace.push(&line[1..]),
+            // Models often drop the leading space of context lines
+            Some(' ') => {
+                search.push(&line[1..]);
+                replace.push(&line[1..]);
+            }
+            _ => {
+                search.push(line);
+                replace.push(line);
+            }
+        }
+    }
+
+    if search.is_empty() && replace.is_empty() {
+        return Err(CoderError::InvalidResponse("Empty diff block".to_string()));
+    }
+    Ok((search.join("\n"), replace.join("\n")))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use indoc::indoc;
+
+    fn expected() -> (String, String) {
+        (
+            "fn add(a: i32, b: i32) -> i32 {\n    <|cursor|>\n}".to_string(),
+            "fn add(a: i32, b: i32) -> i32 {\n    a + b\n}".to_string(),
+        )
+    }
+
+    #[test]
+    fn test_conflict_and_diff_replies_parse_alike() -> anyhow::Result<()> {
+        let conflict = indoc! {"
+            Here is the completion:
+
+            <<<<<<< SEARCH
+            fn add(a: i32, b: i32) -> i32 {
+                <|cursor|>
+            }
+            =======
+            fn add(a: i32, b: i32) -> i32 {
+                a + b
+            }
+            >>>>>>> REPLACE
+        "};
+        let diff = indoc! {"
+            ```diff
+            --- a/src/lib.rs
+            +++ b/src/lib.rs
+            @@ -1,3 +1,3 @@
+             fn add(a: i32, b: i32) -> i32 {
+            -    <|cursor|>
+            +    a + b
+             }
+            ```
+        "};
+
+        assert_eq!(parse_patch(conflict, None)?, expected());
+        assert_eq!(parse_patch(diff, None)?, expected());
+        assert_eq!(parse_patch(diff, Some(PatchFormat::Diff))?, expected());
+        Ok(())
+    }
+
+    #[test]
+    fn test_tokens_reply() -> anyhow::Result<()> {
+        let tokens = "<|SEARCH|>fn add(a: i32, b: i32) -> i32 {\n    <|cursor|>\n}<|DIVIDE|>\
+            fn add(a: i32, b: i32) -> i32 {\n    a + b\n}<|REPLACE|>";
+        assert_eq!(parse_patch(tokens, None)?, expected());
+        Ok(())
+    }
+
+    #[test]
+    fn test_unrecognized_reply() {
+        let missing = parse_patch("no patch here", None);
+        assert!(matches!(missing, Err(CoderError::MissingToken(STOKEN))));
+
+        // A configured format doesn't fall back to the others
+        let wrong = parse_patch("<|SEARCH|>a<|DIVIDE|>b<|REPLACE|>", Some(PatchFormat::Conflict));
+        assert!(matches!(wrong, Err(CoderError::MissingToken(CONFLICT_SEARCH))));
+
+        let truncated = parse_patch("<<<<<<< SEARCH\na\n=======\nb\n", None);
+        assert!(matches!(truncated, Err(CoderError::MissingToken(CONFLICT_REPLACE))));
+    }
+
+    #[test]
+    fn test_patch_format_from_str() {
+        assert_eq!("Diff".parse::<PatchFormat>().unwrap(), PatchFormat::Diff);
+        assert!("json".parse::<PatchFormat>().is_err());
+    }
+
+    #[test]
+    fn test_line_annotation() -> anyhow::Result<()> {
+        let tokens = "@line 12\n<|SEARCH|>fn add(a: i32, b: i32) -> i32 {\n    <|cursor|>\n}<|DIVIDE|>\
+            fn add(a: i32, b: i32) -> i32 {\n    a + b\n}<|REPLACE|>";
+        let (line, rest) = split_line_annotation(tokens);
+        assert_eq!(line, Some(12));
+        assert_eq!(parse_patch(&rest, None)?, expected());
+
+        let conflict = "<<<<<<< SEARCH\n  @line 3\nfn add(a: i32, b: i32) -> i32 {\n    <|cursor|>\n}\n=======\n\
+            fn add(a: i32, b: i32) -> i32 {\n    a + b\n}\n>>>>>>> REPLACE";
+        let (line, rest) = split_line_annotation(conflict);
+        assert_eq!(line, Some(3));
+        assert_eq!(parse_patch(&rest, None)?, expected());
+
+        // Only a line of its own counts, and line numbers start at 1
+        assert_eq!(split_line_annotation("x = 1; // @line 4").0, None);
+        assert_eq!(split_line_annotation("@line 0\n").0, None);
+        Ok(())
+    }
+}
@@ -29,9 +29,12 @@ Important rules for the {{search}} block:
 Important rules for the {{replace}} block:
 - Do NOT include <|cursor|> in the replacement.
 
+To anchor the change precisely, put a line `@line N` before <|SEARCH|>, where N is the line of the file the {{search}} block starts on.
+The small context says which line it starts from.
+
 Important rules:
 Each ORIGINAL text must be large enough to uniquely identify the change in the file. However, bias towards writing as little as possible.
-Your response must begin with <|SEARCH|>. THIS IS VERY IMPORTANT.
+Your response must begin with <|SEARCH|>, or with the @line N line and then <|SEARCH|>. THIS IS VERY IMPORTANT.
 Your response must end with <|REPLACE|>. THIS IS VERY IMPORTANT. do not add anything else after <|REPLACE|>.
 <|SEARCH|>, <|DIVIDE|>, <|REPLACE|> must be only once in the response.
 Do NOT include new line character in blocks for last line, only as separator between multiple lines.  
@@ -39,6 +42,10 @@ Do NOT include new line character in blocks for last line, only as separator bet
 ACCEPTED OUTPUT:
 <|SEARCH|>const foo = <|cursor|><|DIVIDE|>const foo = 42;<|REPLACE|>
 
+ACCEPTED OUTPUT:
+@line 12
+<|SEARCH|>const foo = <|cursor|><|DIVIDE|>const foo = 42;<|REPLACE|>
+
 REJECTED OUTPUT:
 <|SEARCH|>const foo = <|cursor|><|DIVIDE|>const <|REPLACE|> foo = 42;
 
@@ -50,4 +57,353 @@ Edit small context around the <|cursor|>.
 Keep ORIGINAL users code in {{search}} block. 
 Edits MUST AFFECT only small context. Do not include `small context` prefix in answer.
 check it multiple times!
-"#;
\ No newline at end of file
+"#;
+
+pub const SCAFFOLD_PROMPT: &str = r#"
+You are a code editor assistant.
+The user's file is empty apart from the <|cursor|> token.
+Write a minimal, idiomatic starting template for the given language: 
+imports only if required, a main entry point or module skeleton, nothing more.
+Your response must be in the form of a change using the following tokens:
+
+<|SEARCH|> — indicates the text to find
+<|DIVIDE|> — separates the found text and the replacement
+<|REPLACE|> — indicates the new text
+<|cursor|> — the user's cursor position
+
+Your response must begin with <|SEARCH|><|cursor|><|DIVIDE|> and end with <|REPLACE|>.
+Do NOT include <|cursor|> in the replacement.
+
+ACCEPTED OUTPUT:
+<|SEARCH|><|cursor|><|DIVIDE|>def main():
+    pass
+
+
+if __name__ == "__main__":
+    main()
+<|REPLACE|>
+"#;
+
+
+pub const LINE_REMINDER: &str = r#"
+Edit small context around the <|cursor|>. 
+Keep ORIGINAL users code in {{search}} block. 
+Complete ONLY the line with the <|cursor|>. Do not add new lines.
+Prefer the shortest completion that makes the line valid.
+check it multiple times!
+"#;
+
+
+pub const BLOCK_REMINDER: &str = r#"
+Edit small context around the <|cursor|>. 
+Keep ORIGINAL users code in {{search}} block. 
+Complete the whole enclosing block at the <|cursor|> (function body, loop, branch), 
+not just the current line. Do not touch code outside that block.
+check it multiple times!
+"#;
+
+pub const TASK_PROMPT: &str = r#"
+The <|cursor|> marks where the user wants this task implemented:
+"#;
+
+pub const INTERPOLATION_PROMPT: &str = r#"
+The <|cursor|> is inside the interpolation of a string literal. Replace it with an expression only,
+leaving the quotes and the interpolation delimiters around it as they are. The string reads:
+"#;
+
+pub const TEMPLATE_PROMPT: &str = r#"
+You are a code editor assistant.
+The user's file is a scaffold with named holes written as ${name}.
+Write the code for every hole so that the file works as a whole.
+Respond with a single JSON object mapping each hole name to its code, nothing else:
+{"body": "let total = a + b;", "return": "total"}
+The code replaces the hole exactly where it stands. Do not indent it for its position,
+the indentation of the hole's line is added to every line after the first.
+Use an empty string for a hole you cannot fill.
+"#;
+
+pub const REWRITE_PROMPT: &str = r#"
+You are a code editor assistant.
+The user selected part of a file, marked with <|selection|> and <|/selection|>, and tells you how to change it.
+Respond with the new text of the selection only, nothing before or after it and without the markers.
+Keep the indentation of the surrounding code. Leave the code around the selection as it is.
+"#;
+
+pub const SUMMARY_PROMPT: &str = r#"
+You outline source files for a code editor assistant.
+The user sends a whole file. Respond with its structure only: imports, types, functions and methods
+with their signatures, one per line, indented by nesting, with a few words on what each does.
+Leave out the bodies. Respond with the outline only, nothing before or after it.
+"#;
+
+pub const COMMIT_PROMPT: &str = r#"
+You write git commit messages.
+The user sends the diff of a completion made in one file.
+Respond with a single subject line of at most 72 characters in the imperative mood,
+such as "Add bounds check to parse_header", nothing else.
+"#;
+
+/// Tool definition letting capable models return the patch as structured arguments
+pub fn apply_edit_tool() -> serde_json::Value {
+    serde_json::json!({
+        "type": "function",
+        "function": {
+            "name": APPLY_EDIT_TOOL,
+            "description": "Replace the `search` text around the <|cursor|> with `replace`.",
+            "parameters": {
+                "type": "object",
+                "properties": {
+                    "search": {
+                        "type": "string",
+                        "description": "Original text exactly as in the small context, including <|cursor|>"
+                    },
+                    "replace": {
+                        "type": "string",
+                        "description": "New text for the same region, without <|cursor|>"
+                    }
+                },
+                "required": ["search", "replace"]
+            }
+        }
+    })
+}
+
+pub const APPLY_EDIT_TOOL: &str = "apply_edit";
+
+/// System prompt and reminder pair that steers completion scope
+#[derive(Debug, Clone, PartialEq, Eq, serde::Deserialize)]
+pub struct Preset {
+    #[serde(default = "default_system")]
+    pub system: String,
+    #[serde(default = "default_reminder")]
+    pub reminder: String,
+}
+
+fn default_system() -> String { SYSTEM_PROMPT.to_string() }
+
+fn default_reminder() -> String { REMINDER.to_string() }
+
+impl Default for Preset {
+    fn default() -> Self {
+        Self { system: default_system(), reminder: default_reminder() }
+    }
+}
+
+/// Looks up one of the presets shipped with anycoder
+pub fn builtin_preset(name: &str) -> Option<Preset> {
+    let reminder = match name {
+        "default" => REMINDER,
+        "line" => LINE_REMINDER,
+        "block" => BLOCK_REMINDER,
+        _ => return None,
+    };
+    Some(Preset { system: default_system(), reminder: reminder.to_string() })
+}
+
+/// The prompts in use, starting from the built-in ones. Any of them can be
+/// replaced before the registry is handed to the coder.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PromptRegistry {
+    system: String,
+    reminder: String,
+    scaffold: String,
+    task: String,
+    interpolation: String,
+    template: String,
+    commit: String,
+    rewrite: String,
+    summary: String,
+    /// Presets added on top of the built-in ones, which they shadow
+    presets: std::collections::HashMap<String, Preset>,
+}
+
+impl Default for PromptRegistry {
+    fn default() -> Self {
+        Self {
+            system: SYSTEM_PROMPT.to_string(),
+            reminder: REMINDER.to_string(),
+            scaffold: SCAFFOLD_PROMPT.to_string(),
+            task: TASK_PROMPT.to_string(),
+            interpolation: INTERPOLATION_PROMPT.to_string(),
+            template: TEMPLATE_PROMPT.to_string(),
+            commit: COMMIT_PROMPT.to_string(),
+            rewrite: REWRITE_PROMPT.to_string(),
+            summary: SUMMARY_PROMPT.to_string(),
+            presets: std::collections::HashMap::new(),
+        }
+    }
+}
+
+impl PromptRegistry {
+    pub fn system(&self) -> &str {
+        &self.system
+    }
+
+    pub fn reminder(&self) -> &str {
+        &self.reminder
+    }
+
+    pub fn scaffold(&self) -> &str {
+        &self.scaffold
+    }
+
+    /// Text put in front of a TODO task
+    pub fn task(&self) -> &str {
+        &self.task
+    }
+
+    /// Note sent when the cursor sits in a string interpolation, followed by its syntax
+    pub fn interpolation(&self) -> &str {
+        &self.interpolation
+    }
+
+    /// System prompt for filling `${name}` holes
+    pub fn template(&self) -> &str {
+        &self.template
+    }
+
+    /// System prompt for the message of an automatic commit
+    pub fn commit(&self) -> &str {
+        &self.commit
+    }
+
+    /// System prompt for rewriting a selection as instructed
+    pub fn rewrite(&self) -> &str {
+        &self.rewrite
+    }
+
+    /// System prompt asking for the outline sent instead of a large file
+    pub fn summary(&self) -> &str {
+        &self.summary
+    }
+
+    pub fn set_system(&mut self, system: impl Into<String>) {
+        self.system = system.into();
+    }
+
+    pub fn set_reminder(&mut self, reminder: impl Into<String>) {
+        self.reminder = reminder.into();
+    }
+
+    pub fn set_scaffold(&mut self, scaffold: impl Into<String>) {
+        self.scaffold = scaffold.into();
+    }
+
+    pub fn set_task(&mut self, task: impl Into<String>) {
+        self.task = task.into();
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: impl Into<String>) {
+        self.interpolation = interpolation.into();
+    }
+
+    pub fn set_template(&mut self, template: impl Into<String>) {
+        self.template = template.into();
+    }
+
+    pub fn set_commit(&mut self, commit: impl Into<String>) {
+        self.commit = commit.into();
+    }
+
+    pub fn set_rewrite(&mut self, rewrite: impl Into<String>) {
+        self.rewrite = rewrite.into();
+    }
+
+    pub fn set_summary(&mut self, summary: impl Into<String>) {
+        self.summary = summary.into();
+    }
+
+    pub fn add_preset(&mut self, name: impl Into<String>, preset: Preset) {
+        self.presets.insert(name.into(), preset);
+    }
+
+    /// The system prompt and reminder used when no preset is picked
+    pub fn default_preset(&self) -> Preset {
+        Preset { system: self.system.clone(), reminder: self.reminder.clone() }
+    }
+
+    /// Looks up a preset, added ones first. Built-in presets pick up
+    /// the registry's system prompt.
+    pub fn preset(&self, name: &str) -> Option<Preset> {
+        if let Some(preset) = self.presets.get(name) {
+            return Some(preset.clone());
+        }
+        if name == "default" {
+            return Some(self.default_preset());
+        }
+        builtin_preset(name).map(|preset| Preset { system: self.system.clone(), ..preset })
+    }
+}
+
+/// Loads user-defined presets from a JSON file shaped like
+/// `{ "name": { "system": "...", "reminder": "..." } }`
+pub fn load_presets(path: &std::path::Path) -> anyhow::Result<std::collections::HashMap<String, Preset>> {
+    let content = std::fs::read_to_string(path)?;
+    let presets = serde_json::from_str(&content)
+        .map_err(|e| anyhow::anyhow!("Invalid presets file {:?}: {}", path, e))?;
+    Ok(presets)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_presets() {
+        assert_eq!(builtin_preset("default"), Some(Preset::default()));
+        assert_eq!(builtin_preset("line").unwrap().reminder, LINE_REMINDER);
+        assert_eq!(builtin_preset("block").unwrap().reminder, BLOCK_REMINDER);
+        assert_eq!(builtin_preset("missing"), None);
+    }
+
+    #[test]
+    fn test_prompt_registry_defaults() {
+        let prompts = PromptRegistry::default();
+
+        assert_eq!(prompts.system(), SYSTEM_PROMPT);
+        assert_eq!(prompts.reminder(), REMINDER);
+        assert_eq!(prompts.scaffold(), SCAFFOLD_PROMPT);
+        assert_eq!(prompts.task(), TASK_PROMPT);
+        assert_eq!(prompts.default_preset(), Preset::default());
+        assert_eq!(prompts.preset("line"), builtin_preset("line"));
+        assert_eq!(prompts.preset("missing"), None);
+    }
+
+    #[test]
+    fn test_prompt_registry_overrides() {
+        let mut prompts = PromptRegistry::default();
+        prompts.set_system("sys");
+        prompts.set_reminder("remind");
+        prompts.set_task("do this:");
+        let terse = Preset { system: "terse sys".to_string(), reminder: "terse".to_string() };
+        prompts.add_preset("terse", terse.clone());
+        prompts.add_preset("line", terse.clone());
+
+        assert_eq!(prompts.default_preset(), Preset { system: "sys".into(), reminder: "remind".into() });
+        assert_eq!(prompts.preset("default"), Some(prompts.default_preset()));
+        assert_eq!(prompts.task(), "do this:");
+        assert_eq!(prompts.scaffold(), SCAFFOLD_PROMPT);
+
+        // Added presets shadow built-in ones, which follow the system prompt
+        assert_eq!(prompts.preset("terse"), Some(terse.clone()));
+        assert_eq!(prompts.preset("line"), Some(terse));
+        let block = prompts.preset("block").unwrap();
+        assert_eq!(block.system, "sys");
+        assert_eq!(block.reminder, BLOCK_REMINDER);
+    }
+
+    #[test]
+    fn test_load_presets() -> anyhow::Result<()> {
+        let path = std::env::temp_dir().join("anycoder_test_presets.json");
+        std::fs::write(&path, r#"{ "terse": { "reminder": "be terse" } }"#)?;
+
+        let presets = load_presets(&path)?;
+        std::fs::remove_file(&path)?;
+
+        let terse = &presets["terse"];
+        assert_eq!(terse.system, SYSTEM_PROMPT);
+        assert_eq!(terse.reminder, "be terse");
+
+        Ok(())
+    }
+}
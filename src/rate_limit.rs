@@ -0,0 +1,166 @@
+use async_trait::async_trait;
+use log::warn;
+use serde_json::Value;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use crate::error::{CoderError, Result};
+use crate::llm::{ChatModel, ChatResponse};
+
+/// Longest pause after a run of rate limited requests
+const MAX_BACKOFF: Duration = Duration::from_secs(60);
+
+/// Pause shared by every request, set when any of them is rate limited
+#[derive(Default)]
+struct Pause {
+    until: Option<Instant>,
+    /// Rate limited replies since the last success
+    streak: u32,
+}
+
+/// Chat model wrapper that backs off globally: a rate limited reply to one
+/// file's request holds back every request until the pause ends, instead of
+/// only the retries of that one. Waiting is a plain sleep, so a cancelled
+/// completion stops waiting and leaves the pause to the others.
+pub struct RateLimitGate<M> {
+    inner: M,
+    backoff: Duration,
+    retries: usize,
+    pause: Mutex<Pause>,
+}
+
+impl<M: ChatModel> RateLimitGate<M> {
+    /// Pauses for `backoff`, doubling with each rate limited reply in a row,
+    /// and retries a rate limited request up to `retries` times
+    pub fn new(inner: M, backoff: Duration, retries: usize) -> Self {
+        Self { inner, backoff, retries, pause: Mutex::new(Pause::default()) }
+    }
+
+    /// Sleeps until no pause is set, which other requests may extend meanwhile
+    async fn wait(&self) {
+        loop {
+            let until = self.pause.lock().unwrap_or_else(|e| e.into_inner()).until;
+            match until {
+                Some(until) if until > Instant::now() => tokio::time::sleep_until(until.into()).await,
+                _ => return,
+            }
+        }
+    }
+
+    /// Extends the pause after a rate limited reply
+    fn limited(&self) -> Duration {
+        let mut pause = self.pause.lock().unwrap_or_else(|e| e.into_inner());
+        let delay = self.backoff
+            .saturating_mul(2u32.saturating_pow(pause.streak))
+            .min(MAX_BACKOFF);
+        pause.streak += 1;
+        let until = Instant::now() + delay;
+        pause.until = Some(pause.until.map_or(until, |current| current.max(until)));
+        delay
+    }
+
+    fn succeeded(&self) {
+        self.pause.lock().unwrap_or_else(|e| e.into_inner()).streak = 0;
+    }
+
+    async fn gated<T, F>(&self, call: impl Fn() -> F) -> Result<T>
+    where
+        F: Future<Output = Result<T>>,
+    {
+        let mut attempt = 0;
+        loop {
+            self.wait().await;
+            match call().await {
+                Err(CoderError::RateLimited(message)) if attempt < self.retries => {
+                    attempt += 1;
+                    let delay = self.limited();
+                    warn!("Rate limited, pausing all requests for {:?}: {}", delay, message);
+                }
+                Err(CoderError::RateLimited(message)) => {
+                    self.limited();
+                    return Err(CoderError::RateLimited(message));
+                }
+                result => {
+                    if result.is_ok() {
+                        self.succeeded();
+                    }
+                    return result;
+                }
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl<M: ChatModel> ChatModel for RateLimitGate<M> {
+    async fn chat(&self, messages: Vec<Value>) -> Result<String> {
+        self.gated(|| self.inner.chat(messages.clone())).await
+    }
+
+    async fn chat_with_tools(
+        &self, messages: Vec<Value>, tools: Vec<Value>
+    ) -> Result<ChatResponse> {
+        self.gated(|| self.inner.chat_with_tools(messages.clone(), tools.clone())).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    /// Rate limits its first request, answers the rest
+    struct LimitedOnce(AtomicUsize);
+
+    #[async_trait]
+    impl ChatModel for LimitedOnce {
+        async fn chat(&self, _messages: Vec<Value>) -> Result<String> {
+            match self.0.fetch_add(1, Ordering::SeqCst) {
+                0 => Err(CoderError::RateLimited("slow down".to_string())),
+                _ => Ok("ok".to_string()),
+            }
+        }
+    }
+
+    #[tokio::test]
+    async fn test_rate_limit_delays_other_requests() -> anyhow::Result<()> {
+        let backoff = Duration::from_millis(200);
+        let gate = Arc::new(RateLimitGate::new(LimitedOnce(AtomicUsize::new(0)), backoff, 3));
+
+        let first = tokio::spawn({
+            let gate = gate.clone();
+            async move { gate.chat(vec![]).await }
+        });
+        while gate.inner.0.load(Ordering::SeqCst) == 0 {
+            tokio::task::yield_now().await;
+        }
+        tokio::time::sleep(Duration::from_millis(20)).await;
+
+        // A different request, never rate limited itself, still waits out the pause
+        let start = Instant::now();
+        let second = gate.chat(vec![]).await?;
+        let waited = start.elapsed();
+
+        assert_eq!(second, "ok");
+        assert_eq!(first.await??, "ok");
+        assert!(waited >= Duration::from_millis(150), "second request went out after {:?}", waited);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_gives_up_after_retries() {
+        struct AlwaysLimited;
+
+        #[async_trait]
+        impl ChatModel for AlwaysLimited {
+            async fn chat(&self, _messages: Vec<Value>) -> Result<String> {
+                Err(CoderError::RateLimited("slow down".to_string()))
+            }
+        }
+
+        let gate = RateLimitGate::new(AlwaysLimited, Duration::from_millis(1), 2);
+        let result = gate.chat(vec![]).await;
+        assert!(matches!(result, Err(CoderError::RateLimited(_))));
+        assert_eq!(gate.pause.lock().unwrap().streak, 3);
+    }
+}
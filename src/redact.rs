@@ -0,0 +1,156 @@
+use regex::Regex;
+use std::collections::HashMap;
+use std::ops::Range;
+
+/// Stands in for the `n`th secret of a text, closed so that `<|secret_1|>`
+/// is never a prefix of `<|secret_10|>`
+fn placeholder(n: usize) -> String {
+    format!("<|secret_{}|>", n)
+}
+
+/// Patterns of secrets kept out of requests, such as API keys and passwords.
+/// A pattern with a capture group redacts only its first group, so
+/// `password\s*=\s*"([^"]+)"` leaves the name and quotes to the model.
+#[derive(Debug, Clone)]
+pub struct Redactor {
+    patterns: Vec<Regex>,
+}
+
+impl Redactor {
+    pub fn new<S: AsRef<str>>(patterns: &[S]) -> Result<Self, regex::Error> {
+        let patterns = patterns.iter()
+            .map(|pattern| Regex::new(pattern.as_ref()))
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(Self { patterns })
+    }
+
+    /// Replaces the secrets in `texts` with placeholders, the same one for a
+    /// secret wherever it appears. Matches overlapping an occurrence of
+    /// `keep`, such as the cursor token, are left alone.
+    pub fn redact(&self, texts: &[&str], keep: &str) -> Redaction {
+        let mut placeholders: HashMap<&str, String> = HashMap::new();
+        let mut next = 1;
+        let redacted = texts.iter()
+            .map(|text| {
+                let kept = kept_ranges(text, keep);
+                let mut spans = self.patterns.iter()
+                    .flat_map(|pattern| pattern.captures_iter(text))
+                    .filter_map(|captures| captures.get(1).or_else(|| captures.get(0)))
+                    .map(|secret| secret.range())
+                    .filter(|span| !span.is_empty() && !kept.iter().any(|keep| overlaps(span, keep)))
+                    .collect::<Vec<_>>();
+                // Where matches of several patterns overlap, the earliest and longest is redacted
+                spans.sort_by_key(|span| (span.start, std::cmp::Reverse(span.end)));
+
+                let mut redacted = String::with_capacity(text.len());
+                let mut copied = 0;
+                for span in spans {
+                    if span.start < copied {
+                        continue;
+                    }
+                    let placeholder = placeholders.entry(&text[span.clone()]).or_insert_with(|| {
+                        while texts.iter().any(|text| text.contains(&placeholder(next))) {
+                            next += 1;
+                        }
+                        next += 1;
+                        placeholder(next - 1)
+                    });
+                    redacted.push_str(&text[copied..span.start]);
+                    redacted.push_str(placeholder);
+                    copied = span.end;
+                }
+                redacted.push_str(&text[copied..]);
+                redacted
+            })
+            .collect();
+
+        let secrets = placeholders.into_iter()
+            .map(|(secret, placeholder)| (placeholder, secret.to_string()))
+            .collect();
+        Redaction { texts: redacted, secrets }
+    }
+}
+
+/// Byte ranges of every occurrence of `keep` in `text`
+fn kept_ranges(text: &str, keep: &str) -> Vec<Range<usize>> {
+    if keep.is_empty() {
+        return Vec::new();
+    }
+    text.match_indices(keep).map(|(start, _)| start..start + keep.len()).collect()
+}
+
+fn overlaps(a: &Range<usize>, b: &Range<usize>) -> bool {
+    a.start < b.end && b.start < a.end
+}
+
+/// Texts with their secrets replaced by placeholders
+#[derive(Debug, Clone)]
+pub struct Redaction {
+    /// The redacted texts, in the order they were given
+    pub texts: Vec<String>,
+    /// Placeholders with the secrets they stand for
+    secrets: Vec<(String, String)>,
+}
+
+impl Redaction {
+    /// Number of distinct secrets redacted
+    pub fn len(&self) -> usize {
+        self.secrets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.secrets.is_empty()
+    }
+
+    /// Puts the secrets back wherever their placeholders are in `text`
+    pub fn restore(&self, text: &str) -> String {
+        self.secrets.iter().fold(text.to_string(), |text, (placeholder, secret)| {
+            text.replace(placeholder.as_str(), secret)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_redact_and_restore() -> anyhow::Result<()> {
+        let redactor = Redactor::new(&[r"sk-[A-Za-z0-9]{8,}", r#"password = "([^"]*)""#])?;
+        let text = "key = \"sk-abcdef123456\"\npassword = \"hunter2\"\nagain = \"sk-abcdef123456\"\n<|cursor|>\n";
+
+        let redaction = redactor.redact(&[text], "<|cursor|>");
+        assert_eq!(
+            redaction.texts,
+            ["key = \"<|secret_1|>\"\npassword = \"<|secret_2|>\"\nagain = \"<|secret_1|>\"\n<|cursor|>\n"]
+        );
+        assert_eq!(redaction.len(), 2);
+        assert_eq!(redaction.restore(&redaction.texts[0]), text);
+        Ok(())
+    }
+
+    #[test]
+    fn test_secret_has_one_placeholder_across_texts() -> anyhow::Result<()> {
+        let redactor = Redactor::new(&[r"sk-[A-Za-z0-9]{8,}"])?;
+        let texts = ["+let key = \"sk-abcdef123456\";", "let other = \"sk-zyxwvu987654\";\nlet key = \"sk-abcdef123456\";"];
+
+        let redaction = redactor.redact(&texts, "<|cursor|>");
+        assert_eq!(redaction.texts, [
+            "+let key = \"<|secret_1|>\";",
+            "let other = \"<|secret_2|>\";\nlet key = \"<|secret_1|>\";",
+        ]);
+        assert_eq!(redaction.restore(&redaction.texts[1]), texts[1]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_cursor_and_taken_placeholders_left_alone() -> anyhow::Result<()> {
+        let redactor = Redactor::new(&[r"token\S*"])?;
+        let texts = ["<|secret_1|> token<|cursor|>rest token123", "<|secret_2|>"];
+
+        let redaction = redactor.redact(&texts, "<|cursor|>");
+        assert_eq!(redaction.texts[0], "<|secret_1|> token<|cursor|>rest <|secret_3|>");
+        assert_eq!(redaction.restore(&redaction.texts[0]), texts[0]);
+        Ok(())
+    }
+}
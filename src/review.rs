@@ -0,0 +1,130 @@
+use std::ops::Range;
+use std::path::Path;
+use crate::diff::changed_range;
+use crate::lang::{comment_syntax, detect_language};
+
+const START: &str = "anycoder-start";
+const END: &str = "anycoder-end";
+
+/// Comment lines, without indentation, opening and closing the lines of a
+/// completion in `path`. `//` comments when the language is unknown.
+pub fn marks(path: &Path) -> (String, String) {
+    let (open, close) = detect_language(path).and_then(comment_syntax).unwrap_or(("//", ""));
+    (format!("{} {}{}", open, START, close), format!("{} {}{}", open, END, close))
+}
+
+/// Puts the mark lines around the lines of `text` that `range` touches,
+/// indented like the first of them. An empty range, text only removed, is left unmarked.
+pub fn wrap(text: &str, range: Range<usize>, path: &Path) -> String {
+    if range.is_empty() {
+        return text.to_string();
+    }
+    let (start_mark, end_mark) = marks(path);
+    let newline = if text.contains("\r\n") { "\r\n" } else { "\n" };
+
+    let first = text[..range.start].rfind('\n').map_or(0, |i| i + 1);
+    let last = match text[..range.end].ends_with('\n') {
+        true => range.end,
+        false => text[range.end..].find('\n').map_or(text.len(), |i| range.end + i + 1),
+    };
+    let line = &text[first..];
+    let indent = &line[..line.len() - line.trim_start_matches([' ', '\t']).len()];
+
+    let mut wrapped = String::with_capacity(text.len() + 64);
+    wrapped.push_str(&text[..first]);
+    wrapped.push_str(&format!("{}{}{}", indent, start_mark, newline));
+    wrapped.push_str(&text[first..last]);
+    if !text[..last].ends_with('\n') {
+        wrapped.push_str(newline);
+    }
+    wrapped.push_str(&format!("{}{}", indent, end_mark));
+    if last < text.len() || text.ends_with('\n') {
+        wrapped.push_str(newline);
+    }
+    wrapped.push_str(&text[last..]);
+    wrapped
+}
+
+/// Removes the mark lines from `text`, `None` when there are none
+pub fn strip(text: &str, path: &Path) -> Option<String> {
+    let (start_mark, end_mark) = marks(path);
+    let is_mark = |line: &str| {
+        let line = line.trim();
+        line == start_mark.trim() || line == end_mark.trim()
+    };
+
+    let mut found = false;
+    let mut stripped = String::with_capacity(text.len());
+    for line in text.split_inclusive('\n') {
+        if is_mark(line) {
+            found = true;
+        } else {
+            stripped.push_str(line);
+        }
+    }
+    if !found {
+        return None;
+    }
+    // A closing mark on the last line leaves the newline of the line above behind
+    if !text.ends_with('\n') && stripped.ends_with('\n') {
+        stripped.truncate(stripped.trim_end_matches(['\r', '\n']).len());
+    }
+    Some(stripped)
+}
+
+/// Marks what a completion from `before` to `after` inserted, replacing the
+/// marks of any earlier completion still in the file
+pub fn mark_completion(before: &str, after: &str, path: &Path) -> String {
+    let before = strip(before, path).unwrap_or_else(|| before.to_string());
+    let after = strip(after, path).unwrap_or_else(|| after.to_string());
+    let range = changed_range(&before, &after);
+    wrap(&after, range, path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_marks_bracket_inserted_lines() {
+        let before = "fn main() {\n    let x = ??;\n}\n";
+        let after = "fn main() {\n    let x = 1;\n    let y = 2;\n}\n";
+
+        let marked = mark_completion(before, after, Path::new("main.rs"));
+        assert_eq!(
+            marked,
+            "fn main() {\n    // anycoder-start\n    let x = 1;\n    let y = 2;\n    // anycoder-end\n}\n"
+        );
+        assert_eq!(strip(&marked, Path::new("main.rs")).as_deref(), Some(after));
+    }
+
+    #[test]
+    fn test_marks_follow_language_and_line_endings() {
+        let after = "def f():\r\n    return 1\r\n";
+        let range = after.find("return").unwrap()..after.find("1").unwrap() + 1;
+
+        let marked = wrap(after, range, Path::new("f.py"));
+        assert_eq!(marked, "def f():\r\n    # anycoder-start\r\n    return 1\r\n    # anycoder-end\r\n");
+        assert_eq!(strip(&marked, Path::new("f.py")).as_deref(), Some(after));
+
+        let html = "<p>hi</p>";
+        let marked = wrap(html, 3..5, Path::new("index.html"));
+        assert_eq!(marked, "<!-- anycoder-start -->\n<p>hi</p>\n<!-- anycoder-end -->");
+        assert_eq!(strip(&marked, Path::new("index.html")).as_deref(), Some(html));
+    }
+
+    #[test]
+    fn test_earlier_marks_replaced() {
+        let before = "// anycoder-start\nlet a = 1;\n// anycoder-end\nlet b = ??;\n";
+        let after = "// anycoder-start\nlet a = 1;\n// anycoder-end\nlet b = 2;\n";
+
+        let marked = mark_completion(before, after, Path::new("lib.rs"));
+        assert_eq!(marked, "let a = 1;\n// anycoder-start\nlet b = 2;\n// anycoder-end\n");
+    }
+
+    #[test]
+    fn test_removals_and_unmarked_text_left_alone() {
+        assert_eq!(mark_completion("let x = 1;??\n", "let x = 1;\n", Path::new("a.rs")), "let x = 1;\n");
+        assert_eq!(strip("let x = 1; // anycoder-start here\n", Path::new("a.rs")), None);
+    }
+}
@@ -0,0 +1,200 @@
+use log::debug;
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::path::PathBuf;
+use tokio::io::{AsyncBufRead, AsyncBufReadExt, AsyncWrite, AsyncWriteExt};
+use crate::coder::{Coder, CURSOR_MARKER};
+use crate::error::{CoderError, Result};
+use crate::utils::find_markers;
+
+const PARSE_ERROR: i64 = -32700;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// A valid request the completion failed for
+const COMPLETION_FAILED: i64 = -32000;
+
+/// An editor buffer to complete, which may hold unsaved edits
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Buffer {
+    /// Path of the file, used for its language and the context providers
+    pub path: PathBuf,
+    pub content: String,
+    /// Byte offset of the marker to complete, the first marker when unset
+    #[serde(default)]
+    pub cursor: Option<usize>,
+}
+
+/// A selection of an editor buffer to rewrite as instructed
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+pub struct Selection {
+    pub path: PathBuf,
+    pub content: String,
+    /// Byte range of the selection in `content`
+    pub start: usize,
+    pub end: usize,
+    pub instruction: String,
+}
+
+/// Completes `buffer` as the editor has it. Nothing is read from or written
+/// to disk, the completed text is returned.
+pub async fn complete_buffer(coder: &Coder, buffer: &Buffer) -> Result<String> {
+    let cursor = match buffer.cursor {
+        Some(cursor) => cursor,
+        None => *find_markers(&buffer.content, CURSOR_MARKER).first().ok_or(CoderError::MarkerNotFound(0))?,
+    };
+    coder.autocomplete(&buffer.content, &buffer.path, cursor).await
+}
+
+/// Answers one JSON-RPC 2.0 request. Notifications, requests without an id,
+/// are ignored: every method answers with a completion, which nobody would get.
+/// `rewrite` answers the same edit twice: `edit` in byte offsets of `content`,
+/// `char_edit` in char offsets for editors that index strings by character.
+pub async fn handle(coder: &Coder, line: &str) -> Option<Value> {
+    let request: Value = match serde_json::from_str(line) {
+        Ok(request) => request,
+        Err(e) => return Some(failure(Value::Null, PARSE_ERROR, e.to_string())),
+    };
+    let method = request["method"].as_str().unwrap_or_default();
+    let Some(id) = request.get("id").cloned() else {
+        debug!("rpc notification {} ignored, it has no id to answer", method);
+        return None;
+    };
+    debug!("rpc {} {}", method, id);
+
+    let result = match method {
+        "complete" => match Buffer::deserialize(&request["params"]) {
+            Ok(buffer) => complete_buffer(coder, &buffer).await
+                .map(|content| json!({ "content": content }))
+                .map_err(|e| (COMPLETION_FAILED, e.to_string())),
+            Err(e) => Err((INVALID_PARAMS, e.to_string())),
+        },
+        "rewrite" => match Selection::deserialize(&request["params"]) {
+            Ok(selection) => coder.rewrite_range(
+                &selection.content, &selection.path, selection.start, selection.end, &selection.instruction
+            ).await
+                .map(|edit| json!({ "edit": edit, "char_edit": edit.to_char_edit(&selection.content) }))
+                .map_err(|e| (COMPLETION_FAILED, e.to_string())),
+            Err(e) => Err((INVALID_PARAMS, e.to_string())),
+        },
+        _ => Err((METHOD_NOT_FOUND, format!("unknown method {:?}", method))),
+    };
+
+    Some(match result {
+        Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+        Err((code, message)) => failure(id, code, message),
+    })
+}
+
+fn failure(id: Value, code: i64, message: String) -> Value {
+    json!({ "jsonrpc": "2.0", "id": id, "error": { "code": code, "message": message } })
+}
+
+/// Serves newline-delimited JSON-RPC requests from `input` until it closes,
+/// writing one response line each
+pub async fn serve(
+    coder: &Coder, input: impl AsyncBufRead + Unpin, mut output: impl AsyncWrite + Unpin
+) -> std::io::Result<()> {
+    let mut lines = input.lines();
+    while let Some(line) = lines.next_line().await? {
+        if line.trim().is_empty() {
+            continue;
+        }
+        if let Some(response) = handle(coder, &line).await {
+            output.write_all(format!("{}\n", response).as_bytes()).await?;
+            output.flush().await?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::llm::mock::MockLlm;
+
+    #[tokio::test]
+    async fn test_completes_unsaved_buffer() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("anycoder-rpc-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let path = dir.join("main.rs");
+        std::fs::write(&path, "fn main() {}\n")?;
+
+        let reply = "<|SEARCH|>let x = <|cursor|>;<|DIVIDE|>let x = 1;<|REPLACE|>";
+        let llm = MockLlm::new(reply);
+        let coder = Coder::new(llm.clone());
+        let request = json!({
+            "jsonrpc": "2.0", "id": 7, "method": "complete",
+            "params": { "path": path, "content": "fn main() {\n    let x = ??;\n}\n" },
+        });
+
+        let response = handle(&coder, &request.to_string()).await.unwrap();
+        let on_disk = std::fs::read_to_string(&path)?;
+        std::fs::remove_dir_all(&dir)?;
+
+        assert_eq!(response["id"], 7);
+        assert_eq!(response["result"]["content"], "fn main() {\n    let x = 1;\n}\n");
+        assert_eq!(on_disk, "fn main() {}\n");
+        assert!(llm.calls()[0].iter().any(|m| m["content"].as_str().unwrap_or_default().contains("let x = <|cursor|>;")));
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_rewrites_selection() {
+        let coder = Coder::new(MockLlm::new("let y = 2;"));
+        let request = json!({
+            "jsonrpc": "2.0", "id": 1, "method": "rewrite",
+            "params": { "path": "main.rs", "content": "let x = 1;\nlet y = 1;\n", "start": 11, "end": 21, "instruction": "double it" },
+        });
+
+        let response = handle(&coder, &request.to_string()).await.unwrap();
+        let edit = json!({ "start": 11, "end": 21, "text": "let y = 2;" });
+        assert_eq!(response["result"], json!({ "edit": edit, "char_edit": edit }));
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_char_offsets() {
+        let coder = Coder::new(MockLlm::new("let мир = 2;"));
+        // "привет" is 6 chars but 12 bytes, so the second line starts at char 16 but byte 22
+        let content = "let привет = 1;\nlet мир = 1;\n";
+        let request = json!({
+            "jsonrpc": "2.0", "id": 1, "method": "rewrite",
+            "params": { "path": "main.rs", "content": content, "start": 22, "end": 37, "instruction": "double it" },
+        });
+
+        let response = handle(&coder, &request.to_string()).await.unwrap();
+        assert_eq!(response["result"]["edit"], json!({ "start": 22, "end": 37, "text": "let мир = 2;" }));
+        assert_eq!(response["result"]["char_edit"], json!({ "start": 16, "end": 28, "text": "let мир = 2;" }));
+    }
+
+    #[tokio::test]
+    async fn test_serve_reports_errors() -> anyhow::Result<()> {
+        let coder = Coder::new(MockLlm::new(""));
+        let input = concat!(
+            "not json\n",
+            "{\"jsonrpc\": \"2.0\", \"id\": 1, \"method\": \"rename\"}\n",
+            "{\"jsonrpc\": \"2.0\", \"id\": 2, \"method\": \"complete\", \"params\": {\"path\": \"a.rs\"}}\n",
+            "{\"jsonrpc\": \"2.0\", \"id\": 3, \"method\": \"complete\", \"params\": {\"path\": \"a.rs\", \"content\": \"x\"}}\n",
+            "{\"jsonrpc\": \"2.0\", \"method\": \"complete\", \"params\": {}}\n",
+        );
+        let mut output = Vec::new();
+        serve(&coder, input.as_bytes(), &mut output).await?;
+
+        let codes = String::from_utf8(output)?.lines()
+            .map(|line| serde_json::from_str::<Value>(line).map(|r| r["error"]["code"].as_i64()))
+            .collect::<std::result::Result<Vec<_>, _>>()?;
+        assert_eq!(codes, [Some(PARSE_ERROR), Some(METHOD_NOT_FOUND), Some(INVALID_PARAMS), Some(COMPLETION_FAILED)]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_notification_not_completed() {
+        let llm = MockLlm::new("<|SEARCH|>x = <|cursor|>;<|DIVIDE|>x = 1;<|REPLACE|>");
+        let coder = Coder::new(llm.clone());
+        let notification = json!({
+            "jsonrpc": "2.0", "method": "complete", "params": { "path": "a.rs", "content": "x = ??;" },
+        });
+
+        assert_eq!(handle(&coder, &notification.to_string()).await, None);
+        assert!(llm.calls().is_empty());
+    }
+}
@@ -0,0 +1,213 @@
+use async_trait::async_trait;
+use log::debug;
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::sync::Mutex;
+use tokio::sync::oneshot;
+use crate::error::{CoderError, Result};
+use crate::llm::{ChatModel, ChatResponse};
+
+/// Outcome handed to waiters. Errors travel as text since they do not clone.
+type Shared = std::result::Result<ChatResponse, String>;
+
+/// Chat model wrapper that coalesces identical in-flight requests, e.g. the
+/// same marked snippet pasted into two files. The first caller makes the
+/// request, later identical callers await its result instead of calling again.
+/// The model is fixed per wrapped client, so requests are keyed on messages and tools.
+pub struct SingleFlight<M> {
+    inner: M,
+    in_flight: Mutex<HashMap<String, Vec<oneshot::Sender<Shared>>>>,
+}
+
+/// What a caller does for a given key
+enum Role {
+    Leader,
+    Waiter(oneshot::Receiver<Shared>),
+}
+
+impl<M: ChatModel> SingleFlight<M> {
+    pub fn new(inner: M) -> Self {
+        Self { inner, in_flight: Mutex::new(HashMap::new()) }
+    }
+
+    fn join(&self, key: &str) -> Role {
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        match in_flight.get_mut(key) {
+            Some(waiters) => {
+                let (tx, rx) = oneshot::channel();
+                waiters.push(tx);
+                Role::Waiter(rx)
+            }
+            None => {
+                in_flight.insert(key.to_string(), Vec::new());
+                Role::Leader
+            }
+        }
+    }
+
+    /// Runs `call` once per key at a time, sharing its result with identical callers
+    async fn coalesce<F>(&self, key: String, call: impl Fn() -> F) -> Result<ChatResponse>
+    where
+        F: Future<Output = Result<ChatResponse>>,
+    {
+        loop {
+            match self.join(&key) {
+                Role::Leader => {
+                    // Dropping the guard without a result, e.g. when the completion
+                    // is cancelled, releases the key and lets a waiter take over
+                    let guard = Release { in_flight: &self.in_flight, key: &key, taken: false };
+                    let result = call().await;
+                    let shared = result.as_ref()
+                        .map(Clone::clone)
+                        .map_err(ToString::to_string);
+                    for waiter in guard.take() {
+                        let _ = waiter.send(shared.clone());
+                    }
+                    return result;
+                }
+                Role::Waiter(rx) => match rx.await {
+                    Ok(shared) => {
+                        debug!("Reused the reply of an identical in-flight request");
+                        return shared.map_err(CoderError::Shared);
+                    }
+                    // The leader went away without a result, try again
+                    Err(_) => continue,
+                },
+            }
+        }
+    }
+}
+
+/// Removes a key from the registry when the leader finishes or is dropped
+struct Release<'a> {
+    in_flight: &'a Mutex<HashMap<String, Vec<oneshot::Sender<Shared>>>>,
+    key: &'a str,
+    /// Set once `take` removed the key. By the time the guard drops, a new
+    /// leader may have registered the key again, and its entry must stay.
+    taken: bool,
+}
+
+impl Release<'_> {
+    fn take(mut self) -> Vec<oneshot::Sender<Shared>> {
+        self.taken = true;
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        in_flight.remove(self.key).unwrap_or_default()
+    }
+}
+
+impl Drop for Release<'_> {
+    fn drop(&mut self) {
+        if self.taken {
+            return;
+        }
+        let mut in_flight = self.in_flight.lock().unwrap_or_else(|e| e.into_inner());
+        in_flight.remove(self.key);
+    }
+}
+
+#[async_trait]
+impl<M: ChatModel> ChatModel for SingleFlight<M> {
+    async fn chat(&self, messages: Vec<Value>) -> Result<String> {
+        let key = json!({ "messages": messages }).to_string();
+        let response = self.coalesce(key, || {
+            let messages = messages.clone();
+            async move {
+                let content = self.inner.chat(messages).await?;
+                Ok(ChatResponse { content, tool_calls: vec![] })
+            }
+        }).await?;
+        Ok(response.content)
+    }
+
+    async fn chat_with_tools(
+        &self, messages: Vec<Value>, tools: Vec<Value>
+    ) -> Result<ChatResponse> {
+        let key = json!({ "messages": messages, "tools": tools }).to_string();
+        self.coalesce(key, || self.inner.chat_with_tools(messages.clone(), tools.clone())).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::coder::Coder;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::time::Duration;
+
+    /// Model that takes a while to answer and counts its calls
+    #[derive(Clone, Default)]
+    struct SlowCounter {
+        calls: Arc<AtomicUsize>,
+    }
+
+    #[async_trait]
+    impl ChatModel for SlowCounter {
+        async fn chat(&self, _messages: Vec<Value>) -> Result<String> {
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            tokio::time::sleep(Duration::from_millis(50)).await;
+            Ok("<|SEARCH|>let x = <|cursor|>;<|DIVIDE|>let x = 1;<|REPLACE|>".to_string())
+        }
+    }
+
+    #[tokio::test]
+    async fn test_identical_completions_share_one_call() -> anyhow::Result<()> {
+        let model = SlowCounter::default();
+        let coder = Coder::new(SingleFlight::new(model.clone()));
+        let content = "fn main() {\n    let x = ??;\n}\n";
+        let cursor = content.find("??").unwrap();
+
+        let (a, b) = tokio::join!(
+            coder.autocomplete(content, Path::new("a.rs"), cursor),
+            coder.autocomplete(content, Path::new("copy/a.rs"), cursor),
+        );
+
+        assert_eq!(a?, "fn main() {\n    let x = 1;\n}\n");
+        assert_eq!(b?, "fn main() {\n    let x = 1;\n}\n");
+        assert_eq!(model.calls.load(Ordering::SeqCst), 1);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn test_different_requests_are_not_coalesced() {
+        let model = SingleFlight::new(SlowCounter::default());
+        let (a, b) = tokio::join!(
+            model.chat(vec![json!({ "role": "user", "content": "a" })]),
+            model.chat(vec![json!({ "role": "user", "content": "b" })]),
+        );
+        assert!(a.is_ok() && b.is_ok());
+        assert_eq!(model.inner.calls.load(Ordering::SeqCst), 2);
+
+        // Finished requests are not cached
+        model.chat(vec![json!({ "role": "user", "content": "a" })]).await.unwrap();
+        assert_eq!(model.inner.calls.load(Ordering::SeqCst), 3);
+    }
+
+    #[tokio::test]
+    async fn test_cancelled_leader_hands_over() {
+        let model = SingleFlight::new(SlowCounter::default());
+        let messages = vec![json!({ "role": "user", "content": "a" })];
+
+        let mut leader = Box::pin(model.chat(messages.clone()));
+        let mut waiter = Box::pin(model.chat(messages));
+
+        // Start both, then drop the leader before it answers
+        assert!(poll_once(&mut leader).await.is_none());
+        assert!(poll_once(&mut waiter).await.is_none());
+        drop(leader);
+
+        assert!(waiter.await.is_ok());
+        assert_eq!(model.inner.calls.load(Ordering::SeqCst), 2);
+    }
+
+    /// Polls a future once
+    async fn poll_once<F: Future + Unpin>(future: &mut F) -> Option<F::Output> {
+        std::future::poll_fn(|cx| {
+            std::task::Poll::Ready(match std::pin::Pin::new(&mut *future).poll(cx) {
+                std::task::Poll::Ready(output) => Some(output),
+                std::task::Poll::Pending => None,
+            })
+        }).await
+    }
+}
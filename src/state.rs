@@ -1,29 +1,376 @@
-use std::collections::HashMap;
-use std::path::PathBuf;
-use std::sync::Arc;
+use std::collections::{HashMap, HashSet};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex, Weak};
 use tokio::sync::RwLock;
+use crate::activity::Activity;
 use crate::coder::Coder;
+use crate::config::Config;
+use crate::throttle::{CircuitBreaker, CompletionBudget, MarkerLatch, WriteSpacer};
+use crate::utils::content_hash;
+use similar::TextDiff;
+
+/// Lines above a completion watched for changes that may make it stale
+const ABOVE_LINES: usize = 3;
+
+/// Similarity of the lines above below which a completion counts as stale
+const STALE_RATIO: f32 = 0.8;
 
 /// Represents the state of a single file
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Default)]
 pub struct FileState {
-    pub content: String,
+    /// Full text, only kept when diffs of changes are wanted in the log.
+    /// Files with the same content share it through the `ContentStore`.
+    pub content: Option<Arc<str>>,
+    pub hash: u64,
+    pub len: usize,
+    /// TODO tasks already implemented while their comment was kept
+    pub completed_todos: HashSet<String>,
+    /// Template holes the model left unfilled, not asked for again while
+    /// the file holds the same ones
+    pub unfilled_holes: Vec<String>,
+    /// Last completion, tracked to notice when edits above make it stale
+    pub last_completion: Option<Completion>,
+}
+
+/// Text written by a completion and the code it was written under
+#[derive(Debug, Clone, PartialEq)]
+pub struct Completion {
+    pub text: String,
+    pub above: String,
+}
+
+/// What became of a tracked completion after the file changed
+#[derive(Debug, PartialEq)]
+pub enum Tracked {
+    /// Still there with the code above mostly as it was
+    Fresh,
+    /// Still there on this 0-based line, but the code above changed a lot
+    Stale { line: usize },
+    /// Edited or removed, so there is nothing left to track
+    Gone,
+}
+
+impl Completion {
+    /// Tracks the completion at `range` of `content`, unless it only removed text
+    pub fn new(content: &str, range: std::ops::Range<usize>) -> Option<Self> {
+        let text = content.get(range.clone())?;
+        if text.trim().is_empty() {
+            return None;
+        }
+        Some(Self { text: text.to_string(), above: lines_above(content, range.start) })
+    }
+
+    /// Where the completion is in `content`, if it appears exactly once
+    pub fn locate(&self, content: &str) -> Option<usize> {
+        // Ambiguous once the same text appears twice, stay quiet rather than guess
+        let mut found = content.match_indices(self.text.as_str());
+        match (found.next(), found.next()) {
+            (Some((start, _)), None) => Some(start),
+            _ => None,
+        }
+    }
+
+    /// Finds the completion in `content` and compares the code above it with the original
+    pub fn track(&self, content: &str) -> Tracked {
+        let Some(start) = self.locate(content) else {
+            return Tracked::Gone;
+        };
+
+        let above = lines_above(content, start);
+        if TextDiff::from_lines(&self.above, &above).ratio() < STALE_RATIO {
+            Tracked::Stale { line: content[..start].matches('\n').count() }
+        } else {
+            Tracked::Fresh
+        }
+    }
+}
+
+/// Up to `ABOVE_LINES` lines ending at `pos`, including the start of its own line
+fn lines_above(content: &str, pos: usize) -> String {
+    let start = content[..pos].rmatch_indices('\n')
+        .nth(ABOVE_LINES)
+        .map_or(0, |(i, _)| i + 1);
+    content[start..pos].to_string()
+}
+
+impl FileState {
+    pub fn new(content: String, keep_content: bool) -> Self {
+        Self {
+            hash: content_hash(&content),
+            len: content.len(),
+            content: keep_content.then(|| Arc::from(content)),
+            ..Default::default()
+        }
+    }
+
+    /// Tells whether `content` is what this state was built from
+    pub fn matches(&self, content: &str) -> bool {
+        self.len == content.len() && self.hash == content_hash(content)
+    }
+}
+
+/// Interns file contents by hash so identical files, common in monorepos
+/// with vendored or generated code, hold their text once
+#[derive(Default)]
+pub struct ContentStore {
+    entries: HashMap<u64, Weak<str>>,
+    /// Entries after the last sweep of dropped contents
+    swept: usize,
+}
+
+impl ContentStore {
+    /// Shared copy of `content`, reusing a live one with the same text
+    pub fn intern(&mut self, hash: u64, content: String) -> Arc<str> {
+        if let Some(shared) = self.entries.get(&hash).and_then(Weak::upgrade)
+            && *shared == *content
+        {
+            return shared;
+        }
+
+        let shared: Arc<str> = Arc::from(content);
+        self.entries.insert(hash, Arc::downgrade(&shared));
+        if self.entries.len() >= 2 * self.swept.max(64) {
+            self.entries.retain(|_, weak| weak.strong_count() > 0);
+            self.swept = self.entries.len();
+        }
+        shared
+    }
+
+    /// Contents currently referenced by some file state
+    pub fn len(&self) -> usize {
+        self.entries.values().filter(|weak| weak.strong_count() > 0).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
 }
 
 /// Global application state
 pub struct State {
-    pub file2state: HashMap<PathBuf, FileState>,
-    pub coder: Coder,
+    /// Locked on its own, so that dropping states of deleted files doesn't
+    /// wait for running completions, see `State::files`
+    pub file2state: Mutex<HashMap<PathBuf, FileState>>,
+    /// Shared with running completions, which take a copy instead of
+    /// holding the lock while the model answers
+    pub coder: Arc<Coder>,
+    pub config: Arc<Config>,
+    /// Recent completions per file, checked before asking the model
+    pub budget: Mutex<CompletionBudget>,
+    /// Failure streaks per file, pausing files that keep failing
+    pub breaker: Mutex<CircuitBreaker>,
+    /// Files whose current marker already triggered a completion
+    pub latch: Mutex<MarkerLatch>,
+    /// When each file may be written next
+    pub spacer: Mutex<WriteSpacer>,
+    /// Texts of `file2state`, shared between files with the same content
+    pub contents: Mutex<ContentStore>,
+    /// Completions queued and running, for status reports
+    pub activity: Activity,
+    /// Hot files, see `State::pin`
+    pub pinned: Mutex<HashSet<PathBuf>>,
 }
 
 /// Shared state wrapped in Arc<RwLock> for thread-safe access
 pub type SharedState = Arc<RwLock<State>>;
 
 impl State {
-    pub fn new(coder: Coder) -> Self {
+    pub fn new(coder: Coder, config: Config) -> Self {
+        let budget = CompletionBudget::new(config.completion_limit, config.completion_window);
+        let breaker = CircuitBreaker::new(config.failure_limit, config.failure_cooldown);
+        let latch = MarkerLatch::new(config.marker_once);
+        let spacer = WriteSpacer::new(config.write_delay, config.write_interval);
         Self {
-            file2state: HashMap::new(),
-            coder,
+            file2state: Mutex::new(HashMap::new()),
+            coder: Arc::new(coder),
+            config: Arc::new(config),
+            budget: Mutex::new(budget),
+            breaker: Mutex::new(breaker),
+            latch: Mutex::new(latch),
+            spacer: Mutex::new(spacer),
+            contents: Mutex::new(ContentStore::default()),
+            activity: Activity::default(),
+            pinned: Mutex::new(HashSet::new()),
+        }
+    }
+
+    /// Swaps in `coder` and `config` after the config changed. File states are
+    /// kept, the per-file limits start over under the new settings.
+    pub fn reconfigure(&mut self, coder: Coder, config: Config) {
+        let Self { file2state, contents, activity, pinned, .. } = std::mem::replace(self, Self::new(coder, config));
+        self.file2state = file2state;
+        self.contents = contents;
+        self.activity = activity;
+        self.pinned = pinned;
+    }
+
+    /// Marks `path` as edited all the time, so `complete-all` completes it
+    /// ahead of unpinned files
+    pub fn pin(&self, path: &Path) {
+        self.pinned_files().insert(path.to_path_buf());
+    }
+
+    /// Unmarks `path`, telling whether it was pinned
+    pub fn unpin(&self, path: &Path) -> bool {
+        self.pinned_files().remove(path)
+    }
+
+    pub fn is_pinned(&self, path: &Path) -> bool {
+        self.pinned_files().contains(path)
+    }
+
+    /// States of the files seen so far, by path
+    pub fn files(&self) -> std::sync::MutexGuard<'_, HashMap<PathBuf, FileState>> {
+        self.file2state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    fn pinned_files(&self) -> std::sync::MutexGuard<'_, HashSet<PathBuf>> {
+        self.pinned.lock().unwrap_or_else(|e| e.into_inner())
+    }
+
+    /// State of a file holding `content`, keeping its text only when
+    /// configured to and sharing it with files holding the same
+    pub fn file_state(&self, content: String) -> FileState {
+        if !self.config.store_content {
+            return FileState::new(content, false);
+        }
+        let hash = content_hash(&content);
+        let len = content.len();
+        let mut contents = self.contents.lock().unwrap_or_else(|e| e.into_inner());
+        FileState {
+            hash,
+            len,
+            content: Some(contents.intern(hash, content)),
+            ..Default::default()
+        }
+    }
+
+    /// Drops everything kept about `path`, once it is deleted
+    pub fn forget(&self, path: &Path) {
+        self.files().remove(path);
+        self.budget.lock().unwrap_or_else(|e| e.into_inner()).forget(path);
+        self.breaker.lock().unwrap_or_else(|e| e.into_inner()).forget(path);
+        self.latch.lock().unwrap_or_else(|e| e.into_inner()).forget(path);
+        self.spacer.lock().unwrap_or_else(|e| e.into_inner()).forget(path);
+        self.pinned_files().remove(path);
+    }
+
+    /// Forgets the files that no longer exist, returning how many there were.
+    /// Deletions the watcher missed would otherwise be kept for good.
+    pub fn prune(&self) -> usize {
+        let tracked: Vec<PathBuf> = self.files().keys().cloned().collect();
+        let missing: Vec<PathBuf> = tracked.into_iter()
+            .filter(|path| !path.exists())
+            .collect();
+        for path in &missing {
+            self.forget(path);
         }
+        missing.len()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune_drops_deleted_files() -> anyhow::Result<()> {
+        let dir = std::env::temp_dir().join(format!("anycoder-prune-{}", std::process::id()));
+        std::fs::create_dir_all(&dir)?;
+        let kept = dir.join("kept.rs");
+        std::fs::write(&kept, "fn main() {}")?;
+
+        let config = Config::from_lookup(|key| (key == "OPENROUTER_API_KEY").then(|| "sk".to_string()))?;
+        let state = State::new(Coder::new(crate::llm::mock::MockLlm::new("")), config);
+        for path in [&kept, &dir.join("deleted.rs")] {
+            let file_state = state.file_state("fn main() {}".to_string());
+            state.files().insert(path.clone(), file_state);
+        }
+
+        assert_eq!(state.prune(), 1);
+        std::fs::remove_dir_all(&dir)?;
+        assert_eq!(state.files().keys().collect::<Vec<_>>(), [&kept]);
+        Ok(())
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn test_pin_until_forgotten() -> anyhow::Result<()> {
+        let config = Config::from_lookup(|key| (key == "OPENROUTER_API_KEY").then(|| "sk".to_string()))?;
+        let state = State::new(Coder::new(crate::llm::mock::MockLlm::new("")), config);
+        let (hot, deleted) = (Path::new("./hot.rs"), Path::new("./deleted.rs"));
+        state.pin(hot);
+        state.pin(deleted);
+        assert!(state.is_pinned(hot) && state.is_pinned(deleted));
+
+        assert!(state.unpin(hot));
+        assert!(!state.unpin(hot));
+        state.forget(deleted);
+        assert!(!state.is_pinned(deleted));
+        Ok(())
+    }
+
+    #[test]
+    fn test_file_state_matches() {
+        let state = FileState::new("fn main() {}".to_string(), false);
+
+        assert!(state.content.is_none());
+        assert!(state.matches("fn main() {}"));
+        assert!(!state.matches("fn main() { }"));
+        assert!(!state.matches(""));
+    }
+
+    #[test]
+    fn test_completion_tracking() {
+        let content = "fn area(w: f64, h: f64) -> f64 {\n    w * h\n}\n";
+        let start = content.find("w * h").unwrap();
+        let completion = Completion::new(content, start..start + 5).unwrap();
+
+        // Edits elsewhere leave it alone
+        let unrelated = format!("{}\nfn main() {{}}\n", content);
+        assert_eq!(completion.track(&unrelated), Tracked::Fresh);
+
+        // Rewriting the signature right above makes it stale
+        let changed = "fn volume(w: f64, h: f64, d: f64) -> f64 {\n    w * h\n}\n";
+        assert_eq!(completion.track(changed), Tracked::Stale { line: 1 });
+
+        // Touching the completion itself ends tracking
+        assert_eq!(completion.track("fn area(w: f64, h: f64) -> f64 {\n    w + h\n}\n"), Tracked::Gone);
+    }
+
+    #[test]
+    fn test_completion_ignores_deletions() {
+        assert_eq!(Completion::new("abc", 1..1), None);
+        assert_eq!(Completion::new("a  c", 1..3), None);
+    }
+
+    #[test]
+    fn test_file_state_keeps_content() {
+        let state = FileState::new("x".to_string(), true);
+        assert_eq!(state.content.as_deref(), Some("x"));
+    }
+
+    #[test]
+    fn test_identical_contents_are_shared() {
+        let mut store = ContentStore::default();
+        let text = "fn main() {}\n".to_string();
+
+        let a = store.intern(content_hash(&text), text.clone());
+        let b = store.intern(content_hash(&text), text.clone());
+        let other = store.intern(content_hash("x"), "x".to_string());
+        assert!(Arc::ptr_eq(&a, &b));
+        assert!(!Arc::ptr_eq(&a, &other));
+        assert_eq!(store.len(), 2);
+
+        // Once no file holds it, the text is freed
+        drop((a, b));
+        assert_eq!(store.len(), 1);
+    }
+
+    #[test]
+    fn test_hash_collision_keeps_contents_apart() {
+        let mut store = ContentStore::default();
+        let a = store.intern(1, "a".to_string());
+        let b = store.intern(1, "b".to_string());
+        assert_eq!((&*a, &*b), ("a", "b"));
+    }
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::ops::Range;
+use std::path::Path;
+use regex::Regex;
+use serde_json::Value;
+use crate::error::{CoderError, Result};
+use crate::lang::{detect_language, interpolates_dollar_braces};
+
+/// Matches `${name}` holes, the name being an identifier
+const HOLE_PATTERN: &str = r"\$\{([A-Za-z_][A-Za-z0-9_]*)\}";
+
+/// A named placeholder in a scaffold, such as `${body}`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Hole {
+    pub name: String,
+    /// Byte range of the whole `${name}`
+    pub span: Range<usize>,
+}
+
+/// Tells whether a file at `path` may hold holes: it must be in a known
+/// language, and one where `${name}` isn't already interpolation
+pub fn holes_allowed(path: &Path) -> bool {
+    detect_language(path).is_some_and(|language| !interpolates_dollar_braces(language))
+}
+
+/// Holes of `text` in order. A name may appear more than once.
+pub fn find_holes(text: &str) -> Vec<Hole> {
+    let pattern = Regex::new(HOLE_PATTERN).expect("valid hole pattern");
+    pattern.captures_iter(text)
+        .filter_map(|caps| Some(Hole { name: caps[1].to_string(), span: caps.get(0)?.range() }))
+        .collect()
+}
+
+/// Distinct hole names in order of first appearance
+pub fn hole_names(holes: &[Hole]) -> Vec<&str> {
+    let mut names: Vec<&str> = Vec::new();
+    for hole in holes {
+        if !names.contains(&hole.name.as_str()) {
+            names.push(&hole.name);
+        }
+    }
+    names
+}
+
+/// Reads the model's reply, a JSON object from hole names to code.
+/// A reply wrapped in a code fence is accepted.
+pub fn parse_fills(reply: &str) -> Result<HashMap<String, String>> {
+    let json = reply.trim();
+    let json = json.strip_prefix("```")
+        .map(|rest| rest.trim_start_matches(|c: char| c.is_ascii_alphanumeric()))
+        .and_then(|rest| rest.trim_end().strip_suffix("```"))
+        .unwrap_or(json);
+
+    let object = match serde_json::from_str(json) {
+        Ok(Value::Object(object)) => object,
+        _ => return Err(CoderError::InvalidResponse(format!("expected a JSON object of hole fills, got {:?}", reply))),
+    };
+    Ok(object.into_iter()
+        .filter_map(|(name, value)| Some((name, value.as_str()?.to_string())))
+        .collect())
+}
+
+/// Replaces each hole with its fill, everything else is kept as is. Lines
+/// after the first of a fill get the indentation of the hole's line.
+/// Holes without a fill, or with an empty one, stay in place and their
+/// names are returned.
+pub fn fill(text: &str, holes: &[Hole], fills: &HashMap<String, String>) -> (String, Vec<String>) {
+    let mut filled = String::with_capacity(text.len());
+    let mut unfilled = Vec::new();
+    let mut last = 0;
+    for hole in holes {
+        filled.push_str(&text[last..hole.span.start]);
+        match fills.get(&hole.name).filter(|fill| !fill.trim().is_empty()) {
+            Some(fill) => filled.push_str(&indent_lines(fill, line_indent(text, hole.span.start))),
+            None => {
+                filled.push_str(&text[hole.span.clone()]);
+                if !unfilled.contains(&hole.name) {
+                    unfilled.push(hole.name.clone());
+                }
+            }
+        }
+        last = hole.span.end;
+    }
+    filled.push_str(&text[last..]);
+    (filled, unfilled)
+}
+
+/// Leading whitespace of the line holding byte `pos`
+fn line_indent(text: &str, pos: usize) -> &str {
+    let line = &text[text[..pos].rfind('\n').map_or(0, |i| i + 1)..];
+    &line[..line.len() - line.trim_start_matches([' ', '\t']).len()]
+}
+
+/// Indents every line of `code` but the first, blank lines excepted
+fn indent_lines(code: &str, indent: &str) -> String {
+    if indent.is_empty() {
+        return code.to_string();
+    }
+    code.split('\n')
+        .enumerate()
+        .map(|(i, line)| match i {
+            0 => line.to_string(),
+            _ if line.trim().is_empty() => line.to_string(),
+            _ => format!("{}{}", indent, line),
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SCAFFOLD: &str = "fn area(w: f64, h: f64) -> f64 {\n    ${body}\n    ${return}\n}\n// cost: ${cost}\n";
+
+    fn fills(pairs: &[(&str, &str)]) -> HashMap<String, String> {
+        pairs.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect()
+    }
+
+    #[test]
+    fn test_multiple_holes_filled() {
+        let holes = find_holes(SCAFFOLD);
+        assert_eq!(hole_names(&holes), ["body", "return", "cost"]);
+
+        let (filled, unfilled) = fill(SCAFFOLD, &holes, &fills(&[
+            ("body", "let a = w * h;"), ("return", "a"), ("cost", "O(1)"),
+        ]));
+        assert_eq!(filled, "fn area(w: f64, h: f64) -> f64 {\n    let a = w * h;\n    a\n}\n// cost: O(1)\n");
+        assert!(unfilled.is_empty());
+    }
+
+    #[test]
+    fn test_empty_fills_keep_holes() {
+        let holes = find_holes(SCAFFOLD);
+        let (filled, unfilled) = fill(SCAFFOLD, &holes, &fills(&[("body", "let a = w * h;"), ("return", " ")]));
+
+        assert_eq!(filled, "fn area(w: f64, h: f64) -> f64 {\n    let a = w * h;\n    ${return}\n}\n// cost: ${cost}\n");
+        assert_eq!(unfilled, ["return", "cost"]);
+    }
+
+    #[test]
+    fn test_multiline_fill_indented() {
+        let holes = find_holes(SCAFFOLD);
+        let (filled, _) = fill(SCAFFOLD, &holes, &fills(&[
+            ("body", "let a = w * h;\n\nlet b = a;"), ("return", "b"), ("cost", "O(1)"),
+        ]));
+        assert!(filled.contains("{\n    let a = w * h;\n\n    let b = a;\n    b\n}"), "{}", filled);
+    }
+
+    #[test]
+    fn test_repeated_hole_and_non_holes() {
+        let text = "${name} = \"$name ${ not} $${x-y}\"; print(${name})";
+        let holes = find_holes(text);
+        assert_eq!(hole_names(&holes), ["name"]);
+
+        let (filled, _) = fill(text, &holes, &fills(&[("name", "total")]));
+        assert_eq!(filled, "total = \"$name ${ not} $${x-y}\"; print(total)");
+    }
+
+    #[test]
+    fn test_holes_allowed() {
+        assert!(holes_allowed(Path::new("src/area.rs")));
+        assert!(holes_allowed(Path::new("area.py")));
+        assert!(!holes_allowed(Path::new("greet.ts")));
+        assert!(!holes_allowed(Path::new("deploy.sh")));
+        assert!(!holes_allowed(Path::new("Build.kts")));
+        assert!(!holes_allowed(Path::new("index.php")));
+        assert!(!holes_allowed(Path::new("Makefile")));
+    }
+
+    #[test]
+    fn test_parse_fills() -> anyhow::Result<()> {
+        let parsed = parse_fills("```json\n{\"body\": \"x += 1\", \"count\": 3}\n```")?;
+        assert_eq!(parsed, fills(&[("body", "x += 1")]));
+
+        assert!(matches!(parse_fills("body: x += 1"), Err(CoderError::InvalidResponse(_))));
+        Ok(())
+    }
+}
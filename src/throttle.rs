@@ -0,0 +1,369 @@
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use log::info;
+use similar::TextDiff;
+
+/// Line similarity below which a file counts as substantially changed
+const CHANGED_RATIO: f32 = 0.8;
+
+/// Suppresses completions right after startup and during bursts of
+/// filesystem activity such as `git clone` or `cargo build`
+pub struct StormGuard {
+    started_at: Instant,
+    startup_cooldown: Duration,
+    bulk_window: Duration,
+    bulk_threshold: usize,
+    recent: VecDeque<Instant>,
+}
+
+impl StormGuard {
+    /// A `bulk_threshold` of 0 disables bulk detection
+    pub fn new(
+        started_at: Instant, startup_cooldown: Duration,
+        bulk_window: Duration, bulk_threshold: usize,
+    ) -> Self {
+        Self {
+            started_at,
+            startup_cooldown,
+            bulk_window,
+            bulk_threshold,
+            recent: VecDeque::new(),
+        }
+    }
+
+    /// Records an event at `now` and tells whether it may be processed
+    pub fn allow(&mut self, now: Instant) -> bool {
+        if now.duration_since(self.started_at) < self.startup_cooldown {
+            return false;
+        }
+
+        if self.bulk_threshold == 0 {
+            return true;
+        }
+
+        while self.recent.front().is_some_and(|&t| now.duration_since(t) > self.bulk_window) {
+            self.recent.pop_front();
+        }
+        self.recent.push_back(now);
+
+        self.recent.len() <= self.bulk_threshold
+    }
+}
+
+/// Caps the completions of each file within a sliding window, so a file
+/// stuck in a rapid-edit loop can't run up costs
+pub struct CompletionBudget {
+    limit: usize,
+    window: Duration,
+    recent: HashMap<PathBuf, VecDeque<Instant>>,
+}
+
+impl CompletionBudget {
+    /// A `limit` of 0 disables the budget
+    pub fn new(limit: usize, window: Duration) -> Self {
+        Self { limit, window, recent: HashMap::new() }
+    }
+
+    /// Records a completion of `path` at `now` unless the file already had
+    /// `limit` of them within the window
+    pub fn spend(&mut self, path: &Path, now: Instant) -> bool {
+        if self.limit == 0 {
+            return true;
+        }
+
+        let recent = self.recent.entry(path.to_path_buf()).or_default();
+        while recent.front().is_some_and(|&t| now.duration_since(t) >= self.window) {
+            recent.pop_front();
+        }
+        if recent.len() >= self.limit {
+            return false;
+        }
+        recent.push_back(now);
+        true
+    }
+
+    pub fn forget(&mut self, path: &Path) {
+        self.recent.remove(path);
+    }
+}
+
+/// Lets each appearance of a marker trigger one completion, so autosaves of a
+/// file whose marker is still there don't complete it over and over. A marker
+/// only counts as used once its completion was written.
+pub struct MarkerLatch {
+    enabled: bool,
+    fired: HashSet<PathBuf>,
+}
+
+impl MarkerLatch {
+    /// A disabled latch lets every save with a marker through
+    pub fn new(enabled: bool) -> Self {
+        Self { enabled, fired: HashSet::new() }
+    }
+
+    /// Tells whether a save of `path` may complete its marker. A save without
+    /// one re-arms the file, so the next marker added fires again.
+    pub fn allows(&mut self, path: &Path, has_marker: bool) -> bool {
+        if !has_marker {
+            self.fired.remove(path);
+            return true;
+        }
+        !self.enabled || !self.fired.contains(path)
+    }
+
+    /// Marks the marker of `path` as used, once its completion was written
+    pub fn fire(&mut self, path: &Path) {
+        if self.enabled {
+            self.fired.insert(path.to_path_buf());
+        }
+    }
+
+    pub fn forget(&mut self, path: &Path) {
+        self.fired.remove(path);
+    }
+}
+
+/// Failure streak of one file
+#[derive(Default)]
+struct Streak {
+    failures: usize,
+    /// When the breaker tripped and the content it tripped on
+    tripped: Option<(Instant, String)>,
+}
+
+/// Stops completing a file after repeated failed or rejected completions,
+/// until a cooldown passes or the file changes substantially
+pub struct CircuitBreaker {
+    limit: usize,
+    cooldown: Duration,
+    streaks: HashMap<PathBuf, Streak>,
+}
+
+impl CircuitBreaker {
+    /// A `limit` of 0 disables the breaker
+    pub fn new(limit: usize, cooldown: Duration) -> Self {
+        Self { limit, cooldown, streaks: HashMap::new() }
+    }
+
+    /// Tells whether `path` may be completed with `content` at `now`
+    pub fn allow(&mut self, path: &Path, content: &str, now: Instant) -> bool {
+        let Some(streak) = self.streaks.get(path) else {
+            return true;
+        };
+        let Some((tripped_at, tripped_on)) = &streak.tripped else {
+            return true;
+        };
+
+        let cooled_down = now.duration_since(*tripped_at) >= self.cooldown;
+        let changed = TextDiff::from_lines(tripped_on.as_str(), content).ratio() < CHANGED_RATIO;
+        if cooled_down || changed {
+            info!("Completing {:?} again after {} failures", path, streak.failures);
+            self.streaks.remove(path);
+            return true;
+        }
+        false
+    }
+
+    /// Counts a failed completion of `path` with `content`, true when it trips the breaker
+    pub fn failure(&mut self, path: &Path, content: &str, now: Instant) -> bool {
+        if self.limit == 0 {
+            return false;
+        }
+
+        let streak = self.streaks.entry(path.to_path_buf()).or_default();
+        streak.failures += 1;
+        if streak.failures < self.limit || streak.tripped.is_some() {
+            return false;
+        }
+        streak.tripped = Some((now, content.to_string()));
+        true
+    }
+
+    /// A successful completion ends the streak
+    pub fn success(&mut self, path: &Path) {
+        self.streaks.remove(path);
+    }
+
+    pub fn forget(&mut self, path: &Path) {
+        self.streaks.remove(path);
+    }
+}
+
+/// Spaces out our writes to a file so editors that reload on change don't
+/// see a conflict: each write comes at least `delay` after the save that
+/// triggered it and `interval` after the previous write to the same file
+pub struct WriteSpacer {
+    delay: Duration,
+    interval: Duration,
+    last: HashMap<PathBuf, Instant>,
+}
+
+impl WriteSpacer {
+    /// Zero durations write right away
+    pub fn new(delay: Duration, interval: Duration) -> Self {
+        Self { delay, interval, last: HashMap::new() }
+    }
+
+    /// Reserves the next write of `path`, whose save was seen at `saved_at`,
+    /// and returns how long after `now` it may happen
+    pub fn reserve(&mut self, path: &Path, saved_at: Instant, now: Instant) -> Duration {
+        let mut at = (saved_at + self.delay).max(now);
+        if let Some(&last) = self.last.get(path) {
+            at = at.max(last + self.interval);
+        }
+        if !self.interval.is_zero() {
+            self.last.insert(path.to_path_buf(), at);
+        }
+        at - now
+    }
+
+    pub fn forget(&mut self, path: &Path) {
+        self.last.remove(path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_startup_cooldown() {
+        let start = Instant::now();
+        let mut guard = StormGuard::new(start, Duration::from_secs(5), Duration::ZERO, 0);
+
+        assert!(!guard.allow(start + Duration::from_secs(1)));
+        assert!(guard.allow(start + Duration::from_secs(6)));
+    }
+
+    #[test]
+    fn test_bulk_detection() {
+        let start = Instant::now();
+        let window = Duration::from_millis(100);
+        let mut guard = StormGuard::new(start, Duration::ZERO, window, 3);
+
+        let burst = (0..5).map(|i| guard.allow(start + Duration::from_millis(i))).collect::<Vec<_>>();
+        assert_eq!(burst, vec![true, true, true, false, false]);
+
+        // Once the burst has left the window, events flow again
+        assert!(guard.allow(start + Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn test_bulk_detection_disabled() {
+        let start = Instant::now();
+        let mut guard = StormGuard::new(start, Duration::ZERO, Duration::from_secs(1), 0);
+
+        assert!((0..100).all(|_| guard.allow(start)));
+    }
+
+    #[test]
+    fn test_completion_budget() {
+        let start = Instant::now();
+        let minute = Duration::from_secs(60);
+        let mut budget = CompletionBudget::new(10, minute);
+        let path = Path::new("src/main.rs");
+
+        let spent = (0..11).map(|i| budget.spend(path, start + Duration::from_secs(i))).collect::<Vec<_>>();
+        assert_eq!(spent, [vec![true; 10], vec![false]].concat());
+
+        // Other files have their own budget
+        assert!(budget.spend(Path::new("src/lib.rs"), start + Duration::from_secs(11)));
+
+        // The first completion leaves the window after a minute
+        assert!(budget.spend(path, start + minute));
+        assert!(!budget.spend(path, start + minute));
+    }
+
+    #[test]
+    fn test_completion_budget_disabled() {
+        let start = Instant::now();
+        let mut budget = CompletionBudget::new(0, Duration::from_secs(60));
+
+        assert!((0..100).all(|_| budget.spend(Path::new("a.rs"), start)));
+    }
+
+    #[test]
+    fn test_circuit_breaker() {
+        let start = Instant::now();
+        let cooldown = Duration::from_secs(300);
+        let mut breaker = CircuitBreaker::new(3, cooldown);
+        let path = Path::new("src/main.rs");
+        let content = "fn main() {\n    let x = ??;\n}\n";
+
+        assert!(!breaker.failure(path, content, start));
+        assert!(!breaker.failure(path, content, start));
+        assert!(breaker.allow(path, content, start));
+        assert!(breaker.failure(path, content, start));
+        assert!(!breaker.allow(path, content, start));
+
+        // A small edit keeps it open, a cooldown closes it
+        let edited = "fn main() {\n    let x = ??;\n}\n\n";
+        assert!(!breaker.allow(path, edited, start + Duration::from_secs(10)));
+        assert!(breaker.allow(path, edited, start + cooldown));
+        assert!(!breaker.failure(path, content, start + cooldown));
+    }
+
+    #[test]
+    fn test_circuit_breaker_resets() {
+        let start = Instant::now();
+        let mut breaker = CircuitBreaker::new(2, Duration::from_secs(300));
+        let path = Path::new("src/main.rs");
+
+        breaker.failure(path, "a\nb\nc\n", start);
+        breaker.success(path);
+        assert!(!breaker.failure(path, "a\nb\nc\n", start));
+        assert!(breaker.failure(path, "a\nb\nc\n", start));
+
+        // Rewriting most of the file closes it right away
+        assert!(breaker.allow(path, "x\ny\nz\n", start));
+
+        let mut disabled = CircuitBreaker::new(0, Duration::ZERO);
+        assert!((0..100).all(|_| !disabled.failure(path, "a", start)));
+    }
+
+    #[test]
+    fn test_marker_latch() {
+        let mut latch = MarkerLatch::new(true);
+        let path = Path::new("src/main.rs");
+
+        // Saves before the completion is written may still complete it
+        assert!(latch.allows(path, true));
+        assert!(latch.allows(path, true));
+        latch.fire(path);
+        // Autosaves while the marker is still there
+        assert!(!latch.allows(path, true));
+        assert!(!latch.allows(path, true));
+        assert!(latch.allows(Path::new("src/lib.rs"), true));
+
+        // Removed and added again
+        assert!(latch.allows(path, false));
+        assert!(latch.allows(path, true));
+
+        let mut disabled = MarkerLatch::new(false);
+        disabled.fire(path);
+        assert!((0..3).all(|_| disabled.allows(path, true)));
+    }
+
+    #[test]
+    fn test_write_spacer() {
+        let start = Instant::now();
+        let ms = Duration::from_millis;
+        let mut spacer = WriteSpacer::new(ms(200), ms(1000));
+        let path = Path::new("src/main.rs");
+
+        // A completion that took 50ms still waits out the delay after the save
+        assert_eq!(spacer.reserve(path, start, start + ms(50)), ms(150));
+        // One that took longer than the delay writes right away
+        assert_eq!(spacer.reserve(Path::new("src/lib.rs"), start, start + ms(300)), ms(0));
+        // The next write of the same file waits a full interval after the first
+        assert_eq!(spacer.reserve(path, start + ms(300), start + ms(600)), ms(600));
+        assert_eq!(spacer.reserve(path, start + ms(3000), start + ms(3000)), ms(200));
+
+        spacer.forget(path);
+        assert_eq!(spacer.reserve(path, start, start + ms(3300)), ms(0));
+
+        let mut disabled = WriteSpacer::new(Duration::ZERO, Duration::ZERO);
+        assert!((0..3).all(|_| disabled.reserve(path, start, start).is_zero()));
+    }
+}
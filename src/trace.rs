@@ -0,0 +1,146 @@
+use log::{Level, Log, Metadata, Record};
+use std::future::Future;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex};
+use crate::active::ActiveFiles;
+
+tokio::task_local! {
+    static TRACED: bool;
+}
+
+/// Set while some files are traced, holding the others at info level
+static FOCUSED: AtomicBool = AtomicBool::new(false);
+
+/// Files whose completions log at debug and trace level, read from a control
+/// file with one path or glob per line like `ANYCODER_ACTIVE_FILES`
+#[derive(Clone)]
+pub struct TraceFiles(Arc<Mutex<ActiveFiles>>);
+
+impl TraceFiles {
+    pub fn new(source: &Path) -> Self {
+        Self(Arc::new(Mutex::new(ActiveFiles::new(source))))
+    }
+
+    pub fn traces(&self, path: &Path) -> bool {
+        self.0.lock().unwrap_or_else(|e| e.into_inner()).lists(path)
+    }
+}
+
+/// Runs `future` with its logs traced when `traced`, or when the task
+/// running it already is
+pub async fn scope<F: Future>(traced: bool, future: F) -> F::Output {
+    TRACED.scope(traced || is_traced(), future).await
+}
+
+/// Whether the current task works on a traced file
+pub fn is_traced() -> bool {
+    TRACED.try_with(|traced| *traced).unwrap_or(false)
+}
+
+/// Holds debug and trace logs outside traced tasks back while `focused`
+pub fn focus(focused: bool) {
+    FOCUSED.store(focused, Ordering::Relaxed);
+}
+
+/// Logger sending the records of traced tasks through `verbose` and all
+/// others through `base`, dropping their debug and trace records while focused
+pub struct PathLogger {
+    base: env_logger::Logger,
+    verbose: env_logger::Logger,
+}
+
+impl PathLogger {
+    pub fn new(base: env_logger::Logger, verbose: env_logger::Logger) -> Self {
+        Self { base, verbose }
+    }
+
+    /// Installs the logger for the whole process
+    pub fn init(self) -> Result<(), log::SetLoggerError> {
+        log::set_max_level(self.base.filter().max(self.verbose.filter()));
+        log::set_boxed_logger(Box::new(self))
+    }
+
+    fn route(&self, metadata: &Metadata) -> Option<&env_logger::Logger> {
+        if is_traced() {
+            Some(&self.verbose)
+        } else if FOCUSED.load(Ordering::Relaxed) && metadata.level() > Level::Info {
+            None
+        } else {
+            Some(&self.base)
+        }
+    }
+}
+
+impl Log for PathLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.route(metadata).is_some_and(|logger| logger.enabled(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if let Some(logger) = self.route(record.metadata()) {
+            logger.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.base.flush();
+        self.verbose.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+
+    /// Log output shared with the test
+    #[derive(Clone, Default)]
+    struct Captured(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for Captured {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn logger(level: log::LevelFilter, out: &Captured) -> env_logger::Logger {
+        env_logger::Builder::new()
+            .filter_level(level)
+            .format(|buf, record| writeln!(buf, "{} {}", record.level(), record.args()))
+            .target(env_logger::Target::Pipe(Box::new(out.clone())))
+            .build()
+    }
+
+    #[tokio::test]
+    async fn test_only_traced_path_logs_debug() -> anyhow::Result<()> {
+        let source = std::env::temp_dir().join(format!("anycoder-trace-{}.txt", std::process::id()));
+        std::fs::write(&source, "src/traced.rs\n")?;
+        let files = TraceFiles::new(&source);
+        let out = Captured::default();
+        let logger = PathLogger::new(logger(log::LevelFilter::Debug, &out), logger(log::LevelFilter::Trace, &out));
+
+        let log_for = |path: &'static str| {
+            let logger = &logger;
+            scope(files.traces(Path::new(path)), async move {
+                for level in [Level::Info, Level::Debug, Level::Trace] {
+                    logger.log(&Record::builder().level(level).args(format_args!("{}", path)).build());
+                }
+            })
+        };
+        focus(true);
+        log_for("src/traced.rs").await;
+        log_for("src/other.rs").await;
+        focus(false);
+        std::fs::remove_file(&source)?;
+
+        let logged = String::from_utf8(out.0.lock().unwrap().clone())?;
+        assert_eq!(logged, "INFO src/traced.rs\nDEBUG src/traced.rs\nTRACE src/traced.rs\nINFO src/other.rs\n");
+        assert!(!is_traced());
+        Ok(())
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::Result;
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::fs::{File, OpenOptions};
+use std::io::{Read, Write};
+use flate2::Compression;
+use flate2::read::MultiGzDecoder;
+use flate2::write::GzEncoder;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use crate::coder::{Coder, CoderOptions};
+use crate::feedback::Verdict;
+use crate::llm::{ChatModel, ChatResponse};
+
+/// One completion as it happened: the input, the model reply and the result
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Entry {
+    pub path: PathBuf,
+    pub cursor: usize,
+    pub task: Option<String>,
+    pub original: String,
+    pub response: ChatResponse,
+    pub updated: String,
+    pub normalize_newlines: bool,
+    #[serde(default)]
+    pub tab_width: Option<usize>,
+}
+
+/// User verdict on an earlier completion, a label for prompt or model tuning
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct Feedback {
+    pub path: PathBuf,
+    pub verdict: Verdict,
+    /// The completed text and the lines above it when it was made
+    pub completion: String,
+    pub above: String,
+}
+
+/// A transcript line
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum Record {
+    Completion(Entry),
+    Feedback(Feedback),
+}
+
+/// Append-only JSONL log of completions and feedback on them.
+/// Paths ending in `.gz` are written gzip-compressed.
+pub struct Transcript {
+    file: Mutex<File>,
+    compressed: bool,
+}
+
+impl Transcript {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(Self { file: Mutex::new(file), compressed: is_gzip(path) })
+    }
+
+    pub fn record(&self, entry: &Entry) -> Result<()> {
+        self.append(entry)
+    }
+
+    pub fn record_feedback(&self, feedback: &Feedback) -> Result<()> {
+        self.append(feedback)
+    }
+
+    fn append(&self, record: &impl Serialize) -> Result<()> {
+        let mut line = serde_json::to_string(record)?;
+        line.push('\n');
+
+        // One gzip member per record keeps the file valid after every append
+        let bytes = if self.compressed {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+            encoder.write_all(line.as_bytes())?;
+            encoder.finish()?
+        } else {
+            line.into_bytes()
+        };
+
+        let mut file = self.file.lock().unwrap_or_else(|e| e.into_inner());
+        file.write_all(&bytes)?;
+        Ok(())
+    }
+}
+
+fn is_gzip(path: &Path) -> bool {
+    path.extension().is_some_and(|ext| ext == "gz")
+}
+
+/// Reads a transcript, decompressing it when the path ends in `.gz`
+fn read_transcript(path: &Path) -> Result<String> {
+    if !is_gzip(path) {
+        return Ok(std::fs::read_to_string(path)?);
+    }
+    let mut content = String::new();
+    MultiGzDecoder::new(File::open(path)?).read_to_string(&mut content)?;
+    Ok(content)
+}
+
+/// Reads every record of a transcript, skipping blank lines
+pub fn read_records(path: &Path) -> Result<Vec<Record>> {
+    let content = read_transcript(path)?;
+    content.lines().enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(i, line)| serde_json::from_str(line)
+            .map_err(|e| anyhow::anyhow!("{}:{}: {}", path.display(), i + 1, e)))
+        .collect()
+}
+
+/// Reads the completions of a transcript
+pub fn read_entries(path: &Path) -> Result<Vec<Entry>> {
+    Ok(read_records(path)?.into_iter()
+        .filter_map(|record| match record {
+            Record::Completion(entry) => Some(entry),
+            Record::Feedback(_) => None,
+        })
+        .collect())
+}
+
+/// Chat model answering with a recorded reply
+struct RecordedReply(ChatResponse);
+
+#[async_trait]
+impl ChatModel for RecordedReply {
+    async fn chat(&self, _messages: Vec<Value>) -> crate::error::Result<String> {
+        Ok(self.0.content.clone())
+    }
+
+    async fn chat_with_tools(
+        &self, _messages: Vec<Value>, _tools: Vec<Value>
+    ) -> crate::error::Result<ChatResponse> {
+        Ok(self.0.clone())
+    }
+}
+
+/// Outcome of replaying a single entry
+#[derive(Debug, PartialEq)]
+pub enum Replayed {
+    Match,
+    Mismatch { expected: String, actual: String },
+    Failed(String),
+}
+
+/// Runs a recorded completion again, feeding the recorded reply instead of calling the model
+pub async fn replay(entry: &Entry) -> Replayed {
+    // Tool calls on, so both tool and plain text replies are parsed
+    let options = CoderOptions {
+        tool_calls: true,
+        normalize_newlines: entry.normalize_newlines,
+        tab_width: entry.tab_width,
+        ..Default::default()
+    };
+    let coder = Coder::builder(RecordedReply(entry.response.clone())).options(options).build();
+
+    let result = coder.autocomplete_with_task(
+        &entry.original, &entry.path, entry.cursor, entry.task.as_deref()
+    ).await;
+
+    match result {
+        Ok(actual) if actual == entry.updated => Replayed::Match,
+        Ok(actual) => Replayed::Mismatch { expected: entry.updated.clone(), actual },
+        Err(e) => Replayed::Failed(e.to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(reply: &str, updated: &str) -> Entry {
+        let original = "fn main() {\n    let x = ??;\n}\n".to_string();
+        Entry {
+            path: PathBuf::from("main.rs"),
+            cursor: original.find("??").unwrap(),
+            task: None,
+            original,
+            response: ChatResponse { content: reply.to_string(), tool_calls: vec![] },
+            updated: updated.to_string(),
+            normalize_newlines: false,
+            tab_width: None,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_replay() {
+        let reply = "<|SEARCH|>let x = <|cursor|>;<|DIVIDE|>let x = 1;<|REPLACE|>";
+
+        let matching = entry(reply, "fn main() {\n    let x = 1;\n}\n");
+        assert_eq!(replay(&matching).await, Replayed::Match);
+
+        let drifted = entry(reply, "fn main() {\n    let x = 2;\n}\n");
+        assert!(matches!(replay(&drifted).await, Replayed::Mismatch { .. }));
+
+        let broken = entry("no patch here", "");
+        assert!(matches!(replay(&broken).await, Replayed::Failed(_)));
+    }
+
+    #[test]
+    fn test_compressed_transcript_round_trip() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("anycoder-transcript-{}.jsonl.gz", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let first = entry("a", "b");
+        let second = entry("c", "d");
+        Transcript::open(&path)?.record(&first)?;
+        // Reopening appends to the same compressed file
+        Transcript::open(&path)?.record(&second)?;
+
+        let raw = std::fs::read(&path)?;
+        let entries = read_entries(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(&raw[..2], [0x1f, 0x8b]);
+        assert_eq!(entries, vec![first, second]);
+        Ok(())
+    }
+
+    #[test]
+    fn test_record_and_read_entries() -> Result<()> {
+        let path = std::env::temp_dir()
+            .join(format!("anycoder-transcript-{}.jsonl", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        let transcript = Transcript::open(&path)?;
+        let first = entry("a", "b");
+        let second = entry("c", "d");
+        transcript.record(&first)?;
+        let feedback = Feedback {
+            path: PathBuf::from("main.rs"),
+            verdict: Verdict::Accept,
+            completion: "1".to_string(),
+            above: "let x = ".to_string(),
+        };
+        transcript.record_feedback(&feedback)?;
+        transcript.record(&second)?;
+
+        let entries = read_entries(&path)?;
+        let records = read_records(&path)?;
+        std::fs::remove_file(&path)?;
+
+        assert_eq!(entries, vec![first, second]);
+        assert_eq!(records[1], Record::Feedback(feedback));
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use std::collections::HashSet;
+use regex::Regex;
+use crate::coder::CURSOR_MARKER;
+
+/// Matches `// TODO: implement X` style comments in most languages
+pub const DEFAULT_TODO_PATTERN: &str =
+    r"(?m)^(?P<indent>[ \t]*)(?://+|#+|--|/\*+|<!--)[ \t]*TODO:?[ \t]+implement[ \t]+(?P<task>.+?)[ \t]*(?:\*/|-->)?[ \t]*\r?$";
+
+/// A TODO comment found in a file
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Todo {
+    pub task: String,
+    pub indent: String,
+    /// Byte range of the comment line, without its line terminator
+    pub line_start: usize,
+    pub line_end: usize,
+}
+
+/// Treats TODO comments as completion triggers
+#[derive(Debug, Clone)]
+pub struct TodoTrigger {
+    pattern: Regex,
+    remove: bool,
+}
+
+impl TodoTrigger {
+    /// `pattern` must contain a `task` group and may contain an `indent` group
+    pub fn new(pattern: Option<&str>, remove: bool) -> anyhow::Result<Self> {
+        let pattern = Regex::new(pattern.unwrap_or(DEFAULT_TODO_PATTERN))?;
+        if !pattern.capture_names().any(|name| name == Some("task")) {
+            anyhow::bail!("TODO pattern must have a `task` group: {}", pattern);
+        }
+        Ok(Self { pattern, remove })
+    }
+
+    /// Finds the first TODO whose task isn't in `done`
+    pub fn find(&self, content: &str, done: &HashSet<String>) -> Option<Todo> {
+        self.pattern.captures_iter(content)
+            .filter_map(|caps| {
+                let whole = caps.get(0)?;
+                let task = caps.name("task")?.as_str().trim().to_string();
+                let indent = caps.name("indent").map_or("", |m| m.as_str()).to_string();
+
+                let line_start = content[..whole.start()].rfind('\n').map_or(0, |i| i + 1);
+                let line_end = content[whole.end()..].find('\n')
+                    .map_or(content.len(), |i| whole.end() + i);
+                let line_end = if content[..line_end].ends_with('\r') { line_end - 1 } else { line_end };
+
+                Some(Todo { task, indent, line_start, line_end })
+            })
+            .find(|todo| !done.contains(&todo.task))
+    }
+
+    /// Places the marker for `todo`, replacing the comment or going below it.
+    /// Returns the new content and the marker position.
+    pub fn place_marker(&self, content: &str, todo: &Todo) -> (String, usize) {
+        let mut updated = content.to_string();
+        let marker = format!("{}{}", todo.indent, CURSOR_MARKER);
+
+        if self.remove {
+            updated.replace_range(todo.line_start..todo.line_end, &marker);
+            (updated, todo.line_start + todo.indent.len())
+        } else {
+            let newline = if content[todo.line_end..].starts_with("\r\n") { "\r\n" } else { "\n" };
+            updated.insert_str(todo.line_end, &format!("{}{}", newline, marker));
+            (updated, todo.line_end + newline.len() + todo.indent.len())
+        }
+    }
+
+    /// Whether the comment is dropped once implemented
+    pub fn removes_comment(&self) -> bool {
+        self.remove
+    }
+}
+
+/// Treats a line ending with a sentinel, such as two trailing spaces,
+/// as a marker at the end of that line
+#[derive(Debug, Clone)]
+pub struct EolTrigger {
+    sentinel: String,
+}
+
+impl EolTrigger {
+    pub fn new(sentinel: &str) -> anyhow::Result<Self> {
+        if sentinel.is_empty() {
+            anyhow::bail!("end-of-line sentinel must not be empty");
+        }
+        Ok(Self { sentinel: sentinel.to_string() })
+    }
+
+    /// Byte range of the sentinel on the first line ending with it.
+    /// Lines holding nothing but whitespace besides the sentinel don't count.
+    pub fn find(&self, content: &str) -> Option<(usize, usize)> {
+        let mut line_start = 0;
+        for line in content.split_inclusive('\n') {
+            let text = line.trim_end_matches('\n').trim_end_matches('\r');
+            if let Some(before) = text.strip_suffix(self.sentinel.as_str())
+                && !before.trim().is_empty()
+            {
+                let start = line_start + before.len();
+                return Some((start, start + self.sentinel.len()));
+            }
+            line_start += line.len();
+        }
+        None
+    }
+
+    /// Replaces the sentinel with the marker, returning the new content and the marker position
+    pub fn place_marker(&self, content: &str, sentinel: (usize, usize)) -> (String, usize) {
+        let mut updated = content.to_string();
+        updated.replace_range(sentinel.0..sentinel.1, CURSOR_MARKER);
+        (updated, sentinel.0)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn find(content: &str) -> Option<Todo> {
+        TodoTrigger::new(None, true).unwrap().find(content, &HashSet::new())
+    }
+
+    #[test]
+    fn test_detect_todo_per_language() {
+        let cases = [
+            ("fn main() {\n    // TODO: implement parsing of args\n}", "parsing of args"),
+            ("def main():\n    # TODO: implement retry loop\n", "retry loop"),
+            ("-- TODO: implement user lookup\nSELECT 1;", "user lookup"),
+            ("int main() {\n  /* TODO: implement the loop */\n}", "the loop"),
+            ("<body>\n<!-- TODO: implement nav bar -->\n</body>", "nav bar"),
+            ("x = 1\r\n# TODO implement crlf handling\r\n", "crlf handling"),
+        ];
+
+        for (content, task) in cases {
+            let todo = find(content).unwrap_or_else(|| panic!("no todo in {:?}", content));
+            assert_eq!(todo.task, task);
+        }
+    }
+
+    #[test]
+    fn test_ignores_plain_todos() {
+        assert_eq!(find("// TODO: clean this up later"), None);
+        assert_eq!(find("let todo = \"TODO: implement\";"), None);
+    }
+
+    #[test]
+    fn test_skips_done_todos() {
+        let trigger = TodoTrigger::new(None, true).unwrap();
+        let content = "// TODO: implement a\n// TODO: implement b\n";
+
+        let done = HashSet::from(["a".to_string()]);
+        assert_eq!(trigger.find(content, &done).unwrap().task, "b");
+    }
+
+    #[test]
+    fn test_place_marker() {
+        let content = "fn main() {\n    // TODO: implement greeting\n}\n";
+
+        let trigger = TodoTrigger::new(None, true).unwrap();
+        let todo = trigger.find(content, &HashSet::new()).unwrap();
+        let (updated, cursor) = trigger.place_marker(content, &todo);
+        assert_eq!(updated, "fn main() {\n    ??\n}\n");
+        assert_eq!(updated.find(CURSOR_MARKER), Some(cursor));
+
+        let trigger = TodoTrigger::new(None, false).unwrap();
+        let (updated, cursor) = trigger.place_marker(content, &todo);
+        assert_eq!(updated, "fn main() {\n    // TODO: implement greeting\n    ??\n}\n");
+        assert_eq!(updated.find(CURSOR_MARKER), Some(cursor));
+    }
+
+    #[test]
+    fn test_eol_trigger() {
+        let trigger = EolTrigger::new("  ").unwrap();
+
+        let content = "fn main() {\n    let x =  \r\n}\n";
+        let sentinel = trigger.find(content).unwrap();
+        let (updated, cursor) = trigger.place_marker(content, sentinel);
+        assert_eq!(updated, "fn main() {\n    let x =??\r\n}\n");
+        assert_eq!(updated.find(CURSOR_MARKER), Some(cursor));
+
+        // The last line may lack a terminator
+        assert!(trigger.find("let y = 1;\nlet x = ;;").is_none());
+        assert_eq!(EolTrigger::new(";;").unwrap().find("let y = 1;\nlet x = ;;"), Some((19, 21)));
+    }
+
+    #[test]
+    fn test_eol_trigger_ignores_normal_saves() {
+        let trigger = EolTrigger::new("  ").unwrap();
+
+        assert_eq!(trigger.find("fn main() {\n    let x = 1;\n}\n"), None);
+        assert_eq!(trigger.find("fn main() {\n    \n}\n"), None, "indented blank line");
+        assert_eq!(trigger.find("a \nb\n"), None, "single trailing space");
+        assert!(EolTrigger::new("").is_err());
+    }
+
+    #[test]
+    fn test_custom_pattern_requires_task_group() {
+        assert!(TodoTrigger::new(Some(r"FIXME: (?P<task>.+)"), true).is_ok());
+        assert!(TodoTrigger::new(Some(r"FIXME: (.+)"), true).is_err());
+    }
+}
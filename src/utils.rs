@@ -1,6 +1,9 @@
 pub const DEFAULT_IGNORE_DIRS: &[&str] = &[
     // Version control and IDEs
     ".git", ".idea", ".vscode", ".vim", ".netrwhist", ".vs",
+
+    // Watcher health probes
+    ".anycoder-watchdog",
     
     // Build artifacts and output directories
     "node_modules", "dist", "target", "build", "out", "bin", "obj",
@@ -117,6 +120,10 @@ pub const DEFAULT_IGNORE_FILES: &[&str] = &[
     
     // Temporary and backup files
     "*.tmp", "*.swp", "*.swo", "*.bak", "*.orig", "*~",
+    // Written by editors saving via rename: JetBrains temp files and vim's write probe
+    "*___jb_tmp___", "*___jb_old___", "4913",
+    // Completions written next to the original in sidecar mode
+    "*.anycoder",
     
     // Log files
     "*.log",
@@ -168,12 +175,32 @@ pub fn get_ignore_files() -> Vec<&'static str> {
 }
 
 /// Checks if any part of the path matches an ignored directory
-pub fn is_ignored_dir(path: &std::path::PathBuf) -> bool {
-    let ignore_dirs = get_ignore_dirs();
-    path.iter()
-        .any(|p| 
-            ignore_dirs.contains(&p.to_string_lossy().as_ref())
-        )
+pub fn is_ignored_dir(path: &std::path::Path) -> bool {
+    matches_ignored_dir(&relative_slash_path(path), &get_ignore_dirs())
+}
+
+/// Plain names match a directory anywhere in `path`. Entries with a `/` are
+/// globs anchored at the watched directory, such as `target/**` or
+/// `build/generated`, and ignore what they match along with everything below it.
+/// Entries with only wildcards, such as `*.egg-info`, match a directory anywhere.
+fn matches_ignored_dir(path: &str, ignore_dirs: &[&str]) -> bool {
+    let components = path.split('/').filter(|c| !c.is_empty() && *c != ".").collect::<Vec<_>>();
+    ignore_dirs.iter().any(|dir| {
+        let dir = dir.replace('\\', "/");
+        let dir = dir.trim_end_matches('/');
+        let dir = dir.strip_prefix("./").unwrap_or(dir);
+        if !dir.contains(['/', '*', '?']) {
+            return components.contains(&dir);
+        }
+        let Ok(glob) = glob_to_regex(dir) else {
+            return false;
+        };
+        if dir.contains('/') {
+            (1..=components.len()).any(|end| glob.is_match(&components[..end].join("/")))
+        } else {
+            components.iter().any(|c| glob.is_match(c))
+        }
+    })
 }
 
 /// Checks if a file should be ignored based on its name or extension
@@ -192,20 +219,113 @@ pub fn is_ignored_file(file_name: &str) -> bool {
 }
 
 /// Checks if a path should be ignored (either directory or file)
-pub fn is_ignored_path(path: &std::path::PathBuf) -> bool {
+pub fn is_ignored_path(path: &std::path::Path) -> bool {
     // Check if any directory in the path should be ignored
     if is_ignored_dir(path) {
         return true;
     }
     
-    // Check if the file itself should be ignored
-    if let Some(file_name) = path.file_name() {
-        if let Some(file_name_str) = file_name.to_str() {
-            return is_ignored_file(file_name_str);
+    // Check if the file itself should be ignored, by the last component whatever the separator
+    let path = slash_path(path);
+    match path.rsplit('/').next() {
+        Some(file_name) if !file_name.is_empty() => is_ignored_file(file_name),
+        _ => false,
+    }
+}
+
+/// `path` with `/` separators on every platform, without the `\\?\` prefix
+/// Windows puts in front of verbatim absolute paths
+pub fn slash_path(path: &std::path::Path) -> String {
+    let path = path.to_string_lossy();
+    let path = path.strip_prefix(r"\\?\").unwrap_or(&path);
+    path.replace('\\', "/")
+}
+
+/// `path` relative to the working directory with `/` separators, the way
+/// ignore entries and control file patterns are written
+pub fn relative_slash_path(path: &std::path::Path) -> String {
+    let cwd = std::env::current_dir().map(|dir| slash_path(&dir)).unwrap_or_default();
+    strip_base(&slash_path(path), &cwd)
+}
+
+/// `path` relative to `base`, both `/`-separated, or `path` without a leading
+/// `./` when it isn't below `base`. Drive letters compare case-insensitively.
+fn strip_base(path: &str, base: &str) -> String {
+    let base = base.trim_end_matches('/');
+    let lower_drive = |p: &str| match p.as_bytes() {
+        [letter, b':', ..] if letter.is_ascii_alphabetic() => format!("{}{}", p[..1].to_ascii_lowercase(), &p[1..]),
+        _ => p.to_string(),
+    };
+    let (path_key, base_key) = (lower_drive(path), lower_drive(base));
+    let mut rest = match path_key.strip_prefix(&base_key) {
+        Some(rest) if !base.is_empty() && (rest.is_empty() || rest.starts_with('/')) => &path[base.len()..],
+        _ => path,
+    };
+    rest = rest.trim_start_matches('/');
+    while let Some(stripped) = rest.strip_prefix("./") {
+        rest = stripped.trim_start_matches('/');
+    }
+    rest.to_string()
+}
+
+/// Regex for a `/`-separated glob: `*` and `?` stay within a path segment, `**` spans segments
+pub fn glob_to_regex(glob: &str) -> Result<regex::Regex, regex::Error> {
+    let mut pattern = String::from("^");
+    let mut chars = glob.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '*' if chars.peek() == Some(&'*') => {
+                chars.next();
+                // `**/` also matches no directory at all
+                if chars.peek() == Some(&'/') {
+                    chars.next();
+                    pattern.push_str("(?:.*/)?");
+                } else {
+                    pattern.push_str(".*");
+                }
+            }
+            '*' => pattern.push_str("[^/]*"),
+            '?' => pattern.push_str("[^/]"),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
         }
     }
-    
-    false
+    pattern.push('$');
+    regex::Regex::new(&pattern)
+}
+
+/// Where sidecar mode writes the completed version of `path`, `<file>.anycoder`
+pub fn sidecar_path(path: &std::path::Path) -> std::path::PathBuf {
+    let mut name = path.as_os_str().to_os_string();
+    name.push(".anycoder");
+    std::path::PathBuf::from(name)
+}
+
+/// Collects files under `dir`, applying ignore rules below `root` only
+pub fn collect_files(
+    root: &std::path::Path, dir: &std::path::Path, files: &mut Vec<std::path::PathBuf>
+) -> std::io::Result<()> {
+    for entry in std::fs::read_dir(dir)? {
+        let path = entry?.path();
+        if is_ignored_path(path.strip_prefix(root).unwrap_or(&path)) {
+            continue;
+        }
+        if path.is_dir() {
+            collect_files(root, &path, files)?;
+        } else if path.is_file() {
+            files.push(path);
+        }
+    }
+    Ok(())
+}
+
+/// Byte offsets of every `marker` in `content`, in order. Matches don't
+/// overlap, so `???` holds one `??` and `????` two. Offsets always fall on
+/// char boundaries, an empty marker is found nowhere.
+pub fn find_markers(content: &str, marker: &str) -> Vec<usize> {
+    if marker.is_empty() {
+        return Vec::new();
+    }
+    content.match_indices(marker).map(|(pos, _)| pos).collect()
 }
 
 /// Converts a byte index to a line and column number
@@ -231,18 +351,173 @@ pub fn byte_to_point(b: usize, s: &str) -> (usize, usize) {
     (line, col)
 }
 
-pub fn has_content_changed(old: Option<&String>, new: &str) -> bool {
-    match old {
-        Some(old_content) => old_content != new,
-        None => true,
+/// Offsets of the `\r` bytes dropped when normalizing CRLF to LF
+#[derive(Debug, Default)]
+pub struct NewlineMap {
+    /// Normalized offsets of the `\n` that each dropped `\r` preceded
+    removed: Vec<usize>,
+}
+
+impl NewlineMap {
+    /// Converts CRLF line endings to LF, remembering where they were
+    pub fn normalize(text: &str) -> (String, Self) {
+        let mut normalized = String::with_capacity(text.len());
+        let mut removed = Vec::new();
+
+        let mut rest = text;
+        while let Some(i) = rest.find("\r\n") {
+            normalized.push_str(&rest[..i]);
+            removed.push(normalized.len());
+            rest = &rest[i + 1..];
+        }
+        normalized.push_str(rest);
+
+        (normalized, Self { removed })
+    }
+
+    /// Maps an offset in the original text to the normalized text
+    pub fn to_normalized(&self, offset: usize) -> usize {
+        let dropped = self.removed.iter().enumerate()
+            .take_while(|(i, p)| *p + i < offset)
+            .count();
+        offset - dropped
+    }
+
+    /// Maps an offset in the normalized text back to the original text.
+    /// Offsets at a line break land before its `\r`.
+    pub fn to_original(&self, offset: usize) -> usize {
+        offset + self.removed.partition_point(|&p| p < offset)
+    }
+}
+
+/// Expands the tabs in the indentation of every line to spaces, with tab
+/// stops every `width` columns. Tabs after the indentation are kept.
+pub fn expand_indent(text: &str, width: usize) -> String {
+    text.split_inclusive('\n')
+        .map(|line| {
+            let indent_len = line.len() - line.trim_start_matches([' ', '\t']).len();
+            let columns = indent_columns(&line[..indent_len], width);
+            format!("{}{}", " ".repeat(columns), &line[indent_len..])
+        })
+        .collect()
+}
+
+/// `updated`, completed from `expand_indent(original, width)`, with tabs
+/// put back: lines left as they were keep their original bytes, changed and
+/// new lines are indented with tabs, spaces making up any remainder.
+pub fn restore_indent(original: &str, updated: &str, width: usize) -> String {
+    let expanded = expand_indent(original, width);
+    let original_lines: Vec<&str> = original.split_inclusive('\n').collect();
+    let diff = similar::TextDiff::from_lines(expanded.as_str(), updated);
+
+    let mut restored = String::with_capacity(updated.len());
+    for op in diff.ops() {
+        let (tag, old, new) = op.as_tag_tuple();
+        match tag {
+            similar::DiffTag::Equal => original_lines[old].iter().for_each(|line| restored.push_str(line)),
+            similar::DiffTag::Delete => {}
+            _ => {
+                for line in updated.split_inclusive('\n').skip(new.start).take(new.len()) {
+                    let indent_len = line.len() - line.trim_start_matches([' ', '\t']).len();
+                    let columns = indent_columns(&line[..indent_len], width);
+                    restored.push_str(&"\t".repeat(columns / width));
+                    restored.push_str(&" ".repeat(columns % width));
+                    restored.push_str(&line[indent_len..]);
+                }
+            }
+        }
+    }
+    restored
+}
+
+/// Visual width of `indent`, made of tabs and spaces
+fn indent_columns(indent: &str, width: usize) -> usize {
+    indent.chars().fold(0, |column, c| match c {
+        '\t' => (column / width + 1) * width,
+        _ => column + 1,
+    })
+}
+
+/// The line ending used by most lines of `text`
+pub fn dominant_newline(text: &str) -> &'static str {
+    let crlf = text.matches("\r\n").count();
+    let lf = text.matches('\n').count() - crlf;
+    if crlf > lf { "\r\n" } else { "\n" }
+}
+
+/// UTF-8 byte order mark some editors put at the start of files
+pub const BOM: char = '\u{feff}';
+
+/// Drops a leading byte order mark so offsets count from the first real char.
+/// Returns whether there was one, to put it back on write.
+pub fn strip_bom(content: String) -> (String, bool) {
+    match content.strip_prefix(BOM) {
+        Some(rest) => (rest.to_string(), true),
+        None => (content, false),
+    }
+}
+
+/// Fast fingerprint of file content for change detection
+pub fn content_hash(content: &str) -> u64 {
+    use std::hash::{DefaultHasher, Hash, Hasher};
+
+    let mut hasher = DefaultHasher::new();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Shortens text for logging to at most `limit` chars, leaving it intact without a limit
+pub fn truncate_for_log(text: &str, limit: Option<usize>) -> String {
+    match limit {
+        Some(limit) if text.chars().count() > limit => {
+            let kept: String = text.chars().take(limit).collect();
+            format!("{}… ({} bytes)", kept, text.len())
+        }
+        _ => text.to_string(),
+    }
+}
+
+/// Extracts the message carried by a panic payload
+pub fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        message.to_string()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic".to_string()
     }
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
-    use std::path::PathBuf;
+    use std::path::{Path, PathBuf};
     
+    #[test]
+    fn test_find_markers() {
+        assert!(find_markers("let x = 1;", "??").is_empty());
+        assert!(find_markers("", "??").is_empty());
+        assert!(find_markers("??", "").is_empty());
+
+        assert_eq!(find_markers("let x = ??;", "??"), [8]);
+        assert_eq!(find_markers("??\nlet x = ??;\n??", "??"), [0, 11, 15]);
+
+        // Overlapping candidates are matched left to right
+        assert_eq!(find_markers("x = ???;", "??"), [4]);
+        assert_eq!(find_markers("x = ????;", "??"), [4, 6]);
+    }
+
+    #[test]
+    fn test_find_markers_next_to_multibyte_chars() {
+        let text = "привет??мир ??é??";
+        let markers = find_markers(text, "??");
+        assert_eq!(markers, [12, 21, 25]);
+        assert!(markers.iter().all(|&pos| text.is_char_boundary(pos) && &text[pos..pos + 2] == "??"));
+
+        assert_eq!(find_markers("日本??語", "??"), [6]);
+        assert_eq!(find_markers("a ⁇ b ?? c", "??"), [8]);
+    }
+
     #[test]
     fn test_byte_to_point_ascii() {
         let text = "hello\nworld";
@@ -270,6 +545,36 @@ mod tests {
         assert!(is_ignored_dir(&path));
     }
     
+    #[test]
+    fn test_windows_paths_ignored() {
+        assert!(is_ignored_dir(Path::new(r"src\node_modules\package")));
+        assert!(is_ignored_dir(Path::new(r".\target\debug\build.rs")));
+        assert!(!is_ignored_dir(Path::new(r"src\main.rs")));
+        assert!(is_ignored_path(Path::new(r"src\.DS_Store")));
+        assert!(!is_ignored_path(Path::new(r"src\app\main.rs")));
+
+        let dirs = ["target/**", r"build\generated", "*.egg-info", "vendor"];
+        assert!(matches_ignored_dir("target/debug/main.rs", &dirs));
+        assert!(matches_ignored_dir(&slash_path(Path::new(r"build\generated\api.rs")), &dirs));
+        assert!(matches_ignored_dir("pkg/anycoder.egg-info/PKG-INFO", &dirs));
+        assert!(matches_ignored_dir("third_party/vendor/lib.rs", &dirs));
+        assert!(!matches_ignored_dir("src/target/main.rs", &dirs));
+        assert!(!matches_ignored_dir("build/main.rs", &dirs));
+    }
+
+    #[test]
+    fn test_slash_paths() {
+        assert_eq!(slash_path(Path::new(r"src\main.rs")), "src/main.rs");
+        assert_eq!(slash_path(Path::new(r"\\?\C:\proj\src\main.rs")), "C:/proj/src/main.rs");
+        assert_eq!(strip_base("C:/proj/src/main.rs", "c:/proj"), "src/main.rs");
+        assert_eq!(strip_base("c:/proj/src/main.rs", "C:/proj/"), "src/main.rs");
+        assert_eq!(strip_base("C:/project/main.rs", "C:/proj"), "C:/project/main.rs");
+        assert_eq!(strip_base("./src/main.rs", "/home/me/proj"), "src/main.rs");
+        assert_eq!(strip_base("/home/me/proj/src/main.rs", "/home/me/proj"), "src/main.rs");
+        // Only drive letters ignore case
+        assert_eq!(strip_base("/home/Me/proj/a.rs", "/home/me/proj"), "home/Me/proj/a.rs");
+    }
+
     #[test]
     fn test_is_ignored_file() {
         assert!(is_ignored_file(".DS_Store"));
@@ -283,6 +588,73 @@ mod tests {
         assert!(!is_ignored_file("config.json"));
     }
     
+    #[test]
+    fn test_newline_map() {
+        let text = "a\r\nbc\nd\r\n";
+        let (normalized, map) = NewlineMap::normalize(text);
+        assert_eq!(normalized, "a\nbc\nd\n");
+
+        // "d" sits at 6 in the original and at 5 once normalized
+        assert_eq!(map.to_normalized(6), 5);
+        assert_eq!(map.to_original(5), 6);
+
+        // A line break maps to just before its "\r"
+        assert_eq!(map.to_original(1), 1);
+        assert_eq!(map.to_original(2), 3);
+        assert_eq!(map.to_original(normalized.len()), text.len());
+    }
+
+    #[test]
+    fn test_indent_round_trip() {
+        let text = "fn main() {\n\tif x {\n\t  \ty();\n\t}\n  // aligned\tcomment\n}\n";
+        let expanded = expand_indent(text, 4);
+        assert_eq!(expanded, "fn main() {\n    if x {\n        y();\n    }\n  // aligned\tcomment\n}\n");
+
+        // Unchanged lines come back byte for byte, even those indented with spaces
+        assert_eq!(restore_indent(text, &expanded, 4), text);
+
+        let updated = expanded.replace("        y();\n", "        y();\n        z(1,\n          2);\n");
+        assert_eq!(
+            restore_indent(text, &updated, 4),
+            "fn main() {\n\tif x {\n\t  \ty();\n\t\tz(1,\n\t\t  2);\n\t}\n  // aligned\tcomment\n}\n"
+        );
+    }
+
+    #[test]
+    fn test_dominant_newline() {
+        assert_eq!(dominant_newline("a\r\nb\r\nc\n"), "\r\n");
+        assert_eq!(dominant_newline("a\nb\r\nc\n"), "\n");
+        assert_eq!(dominant_newline("single line"), "\n");
+    }
+
+    #[test]
+    fn test_strip_bom() {
+        assert_eq!(strip_bom("\u{feff}x = ??;".to_string()), ("x = ??;".to_string(), true));
+        assert_eq!(strip_bom("x = ??;".to_string()), ("x = ??;".to_string(), false));
+    }
+
+    #[test]
+    fn test_content_hash() {
+        assert_eq!(content_hash("abc"), content_hash("abc"));
+        assert_ne!(content_hash("abc"), content_hash("abd"));
+    }
+
+    #[test]
+    fn test_truncate_for_log() {
+        assert_eq!(truncate_for_log("secret", None), "secret");
+        assert_eq!(truncate_for_log("secret", Some(10)), "secret");
+        assert_eq!(truncate_for_log("секрет", Some(3)), "сек… (12 bytes)");
+    }
+
+    #[test]
+    fn test_panic_message() {
+        let payload = std::panic::catch_unwind(|| panic!("boom {}", 42)).unwrap_err();
+        assert_eq!(panic_message(&*payload), "boom 42");
+
+        let payload = std::panic::catch_unwind(|| panic!("static")).unwrap_err();
+        assert_eq!(panic_message(&*payload), "static");
+    }
+
     #[test]
     fn test_is_ignored_path() {
         let path = PathBuf::from("src/node_modules/package.json");
@@ -0,0 +1,135 @@
+use log::debug;
+use std::path::Path;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::process::Command;
+
+/// Placeholder in the command template replaced by the candidate file
+pub const FILE_PLACEHOLDER: &str = "{file}";
+
+/// Why a candidate was rejected
+#[derive(Debug, thiserror::Error)]
+pub enum ValidationError {
+    #[error("validator exited with {status}: {output}")]
+    Failed { status: std::process::ExitStatus, output: String },
+
+    #[error("validator did not finish within {0:?}")]
+    TimedOut(Duration),
+
+    #[error("validator could not run: {0}")]
+    Io(#[from] std::io::Error),
+}
+
+/// Runs an external command, such as `python -m py_compile {file}`, on a
+/// completed file before it is written. The candidate goes to a temp file
+/// with the original's extension, the original is never touched.
+#[derive(Debug, Clone)]
+pub struct Validator {
+    template: String,
+    timeout: Duration,
+}
+
+impl Validator {
+    pub fn new(template: impl Into<String>, timeout: Duration) -> Self {
+        Self { template: template.into(), timeout }
+    }
+
+    /// Passes when the command exits successfully on `content` in time.
+    /// A validator still running at the timeout is killed.
+    pub async fn check(&self, path: &Path, content: &str) -> Result<(), ValidationError> {
+        let dir = std::env::temp_dir().join(format!("anycoder-validate-{}", std::process::id()));
+        tokio::fs::create_dir_all(&dir).await?;
+        let name = path.file_name().map_or("candidate".into(), |n| n.to_string_lossy());
+        // Concurrent checks of files with the same name must not share a file
+        let candidate = dir.join(format!("{}-{}", unique_id(), name));
+        tokio::fs::write(&candidate, content).await?;
+
+        let result = self.run(&candidate).await;
+        let _ = tokio::fs::remove_file(&candidate).await;
+        result
+    }
+
+    async fn run(&self, candidate: &Path) -> Result<(), ValidationError> {
+        let command = self.template.replace(FILE_PLACEHOLDER, &shell_quote(candidate));
+        debug!("validate {}", command);
+
+        let child = shell(&command)
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .kill_on_drop(true)
+            .spawn()?;
+        let output = tokio::time::timeout(self.timeout, child.wait_with_output()).await
+            .map_err(|_| ValidationError::TimedOut(self.timeout))??;
+
+        if output.status.success() {
+            return Ok(());
+        }
+        let mut text = String::from_utf8_lossy(&output.stderr).trim().to_string();
+        if text.is_empty() {
+            text = String::from_utf8_lossy(&output.stdout).trim().to_string();
+        }
+        Err(ValidationError::Failed { status: output.status, output: text })
+    }
+}
+
+fn unique_id() -> usize {
+    static NEXT: std::sync::atomic::AtomicUsize = std::sync::atomic::AtomicUsize::new(0);
+    NEXT.fetch_add(1, std::sync::atomic::Ordering::Relaxed)
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(windows)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Quotes `path` as a single shell word
+fn shell_quote(path: &Path) -> String {
+    let path = path.to_string_lossy();
+    if cfg!(windows) {
+        format!("\"{}\"", path)
+    } else {
+        format!("'{}'", path.replace('\'', r"'\''"))
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn test_exit_status_gates_candidate() {
+        let validator = Validator::new("grep -q 'x = 1' {file}", Duration::from_secs(5));
+        let path = Path::new("src/it's main.rs");
+
+        assert!(validator.check(path, "x = 1;\n").await.is_ok());
+        let failed = validator.check(path, "x = ;\n").await;
+        assert!(matches!(failed, Err(ValidationError::Failed { .. })));
+    }
+
+    #[tokio::test]
+    async fn test_slow_validator_times_out() {
+        let validator = Validator::new("sleep 5", Duration::from_millis(100));
+        let start = std::time::Instant::now();
+
+        let result = validator.check(Path::new("main.py"), "print(1)\n").await;
+        assert!(matches!(result, Err(ValidationError::TimedOut(_))));
+        assert!(start.elapsed() < Duration::from_secs(2));
+    }
+
+    #[tokio::test]
+    async fn test_failure_output_reported() {
+        let validator = Validator::new("echo broken >&2; exit 3", Duration::from_secs(5));
+        let error = validator.check(Path::new("main.rs"), "").await.unwrap_err();
+        assert!(error.to_string().contains("broken"));
+    }
+}
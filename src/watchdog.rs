@@ -0,0 +1,107 @@
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+
+/// Directory under the watched root holding the probe file
+pub const PROBE_DIR: &str = ".anycoder-watchdog";
+
+/// Checks that the file watcher still delivers events. Each probe touches
+/// a file under the watched root, a probe still unanswered when the next
+/// one is due means the watcher went quiet and has to be rebuilt.
+pub struct Watchdog {
+    probe: PathBuf,
+    /// When the unanswered probe was written
+    pending: Option<Instant>,
+    probes: u64,
+}
+
+impl Drop for Watchdog {
+    /// Removes the probe, and its directory unless something else was put in it
+    fn drop(&mut self) {
+        if self.probes == 0 {
+            return;
+        }
+        let _ = std::fs::remove_file(&self.probe);
+        if let Some(dir) = self.probe.parent() {
+            let _ = std::fs::remove_dir(dir);
+        }
+    }
+}
+
+impl Watchdog {
+    pub fn new(root: &Path) -> Self {
+        Self { probe: root.join(PROBE_DIR).join("probe"), pending: None, probes: 0 }
+    }
+
+    /// Tells whether watcher event `path` comes from a probe
+    pub fn is_probe(path: &Path) -> bool {
+        path.iter().any(|part| part == PROBE_DIR)
+    }
+
+    /// Records that an event for the probe arrived
+    pub fn answered(&mut self) {
+        self.pending = None;
+    }
+
+    /// Writes the next probe at `now`. Returns how long ago the previous
+    /// one was written if it went unanswered, in which case the watcher is dead.
+    pub fn probe(&mut self, now: Instant) -> std::io::Result<Option<Duration>> {
+        let dead = self.pending.map(|written| now.duration_since(written));
+        if let Some(dir) = self.probe.parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+        self.probes += 1;
+        std::fs::write(&self.probe, self.probes.to_string())?;
+        self.pending = Some(now);
+        Ok(dead)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::{recommended_watcher, RecursiveMode, Watcher};
+
+    fn temp_root(name: &str) -> PathBuf {
+        let root = std::env::temp_dir().join(format!("anycoder-{}-{}", name, std::process::id()));
+        std::fs::create_dir_all(&root).unwrap();
+        root
+    }
+
+    #[test]
+    fn test_unanswered_probe_means_dead() -> std::io::Result<()> {
+        let root = temp_root("watchdog-dead");
+        let mut watchdog = Watchdog::new(&root);
+
+        let start = Instant::now();
+        assert_eq!(watchdog.probe(start)?, None);
+        watchdog.answered();
+        assert_eq!(watchdog.probe(start)?, None);
+        // Nothing answered the second probe
+        let later = start + Duration::from_secs(30);
+        assert_eq!(watchdog.probe(later)?, Some(Duration::from_secs(30)));
+
+        drop(watchdog);
+        assert!(!root.join(PROBE_DIR).exists());
+        std::fs::remove_dir_all(&root)?;
+        assert!(Watchdog::is_probe(Path::new("./.anycoder-watchdog/probe")));
+        assert!(!Watchdog::is_probe(Path::new("./src/main.rs")));
+        Ok(())
+    }
+
+    #[test]
+    fn test_live_watcher_answers_probe() -> anyhow::Result<()> {
+        let root = temp_root("watchdog-live");
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut watcher = recommended_watcher(tx)?;
+        watcher.watch(&root, RecursiveMode::Recursive)?;
+
+        let mut watchdog = Watchdog::new(&root);
+        watchdog.probe(Instant::now())?;
+        let event = rx.recv_timeout(Duration::from_secs(5))??;
+        assert!(event.paths.iter().any(|path| Watchdog::is_probe(path)), "{:?}", event);
+
+        drop(watcher);
+        std::fs::remove_dir_all(&root)?;
+        Ok(())
+    }
+}